@@ -0,0 +1,85 @@
+use std::str::FromStr;
+
+/// How problems are reported, as passed to `--output`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Output {
+    Text,
+    /// Workflow commands which GitHub Actions shows as annotations on the run.
+    GithubActions,
+}
+
+impl FromStr for Output {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "text" => Ok(Output::Text),
+            "github-actions" => Ok(Output::GithubActions),
+            _ => anyhow::bail!("Unknown output `{s}`, expected one of: text, github-actions."),
+        }
+    }
+}
+
+/// Returned once the problems which made a command fail have been printed as annotations,
+/// so that the failure isn't annotated again.
+#[derive(Debug)]
+pub struct Annotated(pub String);
+
+impl std::fmt::Display for Annotated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Annotated {}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Level {
+    Error,
+    Notice,
+}
+
+/// Formats a GitHub Actions workflow command, like `::error title=Needs restack::...`.
+pub fn annotation(level: Level, title: &str, message: &str) -> String {
+    let level = match level {
+        Level::Error => "error",
+        Level::Notice => "notice",
+    };
+    format!(
+        "::{level} title={}::{}",
+        escape_property(title),
+        escape_data(message)
+    )
+}
+
+fn escape_data(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn escape_property(value: &str) -> String {
+    escape_data(value).replace(':', "%3A").replace(',', "%2C")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_annotation() {
+        assert_eq!(
+            annotation(
+                Level::Error,
+                "Needs restack",
+                "`ch/a` is behind.\nRun: dmd sync"
+            ),
+            "::error title=Needs restack::`ch/a` is behind.%0ARun: dmd sync"
+        );
+        assert_eq!(
+            annotation(Level::Notice, "a: b, c", "100%"),
+            "::notice title=a%3A b%2C c::100%25"
+        );
+    }
+}
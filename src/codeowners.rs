@@ -0,0 +1,153 @@
+/// Where GitHub looks for a CODEOWNERS file, in the order it looks.
+pub const LOCATIONS: &[&str] = &[".github/CODEOWNERS", "CODEOWNERS", "docs/CODEOWNERS"];
+
+/// A line of a CODEOWNERS file, like `/src/*.rs @crockeo @acme/reviewers`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Rule {
+    pattern: String,
+    owners: Vec<String>,
+}
+
+/// The people and teams to request reviews from.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Reviewers {
+    /// Usernames, without the `@`.
+    pub users: Vec<String>,
+    /// Team slugs, without the `@org/`.
+    pub teams: Vec<String>,
+}
+
+pub fn parse(contents: &str) -> Vec<Rule> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let mut parts = line.split_whitespace();
+            let pattern = parts.next()?;
+            Some(Rule {
+                pattern: pattern.to_owned(),
+                owners: parts.map(str::to_owned).collect(),
+            })
+        })
+        .collect()
+}
+
+/// Finds the owners of `paths`. Like GitHub, only the last rule which matches a path counts,
+/// and owners given by email address are left out, since reviews can't be requested from them.
+pub fn reviewers(rules: &[Rule], paths: &[String]) -> Reviewers {
+    let mut reviewers = Reviewers::default();
+    for path in paths {
+        let Some(rule) = rules.iter().rev().find(|rule| matches(&rule.pattern, path)) else {
+            continue;
+        };
+        for owner in &rule.owners {
+            let Some(owner) = owner.strip_prefix('@') else {
+                continue;
+            };
+            let (list, name) = match owner.split_once('/') {
+                Some((_org, team)) => (&mut reviewers.teams, team),
+                None => (&mut reviewers.users, owner),
+            };
+            if !list.iter().any(|existing| existing == name) {
+                list.push(name.to_owned());
+            }
+        }
+    }
+    reviewers
+}
+
+/// Matches `path` against a CODEOWNERS pattern, which follows `.gitignore`'s rules:
+/// patterns with a slash before their end are relative to the repo's root, others match at any depth,
+/// and a pattern which matches a directory matches everything in it.
+pub fn matches(pattern: &str, path: &str) -> bool {
+    let directory_only = pattern.ends_with('/');
+    let pattern = pattern.trim_end_matches('/');
+    let anchored = pattern.contains('/');
+    let pattern = pattern.trim_start_matches('/');
+    if pattern.is_empty() {
+        return false;
+    }
+
+    let boundaries = |text: &str| -> Vec<usize> {
+        std::iter::once(0)
+            .chain(text.match_indices('/').map(|(i, _)| i + 1))
+            .collect()
+    };
+    let starts = if anchored { vec![0] } else { boundaries(path) };
+    let ends: Vec<usize> = path
+        .match_indices('/')
+        .map(|(i, _)| i)
+        .chain((!directory_only).then_some(path.len()))
+        .collect();
+    starts.iter().any(|&start| {
+        ends.iter()
+            .any(|&end| end > start && glob(pattern.as_bytes(), &path.as_bytes()[start..end]))
+    })
+}
+
+/// Matches `text` against `pattern`, where `*` and `?` don't match `/`, but `**` does.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        // `**/` matches any number of whole directories, including none.
+        [b'*', b'*', b'/', rest @ ..] => (0..=text.len())
+            .filter(|&i| i == 0 || text[i - 1] == b'/')
+            .any(|i| glob(rest, &text[i..])),
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|i| glob(rest, &text[i..])),
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| glob(rest, &text[i..])),
+        [b'?', rest @ ..] => matches!(text, [c, ..] if *c != b'/') && glob(rest, &text[1..]),
+        [c, rest @ ..] => text.first() == Some(c) && glob(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("*", "src/main.rs"));
+        assert!(matches("*.rs", "src/main.rs"));
+        assert!(!matches("*.rs", "README.md"));
+        assert!(matches("/src/", "src/git/remote.rs"));
+        assert!(!matches("/src/", "lib/src/main.rs"));
+        assert!(matches("docs/", "lib/docs/index.md"));
+        assert!(!matches("docs/", "docs"));
+        assert!(matches("src/*.rs", "src/main.rs"));
+        assert!(!matches("src/*.rs", "src/git/remote.rs"));
+        assert!(matches("src/**/remote.rs", "src/git/remote.rs"));
+        assert!(matches("**/remote.rs", "remote.rs"));
+        assert!(!matches("src/**/remote.rs", "src/xremote.rs"));
+        assert!(matches("Cargo.toml", "crates/dmd/Cargo.toml"));
+    }
+
+    #[test]
+    fn test_reviewers() {
+        let rules = parse(
+            "
+# Everything else.
+*       @crockeo
+/src/   @acme/diamond-maintainers @alice  # Core code.
+*.md    docs@example.com
+/src/render.rs
+",
+        );
+        let paths = |paths: &[&str]| -> Vec<String> {
+            paths.iter().map(|path| (*path).to_owned()).collect()
+        };
+        assert_eq!(
+            reviewers(&rules, &paths(&["Cargo.toml", "src/main.rs"])),
+            Reviewers {
+                users: vec!["crockeo".to_owned(), "alice".to_owned()],
+                teams: vec!["diamond-maintainers".to_owned()],
+            }
+        );
+        // The last matching rule wins, even when it has no owners.
+        assert_eq!(
+            reviewers(&rules, &paths(&["README.md", "src/render.rs"])),
+            Reviewers::default()
+        );
+    }
+}
@@ -0,0 +1,625 @@
+use crate::database::Transaction;
+
+/// The kinds of values a configuration key can hold, used to validate values before they're stored.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ValueKind {
+    Bool,
+    /// Either a boolean, or an arbitrary string which implies `true`.
+    BoolOrString,
+    /// A comma-separated list of strings.
+    List,
+    /// Any non-empty string.
+    String,
+    /// The name of a Git remote.
+    Remote,
+    /// The name of a Git branch.
+    Branch,
+    /// One of a fixed set of strings.
+    OneOf(&'static [&'static str]),
+    /// A whole number of seconds.
+    Seconds,
+    /// A whole number of days.
+    Days,
+    /// A comma-separated list of `LabelRule`s.
+    LabelRules,
+    /// A string which has to contain each of these placeholders, like `{key}`.
+    Template(&'static [&'static str]),
+}
+
+#[derive(Debug)]
+pub struct ConfigKey {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub kind: ValueKind,
+}
+
+pub const REMOTE: &str = "remote";
+pub const ROOT_BRANCH: &str = "root-branch";
+pub const BRANCH_PREFIX: &str = "branch-prefix";
+pub const BRANCH_ALLOWED_PREFIXES: &str = "branch.allowed-prefixes";
+pub const BRANCH_MATCH_PREFIX: &str = "branch.match-prefix";
+pub const BRANCH_NAME_TEMPLATE: &str = "branch.name-template";
+pub const RESTACK_STRATEGY: &str = "restack.strategy";
+pub const RESTACK_WORKTREE: &str = "restack.worktree";
+pub const REBASE_GPG_SIGN: &str = "rebase.gpg-sign";
+pub const REBASE_SIGNOFF: &str = "rebase.signoff";
+pub const REBASE_RERERE: &str = "rebase.rerere";
+pub const PUSH_NO_VERIFY: &str = "push.no-verify";
+pub const PUSH_OPTIONS: &str = "push.options";
+pub const PUSH_REMOTE: &str = "push.remote";
+pub const SYNC_CLEAN: &str = "sync.clean";
+pub const PROTECTED_BRANCHES: &str = "protected-branches";
+pub const SUBMIT_PULL_REQUESTS: &str = "submit.pull-requests";
+pub const SUBMIT_TITLE_PREFIX: &str = "submit.title-prefix";
+pub const SUBMIT_COMMENT: &str = "submit.comment";
+pub const SUBMIT_LABELS: &str = "submit.labels";
+pub const SUBMIT_TICKET_TITLE: &str = "submit.ticket-title";
+pub const SUBMIT_TICKET_URL: &str = "submit.ticket-url";
+pub const SUBMIT_TICKET_PROJECTS: &str = "submit.ticket-projects";
+pub const NOTIFY: &str = "notify";
+pub const LAND_MERGE_METHOD: &str = "land.merge-method";
+pub const SUBMODULES_RECURSE: &str = "submodules.recurse";
+pub const GIT_TIMEOUT: &str = "git.timeout";
+pub const RETENTION_UNTRACK_MERGED_AFTER: &str = "retention.untrack-merged-after";
+pub const RETENTION_WARN_STACK_AGE: &str = "retention.warn-stack-age";
+pub const COMMIT_PARENT_TRAILER: &str = "commit.parent-trailer";
+pub const GITHUB_PROXY: &str = "github.proxy";
+pub const GITHUB_API_URL: &str = "github.api-url";
+
+pub const KEYS: &[ConfigKey] = &[
+    ConfigKey {
+        name: REMOTE,
+        description: "The Git remote which stacks are pushed to and synced from.",
+        kind: ValueKind::Remote,
+    },
+    ConfigKey {
+        name: ROOT_BRANCH,
+        description: "The branch which every stack is built on top of, like `main`.",
+        kind: ValueKind::Branch,
+    },
+    ConfigKey {
+        name: BRANCH_PREFIX,
+        description: "A prefix, like `ch/`, added to the names of branches made with `dmd create`.",
+        kind: ValueKind::String,
+    },
+    ConfigKey {
+        name: BRANCH_ALLOWED_PREFIXES,
+        description: "Comma-separated prefixes, like `ch/,fix/`, one of which the name of every branch made with `dmd create` has to start with.",
+        kind: ValueKind::List,
+    },
+    ConfigKey {
+        name: BRANCH_NAME_TEMPLATE,
+        description: "How `dmd create --message` names a branch when no name is given, like `fix/{message}`, where `{message}` stands for the commit message's subject made into a name.",
+        kind: ValueKind::String,
+    },
+    ConfigKey {
+        name: BRANCH_MATCH_PREFIX,
+        description: "Accept the start of a tracked branch's name, like `ch/fix` for `ch/fix-leak`, wherever a branch is named, as long as only one branch starts with it.",
+        kind: ValueKind::Bool,
+    },
+    ConfigKey {
+        name: RESTACK_STRATEGY,
+        description: "How branches are brought up to date with their parents: `rebase` (the default), or `merge` to merge the parent in without rewriting history.",
+        kind: ValueKind::OneOf(&["rebase", "merge"]),
+    },
+    ConfigKey {
+        name: RESTACK_WORKTREE,
+        description: "Whether restack and sync rebase branches other than the checked-out one in a temporary worktree, and pull them without checking them out, so that your working tree is left alone.",
+        kind: ValueKind::Bool,
+    },
+    ConfigKey {
+        name: REBASE_GPG_SIGN,
+        description:
+            "Sign commits rewritten during restacks. Either `true`, `false`, or a GPG key ID.",
+        kind: ValueKind::BoolOrString,
+    },
+    ConfigKey {
+        name: REBASE_SIGNOFF,
+        description: "Add a `Signed-off-by` trailer to commits rewritten during restacks.",
+        kind: ValueKind::Bool,
+    },
+    ConfigKey {
+        name: REBASE_RERERE,
+        description: "Use `git rerere` while restacking, so conflicts resolved once are resolved automatically when restacking again. Set `rerere.enabled` in Git's own config too, to record the resolutions of rebases you continue yourself.",
+        kind: ValueKind::Bool,
+    },
+    ConfigKey {
+        name: PUSH_NO_VERIFY,
+        description: "Skip the `pre-push` hook when pushing branches.",
+        kind: ValueKind::Bool,
+    },
+    ConfigKey {
+        name: PUSH_OPTIONS,
+        description:
+            "Comma-separated push options (`git push -o`) sent with every push, e.g. `ci.skip`.",
+        kind: ValueKind::List,
+    },
+    ConfigKey {
+        name: PUSH_REMOTE,
+        description: "The Git remote which branches are pushed to, like your fork, when it isn't `remote`. Pull requests are still opened against `remote`'s repo, which means only branches on the root branch can have them.",
+        kind: ValueKind::Remote,
+    },
+    ConfigKey {
+        name: SYNC_CLEAN,
+        description: "Run `dmd clean` at the start of every sync.",
+        kind: ValueKind::Bool,
+    },
+    ConfigKey {
+        name: PROTECTED_BRANCHES,
+        description: "Comma-separated branch patterns, like `main,release/*`, which diamond will never rebase, push, or remove.",
+        kind: ValueKind::List,
+    },
+    ConfigKey {
+        name: SUBMIT_PULL_REQUESTS,
+        description: "Create and update GitHub pull requests when submitting, instead of printing links to open them. Needs a token from a Git credential helper, `GITHUB_TOKEN`, or `GH_TOKEN`. New pull requests are described by the branch's `.diamond/<branch>.md` file, its `PR-Body:` commit trailer, or its first commit's message, in that order.",
+        kind: ValueKind::Bool,
+    },
+    ConfigKey {
+        name: SUBMIT_TITLE_PREFIX,
+        description: "Prefix pull request titles with their position in the stack, like `[2/5]`, and renumber them as the stack changes.",
+        kind: ValueKind::Bool,
+    },
+    ConfigKey {
+        name: SUBMIT_COMMENT,
+        description: "When a submit pushes new commits to a branch which already has a pull request, comment on it describing what changed.",
+        kind: ValueKind::Bool,
+    },
+    ConfigKey {
+        name: SUBMIT_LABELS,
+        description: "Comma-separated rules for labelling pull requests, like `path:docs/=documentation,branch:hotfix/*=hotfix`, which label a pull request when it changes a matching path or its branch matches.",
+        kind: ValueKind::LabelRules,
+    },
+    ConfigKey {
+        name: SUBMIT_TICKET_TITLE,
+        description: "How to add a ticket key, like `ABC-123`, to the titles of new pull requests, e.g. `[{key}] {title}`. The key is found at the start of part of the branch's name, like `ch/abc-123-fix-leak`, if its project is in `submit.ticket-projects`, or else in its first commit's message.",
+        kind: ValueKind::Template(&["{key}", "{title}"]),
+    },
+    ConfigKey {
+        name: SUBMIT_TICKET_URL,
+        description: "Link to the ticket in new pull requests' descriptions, with a URL like `https://acme.atlassian.net/browse/{key}`.",
+        kind: ValueKind::Template(&["{key}"]),
+    },
+    ConfigKey {
+        name: SUBMIT_TICKET_PROJECTS,
+        description: "Comma-separated ticket projects, like `ABC,OPS`. Ticket keys are only found in branch names when their project is one of these. If any are set, keys found in commit messages have to be in one of them too.",
+        kind: ValueKind::List,
+    },
+    ConfigKey {
+        name: LAND_MERGE_METHOD,
+        description: "How `dmd land` and `dmd pr merge` merge pull requests: `merge` (the default), `squash`, or `rebase`.",
+        kind: ValueKind::OneOf(&["merge", "squash", "rebase"]),
+    },
+    ConfigKey {
+        name: NOTIFY,
+        description: "Show a desktop notification when a sync, restack, or land finishes or stops, e.g. on a conflict.",
+        kind: ValueKind::Bool,
+    },
+    ConfigKey {
+        name: SUBMODULES_RECURSE,
+        description: "Update submodules to match whenever diamond checks out, rebases, merges, or pulls a branch.",
+        kind: ValueKind::Bool,
+    },
+    ConfigKey {
+        name: GIT_TIMEOUT,
+        description: "How many seconds to wait on a fetch, pull, or push before giving up. Without it, diamond waits as long as Git does.",
+        kind: ValueKind::Seconds,
+    },
+    ConfigKey {
+        name: RETENTION_UNTRACK_MERGED_AFTER,
+        description: "How many days `dmd sync` keeps branches whose pull requests were merged archived, before it stops tracking them. Without it, `dmd sync` asks whether to stop tracking them as soon as they're merged.",
+        kind: ValueKind::Days,
+    },
+    ConfigKey {
+        name: RETENTION_WARN_STACK_AGE,
+        description: "After how many days `dmd sync` warns about stacks, going by their oldest commit.",
+        kind: ValueKind::Days,
+    },
+    ConfigKey {
+        name: COMMIT_PARENT_TRAILER,
+        description: "Add a `Diamond-Parent:` trailer naming the branch's parent to commits made with `dmd create --message`, and with Git once `dmd hook install` has run, so that `dmd recover` can rebuild the stacks if the database is lost.",
+        kind: ValueKind::Bool,
+    },
+    ConfigKey {
+        name: GITHUB_PROXY,
+        description: "The proxy to reach the GitHub API through, like `http://proxy.example.com:8080`, instead of the one in `HTTPS_PROXY` or `ALL_PROXY`. Hosts listed in `NO_PROXY` still skip it.",
+        kind: ValueKind::String,
+    },
+    ConfigKey {
+        name: GITHUB_API_URL,
+        description: "The API URL of the GitHub Enterprise server the remote is on, like `https://github.acme.com/api/v3`. Remotes on hosts other than github.com need it. `GITHUB_API_URL` takes precedence.",
+        kind: ValueKind::String,
+    },
+];
+
+pub fn find_key(name: &str) -> anyhow::Result<&'static ConfigKey> {
+    let Some(key) = KEYS.iter().find(|key| key.name == name) else {
+        let known_keys: Vec<String> = KEYS
+            .iter()
+            .map(|key| format!("  {}: {}", key.name, key.description))
+            .collect();
+        anyhow::bail!(
+            "Unknown config key `{name}`. Known keys are:\n{}",
+            known_keys.join("\n")
+        );
+    };
+    Ok(key)
+}
+
+/// Checks that `value` is valid for `key`, and returns it in its canonical form.
+pub fn validate(key: &ConfigKey, value: &str) -> anyhow::Result<String> {
+    match key.kind {
+        ValueKind::Bool => Ok(parse_bool(value)?.to_string()),
+        ValueKind::BoolOrString => {
+            anyhow::ensure!(!value.is_empty(), "`{}` cannot be empty.", key.name);
+            Ok(match parse_bool(value) {
+                Ok(value) => value.to_string(),
+                Err(_) => value.to_owned(),
+            })
+        }
+        ValueKind::List => Ok(parse_list(value).join(",")),
+        ValueKind::OneOf(choices) => {
+            anyhow::ensure!(
+                choices.contains(&value),
+                "`{}` must be one of: {}.",
+                key.name,
+                choices.join(", ")
+            );
+            Ok(value.to_owned())
+        }
+        ValueKind::Seconds => Ok(parse_seconds(value)?.to_string()),
+        ValueKind::Days => Ok(parse_days(value)?.to_string()),
+        ValueKind::Template(placeholders) => {
+            for placeholder in placeholders {
+                anyhow::ensure!(
+                    value.contains(placeholder),
+                    "`{}` must contain `{placeholder}`.",
+                    key.name
+                );
+            }
+            Ok(value.to_owned())
+        }
+        ValueKind::LabelRules => {
+            let rules = parse_list(value);
+            for rule in &rules {
+                LabelRule::parse(rule)?;
+            }
+            Ok(rules.join(","))
+        }
+        ValueKind::String | ValueKind::Remote | ValueKind::Branch => {
+            anyhow::ensure!(!value.is_empty(), "`{}` cannot be empty.", key.name);
+            Ok(value.to_owned())
+        }
+    }
+}
+
+/// Reads the value of `key`,
+/// including the settings from `dmd init` which are stored outside of the config table.
+pub fn get(tx: &Transaction, key: &ConfigKey) -> anyhow::Result<Option<String>> {
+    match key.name {
+        REMOTE => tx.get_remote(),
+        ROOT_BRANCH => tx.get_root_branch(),
+        _ => tx.get_config(key.name),
+    }
+}
+
+/// Validates `value` and stores it as the value of `key`.
+pub fn set(tx: &mut Transaction, key: &ConfigKey, value: &str) -> anyhow::Result<()> {
+    let value = validate(key, value)?;
+    match key.name {
+        REMOTE => tx.set_remote(&value),
+        ROOT_BRANCH if tx.get_root_branch()?.as_deref() == Some(value.as_str()) => Ok(()),
+        ROOT_BRANCH => tx.set_root_branch(&value),
+        _ => tx.set_config(key.name, &value),
+    }
+}
+
+/// Removes the value of `key`, so that its default is used.
+pub fn unset(tx: &mut Transaction, key: &ConfigKey) -> anyhow::Result<()> {
+    match key.name {
+        REMOTE | ROOT_BRANCH => {
+            anyhow::bail!("`{}` can't be unset, only set to another value.", key.name)
+        }
+        _ => tx.delete_config(key.name),
+    }
+}
+
+/// Writes out every key as a file to edit, with a comment describing each one.
+/// Keys which are set are written as `key = value`, and ones which aren't are commented out.
+pub fn render_file(tx: &Transaction) -> anyhow::Result<String> {
+    let mut contents = String::from(
+        "# The diamond config for this repo, one `key = value` per line.\n\
+        # Uncomment a key to set it, or comment it out to go back to its default.\n\
+        # Lines starting with `#` are ignored.\n",
+    );
+    for key in KEYS {
+        contents.push_str(&format!("\n# {}\n", key.description));
+        match get(tx, key)? {
+            Some(value) => contents.push_str(&format!("{} = {value}\n", key.name)),
+            None => contents.push_str(&format!("# {} =\n", key.name)),
+        }
+    }
+    Ok(contents)
+}
+
+/// Reads a file written by `render_file`, validating each value.
+/// Returns the keys which are set, with their values in canonical form.
+pub fn parse_file(contents: &str) -> anyhow::Result<Vec<(&'static ConfigKey, String)>> {
+    let mut values: Vec<(&'static ConfigKey, String)> = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parsed = line
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected `key = value`, not `{line}`."))
+            .and_then(|(name, value)| {
+                let key = find_key(name.trim())
+                    .map_err(|_| anyhow::anyhow!("Unknown config key `{}`.", name.trim()))?;
+                anyhow::ensure!(
+                    !values.iter().any(|(existing, _)| existing.name == key.name),
+                    "`{}` is set more than once.",
+                    key.name
+                );
+                Ok((key, validate(key, value.trim())?))
+            });
+        match parsed {
+            Ok(value) => values.push(value),
+            Err(err) => anyhow::bail!("Line {}: {err}", i + 1),
+        }
+    }
+    Ok(values)
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+fn parse_bool(value: &str) -> anyhow::Result<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => anyhow::bail!("Expected a boolean (`true` or `false`), not `{value}`."),
+    }
+}
+
+fn parse_seconds(value: &str) -> anyhow::Result<u64> {
+    match value.parse() {
+        Ok(0) | Err(_) => anyhow::bail!("Expected a positive number of seconds, not `{value}`."),
+        Ok(seconds) => Ok(seconds),
+    }
+}
+
+pub fn get_seconds(tx: &Transaction, key: &str) -> anyhow::Result<Option<u64>> {
+    tx.get_config(key)?
+        .map(|value| parse_seconds(&value))
+        .transpose()
+}
+
+fn parse_days(value: &str) -> anyhow::Result<u64> {
+    match value.parse() {
+        Ok(0) | Err(_) => anyhow::bail!("Expected a positive number of days, not `{value}`."),
+        Ok(days) => Ok(days),
+    }
+}
+
+pub fn get_days(tx: &Transaction, key: &str) -> anyhow::Result<Option<u64>> {
+    tx.get_config(key)?
+        .map(|value| parse_days(&value))
+        .transpose()
+}
+
+pub fn get_bool(tx: &Transaction, key: &str) -> anyhow::Result<bool> {
+    match tx.get_config(key)? {
+        Some(value) => parse_bool(&value),
+        None => Ok(false),
+    }
+}
+
+pub fn get_list(tx: &Transaction, key: &str) -> anyhow::Result<Vec<String>> {
+    Ok(tx
+        .get_config(key)?
+        .map(|value| parse_list(&value))
+        .unwrap_or_default())
+}
+
+/// Adds `label` to pull requests which change a path matching `pattern`, like `docs/`,
+/// or whose branch matches `pattern`, like `hotfix/*`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LabelRule {
+    Path { pattern: String, label: String },
+    Branch { pattern: String, label: String },
+}
+
+impl LabelRule {
+    /// Parses a rule written like `path:docs/=documentation` or `branch:hotfix/*=hotfix`.
+    fn parse(rule: &str) -> anyhow::Result<Self> {
+        let parsed = rule.split_once('=').and_then(|(matcher, label)| {
+            let (kind, pattern) = matcher.split_once(':')?;
+            let (pattern, label) = (pattern.trim().to_owned(), label.trim().to_owned());
+            if pattern.is_empty() || label.is_empty() {
+                return None;
+            }
+            match kind.trim() {
+                "path" => Some(LabelRule::Path { pattern, label }),
+                "branch" => Some(LabelRule::Branch { pattern, label }),
+                _ => None,
+            }
+        });
+        let Some(parsed) = parsed else {
+            anyhow::bail!(
+                "Expected a label rule like `path:docs/=documentation` or `branch:hotfix/*=hotfix`, not `{rule}`."
+            );
+        };
+        Ok(parsed)
+    }
+
+    /// Returns the label to add to the pull request for `branch`, which changes `paths`, if the rule matches it.
+    pub fn label_for(&self, branch: &str, paths: &[String]) -> Option<&str> {
+        match self {
+            LabelRule::Path { pattern, label } => paths
+                .iter()
+                .any(|path| crate::codeowners::matches(pattern, path))
+                .then_some(label),
+            LabelRule::Branch { pattern, label } => glob_matches(pattern, branch).then_some(label),
+        }
+        .map(String::as_str)
+    }
+}
+
+pub fn get_label_rules(tx: &Transaction) -> anyhow::Result<Vec<LabelRule>> {
+    get_list(tx, SUBMIT_LABELS)?
+        .iter()
+        .map(|rule| LabelRule::parse(rule))
+        .collect()
+}
+
+/// Returns true if `branch` matches any of the patterns in the `protected-branches` config.
+pub fn is_protected(tx: &Transaction, branch: &str) -> anyhow::Result<bool> {
+    Ok(get_list(tx, PROTECTED_BRANCHES)?
+        .iter()
+        .any(|pattern| glob_matches(pattern, branch)))
+}
+
+/// Matches `name` against a pattern where `*` matches any run of characters (including `/`)
+/// and `?` matches exactly one character.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = backtrack {
+            p = star_p + 1;
+            n = star_n + 1;
+            backtrack = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_file() -> anyhow::Result<()> {
+        let values = parse_file(
+            "# A comment\n\nrebase.signoff = yes\n# branch.prefix =\npush.options =  ci.skip, foo=bar\n",
+        )?;
+        let values: Vec<(&str, &str)> = values
+            .iter()
+            .map(|(key, value)| (key.name, value.as_str()))
+            .collect();
+        assert_eq!(
+            values,
+            [(REBASE_SIGNOFF, "true"), (PUSH_OPTIONS, "ci.skip,foo=bar")]
+        );
+
+        let err = parse_file("rebase.signoff = yes\nrebase.signoff = no\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Line 2: `rebase.signoff` is set more than once."
+        );
+        let err = parse_file("\nrebase.signoff = maybe\n").unwrap_err();
+        assert!(err.to_string().starts_with("Line 2: Expected a boolean"));
+        assert!(parse_file("not.a.key = 1\n").is_err());
+        assert!(parse_file("rebase.signoff\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() -> anyhow::Result<()> {
+        let signoff = find_key(REBASE_SIGNOFF)?;
+        assert_eq!(validate(signoff, "yes")?, "true");
+        assert_eq!(validate(signoff, "FALSE")?, "false");
+        assert!(validate(signoff, "ABCD1234").is_err());
+
+        let gpg_sign = find_key(REBASE_GPG_SIGN)?;
+        assert_eq!(validate(gpg_sign, "on")?, "true");
+        assert_eq!(validate(gpg_sign, "ABCD1234")?, "ABCD1234");
+        assert!(validate(gpg_sign, "").is_err());
+
+        let push_options = find_key(PUSH_OPTIONS)?;
+        assert_eq!(
+            validate(push_options, " ci.skip, ,foo=bar ")?,
+            "ci.skip,foo=bar"
+        );
+
+        let branch_prefix = find_key(BRANCH_PREFIX)?;
+        assert_eq!(validate(branch_prefix, "ch/")?, "ch/");
+        assert!(validate(branch_prefix, "").is_err());
+
+        let strategy = find_key(RESTACK_STRATEGY)?;
+        assert_eq!(validate(strategy, "merge")?, "merge");
+        assert!(validate(strategy, "squash").is_err());
+
+        let ticket_title = find_key(SUBMIT_TICKET_TITLE)?;
+        assert_eq!(
+            validate(ticket_title, "[{key}] {title}")?,
+            "[{key}] {title}"
+        );
+        assert!(validate(ticket_title, "[{key}]").is_err());
+
+        let labels = find_key(SUBMIT_LABELS)?;
+        assert_eq!(
+            validate(
+                labels,
+                "path: docs/ = documentation, branch:hotfix/*=hotfix"
+            )?,
+            "path: docs/ = documentation,branch:hotfix/*=hotfix"
+        );
+        assert!(validate(labels, "docs/=documentation").is_err());
+        assert!(validate(labels, "path:docs/=").is_err());
+
+        let timeout = find_key(GIT_TIMEOUT)?;
+        assert_eq!(validate(timeout, "30")?, "30");
+        assert!(validate(timeout, "0").is_err());
+        assert!(validate(timeout, "30s").is_err());
+
+        let stack_age = find_key(RETENTION_WARN_STACK_AGE)?;
+        assert_eq!(validate(stack_age, "60")?, "60");
+        assert!(validate(stack_age, "-1").is_err());
+
+        assert!(find_key("not.a-key").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_label_rules() -> anyhow::Result<()> {
+        let docs = LabelRule::parse("path:docs/=documentation")?;
+        let hotfix = LabelRule::parse("branch:hotfix/*=hotfix")?;
+        let paths = vec!["docs/index.md".to_owned()];
+        assert_eq!(docs.label_for("ch/docs", &paths), Some("documentation"));
+        assert_eq!(docs.label_for("ch/docs", &["src/main.rs".to_owned()]), None);
+        assert_eq!(hotfix.label_for("hotfix/crash", &[]), Some("hotfix"));
+        assert_eq!(hotfix.label_for("ch/hotfix", &paths), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("main", "main"));
+        assert!(!glob_matches("main", "main2"));
+        assert!(glob_matches("release/*", "release/1.2"));
+        assert!(glob_matches("release/*", "release/1.2/hotfix"));
+        assert!(!glob_matches("release/*", "ch/release/1.2"));
+        assert!(glob_matches("*/wip-*", "ch/wip-parser"));
+        assert!(glob_matches("v?", "v1"));
+        assert!(!glob_matches("v?", "v10"));
+        assert!(glob_matches("*", "anything"));
+    }
+}
@@ -1,10 +1,11 @@
-use rusqlite::{Connection, OptionalExtension};
-use std::path::Path;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 // TODO: WOW is this brittle!!!
 // if i add anything earlier into the migration list (why would I?)
 // it messes up the revision ordering
-const MIGRATIONS: &[&'static str] = &[
+const MIGRATIONS: &[&str] = &[
     "
     CREATE TABLE IF NOT EXISTS repo_info (
         id INT PRIMARY KEY,
@@ -21,6 +22,111 @@ const MIGRATIONS: &[&'static str] = &[
     ALTER TABLE branches
     ADD submitted BOOL DEFAULT FALSE NOT NULL
     ",
+    "
+    ALTER TABLE branches
+    ADD base_commit TEXT
+    ",
+    "
+    CREATE TABLE IF NOT EXISTS config (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    )
+    ",
+    "
+    ALTER TABLE branches
+    ADD pushed_commit TEXT
+    ",
+    "
+    ALTER TABLE branches
+    ADD archived BOOL DEFAULT FALSE NOT NULL
+    ",
+    "
+    CREATE TABLE IF NOT EXISTS stacks (
+        name TEXT PRIMARY KEY,
+        base_branch TEXT NOT NULL UNIQUE
+    )
+    ",
+    "
+    CREATE TABLE IF NOT EXISTS submit_queue (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        operation TEXT NOT NULL,
+        branch TEXT NOT NULL,
+        base TEXT NOT NULL,
+        position INTEGER,
+        stack_size INTEGER
+    )
+    ",
+    "
+    ALTER TABLE branches
+    ADD note TEXT
+    ",
+    "
+    CREATE TABLE IF NOT EXISTS progress (
+        command TEXT NOT NULL,
+        branch TEXT NOT NULL,
+        commit_hash TEXT NOT NULL,
+        PRIMARY KEY (command, branch)
+    )
+    ",
+    "
+    ALTER TABLE branches
+    ADD created_at INTEGER
+    ",
+    "
+    ALTER TABLE branches
+    ADD updated_at INTEGER
+    ",
+    "
+    CREATE TRIGGER IF NOT EXISTS branches_created_at
+    AFTER INSERT ON branches
+    BEGIN
+        UPDATE branches
+        SET created_at = CAST(strftime('%s', 'now') AS INTEGER),
+            updated_at = CAST(strftime('%s', 'now') AS INTEGER)
+        WHERE name = NEW.name;
+    END
+    ",
+    "
+    CREATE TRIGGER IF NOT EXISTS branches_updated_at
+    AFTER UPDATE ON branches
+    WHEN NEW.updated_at IS OLD.updated_at
+    BEGIN
+        UPDATE branches
+        SET updated_at = CAST(strftime('%s', 'now') AS INTEGER)
+        WHERE name = NEW.name;
+    END
+    ",
+    "
+    ALTER TABLE branches
+    ADD remote_name TEXT
+    ",
+    "
+    ALTER TABLE branches
+    ADD closes TEXT
+    ",
+    "
+    ALTER TABLE branches
+    ADD target_branch TEXT
+    ",
+    "
+    ALTER TABLE branches
+    ADD pull_request INTEGER
+    ",
+    "
+    CREATE TABLE IF NOT EXISTS daemon_report (
+        id INT PRIMARY KEY,
+        checked_at INTEGER NOT NULL,
+        root_behind INTEGER NOT NULL
+    )
+    ",
+    "
+    CREATE TABLE IF NOT EXISTS daemon_findings (
+        position INTEGER PRIMARY KEY,
+        branch TEXT NOT NULL,
+        merged BOOL NOT NULL,
+        reason TEXT
+    )
+    ",
 ];
 
 pub struct Database {
@@ -40,6 +146,15 @@ impl Database {
         Ok(db)
     }
 
+    /// Opens an existing database without migrating it or writing to it,
+    /// for reads which must never hold up other commands.
+    /// Rather than waiting on another command which is writing to it, reads fail straight away.
+    pub fn open_read_only(path: impl AsRef<Path>) -> anyhow::Result<Database> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        conn.busy_timeout(std::time::Duration::ZERO)?;
+        Ok(Self { conn })
+    }
+
     fn migrate(&mut self) -> anyhow::Result<()> {
         self.conn.execute(
             "
@@ -81,7 +196,7 @@ impl Database {
         Ok(())
     }
 
-    pub fn transaction(&mut self) -> anyhow::Result<Transaction> {
+    pub fn transaction(&mut self) -> anyhow::Result<Transaction<'_>> {
         Ok(Transaction {
             conn: self.conn.transaction()?,
         })
@@ -98,6 +213,50 @@ impl Transaction<'_> {
         Ok(())
     }
 
+    /// Commits everything written so far and carries on in a new transaction,
+    /// so that work which has already happened in Git is recorded even if the command fails later on.
+    pub fn checkpoint(&mut self) -> anyhow::Result<()> {
+        self.conn.execute_batch("COMMIT; BEGIN")?;
+        Ok(())
+    }
+
+    /// Throws away everything written since the last checkpoint and carries on in a new transaction.
+    pub fn rollback_to_checkpoint(&mut self) -> anyhow::Result<()> {
+        self.conn.execute_batch("ROLLBACK; BEGIN")?;
+        Ok(())
+    }
+
+    /// Commits everything written so far, compacts the database, and carries on in a new transaction.
+    pub fn vacuum(&mut self) -> anyhow::Result<()> {
+        self.conn.execute_batch("COMMIT; VACUUM; BEGIN")?;
+        Ok(())
+    }
+
+    /// Returns the size of the database in bytes.
+    pub fn size(&self) -> anyhow::Result<u64> {
+        let page_count: u64 = self
+            .conn
+            .query_row("PRAGMA page_count", (), |row| row.get(0))?;
+        let page_size: u64 = self
+            .conn
+            .query_row("PRAGMA page_size", (), |row| row.get(0))?;
+        Ok(page_count * page_size)
+    }
+
+    /// Deletes the queued operations, progress, and stack names left behind by branches
+    /// which are no longer tracked, returning how many were deleted.
+    pub fn delete_orphaned_rows(&mut self) -> anyhow::Result<usize> {
+        let mut deleted = 0;
+        for statement in [
+            "DELETE FROM submit_queue WHERE branch NOT IN (SELECT name FROM branches)",
+            "DELETE FROM progress WHERE branch NOT IN (SELECT name FROM branches)",
+            "DELETE FROM stacks WHERE base_branch NOT IN (SELECT name FROM branches)",
+        ] {
+            deleted += self.conn.execute(statement, ())?;
+        }
+        Ok(deleted)
+    }
+
     pub fn set_remote(&mut self, remote: &str) -> anyhow::Result<()> {
         self.conn.execute(
             "
@@ -123,6 +282,29 @@ impl Transaction<'_> {
             .optional()?)
     }
 
+    pub fn set_config(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO config ( key, value ) VALUES ( ?, ? )",
+            (key, value),
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_config(&mut self, key: &str) -> anyhow::Result<()> {
+        self.conn
+            .execute("DELETE FROM config WHERE key = ?", (key,))?;
+        Ok(())
+    }
+
+    pub fn get_config(&self, key: &str) -> anyhow::Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row("SELECT value FROM config WHERE key = ?", (key,), |row| {
+                row.get(0)
+            })
+            .optional()?)
+    }
+
     pub fn set_root_branch(&mut self, root_branch: &str) -> anyhow::Result<()> {
         let existing_root_branch: Option<String> = {
             let mut stmt = self
@@ -171,6 +353,34 @@ impl Transaction<'_> {
             .optional()?)
     }
 
+    /// Renames the root branch, moving its children onto the new name.
+    pub fn rename_root_branch(&mut self, new_name: &str) -> anyhow::Result<()> {
+        let Some(root_branch) = self.get_root_branch()? else {
+            anyhow::bail!("Cannot rename the root branch, because there isn't one.");
+        };
+        let existing: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT name FROM branches WHERE name = ?",
+                (new_name,),
+                |row| row.get(0),
+            )
+            .optional()?;
+        anyhow::ensure!(
+            existing.is_none(),
+            "Cannot rename the root branch to `{new_name}`, because `{new_name}` is already tracked."
+        );
+        self.conn.execute(
+            "UPDATE branches SET name = ? WHERE name = ? AND parent IS NULL",
+            (new_name, &root_branch),
+        )?;
+        self.conn.execute(
+            "UPDATE branches SET parent = ? WHERE parent = ?",
+            (new_name, &root_branch),
+        )?;
+        Ok(())
+    }
+
     pub fn create_branch(&mut self, current_branch: &str, new_branch: &str) -> anyhow::Result<()> {
         let current_branch_exists: bool = {
             let count: usize = self.conn.query_row(
@@ -200,6 +410,15 @@ impl Transaction<'_> {
         Ok(())
     }
 
+    /// Makes `branch` a child of `parent`. Its own children move along with it.
+    pub fn set_parent(&mut self, branch: &str, parent: &str) -> anyhow::Result<()> {
+        self.conn.execute(
+            "UPDATE branches SET parent = ? WHERE name = ?",
+            (parent, branch),
+        )?;
+        Ok(())
+    }
+
     /// Modifies all children of a given branch to be rebase on the branch's parent,
     /// and then removes the branch from the database.
     pub fn remove_branch(&mut self, branch: &str) -> anyhow::Result<()> {
@@ -215,105 +434,1079 @@ impl Transaction<'_> {
             anyhow::bail!("Cannot remove branch `{branch}`, because it doesn't exist.");
         };
 
+        let children = self.get_children(branch)?;
+
+        // Children inherit the removed branch's base commit,
+        // so that its commits are kept when they're restacked onto their new parent,
+        // and its target branch, so that they're restacked onto the same branch it was.
         self.conn.execute(
             "
             UPDATE branches
-            SET parent = ?
-            WHERE parent = ?
+            SET parent = ?1,
+                base_commit = (SELECT base_commit FROM branches WHERE name = ?2),
+                target_branch = (SELECT target_branch FROM branches WHERE name = ?2)
+            WHERE parent = ?2
             ",
             (parent, branch),
         )?;
 
+        self.conn
+            .execute("DELETE FROM branches WHERE name = ?", (branch,))?;
+        self.clear_queued_operations(branch)?;
+
+        // If the branch was the base of a named stack, the stack's name moves up to its child.
+        if let Some(stack_name) = self.get_stack_name(branch)? {
+            self.conn
+                .execute("DELETE FROM stacks WHERE name = ?", (&stack_name,))?;
+            if let [child] = children.as_slice() {
+                self.set_stack_name(child, &stack_name)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Names the stack whose bottom branch is `base_branch`, replacing any existing name.
+    pub fn set_stack_name(&mut self, base_branch: &str, name: &str) -> anyhow::Result<()> {
+        if let Some(existing_base) = self.get_stack_base(name)? {
+            anyhow::ensure!(
+                existing_base == base_branch,
+                "There is already a stack named `{name}`, starting at `{existing_base}`."
+            );
+        }
+        self.conn
+            .execute("DELETE FROM stacks WHERE base_branch = ?", (base_branch,))?;
         self.conn.execute(
-            "DELETE FROM branches WHERE name = ?",
-            (branch,),
+            "INSERT INTO stacks ( name, base_branch ) VALUES ( ?, ? )",
+            (name, base_branch),
         )?;
-
         Ok(())
     }
 
-    /// Returns all of the branches in the stack belonging to `current_branch`.
-    /// Always the branches in "ascending order," such that branches closer to the root branch
-    /// are earlier in the list.
-    pub fn get_branches_in_stack(&mut self, current_branch: &str) -> anyhow::Result<Vec<Branch>> {
-        let mut stmt = self.conn.prepare(
-            "
-            WITH RECURSIVE
-              stack_branches(name, parent, level) AS (
-                VALUES(?, ?, 0)
+    /// Moves the name of a stack onto `base_branch`, after a different branch has become its bottom branch.
+    pub fn move_stack_name(&mut self, name: &str, base_branch: &str) -> anyhow::Result<()> {
+        self.conn
+            .execute("DELETE FROM stacks WHERE name = ?", (name,))?;
+        self.set_stack_name(base_branch, name)
+    }
 
-                UNION
+    pub fn get_stack_name(&self, base_branch: &str) -> anyhow::Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT name FROM stacks WHERE base_branch = ?",
+                (base_branch,),
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
 
-                SELECT branches.name, branches.parent, stack_branches.level + 1
-                FROM branches, stack_branches
-                WHERE branches.parent = stack_branches.name
+    /// Returns the bottom branch of the stack named `name`.
+    pub fn get_stack_base(&self, name: &str) -> anyhow::Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT base_branch FROM stacks WHERE name = ?",
+                (name,),
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
 
-                UNION
+    /// Adds an operation for `branch`, whose pull request merges into `base`,
+    /// to the end of the submit queue.
+    pub fn queue_operation(
+        &mut self,
+        branch: &str,
+        base: &str,
+        operation: &OperationKind,
+    ) -> anyhow::Result<()> {
+        let (name, position) = match operation {
+            OperationKind::Push => ("push", None),
+            OperationKind::PullRequest { position } => ("pull-request", *position),
+        };
+        self.conn.execute(
+            "
+            INSERT INTO submit_queue (
+                operation,
+                branch,
+                base,
+                position,
+                stack_size
+            ) VALUES ( ?, ?, ?, ?, ? )
+            ",
+            (
+                name,
+                branch,
+                base,
+                position.map(|(position, _)| position),
+                position.map(|(_, stack_size)| stack_size),
+            ),
+        )?;
+        Ok(())
+    }
 
-                SELECT branches.name, branches.parent, stack_branches.level - 1
-                FROM branches, stack_branches
-                WHERE stack_branches.parent = branches.name
-                  AND branches.parent IS NOT NULL
-              )
-            SELECT DISTINCT name, parent
-            FROM stack_branches
-            WHERE name <> parent
-            ORDER BY level ASC
+    /// Returns the queued operations, in the order they were queued.
+    pub fn get_queued_operations(&self) -> anyhow::Result<Vec<QueuedOperation>> {
+        let mut stmt = self.conn.prepare(
+            "
+            SELECT id, operation, branch, base, position, stack_size
+            FROM submit_queue
+            ORDER BY id ASC
             ",
         )?;
-        let branches = stmt
-            .query_map((current_branch, current_branch), |row| {
-                Ok(Branch {
-                    name: row.get(0)?,
-                    parent: row.get(1)?,
-                })
+        let rows = stmt
+            .query_map((), |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, Option<usize>>(4)?,
+                    row.get::<_, Option<usize>>(5)?,
+                ))
             })?
-            .collect::<rusqlite::Result<Vec<Branch>>>()?;
-        Ok(branches)
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter()
+            .map(|(id, operation, branch, base, position, stack_size)| {
+                let kind = match operation.as_str() {
+                    "push" => OperationKind::Push,
+                    "pull-request" => OperationKind::PullRequest {
+                        position: position.zip(stack_size),
+                    },
+                    _ => anyhow::bail!("Unknown queued operation `{operation}` for `{branch}`."),
+                };
+                Ok(QueuedOperation {
+                    id,
+                    branch,
+                    base,
+                    kind,
+                })
+            })
+            .collect()
     }
-}
 
-#[derive(Debug, Eq, PartialEq)]
-pub struct Branch {
-    pub name: String,
-    pub parent: String,
-}
+    pub fn remove_queued_operation(&mut self, id: i64) -> anyhow::Result<()> {
+        self.conn
+            .execute("DELETE FROM submit_queue WHERE id = ?", (id,))?;
+        Ok(())
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempdir::TempDir;
+    /// Drops every queued operation for `branch`, e.g. because a newer submit supersedes them.
+    pub fn clear_queued_operations(&mut self, branch: &str) -> anyhow::Result<()> {
+        self.conn
+            .execute("DELETE FROM submit_queue WHERE branch = ?", (branch,))?;
+        Ok(())
+    }
 
-    #[test]
-    fn test_get_branches_in_stack() -> anyhow::Result<()> {
-        let temp_dir = TempDir::new("diamond-unit-tests")?;
-        let mut database = Database::new(temp_dir.path().join("database.sqlite3"))?;
-        let mut tx = database.transaction()?;
+    /// Records that `command` has finished with `branch`, leaving it at `commit`,
+    /// so that a rerun after a failure can pick up where it left off.
+    /// `command` also names what the command ran on, like `sync ch/a` for the stack starting at `ch/a`.
+    pub fn record_progress(
+        &mut self,
+        command: &str,
+        branch: &str,
+        commit: &str,
+    ) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO progress (command, branch, commit_hash) VALUES (?, ?, ?)",
+            (command, branch, commit),
+        )?;
+        Ok(())
+    }
 
-        tx.set_root_branch("main")?;
-        tx.create_branch("main", "ch/unrelated-branch")?;
-        tx.create_branch("main", "ch/branch-1")?;
-        tx.create_branch("ch/branch-1", "ch/branch-2")?;
-        tx.create_branch("ch/branch-2", "ch/branch-3")?;
+    /// Returns the branches an unfinished run of `command` got through, and the commits it left them at.
+    pub fn get_progress(&self, command: &str) -> anyhow::Result<HashMap<String, String>> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT branch, commit_hash FROM progress WHERE command = ?")?;
+        let progress = statement
+            .query_map((command,), |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(progress)
+    }
 
-        let expected_stack = vec![
-            Branch {
-                name: "ch/branch-1".to_owned(),
-                parent: "main".to_owned(),
-            },
-            Branch {
-                name: "ch/branch-2".to_owned(),
-                parent: "ch/branch-1".to_owned(),
-            },
-            Branch {
-                name: "ch/branch-3".to_owned(),
-                parent: "ch/branch-2".to_owned(),
-            },
-        ];
+    pub fn clear_progress(&mut self, command: &str) -> anyhow::Result<()> {
+        self.conn
+            .execute("DELETE FROM progress WHERE command = ?", (command,))?;
+        Ok(())
+    }
 
-        for branch in ["ch/branch-1", "ch/branch-2", "ch/branch-3"] {
-            assert_eq!(tx.get_branches_in_stack(branch)?, expected_stack,);
+    /// Replaces what `dmd daemon` found the last time it checked the repo.
+    pub fn set_daemon_report(&mut self, report: &DaemonReport) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO daemon_report (id, checked_at, root_behind) VALUES (1, ?, ?)",
+            (report.checked_at, report.root_behind),
+        )?;
+        self.conn.execute("DELETE FROM daemon_findings", ())?;
+        let findings = report
+            .merged
+            .iter()
+            .map(|branch| (branch, true, None))
+            .chain(
+                report
+                    .outdated
+                    .iter()
+                    .map(|(branch, reason)| (branch, false, Some(reason))),
+            );
+        for (position, (branch, merged, reason)) in findings.enumerate() {
+            self.conn.execute(
+                "INSERT INTO daemon_findings (position, branch, merged, reason) VALUES (?, ?, ?, ?)",
+                (position, branch, merged, reason),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Returns what `dmd daemon` found the last time it checked the repo, if it ever has.
+    pub fn get_daemon_report(&self) -> anyhow::Result<Option<DaemonReport>> {
+        let Some((checked_at, root_behind)) = self
+            .conn
+            .query_row(
+                "SELECT checked_at, root_behind FROM daemon_report WHERE id = 1",
+                (),
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        let mut report = DaemonReport {
+            checked_at,
+            root_behind,
+            merged: Vec::new(),
+            outdated: Vec::new(),
+        };
+        let mut stmt = self
+            .conn
+            .prepare("SELECT branch, merged, reason FROM daemon_findings ORDER BY position ASC")?;
+        let findings = stmt
+            .query_map((), |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, bool>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for (branch, merged, reason) in findings {
+            match (merged, reason) {
+                (false, Some(reason)) => report.outdated.push((branch, reason)),
+                _ => report.merged.push(branch),
+            }
         }
+        Ok(Some(report))
+    }
+
+    /// Records the commit on the parent branch which `branch` is currently built on top of.
+    pub fn set_base_commit(&mut self, branch: &str, base_commit: &str) -> anyhow::Result<()> {
+        self.conn.execute(
+            "UPDATE branches SET base_commit = ? WHERE name = ?",
+            (base_commit, branch),
+        )?;
+        Ok(())
+    }
+
+    pub fn get_base_commit(&self, branch: &str) -> anyhow::Result<Option<String>> {
+        let base_commit: Option<Option<String>> = self
+            .conn
+            .query_row(
+                "SELECT base_commit FROM branches WHERE name = ?",
+                (branch,),
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(base_commit.flatten())
+    }
+
+    /// Records the commit which diamond last pushed to the remote for `branch`.
+    pub fn set_pushed_commit(&mut self, branch: &str, pushed_commit: &str) -> anyhow::Result<()> {
+        self.conn.execute(
+            "UPDATE branches SET pushed_commit = ? WHERE name = ?",
+            (pushed_commit, branch),
+        )?;
+        Ok(())
+    }
+
+    pub fn get_pushed_commit(&self, branch: &str) -> anyhow::Result<Option<String>> {
+        let pushed_commit: Option<Option<String>> = self
+            .conn
+            .query_row(
+                "SELECT pushed_commit FROM branches WHERE name = ?",
+                (branch,),
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(pushed_commit.flatten())
+    }
+
+    pub fn get_parent(&self, branch: &str) -> anyhow::Result<Option<String>> {
+        let parent: Option<Option<String>> = self
+            .conn
+            .query_row(
+                "SELECT parent FROM branches WHERE name = ?",
+                (branch,),
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(parent.flatten())
+    }
+
+    pub fn get_children(&self, branch: &str) -> anyhow::Result<Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT name FROM branches WHERE parent = ? ORDER BY name ASC")?;
+        let children = stmt
+            .query_map((branch,), |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(children)
+    }
+
+    /// Returns `branch` and each of its ancestors, excluding the root branch.
+    /// Branches closer to the root branch are earlier in the list.
+    pub fn get_ancestors(&self, branch: &str) -> anyhow::Result<Vec<Branch>> {
+        let mut ancestors = Vec::new();
+        let mut name = branch.to_owned();
+        while let Some(parent) = self.get_parent(&name)? {
+            ancestors.push(Branch {
+                name,
+                parent: parent.clone(),
+            });
+            name = parent;
+        }
+        ancestors.reverse();
+        Ok(ancestors)
+    }
+
+    pub fn set_archived(&mut self, branch: &str, archived: bool) -> anyhow::Result<()> {
+        let updated = self.conn.execute(
+            "UPDATE branches SET archived = ? WHERE name = ? AND parent IS NOT NULL",
+            (archived, branch),
+        )?;
+        anyhow::ensure!(
+            updated > 0,
+            "Cannot archive `{branch}`, because it is not tracked or is the root branch."
+        );
+        Ok(())
+    }
+
+    /// Sets the branch that the stack starting at `branch` is restacked onto and opens pull requests against,
+    /// like `release/1.2`, in place of the root branch. `None` goes back to the root branch.
+    pub fn set_target_branch(
+        &mut self,
+        branch: &str,
+        target_branch: Option<&str>,
+    ) -> anyhow::Result<()> {
+        self.conn.execute(
+            "UPDATE branches SET target_branch = ? WHERE name = ?",
+            (target_branch, branch),
+        )?;
+        Ok(())
+    }
+
+    pub fn get_target_branch(&self, branch: &str) -> anyhow::Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT target_branch FROM branches WHERE name = ?",
+                (branch,),
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten())
+    }
+
+    /// Gets the branch that `branch` is built on: its parent,
+    /// or its stack's target branch if its parent is the root branch.
+    /// Returns `None` if `branch` isn't tracked or is the root branch.
+    pub fn get_effective_parent(&self, branch: &str) -> anyhow::Result<Option<String>> {
+        let parent: Option<Option<String>> = self
+            .conn
+            .query_row(
+                "
+                SELECT COALESCE(
+                  CASE
+                    WHEN parent IN (SELECT name FROM branches WHERE parent IS NULL)
+                    THEN target_branch
+                  END,
+                  parent
+                )
+                FROM branches
+                WHERE name = ?
+                ",
+                (branch,),
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(parent.flatten())
+    }
+
+    /// Sets the name `branch` is pushed as on the remote, or clears it to push it under its own name.
+    pub fn set_remote_name(
+        &mut self,
+        branch: &str,
+        remote_name: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let updated = self.conn.execute(
+            "UPDATE branches SET remote_name = ? WHERE name = ?",
+            (remote_name, branch),
+        )?;
+        anyhow::ensure!(
+            updated > 0,
+            "Cannot set the remote name of `{branch}`, because it is not tracked."
+        );
+        Ok(())
+    }
+
+    pub fn get_remote_name(&self, branch: &str) -> anyhow::Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT remote_name FROM branches WHERE name = ?",
+                (branch,),
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten())
+    }
+
+    /// Remembers the number of `branch`'s pull request, so that it can be looked up without searching for it.
+    pub fn set_pull_request(&mut self, branch: &str, number: u64) -> anyhow::Result<()> {
+        let updated = self.conn.execute(
+            "UPDATE branches SET pull_request = ? WHERE name = ?",
+            (number, branch),
+        )?;
+        anyhow::ensure!(
+            updated > 0,
+            "Cannot set the pull request of `{branch}`, because it is not tracked."
+        );
+        Ok(())
+    }
+
+    /// Returns the numbers of the pull requests opened from each branch, as far as they're known.
+    pub fn get_pull_requests(&self) -> anyhow::Result<HashMap<String, u64>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT name, pull_request FROM branches WHERE pull_request IS NOT NULL")?;
+        let pull_requests = stmt
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<HashMap<String, u64>>>()?;
+        Ok(pull_requests)
+    }
+
+    /// Adds to the issues which `branch`'s pull request closes.
+    pub fn add_closed_issues(&mut self, branch: &str, issues: &[u64]) -> anyhow::Result<()> {
+        if issues.is_empty() {
+            return Ok(());
+        }
+        let mut closes = self.get_closed_issues(branch)?;
+        for issue in issues {
+            if !closes.contains(issue) {
+                closes.push(*issue);
+            }
+        }
+        let closes: Vec<String> = closes.iter().map(u64::to_string).collect();
+        let updated = self.conn.execute(
+            "UPDATE branches SET closes = ? WHERE name = ?",
+            (closes.join(","), branch),
+        )?;
+        anyhow::ensure!(
+            updated > 0,
+            "Cannot close issues from `{branch}`, because it is not tracked."
+        );
+        Ok(())
+    }
+
+    pub fn get_closed_issues(&self, branch: &str) -> anyhow::Result<Vec<u64>> {
+        let closes: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT closes FROM branches WHERE name = ?",
+                (branch,),
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+        Ok(closes
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|issue| issue.parse().ok())
+            .collect())
+    }
+
+    /// Attaches `note` to `branch`, or clears its note if `note` is `None`.
+    pub fn set_note(&mut self, branch: &str, note: Option<&str>) -> anyhow::Result<()> {
+        let updated = self.conn.execute(
+            "UPDATE branches SET note = ? WHERE name = ?",
+            (note, branch),
+        )?;
+        anyhow::ensure!(
+            updated > 0,
+            "Cannot add a note to `{branch}`, because it is not tracked."
+        );
+        Ok(())
+    }
+
+    pub fn get_note(&self, branch: &str) -> anyhow::Result<Option<String>> {
+        let note: Option<Option<String>> = self
+            .conn
+            .query_row(
+                "SELECT note FROM branches WHERE name = ?",
+                (branch,),
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(note.flatten())
+    }
+
+    /// Returns every branch with a note, along with its note.
+    pub fn get_notes(&self) -> anyhow::Result<HashMap<String, String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT name, note FROM branches WHERE note IS NOT NULL")?;
+        let notes = stmt
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<HashMap<String, String>>>()?;
+        Ok(notes)
+    }
+
+    /// Returns when each branch was created and last changed, in seconds since the Unix epoch.
+    /// Branches tracked before these were recorded have no timestamps.
+    pub fn get_timestamps(&self) -> anyhow::Result<HashMap<String, Timestamps>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT name, created_at, updated_at FROM branches")?;
+        let timestamps = stmt
+            .query_map((), |row| {
+                Ok((
+                    row.get(0)?,
+                    Timestamps {
+                        created_at: row.get(1)?,
+                        updated_at: row.get(2)?,
+                    },
+                ))
+            })?
+            .collect::<rusqlite::Result<HashMap<String, Timestamps>>>()?;
+        Ok(timestamps)
+    }
+
+    pub fn get_archived_branches(&self) -> anyhow::Result<Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT name FROM branches WHERE archived ORDER BY name ASC")?;
+        let branches = stmt
+            .query_map((), |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(branches)
+    }
+
+    /// Returns all of the descendants of `branch`, with parents listed before their children.
+    pub fn get_descendants(&self, branch: &str) -> anyhow::Result<Vec<String>> {
+        let mut descendants = Vec::new();
+        let mut pending = vec![branch.to_owned()];
+        while let Some(branch) = pending.pop() {
+            for child in self.get_children(&branch)? {
+                descendants.push(child.clone());
+                pending.push(child);
+            }
+        }
+        Ok(descendants)
+    }
+
+    /// Returns all of the branches in the stack belonging to `current_branch`.
+    /// Archived branches are left out.
+    /// If the stack has a target branch, it's given as the bottom branch's parent instead of the root branch.
+    /// Always the branches in "ascending order," such that branches closer to the root branch
+    /// are earlier in the list.
+    pub fn get_branches_in_stack(&mut self, current_branch: &str) -> anyhow::Result<Vec<Branch>> {
+        let mut stmt = self.conn.prepare(
+            "
+            WITH RECURSIVE
+              stack_branches(name, parent, level) AS (
+                VALUES(?, ?, 0)
+
+                UNION
+
+                SELECT branches.name, branches.parent, stack_branches.level + 1
+                FROM branches, stack_branches
+                WHERE branches.parent = stack_branches.name
+
+                UNION
+
+                SELECT branches.name, branches.parent, stack_branches.level - 1
+                FROM branches, stack_branches
+                WHERE stack_branches.parent = branches.name
+                  AND branches.parent IS NOT NULL
+              )
+            SELECT DISTINCT
+              stack_branches.name,
+              COALESCE(
+                CASE
+                  WHEN stack_branches.parent IN (SELECT name FROM branches WHERE parent IS NULL)
+                  THEN branches.target_branch
+                END,
+                stack_branches.parent
+              )
+            FROM stack_branches
+            JOIN branches ON branches.name = stack_branches.name
+            WHERE stack_branches.name <> stack_branches.parent
+              AND NOT branches.archived
+            ORDER BY level ASC
+            ",
+        )?;
+        let branches = stmt
+            .query_map((current_branch, current_branch), |row| {
+                Ok(Branch {
+                    name: row.get(0)?,
+                    parent: row.get(1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<Branch>>>()?;
+        Ok(branches)
+    }
+
+    /// Returns every tracked branch other than the root branch, ordered by name.
+    pub fn get_all_branches(&self) -> anyhow::Result<Vec<Branch>> {
+        let mut stmt = self.conn.prepare(
+            "
+            SELECT name, parent
+            FROM branches
+            WHERE parent IS NOT NULL
+            ORDER BY name ASC
+            ",
+        )?;
+        let branches = stmt
+            .query_map((), |row| {
+                Ok(Branch {
+                    name: row.get(0)?,
+                    parent: row.get(1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<Branch>>>()?;
+        Ok(branches)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Branch {
+    pub name: String,
+    pub parent: String,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Timestamps {
+    pub created_at: Option<u64>,
+    pub updated_at: Option<u64>,
+}
+
+/// What `dmd daemon` found the last time it checked the repo, for `dmd status` to report.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DaemonReport {
+    /// When the check finished, in seconds since the Unix epoch.
+    pub checked_at: u64,
+    /// How many commits the root branch is behind its remote branch.
+    pub root_behind: usize,
+    /// The branches which look merged, whether by their pull requests or because the remote root branch has them.
+    pub merged: Vec<String>,
+    /// The branches which need restacking, with the reason each one does, in the order they'd be restacked.
+    pub outdated: Vec<(String, String)>,
+}
+
+/// An operation recorded by `dmd submit`, which stays queued until it succeeds.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QueuedOperation {
+    pub id: i64,
+    pub branch: String,
+    /// The branch that `branch`'s pull request merges into.
+    pub base: String,
+    pub kind: OperationKind,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum OperationKind {
+    /// Pushes the branch to the remote.
+    Push,
+    /// Creates or updates the branch's pull request.
+    /// `position` is the pull request's place in its stack and the stack's size, for title prefixes.
+    PullRequest { position: Option<(usize, usize)> },
+}
+
+/// Whether `err` says the database file is damaged or isn't a database at all,
+/// as opposed to being locked, unreadable, or failing to migrate.
+pub fn is_corrupt(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<rusqlite::Error>(),
+            Some(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error {
+                    code: rusqlite::ErrorCode::DatabaseCorrupt | rusqlite::ErrorCode::NotADatabase,
+                    ..
+                },
+                _,
+            ))
+        )
+    })
+}
+
+/// Renames the database at `path` out of the way, next to it, returning where it went.
+/// Earlier backups are kept, by numbering later ones.
+pub fn move_aside(path: &Path) -> anyhow::Result<PathBuf> {
+    let mut backup = path.with_extension("sqlite3.corrupted");
+    let mut number = 1;
+    while backup.exists() {
+        backup = path.with_extension(format!("sqlite3.corrupted.{number}"));
+        number += 1;
+    }
+    std::fs::rename(path, &backup)?;
+    Ok(backup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_corrupt_database_is_moved_aside() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new("diamond-unit-tests")?;
+        let path = temp_dir.path().join("diamond.sqlite3");
+        for _ in 0..2 {
+            std::fs::write(
+                &path,
+                "not a database, but long enough to have a header in it",
+            )?;
+            let err = Database::new(&path).err().unwrap();
+            assert!(is_corrupt(&err));
+            move_aside(&path)?;
+        }
+        assert!(temp_dir.path().join("diamond.sqlite3.corrupted").exists());
+        assert!(temp_dir.path().join("diamond.sqlite3.corrupted.1").exists());
+        assert!(!is_corrupt(&anyhow::anyhow!("database is locked")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_root_branch() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new("diamond-unit-tests")?;
+        let mut database = Database::new(temp_dir.path().join("database.sqlite3"))?;
+        let mut tx = database.transaction()?;
+
+        tx.set_root_branch("master")?;
+        tx.create_branch("master", "ch/branch-1")?;
+        tx.create_branch("ch/branch-1", "ch/branch-2")?;
+        assert!(tx.rename_root_branch("ch/branch-2").is_err());
+
+        tx.rename_root_branch("main")?;
+        assert_eq!(tx.get_root_branch()?.as_deref(), Some("main"));
+        assert_eq!(tx.get_parent("ch/branch-1")?.as_deref(), Some("main"));
+        assert_eq!(
+            tx.get_parent("ch/branch-2")?.as_deref(),
+            Some("ch/branch-1")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_timestamps() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new("diamond-unit-tests")?;
+        let mut database = Database::new(temp_dir.path().join("database.sqlite3"))?;
+        let mut tx = database.transaction()?;
+
+        tx.set_root_branch("main")?;
+        tx.create_branch("main", "ch/branch-1")?;
+        let timestamps = tx.get_timestamps()?["ch/branch-1"];
+        assert!(timestamps.created_at.is_some());
+        assert_eq!(timestamps.created_at, timestamps.updated_at);
+
+        tx.conn.execute(
+            "UPDATE branches SET created_at = 1, updated_at = 1 WHERE name = ?",
+            ("ch/branch-1",),
+        )?;
+        assert_eq!(
+            tx.get_timestamps()?["ch/branch-1"],
+            Timestamps {
+                created_at: Some(1),
+                updated_at: Some(1),
+            }
+        );
+        tx.set_note("ch/branch-1", Some("wip"))?;
+        let timestamps = tx.get_timestamps()?["ch/branch-1"];
+        assert_eq!(timestamps.created_at, Some(1));
+        assert!(timestamps.updated_at > Some(1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_submit_queue() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new("diamond-unit-tests")?;
+        let mut database = Database::new(temp_dir.path().join("database.sqlite3"))?;
+        let mut tx = database.transaction()?;
+
+        tx.set_root_branch("main")?;
+        tx.create_branch("main", "ch/branch-1")?;
+        tx.create_branch("ch/branch-1", "ch/branch-2")?;
+        let pull_request = OperationKind::PullRequest {
+            position: Some((1, 2)),
+        };
+        tx.queue_operation("ch/branch-1", "main", &OperationKind::Push)?;
+        tx.queue_operation("ch/branch-2", "ch/branch-1", &OperationKind::Push)?;
+        tx.queue_operation("ch/branch-1", "main", &pull_request)?;
+
+        let queue = tx.get_queued_operations()?;
+        let operations: Vec<(&str, &str, &OperationKind)> = queue
+            .iter()
+            .map(|operation| {
+                (
+                    operation.branch.as_str(),
+                    operation.base.as_str(),
+                    &operation.kind,
+                )
+            })
+            .collect();
+        assert_eq!(
+            operations,
+            vec![
+                ("ch/branch-1", "main", &OperationKind::Push),
+                ("ch/branch-2", "ch/branch-1", &OperationKind::Push),
+                ("ch/branch-1", "main", &pull_request),
+            ]
+        );
+
+        tx.remove_queued_operation(queue[0].id)?;
+        tx.remove_branch("ch/branch-1")?;
+        let queue = tx.get_queued_operations()?;
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].branch, "ch/branch-2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_progress_survives_rollback_after_checkpoint() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new("diamond-unit-tests")?;
+        let mut database = Database::new(temp_dir.path().join("database.sqlite3"))?;
+
+        let mut tx = database.transaction()?;
+        tx.record_progress("sync", "ch/branch-1", "abc123")?;
+        tx.checkpoint()?;
+        tx.record_progress("sync", "ch/branch-2", "def456")?;
+        drop(tx);
+
+        let mut tx = database.transaction()?;
+        let progress = tx.get_progress("sync")?;
+        assert_eq!(progress.len(), 1);
+        assert_eq!(progress["ch/branch-1"], "abc123");
+        assert!(tx.get_progress("submit")?.is_empty());
+
+        tx.clear_progress("sync")?;
+        assert!(tx.get_progress("sync")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_branches_in_stack() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new("diamond-unit-tests")?;
+        let mut database = Database::new(temp_dir.path().join("database.sqlite3"))?;
+        let mut tx = database.transaction()?;
+
+        tx.set_root_branch("main")?;
+        tx.create_branch("main", "ch/unrelated-branch")?;
+        tx.create_branch("main", "ch/branch-1")?;
+        tx.create_branch("ch/branch-1", "ch/branch-2")?;
+        tx.create_branch("ch/branch-2", "ch/branch-3")?;
+
+        let expected_stack = vec![
+            Branch {
+                name: "ch/branch-1".to_owned(),
+                parent: "main".to_owned(),
+            },
+            Branch {
+                name: "ch/branch-2".to_owned(),
+                parent: "ch/branch-1".to_owned(),
+            },
+            Branch {
+                name: "ch/branch-3".to_owned(),
+                parent: "ch/branch-2".to_owned(),
+            },
+        ];
+
+        for branch in ["ch/branch-1", "ch/branch-2", "ch/branch-3"] {
+            assert_eq!(tx.get_branches_in_stack(branch)?, expected_stack,);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_ancestors() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new("diamond-unit-tests")?;
+        let mut database = Database::new(temp_dir.path().join("database.sqlite3"))?;
+        let mut tx = database.transaction()?;
+
+        tx.set_root_branch("main")?;
+        tx.create_branch("main", "ch/branch-1")?;
+        tx.create_branch("ch/branch-1", "ch/branch-2")?;
+        tx.create_branch("ch/branch-2", "ch/branch-3")?;
+
+        assert_eq!(
+            tx.get_ancestors("ch/branch-2")?,
+            vec![
+                Branch {
+                    name: "ch/branch-1".to_owned(),
+                    parent: "main".to_owned(),
+                },
+                Branch {
+                    name: "ch/branch-2".to_owned(),
+                    parent: "ch/branch-1".to_owned(),
+                },
+            ],
+        );
+        assert_eq!(tx.get_ancestors("main")?, vec![]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_branch_keeps_base_commit() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new("diamond-unit-tests")?;
+        let mut database = Database::new(temp_dir.path().join("database.sqlite3"))?;
+        let mut tx = database.transaction()?;
+
+        tx.set_root_branch("main")?;
+        tx.create_branch("main", "ch/branch-1")?;
+        tx.set_base_commit("ch/branch-1", "main-sha")?;
+        tx.create_branch("ch/branch-1", "ch/branch-2")?;
+        tx.set_base_commit("ch/branch-2", "branch-1-sha")?;
+
+        tx.remove_branch("ch/branch-1")?;
+        assert_eq!(tx.get_parent("ch/branch-2")?, Some("main".to_owned()));
+        assert_eq!(
+            tx.get_base_commit("ch/branch-2")?,
+            Some("main-sha".to_owned())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_branches_in_stack_skips_archived() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new("diamond-unit-tests")?;
+        let mut database = Database::new(temp_dir.path().join("database.sqlite3"))?;
+        let mut tx = database.transaction()?;
+
+        tx.set_root_branch("main")?;
+        tx.create_branch("main", "ch/branch-1")?;
+        tx.create_branch("ch/branch-1", "ch/branch-2")?;
+        tx.create_branch("ch/branch-1", "ch/abandoned")?;
+        tx.set_archived("ch/abandoned", true)?;
+
+        let stack: Vec<String> = tx
+            .get_branches_in_stack("ch/branch-2")?
+            .into_iter()
+            .map(|branch| branch.name)
+            .collect();
+        assert_eq!(stack, vec!["ch/branch-1", "ch/branch-2"]);
+        assert_eq!(tx.get_archived_branches()?, vec!["ch/abandoned"]);
+        assert!(tx.set_archived("main", true).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stack_name_moves_up_when_base_is_removed() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new("diamond-unit-tests")?;
+        let mut database = Database::new(temp_dir.path().join("database.sqlite3"))?;
+        let mut tx = database.transaction()?;
+
+        tx.set_root_branch("main")?;
+        tx.create_branch("main", "ch/branch-1")?;
+        tx.create_branch("ch/branch-1", "ch/branch-2")?;
+        tx.create_branch("main", "ch/other")?;
+        tx.set_stack_name("ch/branch-1", "my-stack")?;
+        assert!(tx.set_stack_name("ch/other", "my-stack").is_err());
+
+        tx.remove_branch("ch/branch-1")?;
+        assert_eq!(
+            tx.get_stack_base("my-stack")?,
+            Some("ch/branch-2".to_owned())
+        );
+        assert_eq!(
+            tx.get_stack_name("ch/branch-2")?,
+            Some("my-stack".to_owned())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_daemon_report() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new("diamond-unit-tests")?;
+        let mut database = Database::new(temp_dir.path().join("database.sqlite3"))?;
+        let mut tx = database.transaction()?;
+        assert_eq!(tx.get_daemon_report()?, None);
+
+        let report = DaemonReport {
+            checked_at: 1700000000,
+            root_behind: 3,
+            merged: vec!["ch/branch-1".to_owned()],
+            outdated: vec![
+                (
+                    "ch/branch-3".to_owned(),
+                    "3 behind `origin/main`".to_owned(),
+                ),
+                (
+                    "ch/branch-2".to_owned(),
+                    "`ch/branch-3` is outdated".to_owned(),
+                ),
+            ],
+        };
+        tx.set_daemon_report(&report)?;
+        assert_eq!(tx.get_daemon_report()?, Some(report));
+
+        // Each report replaces the last one.
+        let report = DaemonReport {
+            checked_at: 1700000300,
+            root_behind: 0,
+            merged: vec![],
+            outdated: vec![],
+        };
+        tx.set_daemon_report(&report)?;
+        assert_eq!(tx.get_daemon_report()?, Some(report));
+        Ok(())
+    }
+
+    #[test]
+    fn test_target_branch() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new("diamond-unit-tests")?;
+        let mut database = Database::new(temp_dir.path().join("database.sqlite3"))?;
+        let mut tx = database.transaction()?;
+
+        tx.set_root_branch("main")?;
+        tx.create_branch("main", "ch/branch-1")?;
+        tx.create_branch("ch/branch-1", "ch/branch-2")?;
+        tx.set_target_branch("ch/branch-1", Some("release/1.2"))?;
+
+        let parents: Vec<String> = tx
+            .get_branches_in_stack("ch/branch-2")?
+            .into_iter()
+            .map(|branch| branch.parent)
+            .collect();
+        assert_eq!(parents, vec!["release/1.2", "ch/branch-1"]);
+        assert_eq!(tx.get_parent("ch/branch-1")?, Some("main".to_owned()));
+        assert_eq!(
+            tx.get_effective_parent("ch/branch-1")?,
+            Some("release/1.2".to_owned())
+        );
+        assert_eq!(
+            tx.get_effective_parent("ch/branch-2")?,
+            Some("ch/branch-1".to_owned())
+        );
+        assert_eq!(tx.get_effective_parent("main")?, None);
+
+        tx.remove_branch("ch/branch-1")?;
+        assert_eq!(
+            tx.get_target_branch("ch/branch-2")?,
+            Some("release/1.2".to_owned())
+        );
 
         Ok(())
     }
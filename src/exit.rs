@@ -0,0 +1,80 @@
+use crate::interrupt::Interrupted;
+
+/// What `dmd` exits with when it fails, so that scripts can tell failures apart without parsing messages.
+/// 2 is left out, since clap exits with it when the arguments can't be parsed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExitCode {
+    /// Any failure without a more specific code.
+    Failure = 1,
+    /// The repo hasn't been set up with `dmd init`.
+    NotInitialized = 3,
+    /// A branch which needs to be tracked isn't, or is the root branch.
+    NotTracked = 4,
+    /// A rebase, cherry-pick, or revert stopped on conflicts, which are left for the user to resolve.
+    Conflict = 5,
+    /// A branch has commits on the remote which aren't in the local branch.
+    DivergedRemote = 6,
+    /// A request to the GitHub API failed.
+    Api = 7,
+    /// Local changes are in the way.
+    DirtyWorktree = 8,
+    /// A confirmation was declined, so nothing was done.
+    Aborted = 9,
+    /// Ctrl-C was pressed, like the shell's code for SIGINT.
+    Interrupted = 130,
+}
+
+/// An error which exits with `code`.
+#[derive(Debug)]
+struct CodedError {
+    code: ExitCode,
+    message: String,
+}
+
+impl std::fmt::Display for CodedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CodedError {}
+
+/// Makes an error with `message` which exits with `code`.
+pub fn error(code: ExitCode, message: impl std::fmt::Display) -> anyhow::Error {
+    CodedError {
+        code,
+        message: message.to_string(),
+    }
+    .into()
+}
+
+/// Finds the code to exit with for `err`, from the first error in its chain which has one.
+pub fn code(err: &anyhow::Error) -> ExitCode {
+    for cause in err.chain() {
+        if let Some(coded) = cause.downcast_ref::<CodedError>() {
+            return coded.code;
+        }
+        if cause.is::<Interrupted>() {
+            return ExitCode::Interrupted;
+        }
+    }
+    ExitCode::Failure
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code() {
+        assert_eq!(code(&anyhow::anyhow!("Oops.")), ExitCode::Failure);
+        let err = error(ExitCode::NotTracked, "Cannot edit `ch/a`.");
+        assert_eq!(err.to_string(), "Cannot edit `ch/a`.");
+        assert_eq!(code(&err), ExitCode::NotTracked);
+        assert_eq!(code(&err.context("While syncing.")), ExitCode::NotTracked);
+        assert_eq!(
+            code(&anyhow::Error::from(Interrupted)),
+            ExitCode::Interrupted
+        );
+    }
+}
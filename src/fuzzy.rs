@@ -0,0 +1,84 @@
+/// Finds the candidates which start with `prefix`.
+pub fn prefix_matches<'a>(prefix: &str, candidates: &'a [String]) -> Vec<&'a str> {
+    candidates
+        .iter()
+        .filter(|candidate| candidate.starts_with(prefix))
+        .map(String::as_str)
+        .collect()
+}
+
+/// Finds up to three candidates which `name` is likely a typo of, closest first.
+/// Names are compared with each candidate both whole and without its first prefix,
+/// so that `branch-1` is close to `ch/branch-1`.
+pub fn suggestions<'a>(name: &str, candidates: &'a [String]) -> Vec<&'a str> {
+    let name = name.to_lowercase();
+    let max_distance = (name.chars().count() / 3).max(1);
+    let mut close: Vec<(usize, &str)> = candidates
+        .iter()
+        .filter_map(|candidate| {
+            let lowercase = candidate.to_lowercase();
+            let unprefixed = lowercase
+                .split_once('/')
+                .map_or(lowercase.as_str(), |(_, rest)| rest);
+            let distance = edit_distance(&name, &lowercase).min(edit_distance(&name, unprefixed));
+            (distance <= max_distance).then_some((distance, candidate.as_str()))
+        })
+        .collect();
+    close.sort();
+    close
+        .into_iter()
+        .take(3)
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+/// Counts the characters which have to be inserted, removed, or replaced to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let replace = previous[j] + usize::from(a_char != *b_char);
+            current.push(replace.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("branch", "branch"), 0);
+        assert_eq!(edit_distance("brnach", "branch"), 2);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn test_suggestions() {
+        let candidates = ["ch/branch-1", "ch/branch-2", "ch/fix-leak", "main"].map(str::to_owned);
+        assert_eq!(
+            suggestions("ch/brnach-1", &candidates),
+            ["ch/branch-1", "ch/branch-2"]
+        );
+        assert_eq!(
+            suggestions("branch-2", &candidates),
+            ["ch/branch-2", "ch/branch-1"]
+        );
+        assert_eq!(suggestions("CH/FIX-LEAK", &candidates), ["ch/fix-leak"]);
+        assert!(suggestions("something-else", &candidates).is_empty());
+    }
+
+    #[test]
+    fn test_prefix_matches() {
+        let candidates = ["ch/branch-1", "ch/branch-2", "ch/fix-leak"].map(str::to_owned);
+        assert_eq!(prefix_matches("ch/fix", &candidates), ["ch/fix-leak"]);
+        assert_eq!(prefix_matches("ch/b", &candidates).len(), 2);
+        assert!(prefix_matches("fix", &candidates).is_empty());
+    }
+}
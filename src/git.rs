@@ -1,21 +1,53 @@
-use regex::Regex;
+use std::io::{IsTerminal, Read, Write};
 use std::path::PathBuf;
 use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use std::{
     path::Path,
     process::{ExitStatus, Stdio},
 };
 
+/// Returns to the branch which was checked out when the guard was made, once it's released or dropped.
+///
+/// Uncommitted changes are stashed while the guard is held, so that they don't get in the way of checkouts,
+/// and are reapplied once it's back on the original branch.
 pub struct BranchGuard {
     git_root: PathBuf,
     original_branch: Option<String>,
+    /// The commit the original branch pointed to, which is checked out instead if the branch is deleted.
+    original_commit: Option<String>,
+    /// The stash holding the changes which were uncommitted when the guard was made.
+    stash: Option<String>,
+    recurse_submodules: bool,
 }
 
 impl BranchGuard {
-    pub fn new(git_root: PathBuf, branch: String) -> Self {
-        Self {
+    /// Guards `branch`, which must be the current branch, or a commit if `HEAD` is detached.
+    pub fn new(
+        git_root: PathBuf,
+        branch: String,
+        recurse_submodules: bool,
+    ) -> anyhow::Result<Self> {
+        let original_commit = rev_parse(&git_root, &branch).ok();
+        let stash = stash(&git_root, &format!("dmd: uncommitted changes on {branch}"))?;
+        Ok(Self {
             git_root,
             original_branch: Some(branch),
+            original_commit,
+            stash,
+            recurse_submodules,
+        })
+    }
+
+    /// A guard which does nothing, for when the checked-out branch is left alone.
+    pub fn inactive() -> Self {
+        Self {
+            git_root: PathBuf::new(),
+            original_branch: None,
+            original_commit: None,
+            stash: None,
+            recurse_submodules: false,
         }
     }
 
@@ -23,35 +55,229 @@ impl BranchGuard {
         self.release_impl()
     }
 
+    /// Stays on whichever branch is checked out, instead of returning to the original branch.
+    pub fn dismiss(mut self) {
+        if let (Some(original_branch), Some(stash)) =
+            (self.original_branch.take(), self.stash.take())
+        {
+            eprintln!(
+                "Your uncommitted changes on `{original_branch}` are stashed in {stash}. \
+                Run `git stash pop` once you're back on it."
+            );
+        }
+    }
+
     fn release_impl(&mut self) -> anyhow::Result<()> {
         let Some(original_branch) = self.original_branch.take() else {
-            anyhow::bail!("Somehow something has already taken ");
+            return Ok(());
         };
-        checkout(&self.git_root, &original_branch)?;
+
+        // Anything left uncommitted by the guarded operation would get in the way of the checkout.
+        if let Some(left_behind) = stash(
+            &self.git_root,
+            &format!("dmd: changes left behind while returning to {original_branch}"),
+        )? {
+            eprintln!("Stashed the uncommitted changes which were left behind in {left_behind}.");
+        }
+
+        let original_exists = rev_parse(&self.git_root, &original_branch).is_ok();
+        match &self.original_commit {
+            Some(commit) if !original_exists => {
+                eprintln!("`{original_branch}` no longer exists, so checking out {commit}, where it used to be.");
+                checkout(&self.git_root, commit, self.recurse_submodules)?;
+            }
+            _ => checkout(&self.git_root, &original_branch, self.recurse_submodules)?,
+        }
+
+        if let Some(stash) = self.stash.take() {
+            if let Err(err) = unstash(&self.git_root, &stash) {
+                anyhow::bail!(
+                    "{err:#}\nYour uncommitted changes on `{original_branch}` are still stashed in {stash}."
+                );
+            }
+        }
         Ok(())
     }
 }
 
 impl Drop for BranchGuard {
+    /// Failing to get back to the original branch is reported rather than panicking,
+    /// since the guard is often dropped while an error is already on its way out.
+    fn drop(&mut self) {
+        let original_branch = self.original_branch.clone();
+        if let (Err(err), Some(original_branch)) = (self.release_impl(), original_branch) {
+            eprintln!("Warning: failed to return to `{original_branch}`: {err}");
+        }
+    }
+}
+
+/// A temporary worktree with a branch checked out, so that the branch can be rebased
+/// without touching the user's working tree. It's removed when dropped.
+pub struct Worktree {
+    git_root: PathBuf,
+    path: PathBuf,
+}
+
+impl Worktree {
+    pub fn add(git_root: &Path, branch: &str, recurse_submodules: bool) -> anyhow::Result<Self> {
+        static COUNT: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "dmd-worktree-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let status = Command::new("git")
+            .args(["worktree", "add", "--quiet"])
+            .arg(&path)
+            .arg(branch)
+            .current_dir(git_root)
+            .status()?;
+        check_status(status)?;
+        let worktree = Self {
+            git_root: git_root.to_owned(),
+            path,
+        };
+        update_submodules(&worktree.path, recurse_submodules)?;
+        Ok(worktree)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Worktree {
     fn drop(&mut self) {
-        if self.original_branch.is_some() {
-            self.release_impl()
-                .expect("Failed to move back to original Git branch during BranchGuard drop.");
+        let result = Command::new("git")
+            .args(["worktree", "remove", "--force", "--force"])
+            .arg(&self.path)
+            .current_dir(&self.git_root)
+            .status();
+        if !matches!(result, Ok(status) if status.success()) {
+            eprintln!(
+                "Warning: failed to remove the temporary worktree at {}. Run `git worktree prune` once it's deleted.",
+                self.path.display()
+            );
         }
     }
 }
 
-pub fn using_branch(git_root: &Path, branch: &str) -> anyhow::Result<BranchGuard> {
-    checkout(git_root, branch)?;
-    let _original_branch = get_current_branch(git_root)?;
-    let guard = BranchGuard {
-        git_root: git_root.to_owned(),
-        original_branch: Some(branch.to_owned()),
+/// Runs `command`, which talks to `remote`, giving up if it takes longer than `timeout`.
+/// Fetches, pulls, and pushes all take a `timeout`, where `None` waits as long as Git does.
+/// Git would wait forever on a credential prompt which nobody can answer,
+/// so prompts are turned off when diamond isn't run from a terminal.
+fn run_network(
+    mut command: Command,
+    remote: &str,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    if !std::io::stdin().is_terminal() {
+        command.env("GIT_TERMINAL_PROMPT", "0");
+    }
+    let mut child = command.stderr(Stdio::piped()).spawn()?;
+    // Read on another thread, so that Git can't block on a full pipe while we wait on it.
+    let mut stderr_pipe = child.stderr.take().unwrap();
+    let stderr_reader = std::thread::spawn(move || {
+        let mut stderr = Vec::new();
+        let _ = stderr_pipe.read_to_end(&mut stderr);
+        stderr
+    });
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if let Some(timeout) = timeout.filter(|timeout| started.elapsed() >= *timeout) {
+            child.kill()?;
+            child.wait()?;
+            anyhow::bail!(
+                "Gave up on `{remote}` after {}s. Set `git.timeout` to wait longer.",
+                timeout.as_secs()
+            );
+        }
+        std::thread::sleep(Duration::from_millis(50));
     };
+
+    let stderr = stderr_reader.join().unwrap_or_default();
+    std::io::stderr().write_all(&stderr)?;
+    if !status.success() && is_credentials_failure(&String::from_utf8_lossy(&stderr)) {
+        anyhow::bail!(
+            "Credentials are required to reach `{remote}`. \
+            Set up a credential helper or SSH key for it, since diamond can't prompt for them here."
+        );
+    }
+    check_status(status)
+}
+
+fn is_credentials_failure(stderr: &str) -> bool {
+    [
+        "terminal prompts disabled",
+        "could not read Username",
+        "could not read Password",
+        "Authentication failed",
+        "Permission denied (publickey",
+    ]
+    .iter()
+    .any(|message| stderr.contains(message))
+}
+
+/// Checks out the submodule commits recorded in `HEAD`, if `recurse_submodules` is set.
+/// Checkouts, rebases, merges, and pulls all take it, so that submodules are kept in step with them.
+fn update_submodules(git_root: &Path, recurse_submodules: bool) -> anyhow::Result<()> {
+    if !recurse_submodules {
+        return Ok(());
+    }
+    let status = Command::new("git")
+        .args(["submodule", "--quiet", "update", "--init", "--recursive"])
+        .current_dir(git_root)
+        .status()?;
+    check_status(status)?;
+    Ok(())
+}
+
+/// Returns the paths of submodules with uncommitted changes,
+/// or which are checked out at a different commit than the one recorded in the index.
+pub fn dirty_submodules(git_root: &Path) -> anyhow::Result<Vec<String>> {
+    let output = Command::new("git")
+        .args(["status", "--porcelain=v2", "--ignore-submodules=none"])
+        .current_dir(git_root)
+        .output()?;
+    check_status(output.status)?;
+    Ok(parse_dirty_submodules(&String::from_utf8(output.stdout)?))
+}
+
+fn parse_dirty_submodules(status: &str) -> Vec<String> {
+    status
+        .lines()
+        .filter_map(|line| {
+            // Changed entries look like `1 <XY> <sub> <mH> <mI> <mW> <hH> <hI> <path>`,
+            // where `<sub>` is `S<c><m><u>` for submodules.
+            let fields: Vec<&str> = line.splitn(9, ' ').collect();
+            match fields.as_slice() {
+                ["1", _, sub, .., path] if fields.len() == 9 => {
+                    let sub = sub.as_bytes();
+                    let dirty = sub[0] == b'S' && (sub[1] == b'C' || sub[2] == b'M');
+                    dirty.then(|| (*path).to_owned())
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+pub fn using_branch(
+    git_root: &Path,
+    branch: &str,
+    recurse_submodules: bool,
+) -> anyhow::Result<BranchGuard> {
+    let original_branch = get_current_branch(git_root).or_else(|_| rev_parse(git_root, "HEAD"))?;
+    let guard = BranchGuard::new(git_root.to_owned(), original_branch, recurse_submodules)?;
+    checkout(git_root, branch, recurse_submodules)?;
     Ok(guard)
 }
 
-fn checkout(git_root: &Path, branch: &str) -> anyhow::Result<()> {
+pub fn checkout(git_root: &Path, branch: &str, recurse_submodules: bool) -> anyhow::Result<()> {
     let status = Command::new("git")
         .args(["checkout", branch])
         .current_dir(git_root)
@@ -59,6 +285,120 @@ fn checkout(git_root: &Path, branch: &str) -> anyhow::Result<()> {
         .stderr(Stdio::null())
         .status()?;
     check_status(status)?;
+    update_submodules(git_root, recurse_submodules)
+}
+
+/// Finds the root of the working tree containing `cwd`.
+/// Defers to Git so that `GIT_DIR`, `GIT_WORK_TREE`, and linked worktrees are respected.
+pub fn get_repo_root(cwd: &Path) -> anyhow::Result<PathBuf> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--show-toplevel"])
+        .current_dir(cwd)
+        .output()?;
+    if !output.status.success() {
+        anyhow::bail!("Working directory is not in a Git repo: {cwd:?}");
+    }
+    Ok(PathBuf::from(String::from_utf8(output.stdout)?.trim()))
+}
+
+/// Finds the Git directory shared by every worktree of the repo containing `cwd`.
+pub fn get_common_dir(cwd: &Path) -> anyhow::Result<PathBuf> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--git-common-dir"])
+        .current_dir(cwd)
+        .output()?;
+    if !output.status.success() {
+        anyhow::bail!("Working directory is not in a Git repo: {cwd:?}");
+    }
+    // Git reports this relative to `cwd` unless it's somewhere else entirely.
+    Ok(cwd.join(String::from_utf8(output.stdout)?.trim()))
+}
+
+/// Finds the Git directory shared by every worktree of the repo containing `cwd`, and the current branch,
+/// if one is checked out, with a single call to Git.
+pub fn get_common_dir_and_branch(cwd: &Path) -> anyhow::Result<(PathBuf, Option<String>)> {
+    let output = Command::new("git")
+        .args([
+            "rev-parse",
+            "--git-common-dir",
+            "--symbolic-full-name",
+            "HEAD",
+        ])
+        .current_dir(cwd)
+        .stderr(Stdio::null())
+        .output()?;
+    check_status(output.status)?;
+    let stdout = String::from_utf8(output.stdout)?;
+    let mut lines = stdout.lines();
+    let common_dir = cwd.join(lines.next().unwrap_or_default());
+    let branch = lines
+        .next()
+        .and_then(|head| head.strip_prefix("refs/heads/"))
+        .map(str::to_owned);
+    Ok((common_dir, branch))
+}
+
+/// Counts the commits on `branch` which aren't on `parent`, and the commits on `parent` which aren't on `branch`.
+pub fn ahead_behind(git_root: &Path, parent: &str, branch: &str) -> anyhow::Result<(usize, usize)> {
+    let output = Command::new("git")
+        .args([
+            "rev-list",
+            "--left-right",
+            "--count",
+            &format!("{parent}...{branch}"),
+        ])
+        .current_dir(git_root)
+        .stderr(Stdio::null())
+        .output()?;
+    check_status(output.status)?;
+    let stdout = String::from_utf8(output.stdout)?;
+    let Some((behind, ahead)) = stdout.trim().split_once('\t') else {
+        anyhow::bail!("Malformed output from `git rev-list --left-right --count`: {stdout}");
+    };
+    Ok((ahead.parse()?, behind.parse()?))
+}
+
+/// Finds the directory Git runs hooks from, which respects `core.hooksPath`.
+pub fn get_hooks_dir(git_root: &Path) -> anyhow::Result<PathBuf> {
+    git_path(git_root, "hooks")
+}
+
+/// Resolves `path` inside the repo's Git directory, like `hooks` or `rebase-merge`.
+fn git_path(git_root: &Path, path: &str) -> anyhow::Result<PathBuf> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--git-path", path])
+        .current_dir(git_root)
+        .output()?;
+    check_status(output.status)?;
+    Ok(git_root.join(String::from_utf8(output.stdout)?.trim()))
+}
+
+/// Returns whether a rebase has stopped partway, whether on a conflict or at an `edit` or `break` line.
+pub fn rebase_in_progress(git_root: &Path) -> anyhow::Result<bool> {
+    Ok(
+        git_path(git_root, "rebase-merge")?.exists()
+            || git_path(git_root, "rebase-apply")?.exists(),
+    )
+}
+
+/// Aborts a rebase, cherry-pick, revert, or merge which stopped partway,
+/// putting the branch back the way it was before it started.
+pub fn abort_in_progress(git_root: &Path) -> anyhow::Result<()> {
+    for (path, command) in [
+        ("rebase-merge", "rebase"),
+        ("rebase-apply", "rebase"),
+        ("CHERRY_PICK_HEAD", "cherry-pick"),
+        ("REVERT_HEAD", "revert"),
+        ("MERGE_HEAD", "merge"),
+    ] {
+        if git_path(git_root, path)?.exists() {
+            let status = Command::new("git")
+                .args([command, "--abort"])
+                .current_dir(git_root)
+                .status()?;
+            return check_status(status);
+        }
+    }
     Ok(())
 }
 
@@ -75,6 +415,93 @@ pub fn get_current_branch(git_root: &Path) -> anyhow::Result<String> {
     Ok(branch_name.to_owned())
 }
 
+pub fn branch_exists(git_root: &Path, branch: &str) -> anyhow::Result<bool> {
+    let status = Command::new("git")
+        .args([
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("refs/heads/{branch}"),
+        ])
+        .current_dir(git_root)
+        .stdout(Stdio::null())
+        .status()?;
+    Ok(status.success())
+}
+
+/// Describes the existing ref which would clash with a new branch named `branch`, if there is one:
+/// a local branch, remote-tracking branch, or tag with the same name,
+/// or a local branch which would have to be a directory for `branch` to exist (or the other way around).
+pub fn find_ref_collision(git_root: &Path, branch: &str) -> anyhow::Result<Option<String>> {
+    for (reference, _) in list_refs(git_root, "refs/heads/")? {
+        let Some(existing) = reference.strip_prefix("refs/heads/") else {
+            continue;
+        };
+        if existing == branch {
+            return Ok(Some(format!(
+                "a local branch named `{existing}` already exists"
+            )));
+        }
+        if branch.starts_with(&format!("{existing}/"))
+            || existing.starts_with(&format!("{branch}/"))
+        {
+            return Ok(Some(format!(
+                "it would clash with the local branch `{existing}`, since Git stores branches as paths"
+            )));
+        }
+    }
+    for (reference, _) in list_refs(git_root, "refs/remotes/")? {
+        let Some((remote, existing)) = reference
+            .strip_prefix("refs/remotes/")
+            .and_then(|rest| rest.split_once('/'))
+        else {
+            continue;
+        };
+        if existing == branch {
+            return Ok(Some(format!(
+                "the remote branch `{remote}/{existing}` already exists"
+            )));
+        }
+    }
+    if !list_refs(git_root, &format!("refs/tags/{branch}"))?.is_empty() {
+        return Ok(Some(format!("a tag named `{branch}` already exists")));
+    }
+    Ok(None)
+}
+
+pub fn remote_exists(git_root: &Path, remote: &str) -> anyhow::Result<bool> {
+    let output = Command::new("git")
+        .args(["remote", "get-url", remote])
+        .current_dir(git_root)
+        .output()?;
+    Ok(output.status.success())
+}
+
+/// Makes `branch` track `upstream`, a remote-tracking branch like `origin/main`,
+/// creating `branch` from it if it doesn't exist yet.
+pub fn set_upstream(git_root: &Path, branch: &str, upstream: &str) -> anyhow::Result<()> {
+    let args = if branch_exists(git_root, branch)? {
+        vec![
+            "branch".to_owned(),
+            format!("--set-upstream-to={upstream}"),
+            branch.to_owned(),
+        ]
+    } else {
+        vec![
+            "branch".to_owned(),
+            "--track".to_owned(),
+            branch.to_owned(),
+            upstream.to_owned(),
+        ]
+    };
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(git_root)
+        .output()?;
+    check_status(output.status)?;
+    Ok(())
+}
+
 pub fn create_branch(git_root: &Path, branch_name: &str) -> anyhow::Result<()> {
     let status = Command::new("git")
         .args(["checkout", "-b", branch_name])
@@ -84,22 +511,199 @@ pub fn create_branch(git_root: &Path, branch_name: &str) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Returns whether Git accepts `name` as a branch name, as `git check-ref-format --branch` decides.
+pub fn is_valid_branch_name(name: &str) -> anyhow::Result<bool> {
+    let output = Command::new("git")
+        .args(["check-ref-format", "--branch", name])
+        .output()?;
+    // Shorthands like `@{-1}` are accepted too, but expanded into the branch they stand for.
+    Ok(output.status.success() && String::from_utf8(output.stdout)?.trim_end() == name)
+}
+
+/// Makes `branch` the current branch without touching the index or working tree, like `git symbolic-ref`.
+pub fn set_head(git_root: &Path, branch: &str) -> anyhow::Result<()> {
+    let status = Command::new("git")
+        .args(["symbolic-ref", "HEAD", &format!("refs/heads/{branch}")])
+        .current_dir(git_root)
+        .status()?;
+    check_status(status)?;
+    Ok(())
+}
+
+pub fn delete_branch(git_root: &Path, branch: &str) -> anyhow::Result<()> {
+    let status = Command::new("git")
+        .args(["branch", "--quiet", "-D", branch])
+        .current_dir(git_root)
+        .status()?;
+    check_status(status)?;
+    Ok(())
+}
+
+/// Creates `branch_name` pointing at `start`, without checking it out.
+pub fn create_branch_at(git_root: &Path, branch_name: &str, start: &str) -> anyhow::Result<()> {
+    let status = Command::new("git")
+        .args(["branch", branch_name, start])
+        .current_dir(git_root)
+        .status()?;
+    check_status(status)?;
+    Ok(())
+}
+
+/// Stashes the changes to tracked files, if there are any, returning the stash's commit.
+fn stash(git_root: &Path, message: &str) -> anyhow::Result<Option<String>> {
+    let output = Command::new("git")
+        .args([
+            "status",
+            "--porcelain",
+            "--untracked-files=no",
+            "--ignore-submodules",
+        ])
+        .current_dir(git_root)
+        .output()?;
+    check_status(output.status)?;
+    if output.stdout.is_empty() {
+        return Ok(None);
+    }
+    let status = Command::new("git")
+        .args(["stash", "push", "--quiet", "--message", message])
+        .current_dir(git_root)
+        .status()?;
+    check_status(status)?;
+    Ok(Some(rev_parse(git_root, "refs/stash")?))
+}
+
+/// Reapplies and drops the stash whose commit is `stash`, even if others were stashed since.
+fn unstash(git_root: &Path, stash: &str) -> anyhow::Result<()> {
+    let output = Command::new("git")
+        .args(["stash", "list", "--format=%H"])
+        .current_dir(git_root)
+        .output()?;
+    check_status(output.status)?;
+    let Some(index) = String::from_utf8(output.stdout)?
+        .lines()
+        .position(|commit| commit == stash)
+    else {
+        anyhow::bail!("Cannot find the stash {stash}.");
+    };
+    let status = Command::new("git")
+        .args([
+            "stash",
+            "pop",
+            "--quiet",
+            "--index",
+            &format!("stash@{{{index}}}"),
+        ])
+        .current_dir(git_root)
+        .status()?;
+    check_status(status)
+}
+
+/// Returns true if there are changes staged in the index.
+pub fn has_staged_changes(git_root: &Path) -> anyhow::Result<bool> {
+    let status = Command::new("git")
+        .args(["diff", "--cached", "--quiet"])
+        .current_dir(git_root)
+        .status()?;
+    Ok(!status.success())
+}
+
+/// Stages every change to a tracked file, like `git add --update`.
+pub fn stage_tracked_changes(git_root: &Path) -> anyhow::Result<()> {
+    let status = Command::new("git")
+        .args(["add", "--update"])
+        .current_dir(git_root)
+        .status()?;
+    check_status(status)?;
+    Ok(())
+}
+
+/// Commits the staged changes onto the current branch.
+pub fn commit(git_root: &Path, message: &str) -> anyhow::Result<()> {
+    let status = Command::new("git")
+        .args(["commit", "--message", message])
+        .current_dir(git_root)
+        .status()?;
+    check_status(status)?;
+    Ok(())
+}
+
+/// Commits onto the current branch with `message`, even if nothing has changed.
+pub fn commit_empty(git_root: &Path, message: &str) -> anyhow::Result<()> {
+    let status = Command::new("git")
+        .args(["commit", "--quiet", "--allow-empty", "--message", message])
+        .current_dir(git_root)
+        .status()?;
+    check_status(status)?;
+    Ok(())
+}
+
+/// Opens `path` in the editor Git is configured to use, and waits for it to be closed.
+pub fn edit_file(git_root: &Path, path: &Path) -> anyhow::Result<()> {
+    let output = Command::new("git")
+        .args(["var", "GIT_EDITOR"])
+        .current_dir(git_root)
+        .output()?;
+    check_status(output.status)?;
+    let editor = String::from_utf8(output.stdout)?.trim().to_owned();
+    // Like Git, the editor is run by the shell, so that it can come with arguments of its own.
+    let status = Command::new("sh")
+        .args(["-c", &format!("{editor} \"$@\""), &editor])
+        .arg(path)
+        .current_dir(git_root)
+        .status()?;
+    check_status(status)?;
+    Ok(())
+}
+
+/// Extra options passed to `git push` whenever diamond pushes a branch.
+#[derive(Clone, Debug, Default)]
+pub struct PushOptions {
+    pub no_verify: bool,
+    /// Sent to the remote with `--push-option`, e.g. `ci.skip`.
+    pub push_options: Vec<String>,
+    /// How long to wait on the push before giving up.
+    pub timeout: Option<Duration>,
+}
+
+impl PushOptions {
+    fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.no_verify {
+            args.push("--no-verify".to_owned());
+        }
+        for push_option in &self.push_options {
+            args.push(format!("--push-option={push_option}"));
+        }
+        args
+    }
+}
+
+/// Force-pushes `branch_name` to `remote`.
+/// If `expected_remote_commit` is provided, the push only succeeds if the remote branch still points to it.
 pub fn push_branch(
     git_root: impl AsRef<Path>,
     remote: impl AsRef<str>,
     branch_name: impl AsRef<str>,
+    remote_branch_name: &str,
+    expected_remote_commit: Option<&str>,
+    options: &PushOptions,
 ) -> anyhow::Result<()> {
     let (git_root, remote, branch_name) =
         (git_root.as_ref(), remote.as_ref(), branch_name.as_ref());
 
-    let refspec = format!("refs/heads/{branch_name}:refs/heads/{branch_name}");
-    let status = Command::new("git")
-        .args(["push", "--force-with-lease", remote, &refspec])
+    let refspec = format!("refs/heads/{branch_name}:refs/heads/{remote_branch_name}");
+    let lease = match expected_remote_commit {
+        Some(commit) => format!("--force-with-lease=refs/heads/{remote_branch_name}:{commit}"),
+        None => "--force-with-lease".to_owned(),
+    };
+    let mut command = Command::new("git");
+    command
+        .args(["push", &lease])
+        .args(options.args())
+        .args([remote, &refspec])
         .current_dir(git_root)
-        .stdout(Stdio::null())
-        .status()?;
-    check_status(status)?;
-    Ok(())
+        .stdout(Stdio::null());
+    run_network(command, remote, options.timeout)
 }
 
 fn check_status(status: ExitStatus) -> anyhow::Result<()> {
@@ -124,29 +728,95 @@ pub fn is_ancestor_of(git_root: &Path, parent_branch: &str, branch: &str) -> any
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Remote {
+    pub host: String,
+    /// The owner of the repo. For providers with nested groups (like GitLab subgroups)
+    /// this contains every path segment before the repo, joined with `/`.
     pub organization: String,
     pub repo: String,
 }
 
 impl Remote {
+    /// Parses a remote URL in any of the forms Git accepts for network remotes:
+    ///
+    /// - `scheme://[user@]host[:port]/path/to/repo[.git]`, for `ssh`, `git+ssh`, `git`, `http`, and `https`.
+    /// - `[user@]host:path/to/repo[.git]`, the scp-like syntax used for SSH.
     fn parse(remote_url: &str) -> anyhow::Result<Self> {
-        // TODO: make this support other, non-github providers
-        let re = Regex::new(
-            "(git@github.com:|https://github.com/)(?P<organization>[^/]+)/(?P<repo>[^/.]+)(\\.git)?",
-        )?;
-        let Some(captures) = re.captures(&remote_url) else {
+        let remote_url = remote_url.trim();
+        let (host, path) = if let Some((scheme, rest)) = remote_url.split_once("://") {
+            anyhow::ensure!(
+                ["ssh", "git+ssh", "git", "http", "https"].contains(&scheme),
+                "Unsupported scheme `{scheme}` in remote URL: {remote_url}"
+            );
+            let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+            let host_and_port = authority
+                .rsplit_once('@')
+                .map_or(authority, |(_, host)| host);
+            let host = host_and_port
+                .split_once(':')
+                .map_or(host_and_port, |(host, _port)| host);
+            (host, path)
+        } else if let Some((user_and_host, path)) = remote_url.split_once(':') {
+            anyhow::ensure!(
+                !user_and_host.contains('/'),
+                "Malformed remote URL: {remote_url}"
+            );
+            let host = user_and_host
+                .rsplit_once('@')
+                .map_or(user_and_host, |(_, host)| host);
+            (host, path)
+        } else {
             anyhow::bail!("Malformed remote URL: {remote_url}");
         };
+
+        let path = path.trim_matches('/');
+        let path = path.strip_suffix(".git").unwrap_or(path);
+        let Some((organization, repo)) = path.rsplit_once('/') else {
+            anyhow::bail!("Malformed remote URL, expected an organization and repo: {remote_url}");
+        };
+        anyhow::ensure!(
+            !host.is_empty() && !organization.is_empty() && !repo.is_empty(),
+            "Malformed remote URL: {remote_url}"
+        );
         Ok(Remote {
-            organization: captures["organization"].trim().to_owned(),
-            repo: captures["repo"].trim().to_owned(),
+            host: host.to_owned(),
+            organization: organization.to_owned(),
+            repo: repo.to_owned(),
         })
     }
 
+    /// Replaces `host` with its `HostName` if it is an alias in the provided SSH config,
+    /// e.g. `git@work:org/repo` with `Host work` / `HostName github.com`.
+    fn resolve_ssh_alias(mut self, ssh_config: &str) -> Self {
+        let mut matches_host = false;
+        for line in ssh_config.lines() {
+            let line = line.trim();
+            let Some((keyword, arguments)) =
+                line.split_once(|c: char| c.is_whitespace() || c == '=')
+            else {
+                continue;
+            };
+            let arguments = arguments.trim_start_matches(|c: char| c.is_whitespace() || c == '=');
+            match keyword.to_lowercase().as_str() {
+                "host" | "match" => {
+                    matches_host = keyword.eq_ignore_ascii_case("host")
+                        && arguments
+                            .split_whitespace()
+                            .any(|pattern| pattern == self.host);
+                }
+                "hostname" if matches_host => {
+                    self.host = arguments.trim().to_owned();
+                    break;
+                }
+                _ => {}
+            }
+        }
+        self
+    }
+
     pub fn new_pr_url(&self, base_branch: &str, branch_to_merge: &str) -> String {
         format!(
-            "https://github.com/{}/{}/compare/{base_branch}...{branch_to_merge}?expand=1",
-            self.organization, self.repo,
+            "https://{}/{}/{}/compare/{base_branch}...{branch_to_merge}?expand=1",
+            self.host, self.organization, self.repo,
         )
     }
 }
@@ -156,58 +826,1038 @@ pub fn parse_remote(git_root: &Path, remote: &str) -> anyhow::Result<Remote> {
         .args(["remote", "get-url", remote])
         .current_dir(git_root)
         .output()?;
+    check_status(output.status)?;
 
     let url = String::from_utf8(output.stdout)?;
-    Remote::parse(&url)
+    let remote = Remote::parse(&url)?;
+
+    let ssh_config = std::env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join(".ssh").join("config"))
+        .and_then(|path| std::fs::read_to_string(path).ok());
+    Ok(match ssh_config {
+        Some(ssh_config) => remote.resolve_ssh_alias(&ssh_config),
+        None => remote,
+    })
 }
 
-pub fn rebase(git_root: &Path, parent_branch: &str, branch: &str) -> anyhow::Result<()> {
-    let status = Command::new("git")
-        .args(["rebase", parent_branch, branch])
+/// Asks Git's credential helpers for the password stored for `host`, like a token saved by `gh` or
+/// Git Credential Manager. Returns `None` when no helper has one, rather than prompting for it.
+pub fn credential_password(git_root: &Path, host: &str) -> anyhow::Result<Option<String>> {
+    let mut child = Command::new("git")
+        .args(["-c", "core.askPass=", "credential", "fill"])
         .current_dir(git_root)
-        .status()?;
-    check_status(status)?;
-    Ok(())
+        .env("GIT_TERMINAL_PROMPT", "0")
+        // Git Credential Manager would otherwise open a window to sign in.
+        .env("GCM_INTERACTIVE", "never")
+        .env("GIT_ASKPASS", "")
+        .env_remove("SSH_ASKPASS")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(format!("protocol=https\nhost={host}\n\n").as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(parse_credential_password(&String::from_utf8(
+        output.stdout,
+    )?))
 }
 
-pub fn pull(git_root: &Path, origin: &str, branch: &str) -> anyhow::Result<()> {
-    let guard = using_branch(git_root, branch)?;
-    let status = Command::new("git")
-        .args(["pull", "--ff-only", "--no-edit", origin, branch])
-        .current_dir(git_root)
-        .status()?;
-    guard.release()?;
-    check_status(status)?;
-    Ok(())
+fn parse_credential_password(output: &str) -> Option<String> {
+    output
+        .lines()
+        .find_map(|line| line.strip_prefix("password="))
+        .filter(|password| !password.is_empty())
+        .map(str::to_owned)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Extra options passed to `git rebase` whenever diamond rewrites a branch.
+#[derive(Clone, Debug, Default)]
+pub struct RebaseOptions {
+    /// Signs rewritten commits with the provided key ID, or the default key if it's empty.
+    pub gpg_sign: Option<String>,
+    pub signoff: bool,
+    /// Reuses recorded conflict resolutions (`git rerere`), continuing the rebase
+    /// when they resolve every conflict.
+    pub rerere: bool,
+    /// Updates submodules to match once the rebase is done.
+    pub recurse_submodules: bool,
+}
 
-    #[test]
-    fn test_parse_remote_url_ssh() -> anyhow::Result<()> {
-        let remote = Remote::parse("git@github.com:crockeo/diamond")?;
-        assert_eq!(
-            remote,
-            Remote {
-                organization: "crockeo".to_owned(),
-                repo: "diamond".to_owned(),
-            },
-        );
-        Ok(())
-    }
+impl RebaseOptions {
+    fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        match &self.gpg_sign {
+            Some(key_id) if key_id.is_empty() => args.push("--gpg-sign".to_owned()),
+            Some(key_id) => args.push(format!("--gpg-sign={key_id}")),
+            None => {}
+        }
+        if self.signoff {
+            args.push("--signoff".to_owned());
+        }
+        args
+    }
+}
+
+pub fn rebase(
+    git_root: &Path,
+    parent_branch: &str,
+    branch: &str,
+    options: &RebaseOptions,
+) -> anyhow::Result<()> {
+    run_rebase(git_root, &[parent_branch, branch], options)
+}
+
+/// Rebases the commits on `branch` after `old_base` onto `new_base`.
+pub fn rebase_onto(
+    git_root: &Path,
+    new_base: &str,
+    old_base: &str,
+    branch: &str,
+    options: &RebaseOptions,
+) -> anyhow::Result<()> {
+    run_rebase(git_root, &["--onto", new_base, old_base, branch], options)
+}
+
+/// Interactively rebases the commits on `branch` after `old_base` onto `new_base`.
+pub fn rebase_interactive(
+    git_root: &Path,
+    new_base: &str,
+    old_base: &str,
+    branch: &str,
+    options: &RebaseOptions,
+) -> anyhow::Result<()> {
+    run_rebase(
+        git_root,
+        &["--interactive", "--onto", new_base, old_base, branch],
+        options,
+    )
+}
+
+fn run_rebase(git_root: &Path, args: &[&str], options: &RebaseOptions) -> anyhow::Result<()> {
+    let mut args: Vec<String> = args.iter().map(|arg| (*arg).to_owned()).collect();
+    // The todo list is edited in the user's own editor, so the rebase keeps the terminal to itself.
+    let interactive = args.iter().any(|arg| arg == "--interactive");
+    args.splice(0..0, options.args());
+    loop {
+        let mut command = Command::new("git");
+        if options.rerere {
+            // Enabled just for this rebase, leaving the repo's own config alone.
+            command.args(["-c", "rerere.enabled=true", "-c", "rerere.autoUpdate=true"]);
+        }
+        command.arg("rebase").args(&args).current_dir(git_root);
+        if args == ["--continue"] {
+            // Keeps the message of the commit whose conflicts were resolved, instead of asking for one.
+            command.env("GIT_EDITOR", "true");
+        }
+        if interactive || (options.gpg_sign.is_none() && !options.rerere) {
+            check_status(command.status()?)?;
+            return update_submodules(git_root, options.recurse_submodules);
+        }
+
+        // Capture stderr so that signing failures and reused resolutions can be told apart from other conflicts.
+        let output = command
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::piped())
+            .output()?;
+        std::io::stderr().write_all(&output.stderr)?;
+        if output.status.success() {
+            return update_submodules(git_root, options.recurse_submodules);
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if options.gpg_sign.is_some() && is_signing_failure(&stderr) {
+            anyhow::bail!(
+                "Failed to sign commits while rebasing. \
+                Check that your signing key is available and that gpg can prompt for its passphrase \
+                (e.g. `export GPG_TTY=$(tty)`), then run `git rebase --continue` or `git rebase --abort`."
+            );
+        }
+
+        let reused = reused_resolutions(&stderr);
+        if !reused.is_empty() {
+            let unmerged = unmerged_paths(git_root)?;
+            eprintln!(
+                "Resolved conflicts in {} using resolutions recorded by `git rerere`.",
+                reused.join(", ")
+            );
+            if unmerged.is_empty() {
+                args = vec!["--continue".to_owned()];
+                continue;
+            }
+            eprintln!("Conflicts remain in {}.", unmerged.join(", "));
+        }
+        check_status(output.status)?;
+        return Ok(());
+    }
+}
+
+/// Returns the paths which `git rerere` resolved using a previously recorded resolution.
+fn reused_resolutions(stderr: &str) -> Vec<String> {
+    stderr
+        .lines()
+        .filter_map(|line| {
+            let line = line
+                .strip_prefix("Staged '")
+                .or_else(|| line.strip_prefix("Resolved '"))?;
+            let (path, rest) = line.split_once('\'')?;
+            (rest.trim() == "using previous resolution.").then(|| path.to_owned())
+        })
+        .collect()
+}
+
+/// Returns the paths which still have merge conflicts.
+fn unmerged_paths(git_root: &Path) -> anyhow::Result<Vec<String>> {
+    let output = Command::new("git")
+        .args(["diff", "--name-only", "--diff-filter=U"])
+        .current_dir(git_root)
+        .output()?;
+    check_status(output.status)?;
+    Ok(String::from_utf8(output.stdout)?
+        .lines()
+        .map(str::to_owned)
+        .collect())
+}
+
+fn is_signing_failure(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    ["gpg failed to sign", "failed to sign", "signing failed"]
+        .iter()
+        .any(|message| stderr.contains(message))
+}
+
+pub fn rev_parse(git_root: &Path, rev: &str) -> anyhow::Result<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--verify", "--quiet", rev])
+        .current_dir(git_root)
+        .output()?;
+    check_status(output.status)?;
+    Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
+
+/// Reads the contents of `path` as of `rev`, or returns `None` if it doesn't exist there.
+pub fn show_file(git_root: &Path, rev: &str, path: &str) -> anyhow::Result<Option<String>> {
+    let output = Command::new("git")
+        .args(["show", &format!("{rev}:{path}")])
+        .current_dir(git_root)
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8(output.stdout)?))
+}
+
+/// Returns the value of the newest `key` trailer (like `PR-Body`)
+/// on the commits reachable from `to` but not from `from`.
+pub fn latest_trailer(
+    git_root: &Path,
+    from: &str,
+    to: &str,
+    key: &str,
+) -> anyhow::Result<Option<String>> {
+    newest_trailer(git_root, &[format!("{from}..{to}")], key)
+}
+
+/// Returns the value of the newest `key` trailer on the commits reachable from `branch`
+/// but not from any of the `others`, i.e. the commits which only `branch` has.
+pub fn own_trailer(
+    git_root: &Path,
+    branch: &str,
+    others: &[String],
+    key: &str,
+) -> anyhow::Result<Option<String>> {
+    let mut revisions = vec![branch.to_owned()];
+    revisions.extend(others.iter().map(|other| format!("^{other}")));
+    newest_trailer(git_root, &revisions, key)
+}
+
+fn newest_trailer(
+    git_root: &Path,
+    revisions: &[String],
+    key: &str,
+) -> anyhow::Result<Option<String>> {
+    let output = Command::new("git")
+        .arg("log")
+        .arg(format!(
+            "--format=%(trailers:key={key},valueonly,unfold)%x00"
+        ))
+        .args(revisions)
+        .arg("--")
+        .current_dir(git_root)
+        .output()?;
+    check_status(output.status)?;
+    Ok(String::from_utf8(output.stdout)?
+        .split('\0')
+        .map(str::trim)
+        .find(|value| !value.is_empty())
+        .map(|value| value.lines().next().unwrap_or(value).to_owned()))
+}
+
+/// Adds a `key: value` trailer to the end of `message`, replacing any `key` trailer it has already.
+pub fn with_trailer(
+    git_root: &Path,
+    message: &str,
+    key: &str,
+    value: &str,
+) -> anyhow::Result<String> {
+    let mut child = Command::new("git")
+        .args([
+            "interpret-trailers",
+            "--if-exists",
+            "replace",
+            "--trailer",
+            &format!("{key}: {value}"),
+        ])
+        .current_dir(git_root)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    // Without a final newline, a one-line message is mistaken for the start of the trailers.
+    let mut input = message.to_owned();
+    if !input.ends_with('\n') {
+        input.push('\n');
+    }
+    child.stdin.take().unwrap().write_all(input.as_bytes())?;
+    let output = child.wait_with_output()?;
+    check_status(output.status)?;
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Finds the branch `branch` was created from, going by the oldest entry in its reflog.
+/// Returns `None` if the reflog is gone, or it was created from something other than a branch, like `HEAD`.
+pub fn created_from(git_root: &Path, branch: &str) -> anyhow::Result<Option<String>> {
+    let output = Command::new("git")
+        .args([
+            "reflog",
+            "show",
+            "--format=%gs",
+            &format!("refs/heads/{branch}"),
+            "--",
+        ])
+        .current_dir(git_root)
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(parse_created_from(&String::from_utf8(output.stdout)?))
+}
+
+fn parse_created_from(reflog: &str) -> Option<String> {
+    let start = reflog
+        .lines()
+        .last()?
+        .strip_prefix("branch: Created from ")?;
+    let start = start.strip_prefix("refs/heads/").unwrap_or(start);
+    (start != "HEAD").then(|| start.to_owned())
+}
+
+/// Counts the commits which are reachable from `to` but not from `from`.
+pub fn count_commits(git_root: &Path, from: &str, to: &str) -> anyhow::Result<usize> {
+    let output = Command::new("git")
+        .args(["rev-list", "--count", &format!("{from}..{to}")])
+        .current_dir(git_root)
+        .output()?;
+    check_status(output.status)?;
+    Ok(String::from_utf8(output.stdout)?.trim().parse()?)
+}
+
+/// Returns the subject and body of the oldest commit reachable from `to` but not from `from`.
+pub fn first_commit_message(
+    git_root: &Path,
+    from: &str,
+    to: &str,
+) -> anyhow::Result<Option<(String, String)>> {
+    let output = Command::new("git")
+        .args([
+            "log",
+            "--reverse",
+            "--format=%x00%s%n%b",
+            &format!("{from}..{to}"),
+        ])
+        .current_dir(git_root)
+        .output()?;
+    check_status(output.status)?;
+    let stdout = String::from_utf8(output.stdout)?;
+    let Some(message) = stdout.split('\0').nth(1) else {
+        return Ok(None);
+    };
+    let (subject, body) = message.split_once('\n').unwrap_or((message, ""));
+    Ok(Some((subject.to_owned(), body.trim().to_owned())))
+}
+
+/// Applies `commit` on top of the current branch.
+pub fn cherry_pick(git_root: &Path, commit: &str, recurse_submodules: bool) -> anyhow::Result<()> {
+    let status = Command::new("git")
+        .args(["cherry-pick", commit])
+        .current_dir(git_root)
+        .status()?;
+    check_status(status)?;
+    update_submodules(git_root, recurse_submodules)
+}
+
+/// Merges `parent` into `branch`, checking out `branch`.
+pub fn merge(
+    git_root: &Path,
+    parent: &str,
+    branch: &str,
+    recurse_submodules: bool,
+) -> anyhow::Result<()> {
+    checkout(git_root, branch, recurse_submodules)?;
+    let status = Command::new("git")
+        .args(["merge", "--no-edit", parent])
+        .current_dir(git_root)
+        .status()?;
+    check_status(status)?;
+    update_submodules(git_root, recurse_submodules)
+}
+
+/// Adds a commit which undoes `commit` on top of the current branch.
+pub fn revert(git_root: &Path, commit: &str, recurse_submodules: bool) -> anyhow::Result<()> {
+    let status = Command::new("git")
+        .args(["revert", "--no-edit", commit])
+        .current_dir(git_root)
+        .status()?;
+    check_status(status)?;
+    update_submodules(git_root, recurse_submodules)
+}
+
+pub fn fetch(git_root: &Path, remote: &str, timeout: Option<Duration>) -> anyhow::Result<()> {
+    let mut command = Command::new("git");
+    command
+        .args(["fetch", "--quiet", remote])
+        .current_dir(git_root);
+    run_network(command, remote, timeout)
+}
+
+/// Fetches `remote` like `git fetch --prune`, deleting the remote-tracking refs of branches
+/// which have been deleted from it. Returns the names of the deleted refs, like `origin/ch/branch`.
+pub fn fetch_prune(
+    git_root: &Path,
+    remote: &str,
+    timeout: Option<Duration>,
+) -> anyhow::Result<Vec<String>> {
+    let prefix = format!("refs/remotes/{remote}/");
+    let before = list_refs(git_root, &prefix)?;
+    let mut command = Command::new("git");
+    command
+        .args(["fetch", "--quiet", "--prune", remote])
+        .current_dir(git_root);
+    run_network(command, remote, timeout)?;
+    let after = list_refs(git_root, &prefix)?;
+    Ok(before
+        .into_iter()
+        .filter(|(reference, _)| !after.iter().any(|(kept, _)| kept == reference))
+        .filter_map(|(reference, _)| reference.strip_prefix("refs/remotes/").map(str::to_owned))
+        .collect())
+}
+
+/// Lists the paths which `branch` changes since it branched off of `base`.
+pub fn changed_paths(git_root: &Path, base: &str, branch: &str) -> anyhow::Result<Vec<String>> {
+    let output = Command::new("git")
+        .args(["diff", "--name-only", &format!("{base}...{branch}")])
+        .current_dir(git_root)
+        .output()?;
+    check_status(output.status)?;
+    Ok(String::from_utf8(output.stdout)?
+        .lines()
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Returns one-line summaries of the commits on `other` whose changes aren't in `branch`,
+/// ignoring commits which were rewritten (e.g. rebased) but kept the same patch.
+pub fn missing_commits(git_root: &Path, branch: &str, other: &str) -> anyhow::Result<Vec<String>> {
+    let output = Command::new("git")
+        .args([
+            "log",
+            "--cherry-pick",
+            "--right-only",
+            "--no-merges",
+            "--format=%h %s",
+            &format!("{branch}...{other}"),
+        ])
+        .current_dir(git_root)
+        .output()?;
+    check_status(output.status)?;
+    Ok(String::from_utf8(output.stdout)?
+        .lines()
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Returns one-line summaries of the commits on `new` since `base` whose changes weren't already in `old`,
+/// i.e. what was added when a branch was rewritten from `old` to `new`, ignoring rebased commits.
+pub fn new_commits(
+    git_root: &Path,
+    old: &str,
+    new: &str,
+    base: &str,
+) -> anyhow::Result<Vec<String>> {
+    let output = Command::new("git")
+        .args([
+            "log",
+            "--cherry-pick",
+            "--right-only",
+            "--no-merges",
+            "--format=%h %s",
+            &format!("{old}...{new}"),
+            "--not",
+            base,
+        ])
+        .current_dir(git_root)
+        .output()?;
+    check_status(output.status)?;
+    Ok(String::from_utf8(output.stdout)?
+        .lines()
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Counts the files changed and the lines added and removed on `branch`
+/// since it diverged from `parent`.
+pub fn diff_size(
+    git_root: &Path,
+    parent: &str,
+    branch: &str,
+) -> anyhow::Result<(usize, usize, usize)> {
+    let output = Command::new("git")
+        .args(["diff", "--numstat", &format!("{parent}...{branch}")])
+        .current_dir(git_root)
+        .output()?;
+    check_status(output.status)?;
+    let (mut files, mut added, mut removed) = (0, 0, 0);
+    for line in String::from_utf8(output.stdout)?.lines() {
+        files += 1;
+        // Binary files are listed with `-` instead of line counts.
+        let mut counts = line
+            .split('\t')
+            .map(|count| count.parse::<usize>().unwrap_or(0));
+        added += counts.next().unwrap_or(0);
+        removed += counts.next().unwrap_or(0);
+    }
+    Ok((files, added, removed))
+}
+
+/// Returns when the oldest commit reachable from `to` but not from `from` was authored,
+/// in seconds since the Unix epoch. Author dates survive rebases, unlike commit dates.
+pub fn oldest_author_time(git_root: &Path, from: &str, to: &str) -> anyhow::Result<Option<u64>> {
+    let output = Command::new("git")
+        .args(["log", "--format=%at", &format!("{from}..{to}")])
+        .current_dir(git_root)
+        .output()?;
+    check_status(output.status)?;
+    Ok(String::from_utf8(output.stdout)?
+        .lines()
+        .filter_map(|line| line.trim().parse().ok())
+        .min())
+}
+
+/// Shows the changes on `branch` since it diverged from `parent`.
+pub fn diff(git_root: &Path, parent: &str, branch: &str) -> anyhow::Result<()> {
+    let status = Command::new("git")
+        .args(["diff", &format!("{parent}...{branch}")])
+        .current_dir(git_root)
+        .status()?;
+    check_status(status)?;
+    Ok(())
+}
+
+/// Compares the commits in `base..old` with the commits in `base..new`.
+pub fn range_diff(git_root: &Path, base: &str, old: &str, new: &str) -> anyhow::Result<()> {
+    let status = Command::new("git")
+        .args(["range-diff", base, old, new])
+        .current_dir(git_root)
+        .status()?;
+    check_status(status)?;
+    Ok(())
+}
+
+pub fn merge_base(git_root: &Path, a: &str, b: &str) -> anyhow::Result<String> {
+    let output = Command::new("git")
+        .args(["merge-base", a, b])
+        .current_dir(git_root)
+        .output()?;
+    if output.status.code() == Some(1) && is_shallow(git_root)? {
+        anyhow::bail!(
+            "Cannot find where `{b}` diverged from `{a}`, because this is a shallow clone \
+            which is missing the history they share. Run `git fetch --unshallow` to fetch it."
+        );
+    }
+    check_status(output.status)?;
+    Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
+
+/// Returns the paths which would conflict when merging `a` and `b`, without touching the working tree.
+pub fn conflicting_paths(git_root: &Path, a: &str, b: &str) -> anyhow::Result<Vec<String>> {
+    let output = Command::new("git")
+        .args([
+            "merge-tree",
+            "--write-tree",
+            "--name-only",
+            "--no-messages",
+            a,
+            b,
+        ])
+        .current_dir(git_root)
+        .output()?;
+    if output.status.code() != Some(1) {
+        check_status(output.status)?;
+        return Ok(Vec::new());
+    }
+    // The first line is the merged tree, followed by each conflicted path.
+    Ok(String::from_utf8(output.stdout)?
+        .lines()
+        .skip(1)
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Returns true if `a` and `b` share any history which is present locally.
+pub fn has_merge_base(git_root: &Path, a: &str, b: &str) -> anyhow::Result<bool> {
+    let output = Command::new("git")
+        .args(["merge-base", a, b])
+        .current_dir(git_root)
+        .output()?;
+    Ok(output.status.success())
+}
+
+/// Returns true if the repo is a shallow clone, which is missing history before some commits.
+pub fn is_shallow(git_root: &Path) -> anyhow::Result<bool> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--is-shallow-repository"])
+        .current_dir(git_root)
+        .output()?;
+    check_status(output.status)?;
+    Ok(String::from_utf8(output.stdout)?.trim() == "true")
+}
+
+/// Fetches `depth` more commits of history behind the shallow clone's boundary,
+/// or all of it if `depth` is `None`.
+pub fn deepen(
+    git_root: &Path,
+    remote: &str,
+    depth: Option<usize>,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    let depth_arg = match depth {
+        Some(depth) => format!("--deepen={depth}"),
+        None => "--unshallow".to_owned(),
+    };
+    let mut command = Command::new("git");
+    command
+        .args(["fetch", "--quiet", &depth_arg, remote])
+        .current_dir(git_root);
+    run_network(command, remote, timeout)
+}
+
+/// Fast-forwards `branch` to `remote_branch` on `origin`.
+pub fn pull(
+    git_root: &Path,
+    origin: &str,
+    remote_branch: &str,
+    branch: &str,
+    recurse_submodules: bool,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    let guard = using_branch(git_root, branch, recurse_submodules)?;
+    let mut command = Command::new("git");
+    command
+        .args(["pull", "--ff-only", "--no-edit", origin, remote_branch])
+        .current_dir(git_root);
+    let result = run_network(command, origin, timeout)
+        .and_then(|()| update_submodules(git_root, recurse_submodules));
+    guard.release()?;
+    result
+}
+
+/// Fast-forwards `branch` to `remote_branch` on `origin` without checking it out,
+/// failing if it can't be fast-forwarded. `branch` can't be the current branch.
+pub fn fast_forward(
+    git_root: &Path,
+    origin: &str,
+    remote_branch: &str,
+    branch: &str,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    fetch_into(
+        git_root,
+        origin,
+        &format!("refs/heads/{remote_branch}"),
+        branch,
+        timeout,
+    )
+}
+
+/// Fetches `reference` from `origin`, like `refs/pull/1/head`, without updating any local branch.
+pub fn fetch_ref(
+    git_root: &Path,
+    origin: &str,
+    reference: &str,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    let mut command = Command::new("git");
+    command
+        .args(["fetch", "--quiet", origin, reference])
+        .current_dir(git_root);
+    run_network(command, origin, timeout)
+}
+
+/// Fetches `reference` from `origin` into the local branch `branch`, creating it if it doesn't exist,
+/// and failing if it does and can't be fast-forwarded. `branch` can't be the current branch.
+pub fn fetch_into(
+    git_root: &Path,
+    origin: &str,
+    reference: &str,
+    branch: &str,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    let mut command = Command::new("git");
+    command
+        .args([
+            "fetch",
+            "--quiet",
+            origin,
+            &format!("{reference}:refs/heads/{branch}"),
+        ])
+        .current_dir(git_root);
+    run_network(command, origin, timeout)
+}
+
+/// Points `reference` (e.g. `refs/diamond/...`) at `commit`, creating it if needed.
+pub fn update_ref(git_root: &Path, reference: &str, commit: &str) -> anyhow::Result<()> {
+    let status = Command::new("git")
+        .args(["update-ref", reference, commit])
+        .current_dir(git_root)
+        .status()?;
+    check_status(status)?;
+    Ok(())
+}
+
+pub fn delete_ref(git_root: &Path, reference: &str) -> anyhow::Result<()> {
+    let status = Command::new("git")
+        .args(["update-ref", "-d", reference])
+        .current_dir(git_root)
+        .status()?;
+    check_status(status)?;
+    Ok(())
+}
+
+/// Returns every reference under `prefix` along with the commit it points at, sorted by name.
+pub fn list_refs(git_root: &Path, prefix: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let output = Command::new("git")
+        .args([
+            "for-each-ref",
+            "--sort=refname",
+            "--format=%(refname) %(objectname)",
+            prefix,
+        ])
+        .current_dir(git_root)
+        .output()?;
+    check_status(output.status)?;
+    Ok(String::from_utf8(output.stdout)?
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(reference, commit)| (reference.to_owned(), commit.to_owned()))
+        .collect())
+}
+
+/// Lists the local branches which have `commit` in their history, including any which are at it.
+pub fn branches_containing(git_root: &Path, commit: &str) -> anyhow::Result<Vec<String>> {
+    let output = Command::new("git")
+        .args([
+            "for-each-ref",
+            "--format=%(refname:lstrip=2)",
+            &format!("--contains={commit}"),
+            "refs/heads/",
+        ])
+        .current_dir(git_root)
+        .output()?;
+    check_status(output.status)?;
+    Ok(String::from_utf8(output.stdout)?
+        .lines()
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Moves `branch` to `commit`.
+/// The checked out branch is moved with `git reset --keep`, which refuses to throw away local changes.
+pub fn reset_branch(
+    git_root: &Path,
+    branch: &str,
+    commit: &str,
+    recurse_submodules: bool,
+) -> anyhow::Result<()> {
+    if get_current_branch(git_root)? == branch {
+        let status = Command::new("git")
+            .args(["reset", "--quiet", "--keep", commit])
+            .current_dir(git_root)
+            .status()?;
+        check_status(status)?;
+        return update_submodules(git_root, recurse_submodules);
+    }
+    let status = Command::new("git")
+        .args(["update-ref", &format!("refs/heads/{branch}"), commit])
+        .current_dir(git_root)
+        .status()?;
+    check_status(status)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote(host: &str, organization: &str, repo: &str) -> Remote {
+        Remote {
+            host: host.to_owned(),
+            organization: organization.to_owned(),
+            repo: repo.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_is_valid_branch_name() -> anyhow::Result<()> {
+        for valid in [
+            "ch/fix-crash",
+            "users/chris/v1.2",
+            "feature_x",
+            "@home",
+            "ch/café",
+        ] {
+            assert!(is_valid_branch_name(valid)?, "{valid}");
+        }
+        for invalid in [
+            "",
+            "-x",
+            "@{-1}",
+            "fix crash",
+            "fix:crash",
+            "a..b",
+            "a@{b",
+            "ch//x",
+            "/ch",
+            "ch/",
+            "ch.",
+            "ch/.x",
+            "ch/x.lock",
+        ] {
+            assert!(!is_valid_branch_name(invalid)?, "{invalid}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_remote_url_ssh() -> anyhow::Result<()> {
+        let remote = Remote::parse("git@github.com:crockeo/diamond")?;
+        assert_eq!(remote, self::remote("github.com", "crockeo", "diamond"));
+        Ok(())
+    }
 
     #[test]
     fn test_parse_remote_url_https() -> anyhow::Result<()> {
         let remote = Remote::parse("https://github.com/crockeo/diamond")?;
+        assert_eq!(remote, self::remote("github.com", "crockeo", "diamond"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_remote_url_trailing_git() -> anyhow::Result<()> {
+        for url in [
+            "git@github.com:crockeo/diamond.git",
+            "https://github.com/crockeo/diamond.git\n",
+            "https://github.com/crockeo/diamond/",
+        ] {
+            assert_eq!(
+                Remote::parse(url)?,
+                remote("github.com", "crockeo", "diamond")
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_remote_url_ssh_scheme_with_port() -> anyhow::Result<()> {
+        let remote = Remote::parse("ssh://git@git.example.com:2222/crockeo/diamond.git")?;
+        assert_eq!(
+            remote,
+            self::remote("git.example.com", "crockeo", "diamond")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_remote_url_nested_groups() -> anyhow::Result<()> {
+        let remote = Remote::parse("https://gitlab.com/group/subgroup/diamond.git")?;
+        assert_eq!(
+            remote,
+            self::remote("gitlab.com", "group/subgroup", "diamond")
+        );
+
+        let remote = Remote::parse("git@gitlab.com:group/subgroup/diamond.git")?;
         assert_eq!(
             remote,
-            Remote {
-                organization: "crockeo".to_owned(),
-                repo: "diamond".to_owned(),
-            },
+            self::remote("gitlab.com", "group/subgroup", "diamond")
         );
         Ok(())
     }
+
+    #[test]
+    fn test_parse_remote_url_malformed() {
+        for url in [
+            "",
+            "diamond",
+            "ftp://github.com/crockeo/diamond",
+            "git@github.com:diamond",
+            "/local/path/to/repo",
+        ] {
+            assert!(Remote::parse(url).is_err(), "{url:?} should not parse");
+        }
+    }
+
+    #[test]
+    fn test_rebase_options_args() {
+        assert!(RebaseOptions::default().args().is_empty());
+        let options = RebaseOptions {
+            gpg_sign: Some("".to_owned()),
+            signoff: true,
+            ..RebaseOptions::default()
+        };
+        assert_eq!(options.args(), vec!["--gpg-sign", "--signoff"]);
+        let options = RebaseOptions {
+            gpg_sign: Some("ABCD1234".to_owned()),
+            signoff: false,
+            ..RebaseOptions::default()
+        };
+        assert_eq!(options.args(), vec!["--gpg-sign=ABCD1234"]);
+    }
+
+    #[test]
+    fn test_push_options_args() {
+        assert!(PushOptions::default().args().is_empty());
+        let options = PushOptions {
+            no_verify: true,
+            push_options: vec!["ci.skip".to_owned(), "merge_request.create".to_owned()],
+            ..PushOptions::default()
+        };
+        assert_eq!(
+            options.args(),
+            vec![
+                "--no-verify",
+                "--push-option=ci.skip",
+                "--push-option=merge_request.create",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_reused_resolutions() {
+        let stderr = "\
+CONFLICT (content): Merge conflict in src/lib.rs
+Staged 'src/lib.rs' using previous resolution.
+Resolved 'README.md' using previous resolution.
+Recorded preimage for 'Cargo.toml'
+Could not apply 4fada59... Add parser
+";
+        assert_eq!(reused_resolutions(stderr), vec!["src/lib.rs", "README.md"]);
+        assert!(reused_resolutions("CONFLICT (content): Merge conflict in f\n").is_empty());
+    }
+
+    #[test]
+    fn test_parse_created_from() {
+        assert_eq!(
+            parse_created_from("commit: Two\ncommit: One\nbranch: Created from ch/one\n")
+                .as_deref(),
+            Some("ch/one")
+        );
+        assert_eq!(
+            parse_created_from("branch: Created from refs/heads/main\n").as_deref(),
+            Some("main")
+        );
+        assert_eq!(parse_created_from("branch: Created from HEAD\n"), None);
+        assert_eq!(parse_created_from("commit: One\n"), None);
+        assert_eq!(parse_created_from(""), None);
+    }
+
+    #[test]
+    fn test_parse_credential_password() {
+        assert_eq!(
+            parse_credential_password(
+                "protocol=https\nhost=github.com\nusername=chris\npassword=gho_abc123\n"
+            )
+            .as_deref(),
+            Some("gho_abc123")
+        );
+        assert_eq!(
+            parse_credential_password("protocol=https\nhost=github.com\npassword=\n"),
+            None
+        );
+        assert_eq!(parse_credential_password(""), None);
+    }
+
+    #[test]
+    fn test_is_credentials_failure() {
+        assert!(is_credentials_failure(
+            "fatal: could not read Username for 'https://github.com': terminal prompts disabled"
+        ));
+        assert!(is_credentials_failure(
+            "git@github.com: Permission denied (publickey).\nfatal: Could not read from remote repository."
+        ));
+        assert!(!is_credentials_failure(
+            "! [rejected] ch/a -> ch/a (stale info)"
+        ));
+    }
+
+    #[test]
+    fn test_is_signing_failure() {
+        assert!(is_signing_failure(
+            "error: gpg failed to sign the data\nfatal: failed to write commit object"
+        ));
+        assert!(!is_signing_failure(
+            "CONFLICT (content): Merge conflict in src/main.rs"
+        ));
+    }
+
+    #[test]
+    fn test_resolve_ssh_alias() -> anyhow::Result<()> {
+        let ssh_config = "
+            Host personal
+                HostName gitlab.com
+
+            Host work work-alias
+                User git
+                HostName github.com
+        ";
+        let remote =
+            Remote::parse("git@work-alias:crockeo/diamond.git")?.resolve_ssh_alias(ssh_config);
+        assert_eq!(remote, self::remote("github.com", "crockeo", "diamond"));
+
+        let remote =
+            Remote::parse("git@github.com:crockeo/diamond.git")?.resolve_ssh_alias(ssh_config);
+        assert_eq!(remote, self::remote("github.com", "crockeo", "diamond"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_dirty_submodules() {
+        let hash = "1234567890123456789012345678901234567890";
+        let status = [
+            format!("1 .M N... 100644 100644 100644 {hash} {hash} README.md"),
+            format!("1 .M SC.. 160000 160000 160000 {hash} {hash} vendor/moved"),
+            format!("1 .M S.M. 160000 160000 160000 {hash} {hash} vendor/edited lib"),
+            format!("1 .M S..U 160000 160000 160000 {hash} {hash} vendor/untracked"),
+            "? notes.txt".to_owned(),
+        ]
+        .join("\n");
+        assert_eq!(
+            parse_dirty_submodules(&status),
+            vec!["vendor/moved", "vendor/edited lib"]
+        );
+    }
 }
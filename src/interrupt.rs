@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Returned once Ctrl-C has been pressed, after whatever was in flight has been undone.
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Interrupted.")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+extern "C" fn handle_interrupt(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    // A second Ctrl-C quits right away, in case cleaning up is what's stuck.
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+/// Catches the first Ctrl-C, so that a long-running command can stop between steps
+/// instead of being killed partway through one.
+///
+/// Git subprocesses still get the signal and stop, since they're in the same process group,
+/// so callers check `interrupted` when one fails.
+pub fn install() {
+    let handler: extern "C" fn(libc::c_int) = handle_interrupt;
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Fails with `Interrupted` if Ctrl-C has been pressed.
+pub fn check() -> anyhow::Result<()> {
+    if interrupted() {
+        return Err(Interrupted.into());
+    }
+    Ok(())
+}
+
+/// Sleeps for `duration`, but fails with `Interrupted` soon after Ctrl-C is pressed,
+/// since the handler only sets a flag and sleeping carries on through the signal.
+pub fn sleep(duration: Duration) -> anyhow::Result<()> {
+    let deadline = Instant::now() + duration;
+    loop {
+        check()?;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(());
+        }
+        std::thread::sleep(remaining.min(Duration::from_millis(100)));
+    }
+}
@@ -1,210 +1,9206 @@
+mod annotate;
+mod codeowners;
+mod config;
 mod database;
+mod exit;
+mod fuzzy;
 mod git;
+mod github;
+mod interrupt;
+mod man;
+#[cfg(test)]
+mod mock_github;
+mod notify;
+mod plan;
+mod prompt;
+mod render;
+mod rpc;
+mod summary;
+#[cfg(test)]
+mod test_support;
+mod ticket;
 
-use database::Transaction;
-use std::path::Path;
-use std::path::PathBuf;
+use annotate::Output;
+use database::{OperationKind, QueuedOperation, Transaction};
+use github::{
+    CheckStatus, GitHub, MergeMethod, PullRequest, PullRequestStatus, ReviewDecision, ReviewThread,
+};
+use render::{Format, StyleChoice};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
 use structopt::StructOpt;
+use summary::{Outcome, Summary};
 
 use crate::database::Database;
+use crate::exit::ExitCode;
 
-const RED: &'static str = "\x1b[1;31m";
-const RESET: &'static str = "\x1b[1;0m";
+const RED: &str = "\x1b[1;31m";
+const RESET: &str = "\x1b[1;0m";
 
+/// Manages stacks of Git branches.
+///
+/// When it fails, dmd exits with 3 if the repo isn't initialized,
+/// 4 if a branch isn't tracked, 5 if a rebase, cherry-pick, or revert stopped on conflicts,
+/// 6 if a branch has diverged from its remote branch, 7 if a GitHub API request failed,
+/// 8 if local changes are in the way, 9 if a confirmation was declined,
+/// 130 if it was interrupted with Ctrl-C, or 1 otherwise.
 #[derive(StructOpt)]
 struct Opt {
+    /// Print extra information, like the remaining GitHub API rate limit.
+    #[structopt(short, long, global = true)]
+    verbose: bool,
+
+    /// Run as if dmd was started in this directory instead of the current one, like `git -C`.
+    #[structopt(short = "C", global = true, parse(from_os_str))]
+    directory: Option<PathBuf>,
+
+    /// Answer yes to every confirmation, e.g. before force-pushing, instead of asking.
+    /// Without it, confirmations are answered no when stdin isn't a terminal.
+    #[structopt(short, long, global = true)]
+    yes: bool,
+
+    /// How to report problems: `text`, or `github-actions` to also print them as workflow annotations,
+    /// e.g. to run `dmd log` as a stack health check in CI.
+    #[structopt(long, global = true, default_value = "text")]
+    output: Output,
+
     #[structopt(subcommand)]
     command: Mode,
 }
 
 #[derive(StructOpt)]
 enum Mode {
+    /// Creates and tracks the branches described in a plan file, to set up a whole stack at once.
+    ///
+    /// A plan is a TOML file with a `[[branch]]` table for each branch, giving its `name`,
+    /// and optionally its `parent` and a `message` for an empty first commit.
+    /// Each branch's parent defaults to the branch before it, or the current branch for the first one.
+    /// Branches which already exist are tracked instead of created.
+    #[structopt()]
+    Apply(ApplyOpt),
+
+    /// Archives a branch, so that it's left out of sync, restack, submit, and log
+    /// while staying tracked and in Git.
+    #[structopt()]
+    Archive(ArchiveOpt),
+
+    /// Checks out a branch, or the top of a named stack.
+    #[structopt()]
+    Checkout(CheckoutOpt),
+
+    /// Stops tracking branches which no longer exist in Git,
+    /// marking each of their children as now being children of the branch's parent.
+    #[structopt()]
+    Clean(CleanOpt),
+
+    /// Reads or writes the configuration for this repo.
+    #[structopt()]
+    Config(ConfigOpt),
+
+    /// Copies the current stack's branches onto another branch, like a release branch to backport to.
+    /// The copies are named with a suffix, and tracked as a new stack on top of that branch.
+    #[structopt()]
+    CopyStack(CopyStackOpt),
+
     /// Creates a new branch with the provided name based on the current branch.
+    /// With `--message`, also commits the staged changes onto it.
     #[structopt()]
     Create(CreateOpt),
 
+    /// Keeps checking the repo in the background: fetches the remote, and records which branches look merged
+    /// and which stacks need restacking, for `dmd status` to report. Nothing is pulled, restacked, or untracked.
+    #[structopt()]
+    Daemon(DaemonOpt),
+
+    /// Lists the open pull requests in every stack, with their review and check status and how long they've been open.
+    #[structopt()]
+    Dash,
+
+    /// Shows the changes a branch makes on top of its parent.
+    #[structopt()]
+    Diff(DiffOpt),
+
+    /// Restacks or submits the current branch and the branches below it, down to the root branch.
+    #[structopt()]
+    Downstack(ScopedOpt),
+
+    /// Interactively rebases a branch's own commits onto its parent,
+    /// and then restacks the branches above it.
+    #[structopt()]
+    Edit(EditOpt),
+
+    /// Checks out each branch in the current stack, in order, and runs a shell command on it.
+    /// Returns to the original branch afterwards, and fails if the command failed on any branch.
+    #[structopt()]
+    Foreach(ForeachOpt),
+
+    /// Tidies up diamond's own data: stops tracking branches which no longer exist in Git,
+    /// deletes leftover queued operations and progress, prunes old snapshots, and compacts the database.
+    #[structopt()]
+    Gc(GcOpt),
+
+    /// Writes man pages for dmd and each of its subcommands.
+    #[structopt(setting = structopt::clap::AppSettings::Hidden)]
+    GenerateMan(GenerateManOpt),
+
+    /// Manages Git hooks which keep diamond in sync as you work.
+    #[structopt()]
+    Hook(HookOpt),
+
+    /// Tracks a chain of pull requests made without diamond, by following each one's base
+    /// down to a tracked branch or the root branch. Each pull request's branch is fetched.
+    #[structopt()]
+    Import(ImportOpt),
+
     /// Initializes a repository to be ready to use with diamond.
     /// Requires that you specify the root branch of that repo,
     /// which is usually `master` or `main`.
     #[structopt()]
     Init(InitOpt),
 
+    /// Merges the pull request for the bottom branch of the current stack,
+    /// then restacks and re-pushes the branches which were built on top of it.
+    #[structopt()]
+    Land(LandOpt),
+
+    /// Shows the tree of tracked branches, starting from the root branch.
+    #[structopt()]
+    Log(LogOpt),
+
+    /// Moves a branch, along with the branches above it, onto a new parent.
+    #[structopt()]
+    Move(MoveOpt),
+
+    /// Attaches a note to a branch, which is shown next to it in `dmd log`.
+    /// Prints the branch's note when no note is provided.
+    #[structopt()]
+    Note(NoteOpt),
+
+    /// Lists the branches in every stack which `dmd restack` would rebase,
+    /// because their parent has moved on or is going to be restacked itself.
+    #[structopt()]
+    Outdated,
+
+    /// Moves a commit into a branch lower in its stack, and then restacks the branches above that branch.
+    /// Commits which aren't on any of those branches are copied instead.
+    #[structopt()]
+    Pick(PickOpt),
+
+    /// Deletes the current branch and checks out its parent, leaving the branch's changes staged there,
+    /// e.g. to undo an accidental `dmd create`.
+    #[structopt()]
+    Pop(PopOpt),
+
+    /// Works with the pull requests for the current stack.
+    #[structopt()]
+    Pr(PrOpt),
+
+    /// Prints the current branch's place in its stack, like `main→ch/one→*ch/two (2↑)`, to show in a shell prompt.
+    /// The counts are the commits on the branch and, if it needs restacking, the commits it's behind its parent by.
+    /// It never migrates or locks the database, and prints nothing outside of a tracked branch.
+    #[structopt()]
+    Prompt,
+
+    /// Rebuilds the stacks after the database is lost or corrupted, tracking each local branch
+    /// on the branch named by its latest `Diamond-Parent:` trailer (see `commit.parent-trailer`),
+    /// or else on the branch its reflog says it was created from. Branches which are still tracked are left alone.
+    #[structopt()]
+    Recover(RecoverOpt),
+
+    /// Pushes a branch to a differently named branch on the remote, e.g. to follow a naming policy like `users/<name>/...`,
+    /// or prints the name it's pushed to.
+    #[structopt()]
+    RemoteName(RemoteNameOpt),
+
     /// Removes a branch from diamond, and marks each of its children as now being children of the branch's parent.
     #[structopt()]
     Remove(RemoveOpt),
 
-    /// Restacks the branches on the current stack onto the most recent version of the priamry branch.
+    /// Restacks the branches on the current stack, or the stack containing `branch`,
+    /// onto the most recent version of the priamry branch.
+    #[structopt()]
+    Restack(RestackOpt),
+
+    /// Moves branches back to where they were before a restack or sync,
+    /// using the snapshot of them that diamond saves before rewriting them.
+    #[structopt()]
+    RestoreSnapshot(RestoreSnapshotOpt),
+
+    /// Reverts a commit on the branch which introduced it, rather than at the top of the stack,
+    /// and then restacks the branches above that branch.
+    #[structopt()]
+    Revert(RevertOpt),
+
+    /// Shows the review threads on a branch's pull request, each under the lines of the diff it's on,
+    /// so that feedback can be read and addressed from the terminal.
+    #[structopt()]
+    Review(ReviewOpt),
+
+    /// Answers JSON-RPC 2.0 requests on a Unix socket, one per line, so that editors can show and change stacks
+    /// without parsing dmd's output. The methods are `log`, `status`, `checkout`, `restack`, `submit`, and `shutdown`.
+    #[structopt()]
+    Serve(ServeOpt),
+
+    /// Collapses the current stack into its bottom branch, which ends up with all of the stack's commits in order,
+    /// for when a stack should land as one pull request after all.
+    /// The other branches are untracked, but kept in Git unless `--delete-branches` is passed.
+    #[structopt()]
+    SquashStack(SquashStackOpt),
+
+    /// Names stacks, lists them, and sets the branch they target.
+    #[structopt()]
+    Stack(StackOpt),
+
+    /// Shows statistics about each stack, to spot the ones which are getting unwieldy.
+    #[structopt()]
+    Stats,
+
+    /// Shows the current branch, and what `dmd daemon` found the last time it checked the repo.
     #[structopt()]
-    Restack,
+    Status,
 
     /// Submits the contents of the current stack to the remote repo.
     #[structopt()]
-    Submit,
+    Submit(SubmitOpt),
+
+    /// Swaps the current branch with its parent, for when two changes were stacked in the wrong order.
+    /// The current branch's children move onto its old parent, which now sits on top of it.
+    #[structopt()]
+    Swap,
 
     /// Fetches the most recent contents of the repo's primary branch
     /// and then restacks all of the tracked branches on top of the primary branch.
     #[structopt()]
-    Sync,
+    Sync(SyncOpt),
 
     /// Starts tracking the current branch inside of Diamond.
     /// If no `parent` is provided, assume that the current branch is based on `main`.
     #[structopt()]
     Track(TrackOpt),
+
+    /// Prints the root branch, or moves diamond onto a new one after it's been renamed upstream.
+    #[structopt()]
+    Trunk(TrunkOpt),
+
+    /// Brings back a branch which was archived with `dmd archive`.
+    #[structopt()]
+    Unarchive(UnarchiveOpt),
+
+    /// Restacks or submits the current branch and the branches above it.
+    #[structopt()]
+    Upstack(ScopedOpt),
 }
 
 #[derive(StructOpt)]
-struct CreateOpt {
+struct ApplyOpt {
+    /// The plan file, like `plan.toml`.
+    #[structopt(parse(from_os_str))]
+    plan: PathBuf,
+}
+
+#[derive(StructOpt)]
+struct ArchiveOpt {
+    #[structopt(required_unless = "list")]
+    branch: Option<String>,
+
+    /// Also archive every branch built on top of `branch`.
+    #[structopt(long)]
+    recursive: bool,
+
+    /// List the archived branches instead.
+    #[structopt(long, conflicts_with = "branch")]
+    list: bool,
+}
+
+#[derive(StructOpt)]
+struct UnarchiveOpt {
     #[structopt()]
     branch: String,
+
+    /// Also unarchive every branch built on top of `branch`.
+    #[structopt(long)]
+    recursive: bool,
 }
 
 #[derive(StructOpt)]
-struct RemoveOpt {
+struct CheckoutOpt {
+    #[structopt(required_unless_one = &["stack", "pr"])]
+    branch: Option<String>,
+
+    /// Check out the top branch of the stack with this name.
+    #[structopt(long, conflicts_with = "branch")]
+    stack: Option<String>,
+
+    /// Fetch the branch of the pull request with this number, e.g. to review someone else's change,
+    /// and track it on the branch the pull request targets.
+    #[structopt(long, conflicts_with_all = &["branch", "stack"])]
+    pr: Option<u64>,
+}
+
+#[derive(StructOpt)]
+struct CleanOpt {
+    /// Only list the branches which would be removed.
+    #[structopt(long)]
+    dry_run: bool,
+}
+
+#[derive(StructOpt)]
+enum ConfigOpt {
+    /// Prints the value of a key, if it's set.
+    Get(ConfigGetOpt),
+
+    /// Sets a key, after checking that the value is valid for it.
+    Set(ConfigSetOpt),
+
+    /// Removes a key's value, so that its default is used.
+    Unset(ConfigGetOpt),
+
+    /// Lists every key, with its value and a description of what it does.
+    List,
+
+    /// Opens every key in Git's editor, and applies the changes once they're saved,
+    /// as long as every value is valid.
+    Edit,
+}
+
+#[derive(StructOpt)]
+struct ConfigGetOpt {
     #[structopt()]
-    branch: String,
+    key: String,
 }
 
 #[derive(StructOpt)]
-struct InitOpt {
+struct ConfigSetOpt {
+    #[structopt()]
+    key: String,
+
+    #[structopt()]
+    value: String,
+}
+
+#[derive(StructOpt)]
+struct CopyStackOpt {
+    /// The branch to copy the stack onto. If it isn't tracked, like a release branch,
+    /// the copies' pull requests and restacks target it, as with `dmd stack target`.
     #[structopt(long)]
-    remote: String,
+    onto: String,
 
+    /// What to add to the end of each branch's name to name its copy.
+    /// Defaults to `-` and the name of the branch it's copied onto, like `-release-1.2`.
     #[structopt(long)]
-    root_branch: String,
+    suffix: Option<String>,
 }
 
 #[derive(StructOpt)]
-struct TrackOpt {
+struct CreateOpt {
+    /// The name of the new branch. Defaults to a name made from `--message`, following the `branch.name-template` config.
+    #[structopt(required_unless = "message")]
+    branch: Option<String>,
+
+    /// Commit the staged changes onto the new branch, with this message.
+    #[structopt(short, long)]
+    message: Option<String>,
+
+    /// Stage changes to tracked files before committing, like `git commit --all`.
+    #[structopt(short, long, requires = "message")]
+    all: bool,
+
+    /// Turn a branch name which Git wouldn't accept into one it would, like `Fix the crash!` into `fix-the-crash`,
+    /// instead of refusing it.
     #[structopt(long)]
-    parent: Option<String>,
+    slugify: bool,
+
+    /// Close this issue, like `--closes 123`, when the new branch's pull request is merged. Can be repeated.
+    /// Issues named at the start of the branch, like `gh-123-fix-leak`, are closed too.
+    #[structopt(long)]
+    closes: Vec<u64>,
 }
 
-fn main() -> anyhow::Result<()> {
-    let repo_root = git_repo_root(std::env::current_dir()?)?;
-    let mut database = Database::new(repo_root.join(".git").join("diamond.sqlite3"))?;
-    let mut tx = database.transaction()?;
+#[derive(StructOpt)]
+struct DaemonOpt {
+    /// How long to wait between checks, in seconds.
+    #[structopt(long, default_value = "300")]
+    interval: u64,
 
-    let opt = Opt::from_args();
-    match &opt.command {
-        Mode::Create(ref create_opt) => create(&mut tx, &create_opt),
-        Mode::Init(ref init_opt) => init(&mut tx, &init_opt),
-        Mode::Remove(ref remove_opt) => remove(&mut tx, &remove_opt),
-        Mode::Restack => restack(&mut tx),
-        Mode::Submit => submit(&mut tx),
-        Mode::Sync => sync(&mut tx),
-        Mode::Track(ref track_opt) => track(&mut tx, track_opt),
-    }?;
+    /// Check the repo once and exit, e.g. to run it from cron instead.
+    #[structopt(long)]
+    once: bool,
+}
 
-    tx.commit()?;
-    Ok(())
+#[derive(StructOpt)]
+struct DiffOpt {
+    /// The branch to diff. Defaults to the current branch.
+    #[structopt()]
+    branch: Option<String>,
+
+    /// Instead of the diff, show a `git range-diff` between the commits last pushed for the branch
+    /// and its current commits.
+    #[structopt(long)]
+    range: bool,
 }
 
-fn create(tx: &mut Transaction, create_opt: &CreateOpt) -> anyhow::Result<()> {
-    let repo_root = git_repo_root(std::env::current_dir()?)?;
-    let current_branch = git::get_current_branch(&repo_root)?;
-    git::create_branch(&repo_root, &create_opt.branch)?;
-    tx.create_branch(&current_branch, &create_opt.branch)?;
-    Ok(())
+#[derive(StructOpt)]
+struct NoteOpt {
+    #[structopt()]
+    note: Option<String>,
+
+    /// The branch to annotate. Defaults to the current branch.
+    #[structopt(long)]
+    branch: Option<String>,
+
+    /// Remove the branch's note.
+    #[structopt(long, conflicts_with = "note")]
+    clear: bool,
 }
 
-fn init(tx: &mut Transaction, init_opt: &InitOpt) -> anyhow::Result<()> {
-    tx.set_remote(&init_opt.remote)?;
-    tx.set_root_branch(&init_opt.root_branch)?;
-    Ok(())
+#[derive(StructOpt)]
+struct EditOpt {
+    /// The branch to edit. Defaults to the current branch.
+    #[structopt()]
+    branch: Option<String>,
 }
 
-fn remove(tx: &mut Transaction, remove_opt: &RemoveOpt) -> anyhow::Result<()> {
-    tx.remove_branch(&remove_opt.branch)?;
-    Ok(())
+#[derive(StructOpt)]
+struct ForeachOpt {
+    /// Stop at the first branch where the command fails.
+    #[structopt(long)]
+    fail_fast: bool,
+
+    /// The command to run, e.g. `dmd foreach -- cargo test`.
+    /// It's run without a shell, so use `sh -c '...'` for pipes and the like.
+    #[structopt(required = true)]
+    command: Vec<String>,
+}
+
+#[derive(StructOpt)]
+struct GcOpt {
+    /// How many of the newest snapshots to keep for `dmd restore-snapshot`.
+    #[structopt(long, default_value = "5")]
+    keep_snapshots: usize,
 }
 
-fn restack(tx: &mut Transaction) -> anyhow::Result<()> {
-    let repo_root = git_repo_root(std::env::current_dir()?)?;
-    let current_branch = git::get_current_branch(&repo_root)?;
-    let _guard = git::BranchGuard::new(repo_root.clone(), current_branch.clone());
+#[derive(StructOpt)]
+struct GenerateManOpt {
+    /// The directory to write the pages into.
+    #[structopt(long, default_value = "man")]
+    out_dir: PathBuf,
+}
 
-    let branches_in_stack = tx.get_branches_in_stack(&current_branch)?;
-    for branch in branches_in_stack {
-        println!("Restacking `{}` onto `{}`...", branch.name, branch.parent);
-        git::rebase(&repo_root, &branch.parent, &branch.name)?;
-    }
+#[derive(StructOpt)]
+enum HookOpt {
+    /// Installs a `post-checkout` hook which warns when you switch to an untracked branch,
+    /// a `post-commit` hook which reminds you to restack the branches above a commit,
+    /// and a `prepare-commit-msg` hook which adds the `Diamond-Parent:` trailer when `commit.parent-trailer` is set.
+    Install(HookInstallOpt),
 
-    Ok(())
+    /// Runs one of the hooks installed by `dmd hook install`.
+    #[structopt(setting = structopt::clap::AppSettings::Hidden)]
+    Run(HookRunOpt),
 }
 
-fn submit(tx: &mut Transaction) -> anyhow::Result<()> {
-    let repo_root = git_repo_root(std::env::current_dir()?)?;
-    let current_branch = git::get_current_branch(&repo_root)?;
+#[derive(StructOpt)]
+struct HookInstallOpt {
+    /// Replace existing hooks which weren't installed by diamond.
+    #[structopt(long)]
+    force: bool,
+}
 
-    let Some(remote_name) = tx.get_remote()? else {
-        eprintln!("{RED}Cannot find remote. Configure repo with `dmd init`.{RESET}");
-        return Ok(());
-    };
-    let remote = git::parse_remote(&repo_root, &remote_name)?;
+#[derive(StructOpt)]
+struct HookRunOpt {
+    #[structopt()]
+    hook: String,
 
-    let branches_in_stack = tx.get_branches_in_stack(&current_branch)?;
-    for branch in branches_in_stack {
-        git::push_branch(&repo_root, "origin", &branch.name)?;
-        println!(
-            "[{}] -> {}",
-            &branch.name,
-            remote.new_pr_url(&branch.parent, &branch.name),
-        );
-    }
+    /// The arguments Git passed to the hook.
+    #[structopt()]
+    args: Vec<String>,
+}
 
-    Ok(())
+#[derive(StructOpt)]
+struct LandOpt {
+    /// Wait for each pull request's checks to pass before merging it,
+    /// and keep landing branches until the whole stack has landed.
+    #[structopt(long)]
+    wait: bool,
+
+    /// The last branch to land. Defaults to the current branch,
+    /// or with `--wait`, the top of its stack.
+    #[structopt(long)]
+    until: Option<String>,
+
+    /// How long to wait between checking on a pull request's checks, in seconds.
+    #[structopt(long, default_value = "30")]
+    interval: u64,
+
+    /// How to merge each pull request: `merge`, `squash`, or `rebase`.
+    /// Defaults to the `land.merge-method` config.
+    #[structopt(long)]
+    method: Option<MergeMethod>,
 }
 
-fn sync(tx: &mut Transaction) -> anyhow::Result<()> {
-    let repo_root = git_repo_root(std::env::current_dir()?)?;
-    let current_branch = git::get_current_branch(&repo_root)?;
-    let _guard = git::BranchGuard::new(repo_root.clone(), current_branch.clone());
+#[derive(StructOpt)]
+struct PickOpt {
+    #[structopt()]
+    commit: String,
 
-    let Some(remote) = tx.get_remote()? else {
-        anyhow::bail!("{RED}Cannot find origin. Is the repo initialized?{RESET}");
-    };
-    let Some(root_branch) = tx.get_root_branch()? else {
-        anyhow::bail!("{RED}Cannot find root branch. Configure repo with `dmd init`.{RESET}");
-    };
-    git::pull(&repo_root, &remote, &root_branch)?;
+    /// The branch to move the commit into.
+    #[structopt(long)]
+    into: String,
+}
 
-    let branches_in_stack = tx.get_branches_in_stack(&current_branch)?;
-    for branch in branches_in_stack {
-        println!("Restacking `{}` onto `{}`...", branch.name, branch.parent);
-        git::pull(&repo_root, &remote, &branch.name)?;
-        git::rebase(&repo_root, &branch.parent, &branch.name)?;
-    }
+#[derive(StructOpt)]
+struct PopOpt {
+    /// Move the branch's commits onto its parent, instead of leaving its changes uncommitted.
+    /// The parent has to be a tracked branch, not the root branch or a target branch.
+    #[structopt(long)]
+    keep_commits: bool,
+}
 
-    Ok(())
+#[derive(StructOpt)]
+enum PrOpt {
+    /// Lists the check runs on the current branch's pull request.
+    Checks(PrChecksOpt),
+
+    /// Comments on the current branch's pull request.
+    Comment(PrCommentOpt),
+
+    /// Waits for the checks on the current branch's pull request to pass, then merges it
+    /// and restacks the branches above it onto the root branch.
+    Merge(PrMergeOpt),
+
+    /// Watches the checks on every pull request in the current stack until they've all finished.
+    /// Fails if any of them fail.
+    Watch(PrWatchOpt),
 }
 
-fn track(tx: &mut Transaction, track_opt: &TrackOpt) -> anyhow::Result<()> {
-    let repo_root = git_repo_root(std::env::current_dir()?)?;
-    let current_branch = git::get_current_branch(&repo_root)?;
+#[derive(StructOpt)]
+struct PrMergeOpt {
+    /// How long to wait between checking on the pull request's checks, in seconds.
+    #[structopt(long, default_value = "30")]
+    interval: u64,
 
-    let Some(root_branch) = tx.get_root_branch()? else {
-        anyhow::bail!("{RED}Cannot find root branch. Configure repo with `dmd init`.{RESET}");
-    };
+    /// How to merge the pull request: `merge`, `squash`, or `rebase`.
+    /// Defaults to the `land.merge-method` config.
+    #[structopt(long)]
+    method: Option<MergeMethod>,
+}
 
-    let parent = match &track_opt.parent {
-        Some(parent) => parent.clone(),
-        None => root_branch,
-    };
-    if !git::is_ancestor_of(&repo_root, &parent, &current_branch)? {
-        anyhow::bail!("Cannot track {current_branch} as branching off of {parent}, because {parent} is not its ancestor.");
-    }
-    tx.create_branch(&parent, &current_branch)?;
-    Ok(())
+#[derive(StructOpt)]
+struct PrChecksOpt {
+    /// Exit with an error if any check has failed, even while others are still running.
+    #[structopt(long)]
+    fail_fast: bool,
 }
 
-fn git_repo_root(cwd: impl AsRef<Path>) -> anyhow::Result<PathBuf> {
-    let cwd = cwd.as_ref();
-    let mut candidate_path = Some(cwd);
-    while let Some(path) = candidate_path {
-        if path.join(".git").is_dir() {
-            return Ok(path.to_owned());
-        }
-        candidate_path = path.parent();
+#[derive(StructOpt)]
+struct PrCommentOpt {
+    /// The text of the comment, which can use GitHub Markdown.
+    #[structopt(short, long)]
+    message: String,
+}
+
+#[derive(StructOpt)]
+struct PrWatchOpt {
+    /// How long to wait between refreshes, in seconds.
+    #[structopt(long, default_value = "30")]
+    interval: u64,
+}
+
+#[derive(StructOpt)]
+struct LogOpt {
+    /// How to draw the graph: `auto`, `unicode`, or `ascii`.
+    /// `auto` uses ASCII on dumb terminals and non-UTF-8 locales.
+    #[structopt(long, default_value = "auto")]
+    style: StyleChoice,
+
+    /// What to print: `tree`, or a `mermaid` or `dot` (Graphviz) diagram of the stack,
+    /// which links to each branch's pull request when a GitHub token is set.
+    #[structopt(long, default_value = "tree")]
+    format: Format,
+
+    /// Include archived branches.
+    #[structopt(long)]
+    all: bool,
+
+    /// Show how many files and lines each branch changes relative to its parent.
+    #[structopt(long)]
+    stat: bool,
+}
+
+#[derive(StructOpt)]
+struct MoveOpt {
+    /// The branch to move. Defaults to the current branch.
+    #[structopt()]
+    branch: Option<String>,
+
+    /// The branch to move it onto.
+    #[structopt(long, required_unless = "interactive")]
+    onto: Option<String>,
+
+    /// Pick the branch and its new parent from the tree of branches,
+    /// and preview the result before anything is rebased.
+    #[structopt(short, long, conflicts_with_all = &["branch", "onto"])]
+    interactive: bool,
+}
+
+#[derive(StructOpt)]
+struct RecoverOpt {
+    /// The root branch, if the database doesn't remember it any more.
+    #[structopt(long)]
+    root_branch: Option<String>,
+
+    /// The remote, if the database doesn't remember it any more. Defaults to `origin` if it exists.
+    #[structopt(long)]
+    remote: Option<String>,
+}
+
+#[derive(StructOpt)]
+struct RemoteNameOpt {
+    /// The name of the branch on the remote.
+    #[structopt()]
+    name: Option<String>,
+
+    /// The branch to rename on the remote. Defaults to the current branch.
+    #[structopt(long)]
+    branch: Option<String>,
+
+    /// Push the branch under its own name again.
+    #[structopt(long, conflicts_with = "name")]
+    clear: bool,
+}
+
+#[derive(StructOpt)]
+struct RemoveOpt {
+    #[structopt()]
+    branch: String,
+}
+
+#[derive(StructOpt)]
+struct RevertOpt {
+    #[structopt()]
+    commit: String,
+}
+
+#[derive(StructOpt)]
+struct ReviewOpt {
+    /// The branch whose pull request to show. Defaults to the current branch.
+    #[structopt()]
+    branch: Option<String>,
+
+    /// Only show threads which haven't been resolved.
+    #[structopt(long)]
+    unresolved: bool,
+}
+
+#[derive(StructOpt)]
+struct ServeOpt {
+    /// The socket to listen on. Defaults to `diamond.sock` in the repo's `.git` directory.
+    #[structopt(long, parse(from_os_str))]
+    socket: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+struct SquashStackOpt {
+    /// Only list the branches and commits which would be combined.
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Also delete the branches above the bottom one from Git, instead of only untracking them.
+    #[structopt(long)]
+    delete_branches: bool,
+}
+
+#[derive(StructOpt)]
+enum ScopedOpt {
+    /// Restacks these branches onto their parents.
+    Restack(RestackOpt),
+
+    /// Submits these branches to the remote repo.
+    Submit(SubmitOpt),
+}
+
+/// Which part of a stack a command works on, relative to one of its branches.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Scope {
+    /// The whole stack.
+    Stack,
+    /// The branch and every branch above it.
+    Upstack,
+    /// The branch and every branch below it.
+    Downstack,
+}
+
+#[derive(StructOpt)]
+struct RestackOpt {
+    /// Restack relative to this branch, instead of the current branch.
+    #[structopt()]
+    branch: Option<String>,
+
+    /// Print which branches will be rebased, and onto what, and ask for confirmation before restacking.
+    #[structopt(long)]
+    plan: bool,
+}
+
+#[derive(StructOpt)]
+struct RestoreSnapshotOpt {
+    /// The snapshot to restore, as shown by `--list`. Defaults to the most recent one.
+    #[structopt()]
+    snapshot: Option<u128>,
+
+    /// List the saved snapshots instead of restoring one.
+    #[structopt(long, conflicts_with = "snapshot")]
+    list: bool,
+}
+
+#[derive(StructOpt)]
+enum StackOpt {
+    /// Gives the current stack a name, which other commands can use to refer to it.
+    Name(StackNameOpt),
+
+    /// Lists every stack, by name if it has one or by its top branch otherwise.
+    List,
+
+    /// Shows or sets the branch the current stack is restacked onto and opens pull requests against,
+    /// like `release/1.2`, for stacks which don't go into the root branch.
+    Target(StackTargetOpt),
+}
+
+#[derive(StructOpt)]
+struct StackNameOpt {
+    #[structopt()]
+    name: String,
+}
+
+#[derive(StructOpt)]
+struct StackTargetOpt {
+    /// The branch to target. Without it, the current target is shown.
+    #[structopt()]
+    branch: Option<String>,
+
+    /// Go back to targeting the root branch.
+    #[structopt(long, conflicts_with = "branch")]
+    clear: bool,
+}
+
+#[derive(StructOpt)]
+struct SyncOpt {
+    /// Sync the stack with this name, instead of the current stack.
+    #[structopt(long)]
+    stack: Option<String>,
+}
+
+#[derive(StructOpt)]
+struct SubmitOpt {
+    /// Skip the `pre-push` hook. Defaults to the `push.no-verify` config.
+    #[structopt(long)]
+    no_verify: bool,
+
+    /// A push option to send to the remote, like `git push -o`.
+    /// Can be repeated, and is added to the options from the `push.options` config.
+    #[structopt(short = "o", long = "push-option")]
+    push_options: Vec<String>,
+
+    /// Retry the pushes and pull request updates left queued by a submit which couldn't finish,
+    /// e.g. because the network was down, without submitting anything new.
+    #[structopt(long)]
+    flush: bool,
+
+    /// Only push the stack's branches, without creating, updating, or linking to pull requests.
+    #[structopt(long)]
+    no_pr: bool,
+
+    /// Don't request reviews from the CODEOWNERS of the files each pull request changes.
+    #[structopt(long)]
+    no_reviewers: bool,
+
+    /// Close this issue, like `--closes 123`, when the current branch's pull request is merged.
+    /// Can be repeated, and is remembered for later submits.
+    #[structopt(long)]
+    closes: Vec<u64>,
+}
+
+#[derive(StructOpt)]
+struct ImportOpt {
+    /// The number of the pull request at the top of the chain.
+    #[structopt(long)]
+    from_prs: u64,
+}
+
+#[derive(StructOpt)]
+struct InitOpt {
+    #[structopt(long)]
+    remote: String,
+
+    #[structopt(long)]
+    root_branch: String,
+}
+
+#[derive(StructOpt)]
+struct TrunkOpt {
+    /// Replace the root branch with this one, e.g. when `master` was renamed to `main` upstream.
+    /// The new root branch must exist on the remote.
+    #[structopt(long)]
+    rename_to: Option<String>,
+}
+
+#[derive(StructOpt)]
+struct TrackOpt {
+    #[structopt(long)]
+    parent: Option<String>,
+
+    /// Also track the untracked branches beneath this one,
+    /// each on top of the nearest branch it's built on, down to a tracked branch.
+    #[structopt(long, conflicts_with = "parent")]
+    with_ancestors: bool,
+}
+
+/// State shared by every command handler over the course of a single run.
+/// There's exactly one database transaction per run, which is committed once the command succeeds.
+struct Context<'conn> {
+    repo_root: PathBuf,
+    /// The directory `dmd` was run from, or the one passed with `-C`.
+    cwd: PathBuf,
+    /// Whether checkouts, rebases, merges, and pulls also update submodules, from `submodules.recurse`.
+    recurse_submodules: bool,
+    /// How long to wait on a fetch, pull, or push before giving up, from `git.timeout`.
+    git_timeout: Option<Duration>,
+    tx: Transaction<'conn>,
+    verbose: bool,
+    output: Output,
+    /// Whether `--yes` was passed, to skip confirmations.
+    yes: bool,
+    summary: Summary,
+    /// Where to reach GitHub, instead of working it out from the remote and the environment.
+    /// Once it has been worked out, it's kept here for the rest of the run.
+    github_endpoint: Option<github::Endpoint>,
+}
+
+/// Returned when `dmd submit` fails partway through, listing the operations it left in the submit queue.
+/// Unlike other errors, the changes made to the database before it are kept,
+/// so that the queue and the record of what was already pushed aren't lost.
+#[derive(Debug)]
+struct SubmitQueuedError {
+    err: anyhow::Error,
+    remaining: Vec<String>,
+}
+
+impl std::fmt::Display for SubmitQueuedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{RED}{:#}\nThese are still queued, run `dmd submit --flush` to retry them:\n  {}{RESET}",
+            self.err,
+            self.remaining.join("\n  ")
+        )
+    }
+}
+
+impl std::error::Error for SubmitQueuedError {}
+
+/// Commits the run's transaction if the command succeeded,
+/// or if it failed but still has changes to keep (see `SubmitQueuedError`).
+fn finish(ctx: Context, result: anyhow::Result<()>) -> anyhow::Result<()> {
+    match result {
+        Ok(()) => ctx.tx.commit(),
+        Err(err) if err.is::<SubmitQueuedError>() => {
+            ctx.tx.commit()?;
+            Err(err)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("Error: {err:?}");
+        std::process::exit(exit::code(&err) as i32);
+    }
+}
+
+fn run() -> anyhow::Result<()> {
+    let opt = Opt::from_args();
+    if let Mode::GenerateMan(ref generate_man_opt) = opt.command {
+        // Runs outside of a repo, since it's used while packaging dmd.
+        return generate_man(generate_man_opt);
+    }
+
+    std::env::set_var(HOOK_QUIET_VAR, "1");
+    let cwd = match &opt.directory {
+        // Joining an absolute path replaces the current directory outright.
+        Some(directory) => std::env::current_dir()?.join(directory),
+        None => std::env::current_dir()?,
+    };
+    if !cwd.is_dir() {
+        anyhow::bail!(
+            "{RED}Cannot run in `{}`, because it isn't a directory.{RESET}",
+            cwd.display()
+        );
+    }
+    if let Mode::Prompt = opt.command {
+        // Any problem just leaves the prompt empty, rather than printing errors into it.
+        if let Ok(Some(prompt)) = shell_prompt(&cwd) {
+            println!("{prompt}");
+        }
+        return Ok(());
+    }
+    let repo_root = git::get_repo_root(&cwd)?;
+    let database_path = git::get_common_dir(&cwd)?.join("diamond.sqlite3");
+    let mut database = match Database::new(&database_path) {
+        // `dmd recover` starts over with an empty database rather than giving up on a corrupted one.
+        Err(err) if matches!(opt.command, Mode::Recover(_)) && database::is_corrupt(&err) => {
+            let corrupted_path = database::move_aside(&database_path)?;
+            eprintln!(
+                "Cannot open the database ({err}), so it was moved to {}.",
+                corrupted_path.display()
+            );
+            Database::new(&database_path)?
+        }
+        database => database?,
+    };
+    let tx = database.transaction()?;
+    let mut ctx = Context {
+        repo_root,
+        cwd,
+        recurse_submodules: config::get_bool(&tx, config::SUBMODULES_RECURSE)?,
+        git_timeout: config::get_seconds(&tx, config::GIT_TIMEOUT)?.map(Duration::from_secs),
+        tx,
+        verbose: opt.verbose,
+        output: opt.output,
+        yes: opt.yes,
+        summary: Summary::default(),
+        github_endpoint: None,
+    };
+
+    // Ctrl-C stops long-running commands between steps, instead of killing them partway through one.
+    interrupt::install();
+    let result = match &opt.command {
+        Mode::Apply(ref apply_opt) => apply(&mut ctx, apply_opt),
+        Mode::Archive(ref archive_opt) => archive(&mut ctx, archive_opt),
+        Mode::Checkout(ref checkout_opt) => checkout(&mut ctx, checkout_opt),
+        Mode::Clean(ref clean_opt) => clean(&mut ctx, clean_opt),
+        Mode::Config(ref config_opt) => config(&mut ctx, config_opt),
+        Mode::CopyStack(ref copy_stack_opt) => copy_stack(&mut ctx, copy_stack_opt),
+        Mode::Create(ref create_opt) => create(&mut ctx, create_opt),
+        Mode::Daemon(ref daemon_opt) => daemon(&mut ctx, daemon_opt),
+        Mode::Dash => dash(&mut ctx),
+        Mode::Diff(ref diff_opt) => diff(&mut ctx, diff_opt),
+        Mode::Downstack(ref scoped_opt) => scoped(&mut ctx, scoped_opt, Scope::Downstack),
+        Mode::Edit(ref edit_opt) => edit(&mut ctx, edit_opt),
+        Mode::Foreach(ref foreach_opt) => foreach(&mut ctx, foreach_opt),
+        Mode::Gc(ref gc_opt) => gc(&mut ctx, gc_opt),
+        Mode::GenerateMan(_) => unreachable!("handled before opening the repo"),
+        Mode::Hook(ref hook_opt) => hook(&mut ctx, hook_opt),
+        Mode::Import(ref import_opt) => import(&mut ctx, import_opt),
+        Mode::Init(ref init_opt) => init(&mut ctx, init_opt),
+        Mode::Land(ref land_opt) => land(&mut ctx, land_opt),
+        Mode::Log(ref log_opt) => log(&mut ctx, log_opt),
+        Mode::Move(ref move_opt) => move_branch(&mut ctx, move_opt),
+        Mode::Note(ref note_opt) => note(&mut ctx, note_opt),
+        Mode::Outdated => outdated(&mut ctx),
+        Mode::Pick(ref pick_opt) => pick(&mut ctx, pick_opt),
+        Mode::Pop(ref pop_opt) => pop(&mut ctx, pop_opt),
+        Mode::Pr(ref pr_opt) => pr(&mut ctx, pr_opt),
+        Mode::Prompt => unreachable!("handled before opening the database"),
+        Mode::Recover(ref recover_opt) => recover(&mut ctx, recover_opt),
+        Mode::RemoteName(ref remote_name_opt) => remote_name(&mut ctx, remote_name_opt),
+        Mode::Remove(ref remove_opt) => remove(&mut ctx, remove_opt),
+        Mode::Restack(ref restack_opt) => restack(&mut ctx, restack_opt),
+        Mode::RestoreSnapshot(ref restore_snapshot_opt) => {
+            restore_snapshot(&mut ctx, restore_snapshot_opt)
+        }
+        Mode::Revert(ref revert_opt) => revert(&mut ctx, revert_opt),
+        Mode::Review(ref review_opt) => review(&mut ctx, review_opt),
+        Mode::Serve(ref serve_opt) => serve(&mut ctx, serve_opt),
+        Mode::SquashStack(ref squash_stack_opt) => squash_stack(&mut ctx, squash_stack_opt),
+        Mode::Stack(ref stack_opt) => stack(&mut ctx, stack_opt),
+        Mode::Stats => stats(&mut ctx),
+        Mode::Status => status(&mut ctx),
+        Mode::Submit(ref submit_opt) => submit(&mut ctx, submit_opt),
+        Mode::Swap => swap(&mut ctx),
+        Mode::Sync(ref sync_opt) => sync(&mut ctx, sync_opt),
+        Mode::Track(ref track_opt) => track(&mut ctx, track_opt),
+        Mode::Trunk(ref trunk_opt) => trunk(&mut ctx, trunk_opt),
+        Mode::Unarchive(ref unarchive_opt) => unarchive(&mut ctx, unarchive_opt),
+        Mode::Upstack(ref scoped_opt) => scoped(&mut ctx, scoped_opt, Scope::Upstack),
+    };
+
+    let long_running_command = match opt.command {
+        Mode::Land(_) => Some("land"),
+        Mode::Restack(_)
+        | Mode::Downstack(ScopedOpt::Restack(_))
+        | Mode::Upstack(ScopedOpt::Restack(_)) => Some("restack"),
+        Mode::Sync(_) => Some("sync"),
+        _ => None,
+    };
+    if let Some(command) = long_running_command {
+        if config::get_bool(&ctx.tx, config::NOTIFY).unwrap_or(false) {
+            match &result {
+                Ok(()) => notify::send(&format!("dmd {command} finished"), "All done."),
+                Err(err) => notify::send(
+                    &format!("dmd {command} stopped"),
+                    &err.to_string().replace(RED, "").replace(RESET, ""),
+                ),
+            }
+        }
+    }
+    let summarized = matches!(
+        opt.command,
+        Mode::CopyStack(_)
+            | Mode::Downstack(_)
+            | Mode::Restack(_)
+            | Mode::Submit(_)
+            | Mode::Sync(_)
+            | Mode::Upstack(_)
+    );
+    if summarized && !ctx.summary.is_empty() {
+        print!("{}", ctx.summary.render());
+    }
+    if let (Output::GithubActions, Err(err)) = (ctx.output, &result) {
+        if !err.is::<annotate::Annotated>() {
+            println!(
+                "{}",
+                annotate::annotation(
+                    annotate::Level::Error,
+                    "dmd failed",
+                    &err.to_string().replace(RED, "").replace(RESET, "")
+                )
+            );
+        }
+    }
+    finish(ctx, result)
+}
+
+/// What `dmd apply` does with each branch in a plan.
+enum PlanStep {
+    Create,
+    Track,
+    AlreadyTracked,
+}
+
+fn apply(ctx: &mut Context, apply_opt: &ApplyOpt) -> anyhow::Result<()> {
+    // Like the paths Git is given, it's relative to the directory passed with `-C`.
+    let path = ctx.cwd.join(&apply_opt.plan);
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|err| anyhow::anyhow!("Cannot read `{}`: {err}", path.display()))?;
+    let plan = plan::parse(&contents)?;
+    let current_branch = git::get_current_branch(&ctx.repo_root)?;
+    let Some(root_branch) = ctx.tx.get_root_branch()? else {
+        return Err(not_initialized("root branch"));
+    };
+
+    // Check the whole plan before changing anything, so that a mistake doesn't leave it half applied.
+    let mut steps: Vec<(&plan::PlannedBranch, String, PlanStep)> = Vec::new();
+    let mut previous = current_branch.clone();
+    for branch in &plan {
+        let name = &branch.name;
+        let parent = branch.parent.clone().unwrap_or(previous);
+        let parent_is_known = parent == root_branch
+            || ctx.tx.get_parent(&parent)?.is_some()
+            || steps.iter().any(|(planned, _, _)| planned.name == parent);
+        if !parent_is_known {
+            return Err(exit::error(
+                ExitCode::NotTracked,
+                format!(
+                    "Cannot apply the plan, because `{parent}`, the parent of `{name}`, \
+                    is not tracked or earlier in the plan."
+                ),
+            ));
+        }
+
+        let step = match ctx.tx.get_parent(name)? {
+            Some(existing_parent) if existing_parent == parent => PlanStep::AlreadyTracked,
+            Some(existing_parent) => anyhow::bail!(
+                "{RED}Cannot apply the plan, because `{name}` is already tracked on `{existing_parent}`, not `{parent}`.{RESET}"
+            ),
+            None if *name == root_branch => {
+                anyhow::bail!("{RED}Cannot apply the plan, because `{name}` is the root branch.{RESET}")
+            }
+            None if git::branch_exists(&ctx.repo_root, name)? => {
+                if git::branch_exists(&ctx.repo_root, &parent)?
+                    && !git::is_ancestor_of(&ctx.repo_root, &parent, name)?
+                {
+                    anyhow::bail!("{RED}Cannot track `{name}` on `{parent}`, because `{parent}` is not its ancestor.{RESET}");
+                }
+                PlanStep::Track
+            }
+            None => {
+                if let Some(collision) = git::find_ref_collision(&ctx.repo_root, name)? {
+                    anyhow::bail!("{RED}Cannot create `{name}`, because {collision}.{RESET}");
+                }
+                PlanStep::Create
+            }
+        };
+        previous = name.clone();
+        steps.push((branch, parent, step));
+    }
+
+    // Only the branches created with a message are checked out, to make their first commits,
+    // so uncommitted changes are only stashed if there are any.
+    let checks_out = steps
+        .iter()
+        .any(|(branch, _, step)| matches!(step, PlanStep::Create) && branch.message.is_some());
+    let guard = if checks_out {
+        git::BranchGuard::new(
+            ctx.repo_root.clone(),
+            current_branch,
+            ctx.recurse_submodules,
+        )?
+    } else {
+        git::BranchGuard::inactive()
+    };
+    for (branch, parent, step) in steps {
+        let name = &branch.name;
+        match step {
+            PlanStep::Create => {
+                git::create_branch_at(&ctx.repo_root, name, &parent)?;
+                ctx.tx.create_branch(&parent, name)?;
+                ctx.tx
+                    .set_base_commit(name, &git::rev_parse(&ctx.repo_root, &parent)?)?;
+                if let Some(message) = &branch.message {
+                    git::checkout(&ctx.repo_root, name, ctx.recurse_submodules)?;
+                    git::commit_empty(&ctx.repo_root, message)?;
+                }
+                println!("Created `{name}` on `{parent}`.");
+            }
+            PlanStep::Track => {
+                track_branch(ctx, name, &parent)?;
+                println!("Tracked `{name}` on `{parent}`.");
+            }
+            PlanStep::AlreadyTracked => println!("`{name}` is already tracked on `{parent}`."),
+        }
+    }
+    guard.release()
+}
+
+fn archive(ctx: &mut Context, archive_opt: &ArchiveOpt) -> anyhow::Result<()> {
+    if archive_opt.list {
+        for branch in ctx.tx.get_archived_branches()? {
+            println!("{branch}");
+        }
+        return Ok(());
+    }
+    let Some(branch) = &archive_opt.branch else {
+        anyhow::bail!("Provide a branch to archive, or `--list` to list archived branches.");
+    };
+    let branch = resolve_branch(ctx, branch)?;
+    set_archived(ctx, &branch, archive_opt.recursive, true)
+}
+
+fn unarchive(ctx: &mut Context, unarchive_opt: &UnarchiveOpt) -> anyhow::Result<()> {
+    let branch = resolve_branch(ctx, &unarchive_opt.branch)?;
+    set_archived(ctx, &branch, unarchive_opt.recursive, false)
+}
+
+fn set_archived(
+    ctx: &mut Context,
+    branch: &str,
+    recursive: bool,
+    archived: bool,
+) -> anyhow::Result<()> {
+    let mut branches = vec![branch.to_owned()];
+    if recursive {
+        branches.extend(ctx.tx.get_descendants(branch)?);
+    }
+    for branch in branches {
+        ctx.tx.set_archived(&branch, archived)?;
+        if archived {
+            println!("Archived `{branch}`.");
+        } else {
+            println!("Unarchived `{branch}`.");
+        }
+    }
+    Ok(())
+}
+
+fn checkout(ctx: &mut Context, checkout_opt: &CheckoutOpt) -> anyhow::Result<()> {
+    if let Some(number) = checkout_opt.pr {
+        return checkout_pull_request(ctx, number);
+    }
+    let branch = match (&checkout_opt.branch, &checkout_opt.stack) {
+        (Some(branch), _) => resolve_branch(ctx, branch)?,
+        (None, Some(stack_name)) => {
+            // Follow the stack upwards, taking the first child wherever it forks.
+            let mut branch = find_stack(ctx, stack_name)?;
+            while let Some(child) = ctx.tx.get_children(&branch)?.into_iter().next() {
+                branch = child;
+            }
+            branch
+        }
+        (None, None) => anyhow::bail!("Provide a branch or `--stack` to check out."),
+    };
+    git::checkout(&ctx.repo_root, &branch, ctx.recurse_submodules)?;
+    println!("Checked out `{branch}`.");
+    Ok(())
+}
+
+/// Names the local branch for `pull_request`: the same as its branch, unless it's from a fork,
+/// whose branch names can clash with the repo's own, like `main`, and which can't be pushed to anyway.
+fn pull_request_branch(github: &GitHub, pull_request: &PullRequest) -> String {
+    if github.is_from_fork(pull_request) {
+        format!("pr/{}", pull_request.number)
+    } else {
+        pull_request.head.branch.clone()
+    }
+}
+
+/// Fetches the head of pull request `number` into a new branch, tracks it, and checks it out.
+/// Its parent is the branch which is pushed to the pull request's base, or the root branch if there isn't one.
+fn checkout_pull_request(ctx: &mut Context, number: u64) -> anyhow::Result<()> {
+    let Some(remote_name) = ctx.tx.get_remote()? else {
+        return Err(not_initialized("remote"));
+    };
+    let Some(root_branch) = ctx.tx.get_root_branch()? else {
+        return Err(not_initialized("root branch"));
+    };
+    let github = connect_github(ctx, &remote_name)?;
+    let Some(status) = github.get_pull_request_statuses(&[number])?.remove(&number) else {
+        anyhow::bail!("Cannot find pull request #{number}.");
+    };
+    let pull_request = status.pull_request;
+    let from_fork = github.is_from_fork(&pull_request);
+    let branch = pull_request_branch(&github, &pull_request);
+    if git::branch_exists(&ctx.repo_root, &branch)? {
+        anyhow::bail!(
+            "Cannot check out #{number} as `{branch}`, because that branch already exists. \
+            Use `dmd checkout {branch}` instead."
+        );
+    }
+
+    let base = pull_request.base.branch;
+    let parent = match find_branch_pushed_to(ctx, &base)? {
+        Some(parent) => parent,
+        None => {
+            println!(
+                "#{number} is based on `{base}`, which isn't tracked, so `{branch}` will be tracked on `{root_branch}`."
+            );
+            root_branch
+        }
+    };
+
+    // GitHub keeps every pull request's head here, including those opened from forks.
+    git::fetch_ref(
+        &ctx.repo_root,
+        &remote_name,
+        &format!("refs/pull/{number}/head"),
+        ctx.git_timeout,
+    )?;
+    git::create_branch_at(&ctx.repo_root, &branch, &pull_request.head.sha)?;
+    let result = track_branch(ctx, &branch, &parent)
+        .and_then(|()| ctx.tx.set_pull_request(&branch, number))
+        .and_then(|()| git::checkout(&ctx.repo_root, &branch, ctx.recurse_submodules));
+    if let Err(err) = result {
+        // Otherwise the branch would be left behind untracked, and trying again would fail.
+        git::delete_branch(&ctx.repo_root, &branch)?;
+        return Err(err);
+    }
+    println!("Checked out #{number} as `{branch}`, on top of `{parent}`.");
+    if from_fork {
+        println!(
+            "#{number} is from a fork, so `dmd submit` pushes `{branch}` to `{remote_name}` instead of updating it."
+        );
+    }
+    Ok(())
+}
+
+fn clean(ctx: &mut Context, clean_opt: &CleanOpt) -> anyhow::Result<()> {
+    let missing_branches = find_missing_branches(ctx)?;
+    if missing_branches.is_empty() {
+        println!("All tracked branches exist.");
+        return Ok(());
+    }
+
+    for branch in missing_branches {
+        if config::is_protected(&ctx.tx, &branch)? {
+            println!("Skipping `{branch}`, which no longer exists but is protected.");
+        } else if clean_opt.dry_run {
+            println!("Would remove `{branch}`, which no longer exists.");
+        } else {
+            println!("Removing `{branch}`, which no longer exists.");
+            ctx.tx.remove_branch(&branch)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns every tracked branch, other than the root branch, whose Git ref has been deleted.
+fn find_missing_branches(ctx: &Context) -> anyhow::Result<Vec<String>> {
+    let mut missing_branches = Vec::new();
+    for branch in ctx.tx.get_all_branches()? {
+        if !git::branch_exists(&ctx.repo_root, &branch.name)? {
+            missing_branches.push(branch.name);
+        }
+    }
+    Ok(missing_branches)
+}
+
+fn config(ctx: &mut Context, config_opt: &ConfigOpt) -> anyhow::Result<()> {
+    match config_opt {
+        ConfigOpt::Get(config_get_opt) => {
+            let key = config::find_key(&config_get_opt.key)?;
+            if let Some(value) = config::get(&ctx.tx, key)? {
+                println!("{value}");
+            }
+        }
+        ConfigOpt::Set(config_set_opt) => {
+            let key = config::find_key(&config_set_opt.key)?;
+            ensure_config_target_exists(ctx, key, &config_set_opt.value)?;
+            config::set(&mut ctx.tx, key, &config_set_opt.value)?;
+        }
+        ConfigOpt::Unset(config_unset_opt) => {
+            let key = config::find_key(&config_unset_opt.key)?;
+            config::unset(&mut ctx.tx, key)?;
+        }
+        ConfigOpt::List => {
+            for key in config::KEYS {
+                match config::get(&ctx.tx, key)? {
+                    Some(value) => println!("{} = {value}", key.name),
+                    None => println!("{} (unset)", key.name),
+                }
+                println!("    {}", key.description);
+            }
+        }
+        ConfigOpt::Edit => config_edit(ctx)?,
+    }
+    Ok(())
+}
+
+/// Checks that the remote or branch which `value` names exists, for keys which name one.
+fn ensure_config_target_exists(
+    ctx: &Context,
+    key: &config::ConfigKey,
+    value: &str,
+) -> anyhow::Result<()> {
+    match key.kind {
+        config::ValueKind::Remote if !git::remote_exists(&ctx.repo_root, value)? => {
+            anyhow::bail!("There is no remote named `{value}`.");
+        }
+        config::ValueKind::Branch if !git::branch_exists(&ctx.repo_root, value)? => {
+            anyhow::bail!("There is no branch named `{value}`.");
+        }
+        _ => Ok(()),
+    }
+}
+
+fn config_edit(ctx: &mut Context) -> anyhow::Result<()> {
+    let path = git::get_common_dir(&ctx.repo_root)?.join("DIAMOND_CONFIG");
+    std::fs::write(&path, config::render_file(&ctx.tx)?)?;
+    let changes = loop {
+        git::edit_file(&ctx.repo_root, &path)?;
+        match config_changes(ctx, &std::fs::read_to_string(&path)?) {
+            Ok(changes) => break changes,
+            Err(err) => {
+                eprintln!("{RED}{err}{RESET}");
+                // Not a destructive question, so `--yes` doesn't answer it.
+                if !prompt::confirm(false, "Edit the config again?")? {
+                    anyhow::bail!(
+                        "The config is unchanged. Your edits are still in {}.",
+                        path.display()
+                    );
+                }
+            }
+        }
+    };
+
+    apply_config_changes(ctx, changes)?;
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+fn apply_config_changes(
+    ctx: &mut Context,
+    changes: Vec<(&'static config::ConfigKey, Option<String>)>,
+) -> anyhow::Result<()> {
+    for (key, value) in changes {
+        match value {
+            Some(value) => {
+                config::set(&mut ctx.tx, key, &value)?;
+                println!("Set `{}` to `{value}`.", key.name);
+            }
+            None => {
+                config::unset(&mut ctx.tx, key)?;
+                println!("Unset `{}`.", key.name);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Compares an edited config file to the current config, returning the keys whose values changed,
+/// with `None` for the ones which were removed. Nothing is returned unless every change can be made.
+fn config_changes(
+    ctx: &Context,
+    contents: &str,
+) -> anyhow::Result<Vec<(&'static config::ConfigKey, Option<String>)>> {
+    let values = config::parse_file(contents)?;
+    let mut changes = Vec::new();
+    for key in config::KEYS {
+        let old_value = config::get(&ctx.tx, key)?;
+        let new_value = values
+            .iter()
+            .find(|(set_key, _)| set_key.name == key.name)
+            .map(|(_, value)| value.clone());
+        if new_value == old_value {
+            continue;
+        }
+        match &new_value {
+            Some(value) => ensure_config_target_exists(ctx, key, value)?,
+            None if matches!(key.name, config::REMOTE | config::ROOT_BRANCH) => {
+                anyhow::bail!("`{}` can't be unset, only set to another value.", key.name);
+            }
+            None => {}
+        }
+        changes.push((key, new_value));
+    }
+    Ok(changes)
+}
+
+fn copy_stack(ctx: &mut Context, copy_stack_opt: &CopyStackOpt) -> anyhow::Result<()> {
+    ensure_submodules_clean(ctx, "copy the stack")?;
+    let onto = &resolve_branch(ctx, &copy_stack_opt.onto)?;
+    let current_branch = git::get_current_branch(&ctx.repo_root)?;
+    let branches = ctx.tx.get_branches_in_stack(&current_branch)?;
+    let Some(bottom) = branches.first() else {
+        return Err(exit::error(
+            ExitCode::NotTracked,
+            format!("`{current_branch}` is not part of a stack."),
+        ));
+    };
+    let Some(root_branch) = ctx.tx.get_root_branch()? else {
+        return Err(not_initialized("root branch"));
+    };
+    // An untracked branch, like a release branch, becomes the target branch of the new stack.
+    let untracked_onto = ctx.tx.get_parent(onto)?.is_none() && onto != &root_branch;
+    if untracked_onto && !git::branch_exists(&ctx.repo_root, onto)? {
+        anyhow::bail!("{RED}Cannot copy the stack onto `{onto}`, because it doesn't exist.{RESET}");
+    }
+    if branches.iter().any(|branch| &branch.name == onto) {
+        anyhow::bail!("Cannot copy the stack onto `{onto}`, because `{onto}` is part of it.");
+    }
+    let suffix = match &copy_stack_opt.suffix {
+        Some(suffix) => suffix.clone(),
+        None => format!("-{}", onto.replace('/', "-")),
+    };
+    let copy_name = |branch: &str| format!("{branch}{suffix}");
+    for branch in &branches {
+        let copy = copy_name(&branch.name);
+        if !git::is_valid_branch_name(&copy)? {
+            anyhow::bail!(
+                "{RED}Cannot copy `{}` to `{copy}`, because it isn't a valid branch name.{RESET}",
+                branch.name
+            );
+        }
+    }
+    ensure_history(ctx, &branches)?;
+
+    let guard = git::BranchGuard::new(
+        ctx.repo_root.clone(),
+        current_branch,
+        ctx.recurse_submodules,
+    )?;
+    let options = rebase_options(&ctx.tx)?;
+    for branch in &branches {
+        let copy = copy_name(&branch.name);
+        let copy_parent = if branches.iter().any(|other| other.name == branch.parent) {
+            copy_name(&branch.parent)
+        } else {
+            onto.clone()
+        };
+        let targets_onto = untracked_onto && &copy_parent == onto;
+        let tracked_parent = if targets_onto {
+            root_branch.clone()
+        } else {
+            copy_parent.clone()
+        };
+        // A copy which is already there is left alone, so that a copy which stopped at a conflict can be picked up again.
+        // If its rebase was aborted instead, it isn't on top of its parent yet, so it's rebased again.
+        let exists = git::branch_exists(&ctx.repo_root, &copy)?;
+        if exists {
+            if ctx.tx.get_parent(&copy)?.as_ref() != Some(&tracked_parent) {
+                anyhow::bail!(
+                    "{RED}Cannot copy `{}` to `{copy}`, because `{copy}` already exists.{RESET}",
+                    branch.name
+                );
+            }
+            if git::is_ancestor_of(&ctx.repo_root, &copy_parent, &copy)? {
+                println!("Skipping `{copy}`, which was already copied.");
+                ctx.summary
+                    .record(&copy, Outcome::Skipped, "already copied");
+                continue;
+            }
+        }
+
+        let old_base = own_commits_base(ctx, &branch.name, &branch.parent)?;
+        if !exists {
+            git::create_branch_at(&ctx.repo_root, &copy, &branch.name)?;
+            ctx.tx.create_branch(&tracked_parent, &copy)?;
+            if targets_onto {
+                ctx.tx.set_target_branch(&copy, Some(onto))?;
+            }
+        }
+        ctx.tx
+            .set_base_commit(&copy, &git::rev_parse(&ctx.repo_root, &copy_parent)?)?;
+        println!(
+            "Copying `{}` onto `{copy_parent}` as `{copy}`...",
+            branch.name
+        );
+        if let Err(err) = git::rebase_onto(&ctx.repo_root, &copy_parent, &old_base, &copy, &options)
+        {
+            // The copies so far are kept, so that running this again picks up where it stopped.
+            ctx.tx.checkpoint()?;
+            guard.dismiss();
+            ctx.summary
+                .record(&copy, Outcome::Failed, failure_detail(&err));
+            return Err(exit::error(
+                ExitCode::Conflict,
+                format!(
+                    "{err:#}\nOnce you've finished the rebase, run `dmd copy-stack --onto {onto}` again \
+                    to copy the rest of the stack."
+                ),
+            ));
+        }
+        ctx.summary.record(&copy, Outcome::Rebased, "");
+    }
+
+    if let Some(stack_name) = ctx.tx.get_stack_name(&bottom.name)? {
+        let copy_stack_name = copy_name(&stack_name);
+        if ctx.tx.get_stack_base(&copy_stack_name)?.is_none() {
+            ctx.tx
+                .set_stack_name(&copy_name(&bottom.name), &copy_stack_name)?;
+        }
+    }
+    println!(
+        "Copied {} branches onto `{onto}`, ending with `{}`.",
+        branches.len(),
+        copy_name(&branches[branches.len() - 1].name)
+    );
+    Ok(())
+}
+
+fn create(ctx: &mut Context, create_opt: &CreateOpt) -> anyhow::Result<()> {
+    let current_branch = git::get_current_branch(&ctx.repo_root)?;
+    if create_opt.all {
+        git::stage_tracked_changes(&ctx.repo_root)?;
+    }
+    if create_opt.message.is_some() && !git::has_staged_changes(&ctx.repo_root)? {
+        anyhow::bail!(
+            "Nothing is staged to commit. Stage your changes with `git add`, or pass `--all`."
+        );
+    }
+
+    let (name, named_from_message) = match (&create_opt.branch, &create_opt.message) {
+        (Some(branch), _) => (branch.clone(), false),
+        (None, Some(message)) => {
+            let name = branch_name_from_message(message);
+            match ctx.tx.get_config(config::BRANCH_NAME_TEMPLATE)? {
+                Some(template) if !name.is_empty() => (template.replace("{message}", &name), true),
+                _ => (name, true),
+            }
+        }
+        (None, None) => anyhow::bail!("Provide a branch name or `--message`."),
+    };
+    if name.is_empty() {
+        anyhow::bail!("Cannot make a branch name out of the commit message. Provide one instead.");
+    }
+    let name = match git::is_valid_branch_name(&name)? {
+        false if create_opt.slugify => {
+            let slug = slugify(&name);
+            if slug.is_empty() {
+                anyhow::bail!("{RED}Cannot make a branch name out of `{name}`.{RESET}");
+            }
+            println!("Using `{slug}` as the branch name.");
+            slug
+        }
+        _ => name,
+    };
+    let mut branch = match ctx.tx.get_config(config::BRANCH_PREFIX)? {
+        Some(prefix) if !name.starts_with(&prefix) => format!("{prefix}{name}"),
+        _ => name,
+    };
+    // Checked up front, so that a bad name is reported instead of failing partway through.
+    if !git::is_valid_branch_name(&branch)? {
+        anyhow::bail!(
+            "{RED}Cannot create `{branch}`, because it isn't a valid branch name. \
+            Pass `--slugify` to make a valid name out of it.{RESET}"
+        );
+    }
+    let allowed_prefixes = config::get_list(&ctx.tx, config::BRANCH_ALLOWED_PREFIXES)?;
+    if !allowed_prefixes.is_empty()
+        && !allowed_prefixes
+            .iter()
+            .any(|prefix| branch.starts_with(prefix.as_str()))
+    {
+        anyhow::bail!(
+            "{RED}Cannot create `{branch}`, because branch names must start with one of: {}.{RESET}",
+            allowed_prefixes.join(", ")
+        );
+    }
+    if named_from_message {
+        // Similar commit messages shouldn't stop a name from being made, so number the duplicates.
+        let base_name = branch.clone();
+        let mut suffix = 2;
+        while git::find_ref_collision(&ctx.repo_root, &branch)?.is_some() {
+            branch = format!("{base_name}-{suffix}");
+            suffix += 1;
+        }
+    } else if let Some(collision) = git::find_ref_collision(&ctx.repo_root, &branch)? {
+        anyhow::bail!("{RED}Cannot create `{branch}`, because {collision}.{RESET}");
+    }
+
+    let message = match &create_opt.message {
+        Some(message) if config::get_bool(&ctx.tx, config::COMMIT_PARENT_TRAILER)? => Some(
+            git::with_trailer(&ctx.repo_root, message, PARENT_TRAILER, &current_branch)?,
+        ),
+        message => message.clone(),
+    };
+    git::create_branch(&ctx.repo_root, &branch)?;
+    if let Some(message) = &message {
+        if let Err(err) = git::commit(&ctx.repo_root, message) {
+            // Without its commit the branch is just a copy of its parent, so it's taken back out,
+            // leaving the changes staged on the parent to try again.
+            git::checkout(&ctx.repo_root, &current_branch, ctx.recurse_submodules)?;
+            git::delete_branch(&ctx.repo_root, &branch)?;
+            return Err(err);
+        }
+    }
+    ctx.tx.create_branch(&current_branch, &branch)?;
+    ctx.tx
+        .set_base_commit(&branch, &git::rev_parse(&ctx.repo_root, &current_branch)?)?;
+    ctx.tx.add_closed_issues(&branch, &create_opt.closes)?;
+    Ok(())
+}
+
+/// Finds the issue named at the start of the last part of `branch`, like `123` in `ch/gh-123-fix-leak`.
+/// The `gh-` marker is required, so that names which merely start with a number, like `2024-cleanup`, aren't read as issues.
+fn issue_from_branch_name(branch: &str) -> Option<u64> {
+    let name = branch.rsplit('/').next().unwrap_or(branch);
+    let name = name.strip_prefix("gh-")?;
+    let (number, rest) = name.split_at(
+        name.find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(name.len()),
+    );
+    if !(rest.is_empty() || rest.starts_with('-')) {
+        return None;
+    }
+    number.parse().ok()
+}
+
+/// The issues which `branch`'s pull request closes, from `--closes` and from its name.
+fn closed_issues(tx: &Transaction, branch: &str) -> anyhow::Result<Vec<u64>> {
+    let mut issues = tx.get_closed_issues(branch)?;
+    if let Some(issue) = issue_from_branch_name(branch) {
+        if !issues.contains(&issue) {
+            issues.push(issue);
+        }
+    }
+    Ok(issues)
+}
+
+/// Makes a valid branch name out of `name`, keeping its `/`-separated parts
+/// but turning each into lowercase words separated by `-`, like `Chris/Fix the crash!` into `chris/fix-the-crash`.
+fn slugify(name: &str) -> String {
+    let parts: Vec<String> = name
+        .split('/')
+        .map(|part| {
+            part.to_lowercase()
+                .split(|c: char| !c.is_alphanumeric() && c != '_')
+                .filter(|word| !word.is_empty())
+                .collect::<Vec<_>>()
+                .join("-")
+        })
+        .filter(|part| !part.is_empty())
+        .collect();
+    parts.join("/")
+}
+
+/// Turns the subject line of a commit message into a branch name,
+/// like `fix-crash-when-the-remote-is-missing`.
+fn branch_name_from_message(message: &str) -> String {
+    const MAX_LENGTH: usize = 50;
+
+    let subject = message.lines().next().unwrap_or("").to_lowercase();
+    let words: Vec<&str> = subject
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+    let mut name = String::new();
+    for word in words {
+        if !name.is_empty() && name.len() + 1 + word.len() > MAX_LENGTH {
+            break;
+        }
+        if !name.is_empty() {
+            name.push('-');
+        }
+        name.push_str(word);
+    }
+    name.truncate(MAX_LENGTH);
+    name
+}
+
+fn daemon(ctx: &mut Context, daemon_opt: &DaemonOpt) -> anyhow::Result<()> {
+    loop {
+        interrupt::check()?;
+        match daemon_check(ctx) {
+            Ok(report) => {
+                ctx.tx.set_daemon_report(&report)?;
+                ctx.tx.checkpoint()?;
+                if ctx.verbose {
+                    eprintln!(
+                        "Checked the repo: {} merged, {} to restack.",
+                        report.merged.len(),
+                        report.outdated.len()
+                    );
+                }
+            }
+            Err(err) if daemon_opt.once => return Err(err),
+            // Being offline for a while, say, only costs a check.
+            Err(err) => {
+                ctx.tx.rollback_to_checkpoint()?;
+                eprintln!("Failed to check the repo: {err:#}");
+            }
+        }
+        if daemon_opt.once {
+            return Ok(());
+        }
+        interrupt::sleep(Duration::from_secs(daemon_opt.interval))?;
+    }
+}
+
+/// Fetches the remote and works out what `dmd sync` and `dmd restack` would do, without doing any of it.
+fn daemon_check(ctx: &mut Context) -> anyhow::Result<database::DaemonReport> {
+    let Some(remote) = ctx.tx.get_remote()? else {
+        return Err(not_initialized("remote"));
+    };
+    let Some(root_branch) = ctx.tx.get_root_branch()? else {
+        return Err(not_initialized("root branch"));
+    };
+    // Other commands can use the database while the fetch runs.
+    ctx.tx.checkpoint()?;
+    git::fetch(&ctx.repo_root, &remote, ctx.git_timeout)?;
+    let remote_root = format!("{remote}/{root_branch}");
+    let root_behind = git::count_commits(&ctx.repo_root, &root_branch, &remote_root)?;
+
+    // Pull requests are only checked when their numbers are known, so that a check is at most one query.
+    let numbers = ctx.tx.get_pull_requests()?;
+    let mut pull_requests = HashMap::new();
+    // Without a GitHub token, merges can still be spotted on the remote root branch.
+    if !numbers.is_empty() {
+        // Nor when GitHub can't be reached, which shouldn't stop the rest of the check.
+        if let Ok(github) = connect_github(ctx, &remote) {
+            pull_requests = github
+                .get_pull_request_statuses(&numbers.values().copied().collect::<Vec<_>>())
+                .unwrap_or_default();
+        }
+    }
+    let mut merged = Vec::new();
+    for branch in effective_branches(ctx)? {
+        if config::is_protected(&ctx.tx, &branch.name)?
+            || !git::branch_exists(&ctx.repo_root, &branch.name)?
+        {
+            continue;
+        }
+        let commit = git::rev_parse(&ctx.repo_root, &branch.name)?;
+        let merged_pull_request = numbers
+            .get(&branch.name)
+            .and_then(|number| pull_requests.get(number))
+            .is_some_and(|status| {
+                status.pull_request.merged_at.is_some() && status.pull_request.head.sha == commit
+            });
+        let merged_into_root = git::count_commits(&ctx.repo_root, &branch.parent, &branch.name)?
+            > 0
+            && git::is_ancestor_of(&ctx.repo_root, &branch.name, &remote_root)?;
+        if merged_pull_request || merged_into_root {
+            merged.push(branch.name);
+        }
+    }
+    let outdated = outdated_branches(ctx, Some(&remote), &merged)?;
+
+    Ok(database::DaemonReport {
+        checked_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs(),
+        root_behind,
+        merged,
+        outdated,
+    })
+}
+
+fn dash(ctx: &mut Context) -> anyhow::Result<()> {
+    let Some(remote_name) = ctx.tx.get_remote()? else {
+        return Err(not_initialized("remote"));
+    };
+    let github = connect_github(ctx, &remote_name)?;
+    let stacks = dash_stacks(ctx, &github)?;
+    if stacks.is_empty() {
+        println!("No open pull requests.");
+    }
+    for (name, lines) in stacks {
+        println!("{name}");
+        for line in lines {
+            println!("  {line}");
+        }
+    }
+    Ok(())
+}
+
+/// Describes the open pull requests in each stack, leaving out stacks without any.
+/// Pull requests whose numbers are remembered are fetched by number, and the rest are found by branch,
+/// and their numbers remembered for next time.
+fn dash_stacks(ctx: &mut Context, github: &GitHub) -> anyhow::Result<Vec<(String, Vec<String>)>> {
+    let Some(root_branch) = ctx.tx.get_root_branch()? else {
+        return Err(not_initialized("root branch"));
+    };
+    let mut stacks = Vec::new();
+    for base_branch in ctx.tx.get_children(&root_branch)? {
+        let branches: Vec<String> = ctx
+            .tx
+            .get_branches_in_stack(&base_branch)?
+            .into_iter()
+            .map(|branch| branch.name)
+            .collect();
+        let Some(top_branch) = branches.last() else {
+            continue;
+        };
+        let name = match ctx.tx.get_stack_name(&base_branch)? {
+            Some(name) => name,
+            None => top_branch.clone(),
+        };
+        stacks.push((name, branches));
+    }
+    let all_branches: Vec<&String> = stacks.iter().flat_map(|(_, branches)| branches).collect();
+
+    let numbers = ctx.tx.get_pull_requests()?;
+    let known: Vec<(&String, u64)> = all_branches
+        .iter()
+        .filter_map(|branch| Some((*branch, *numbers.get(*branch)?)))
+        .collect();
+    let mut by_number = github
+        .get_pull_request_statuses(&known.iter().map(|(_, number)| *number).collect::<Vec<_>>())?;
+    let mut statuses: HashMap<String, PullRequestStatus> = HashMap::new();
+    for (branch, number) in known {
+        if let Some(status) = by_number.remove(&number) {
+            statuses.insert(branch.clone(), status);
+        }
+    }
+    // A remembered pull request which has since closed may have been replaced by a new one.
+    let unknown: Vec<String> = all_branches
+        .iter()
+        .filter(|branch| {
+            statuses
+                .get(**branch)
+                .is_none_or(|status| status.pull_request.state != "open")
+        })
+        .map(|branch| (*branch).clone())
+        .collect();
+    for (branch, status) in find_pull_request_statuses(&ctx.tx, github, &unknown)? {
+        ctx.tx
+            .set_pull_request(&branch, status.pull_request.number)?;
+        statuses.insert(branch, status);
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let mut dash = Vec::new();
+    for (name, branches) in stacks {
+        let mut lines = Vec::new();
+        for branch in branches {
+            let Some(status) = statuses
+                .get(&branch)
+                .filter(|status| status.pull_request.state == "open")
+            else {
+                continue;
+            };
+            let mut details = Vec::new();
+            match status.review_decision {
+                Some(ReviewDecision::Approved) => details.push("approved".to_owned()),
+                Some(ReviewDecision::ChangesRequested) => {
+                    details.push("changes requested".to_owned())
+                }
+                Some(ReviewDecision::ReviewRequired) => details.push("review required".to_owned()),
+                None => {}
+            }
+            details.push(
+                match status.check_status {
+                    CheckStatus::Pending => "checks running",
+                    CheckStatus::Success => "checks passed",
+                    CheckStatus::Failure => "checks failed",
+                }
+                .to_owned(),
+            );
+            if let Some(created_time) = status.created_time {
+                details.push(format!(
+                    "opened {}",
+                    format_age(now.saturating_sub(created_time))
+                ));
+            }
+            lines.push(format!(
+                "#{} {branch}: {} ({})",
+                status.pull_request.number,
+                details.join(", "),
+                status.pull_request.html_url
+            ));
+        }
+        if !lines.is_empty() {
+            dash.push((name, lines));
+        }
+    }
+    Ok(dash)
+}
+
+fn diff(ctx: &mut Context, diff_opt: &DiffOpt) -> anyhow::Result<()> {
+    let branch = resolve_branch_or_current(ctx, &diff_opt.branch)?;
+    let Some(parent) = ctx.tx.get_effective_parent(&branch)? else {
+        return Err(exit::error(
+            ExitCode::NotTracked,
+            format!("Cannot diff `{branch}`, because it is not tracked or is the root branch."),
+        ));
+    };
+
+    if !diff_opt.range {
+        return git::diff(&ctx.repo_root, &parent, &branch);
+    }
+
+    let pushed_commit = match ctx.tx.get_pushed_commit(&branch)? {
+        Some(pushed_commit) => Some(pushed_commit),
+        None => match ctx.tx.get_remote()? {
+            Some(remote) => {
+                let remote = push_remote(&ctx.tx, &remote)?;
+                let remote_branch = remote_branch_name(&ctx.tx, &branch)?;
+                git::rev_parse(
+                    &ctx.repo_root,
+                    &format!("refs/remotes/{remote}/{remote_branch}"),
+                )
+                .ok()
+            }
+            None => None,
+        },
+    };
+    let Some(pushed_commit) = pushed_commit else {
+        anyhow::bail!(
+            "Cannot find a pushed version of `{branch}` to compare against. Has it been submitted?"
+        );
+    };
+    git::range_diff(&ctx.repo_root, &parent, &pushed_commit, &branch)
+}
+
+fn edit(ctx: &mut Context, edit_opt: &EditOpt) -> anyhow::Result<()> {
+    ensure_submodules_clean(ctx, "edit")?;
+    let current_branch = git::get_current_branch(&ctx.repo_root)?;
+    let branch = match &edit_opt.branch {
+        Some(branch) => resolve_branch(ctx, branch)?,
+        None => current_branch.clone(),
+    };
+    let Some(parent) = ctx.tx.get_effective_parent(&branch)? else {
+        return Err(exit::error(
+            ExitCode::NotTracked,
+            format!("Cannot edit `{branch}`, because it is not tracked or is the root branch."),
+        ));
+    };
+    ensure_unprotected(&ctx.tx, &branch, "rebase")?;
+    let guard = git::BranchGuard::new(
+        ctx.repo_root.clone(),
+        current_branch,
+        ctx.recurse_submodules,
+    )?;
+
+    let old_base = match ctx.tx.get_base_commit(&branch)? {
+        Some(base_commit) if git::is_ancestor_of(&ctx.repo_root, &base_commit, &branch)? => {
+            base_commit
+        }
+        _ => git::merge_base(&ctx.repo_root, &parent, &branch)?,
+    };
+    let options = rebase_options(&ctx.tx)?;
+    if let Err(err) = git::rebase_interactive(&ctx.repo_root, &parent, &old_base, &branch, &options)
+    {
+        // Leave the user in the middle of the rebase, instead of switching branches out from under it.
+        guard.dismiss();
+        return Err(exit::error(ExitCode::Conflict, format!("{err:#}\nOnce you've finished the rebase, run `dmd restack` to restack the branches above `{branch}`."
+        )));
+    }
+    // Git exits successfully when the rebase stops at an `edit` or `break` line.
+    if git::rebase_in_progress(&ctx.repo_root)? {
+        guard.dismiss();
+        return Err(exit::error(
+            ExitCode::Conflict,
+            format!(
+                "The rebase of `{branch}` stopped partway.\n\
+                Once you've finished the rebase, run `dmd restack` to restack the branches above `{branch}`."
+            ),
+        ));
+    }
+    ctx.tx
+        .set_base_commit(&branch, &git::rev_parse(&ctx.repo_root, &parent)?)?;
+    restack_descendants(ctx, &branch, guard)
+}
+
+/// Rebases every branch above `branch` onto its parent, parents first,
+/// after `branch` has been rewritten. Archived branches, and the branches above them, are left alone.
+/// Releases `guard` once they're all restacked, or dismisses it when one conflicts.
+fn restack_descendants(
+    ctx: &mut Context,
+    branch: &str,
+    guard: git::BranchGuard,
+) -> anyhow::Result<()> {
+    let mut descendants = Vec::new();
+    for name in ctx.tx.get_descendants(branch)? {
+        if let Some(parent) = ctx.tx.get_effective_parent(&name)? {
+            descendants.push(database::Branch { name, parent });
+        }
+    }
+    let descendants = skip_archived(ctx, descendants)?;
+    for descendant in &descendants {
+        ensure_unprotected(&ctx.tx, &descendant.name, "restack")?;
+    }
+    for descendant in descendants {
+        println!(
+            "Restacking `{}` onto `{}`...",
+            descendant.name, descendant.parent
+        );
+        if let Err(err) = restack_onto_parent(ctx, &descendant.name, &descendant.parent) {
+            if err.is::<interrupt::Interrupted>() {
+                return Err(exit::error(
+                    ExitCode::Interrupted,
+                    format!("{err:#} Run `dmd restack` to restack the rest of the stack."),
+                ));
+            }
+            // Leave the user in the middle of the rebase, instead of switching branches out from under it.
+            guard.dismiss();
+            return Err(exit::error(ExitCode::Conflict, format!("{err:#}\nOnce you've finished the rebase, run `dmd restack` to restack the rest of the stack."
+            )));
+        }
+        // Keep the restacked branches' new bases, even if a later branch conflicts.
+        ctx.tx.checkpoint()?;
+    }
+    guard.release()
+}
+
+/// Leaves the archived branches out of `branches`, which are in stack order,
+/// along with the branches built on top of them: an archived branch isn't restacked,
+/// so restacking the branches above it would only build them on its outdated commits.
+fn skip_archived(
+    ctx: &Context,
+    branches: Vec<database::Branch>,
+) -> anyhow::Result<Vec<database::Branch>> {
+    let archived_branches = ctx.tx.get_archived_branches()?;
+    let mut skipped: Vec<String> = Vec::new();
+    let mut kept = Vec::new();
+    for branch in branches {
+        if archived_branches.contains(&branch.name) {
+            skipped.push(branch.name);
+        } else if archived_branches.contains(&branch.parent) || skipped.contains(&branch.parent) {
+            println!(
+                "Skipping `{}`, which is built on an archived branch.",
+                branch.name
+            );
+            skipped.push(branch.name);
+        } else {
+            kept.push(branch);
+        }
+    }
+    Ok(kept)
+}
+
+fn foreach(ctx: &mut Context, foreach_opt: &ForeachOpt) -> anyhow::Result<()> {
+    let current_branch = git::get_current_branch(&ctx.repo_root)?;
+    let _guard = git::BranchGuard::new(
+        ctx.repo_root.clone(),
+        current_branch.clone(),
+        ctx.recurse_submodules,
+    )?;
+
+    let command = foreach_opt.command.join(" ");
+    let mut results: Vec<(String, bool)> = Vec::new();
+    for branch in ctx.tx.get_branches_in_stack(&current_branch)? {
+        interrupt::check()?;
+        println!("Running `{command}` on `{}`...", branch.name);
+        git::checkout(&ctx.repo_root, &branch.name, ctx.recurse_submodules)?;
+        // The directory it was run from might not exist on every branch.
+        let dir = if ctx.cwd.is_dir() {
+            &ctx.cwd
+        } else {
+            &ctx.repo_root
+        };
+        let status = std::process::Command::new(&foreach_opt.command[0])
+            .args(&foreach_opt.command[1..])
+            .current_dir(dir)
+            .status()?;
+        results.push((branch.name, status.success()));
+        if foreach_opt.fail_fast && !status.success() {
+            break;
+        }
+    }
+
+    println!();
+    for (branch, success) in &results {
+        if *success {
+            println!("  pass  {branch}");
+        } else {
+            println!("  {RED}fail{RESET}  {branch}");
+        }
+    }
+    let failures = results.iter().filter(|(_, success)| !success).count();
+    if failures > 0 {
+        anyhow::bail!("`{command}` failed on {failures} branch(es).");
+    }
+    Ok(())
+}
+
+fn gc(ctx: &mut Context, gc_opt: &GcOpt) -> anyhow::Result<()> {
+    let mut missing_branches = Vec::new();
+    for branch in find_missing_branches(ctx)? {
+        if config::is_protected(&ctx.tx, &branch)? {
+            println!("Skipping `{branch}`, which no longer exists but is protected.");
+        } else {
+            missing_branches.push(branch);
+        }
+    }
+    if !missing_branches.is_empty() {
+        println!("These branches no longer exist in Git:");
+        for branch in &missing_branches {
+            println!("  {branch}");
+        }
+        if prompt::confirm(ctx.yes, "Stop tracking them?")? {
+            for branch in &missing_branches {
+                ctx.tx.remove_branch(branch)?;
+            }
+            println!("Removed {} branches.", missing_branches.len());
+        }
+    }
+
+    let deleted = ctx.tx.delete_orphaned_rows()?;
+    if deleted > 0 {
+        println!("Deleted {deleted} queued operations, progress records, and stack names of untracked branches.");
+    }
+
+    let pruned = prune_snapshots(ctx, gc_opt.keep_snapshots)?;
+    if pruned > 0 {
+        println!(
+            "Deleted {pruned} snapshots, keeping the newest {}.",
+            gc_opt.keep_snapshots
+        );
+    }
+
+    // Vacuuming commits what's been done so far, so it goes last.
+    let size = ctx.tx.size()?;
+    ctx.tx.vacuum()?;
+    println!(
+        "Compacted the database from {} KiB to {} KiB.",
+        size / 1024,
+        ctx.tx.size()? / 1024
+    );
+    Ok(())
+}
+
+fn generate_man(generate_man_opt: &GenerateManOpt) -> anyhow::Result<()> {
+    for path in man::write_pages(&Opt::clap(), &generate_man_opt.out_dir)? {
+        println!("Wrote {path}");
+    }
+    Ok(())
+}
+
+/// The hooks installed by `dmd hook install`.
+const HOOKS: &[&str] = &["post-checkout", "post-commit", "prepare-commit-msg"];
+
+/// The commit trailer naming the parent of the branch a commit was made on, which `dmd recover` reads.
+const PARENT_TRAILER: &str = "Diamond-Parent";
+
+/// Marks hooks as installed by diamond, so that reinstalling them can replace them.
+const HOOK_MARKER: &str = "# Installed by `dmd hook install`.";
+
+/// Set while dmd runs, so that hooks stay quiet during the checkouts and commits it makes itself.
+const HOOK_QUIET_VAR: &str = "DMD_RUNNING";
+
+fn hook(ctx: &mut Context, hook_opt: &HookOpt) -> anyhow::Result<()> {
+    match hook_opt {
+        HookOpt::Install(hook_install_opt) => hook_install(ctx, hook_install_opt),
+        HookOpt::Run(hook_run_opt) => hook_run(ctx, hook_run_opt),
+    }
+}
+
+fn hook_install(ctx: &mut Context, hook_install_opt: &HookInstallOpt) -> anyhow::Result<()> {
+    let hooks_dir = git::get_hooks_dir(&ctx.repo_root)?;
+    std::fs::create_dir_all(&hooks_dir)?;
+    for hook in HOOKS {
+        let path = hooks_dir.join(hook);
+        if let Ok(existing) = std::fs::read_to_string(&path) {
+            if !existing.contains(HOOK_MARKER) && !hook_install_opt.force {
+                anyhow::bail!(
+                    "{RED}Cannot install the `{hook}` hook, because {} already exists. \
+                    Use `--force` to replace it.{RESET}",
+                    path.display()
+                );
+            }
+        }
+    }
+    for hook in HOOKS {
+        let path = hooks_dir.join(hook);
+        std::fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\n\
+                {HOOK_MARKER}\n\
+                [ -n \"${HOOK_QUIET_VAR}\" ] && exit 0\n\
+                command -v dmd >/dev/null 2>&1 || exit 0\n\
+                exec dmd hook run {hook} \"$@\"\n"
+            ),
+        )?;
+        std::fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o755))?;
+        println!("Installed {}", path.display());
+    }
+    Ok(())
+}
+
+fn hook_run(ctx: &mut Context, hook_run_opt: &HookRunOpt) -> anyhow::Result<()> {
+    let Ok(branch) = git::get_current_branch(&ctx.repo_root) else {
+        // Detached HEADs, like during a rebase, aren't worth warning about.
+        return Ok(());
+    };
+    match hook_run_opt.hook.as_str() {
+        "post-checkout" => {
+            // The last argument is 1 for branch checkouts, and 0 for checkouts of files.
+            if hook_run_opt.args.last().map(String::as_str) != Some("1") {
+                return Ok(());
+            }
+            let is_tracked = ctx.tx.get_parent(&branch)?.is_some()
+                || ctx.tx.get_root_branch()?.as_ref() == Some(&branch);
+            if !is_tracked {
+                println!(
+                    "diamond: `{branch}` isn't tracked. Run `dmd track --parent <branch>` to add it to a stack."
+                );
+            }
+        }
+        "prepare-commit-msg" => {
+            let Some(message_path) = hook_run_opt.args.first() else {
+                return Ok(());
+            };
+            let Some(parent) = ctx.tx.get_parent(&branch)? else {
+                return Ok(());
+            };
+            if config::get_bool(&ctx.tx, config::COMMIT_PARENT_TRAILER)? {
+                let message = std::fs::read_to_string(message_path)?;
+                let message = git::with_trailer(&ctx.repo_root, &message, PARENT_TRAILER, &parent)?;
+                std::fs::write(message_path, message)?;
+            }
+        }
+        "post-commit" => {
+            let descendants = ctx.tx.get_descendants(&branch)?;
+            if !descendants.is_empty() {
+                println!(
+                    "diamond: The branches built on `{branch}` need restacking: {}. Run `dmd restack` to update them.",
+                    descendants.join(", ")
+                );
+            }
+        }
+        hook => anyhow::bail!("Unknown hook `{hook}`."),
+    }
+    Ok(())
+}
+
+fn import(ctx: &mut Context, import_opt: &ImportOpt) -> anyhow::Result<()> {
+    let Some(remote_name) = ctx.tx.get_remote()? else {
+        return Err(not_initialized("remote"));
+    };
+    let Some(root_branch) = ctx.tx.get_root_branch()? else {
+        return Err(not_initialized("root branch"));
+    };
+    let github = connect_github(ctx, &remote_name)?;
+    let number = import_opt.from_prs;
+    let Some(status) = github.get_pull_request_statuses(&[number])?.remove(&number) else {
+        anyhow::bail!("Cannot find pull request #{number}.");
+    };
+
+    // Follow the bases down until one of them is tracked, collecting the chain from the top.
+    let mut chain = vec![status.pull_request];
+    let mut parent = loop {
+        let base = chain[chain.len() - 1].base.branch.clone();
+        if let Some(parent) = find_branch_pushed_to(ctx, &base)? {
+            break parent;
+        }
+        match github.find_pull_request(&base)? {
+            Some(pull_request) if !chain.iter().any(|seen| seen.number == pull_request.number) => {
+                chain.push(pull_request);
+            }
+            _ => {
+                println!(
+                    "`{base}` isn't tracked and has no open pull request, so the chain will be tracked on `{root_branch}`."
+                );
+                break root_branch;
+            }
+        }
+    };
+    // Branches which already exist, e.g. because the stack was made here before diamond,
+    // are adopted as they are, as long as they have their pull request's latest commits.
+    let mut branches = Vec::new();
+    for pull_request in &chain {
+        let number = pull_request.number;
+        let branch = pull_request_branch(&github, pull_request);
+        if ctx.tx.get_parent(&branch)?.is_some() {
+            anyhow::bail!("Cannot import #{number}, because `{branch}` is already tracked.");
+        }
+        git::fetch_ref(
+            &ctx.repo_root,
+            &remote_name,
+            &format!("refs/pull/{number}/head"),
+            ctx.git_timeout,
+        )?;
+        let exists = git::branch_exists(&ctx.repo_root, &branch)?;
+        if exists && !git::is_ancestor_of(&ctx.repo_root, &pull_request.head.sha, &branch)? {
+            anyhow::bail!(
+                "Cannot import #{number}, because `{branch}` is missing some of its commits. \
+                Pull them into `{branch}` first."
+            );
+        }
+        branches.push((branch, exists));
+    }
+
+    let mut created = Vec::new();
+    let track_chain = || -> anyhow::Result<()> {
+        for (pull_request, (branch, exists)) in chain.iter().zip(branches).rev() {
+            if !exists {
+                git::create_branch_at(&ctx.repo_root, &branch, &pull_request.head.sha)?;
+                created.push(branch.clone());
+            }
+            track_branch(ctx, &branch, &parent)?;
+            ctx.tx.set_pull_request(&branch, pull_request.number)?;
+            println!(
+                "Tracked `{branch}` (#{}) on top of `{parent}`.",
+                pull_request.number
+            );
+            parent = branch;
+        }
+        Ok(())
+    };
+    let result = track_chain();
+    if result.is_err() {
+        // The database goes back to how it was, so the branches go too.
+        for branch in &created {
+            git::delete_branch(&ctx.repo_root, branch)?;
+        }
+    }
+    result
+}
+
+fn init(ctx: &mut Context, init_opt: &InitOpt) -> anyhow::Result<()> {
+    ctx.tx.set_remote(&init_opt.remote)?;
+    ctx.tx.set_root_branch(&init_opt.root_branch)?;
+    Ok(())
+}
+
+fn land(ctx: &mut Context, land_opt: &LandOpt) -> anyhow::Result<()> {
+    ensure_submodules_clean(ctx, "land")?;
+    let current_branch = git::get_current_branch(&ctx.repo_root)?;
+    // Made once the first pull request is merged, since nothing is checked out before then.
+    let mut guard = None;
+
+    let Some(remote_name) = ctx.tx.get_remote()? else {
+        return Err(not_initialized("remote"));
+    };
+    let Some(root_branch) = ctx.tx.get_root_branch()? else {
+        return Err(not_initialized("root branch"));
+    };
+    let github = connect_github(ctx, &remote_name)?;
+    let push_remote_name = push_remote(&ctx.tx, &remote_name)?;
+    let method = match land_opt.method {
+        Some(method) => method,
+        None => match ctx.tx.get_config(config::LAND_MERGE_METHOD)? {
+            Some(method) => method.parse()?,
+            None => MergeMethod::Merge,
+        },
+    };
+
+    let last_branch = match &land_opt.until {
+        Some(until) => until.clone(),
+        None if land_opt.wait => {
+            let mut top = current_branch.clone();
+            loop {
+                match ctx.tx.get_children(&top)?.as_slice() {
+                    [] => break,
+                    [child] => top = child.clone(),
+                    _ => anyhow::bail!(
+                        "`{top}` has more than one branch stacked on it. \
+                        Use `--until` to say which branch to land up to."
+                    ),
+                }
+            }
+            top
+        }
+        None => current_branch.clone(),
+    };
+    let branches_to_land = ctx.tx.get_ancestors(&last_branch)?;
+    if branches_to_land.is_empty() {
+        anyhow::bail!(
+            "Nothing to land: `{last_branch}` is not a tracked branch above `{root_branch}`."
+        );
+    }
+
+    let mut restacked_branches = Vec::new();
+    for branch in branches_to_land {
+        let Some(pull_request) = find_pull_request(&ctx.tx, &github, &branch.name)? else {
+            anyhow::bail!(
+                "Cannot find an open pull request for `{}`. Has it been submitted?",
+                branch.name
+            );
+        };
+        // Stacks with a target branch land into it instead of the root branch.
+        let base = ctx
+            .tx
+            .get_target_branch(&branch.name)?
+            .unwrap_or_else(|| root_branch.clone());
+        if pull_request.base.branch != base {
+            github.set_pull_request_base(pull_request.number, &base)?;
+        }
+
+        loop {
+            interrupt::check()?;
+            match github.get_check_status(&pull_request.head.sha)? {
+                CheckStatus::Success => break,
+                CheckStatus::Failure => {
+                    anyhow::bail!(
+                        "{RED}Checks failed for `{}`: {}{RESET}",
+                        branch.name,
+                        pull_request.html_url
+                    );
+                }
+                CheckStatus::Pending if land_opt.wait => {
+                    println!("Waiting for checks on `{}`...", branch.name);
+                    interrupt::sleep(Duration::from_secs(land_opt.interval))?;
+                }
+                CheckStatus::Pending => {
+                    anyhow::bail!(
+                        "Checks are still running for `{}`. Use `--wait` to wait for them.",
+                        branch.name
+                    );
+                }
+            }
+        }
+
+        ensure_unprotected(&ctx.tx, &branch.name, "land")?;
+        for child in ctx.tx.get_children(&branch.name)? {
+            ensure_unprotected(&ctx.tx, &child, "restack")?;
+        }
+
+        println!("Landing `{}` ({})...", branch.name, pull_request.html_url);
+        github.merge_pull_request(pull_request.number, method)?;
+        if guard.is_none() {
+            guard = Some(git::BranchGuard::new(
+                ctx.repo_root.clone(),
+                current_branch.clone(),
+                ctx.recurse_submodules,
+            )?);
+        }
+
+        // Squash and rebase merges rewrite the landed commits, so the root branch doesn't contain them,
+        // and only the children's own commits should be moved onto the root branch.
+        // A merge commit keeps them, so this is just an ordinary restack.
+        let landed_commit = git::rev_parse(&ctx.repo_root, &branch.name)?;
+        let children = ctx.tx.get_children(&branch.name)?;
+        ctx.tx.remove_branch(&branch.name)?;
+        for child in &children {
+            ctx.tx.set_base_commit(child, &landed_commit)?;
+        }
+        // The pull request is merged now, whatever goes wrong afterwards.
+        ctx.tx.checkpoint()?;
+
+        git::pull(
+            &ctx.repo_root,
+            &remote_name,
+            &base,
+            &base,
+            ctx.recurse_submodules,
+            ctx.git_timeout,
+        )?;
+        git::fetch(&ctx.repo_root, &remote_name, ctx.git_timeout)?;
+        if push_remote_name != remote_name {
+            git::fetch(&ctx.repo_root, &push_remote_name, ctx.git_timeout)?;
+        }
+        for child in children {
+            println!("Restacking `{child}` onto `{base}`...");
+            restack_onto_parent(ctx, &child, &base)?;
+            ctx.tx.checkpoint()?;
+            let push_options = push_options(&ctx.tx)?;
+            push_branch(ctx, &push_remote_name, &child, &push_options)?;
+            if let Some(child_pull_request) = find_pull_request(&ctx.tx, &github, &child)? {
+                github.set_pull_request_base(child_pull_request.number, &base)?;
+            }
+            restacked_branches.push(child);
+        }
+
+        if !land_opt.wait {
+            break;
+        }
+    }
+
+    // Each remaining stack now has fewer pull requests in it.
+    if config::get_bool(&ctx.tx, config::SUBMIT_TITLE_PREFIX)? {
+        for branch in restacked_branches {
+            if ctx.tx.get_parent(&branch)?.as_deref() == Some(root_branch.as_str()) {
+                renumber_pull_requests(ctx, &github, &branch)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn log(ctx: &mut Context, log_opt: &LogOpt) -> anyhow::Result<()> {
+    let current_branch = git::get_current_branch(&ctx.repo_root).ok();
+
+    let Some(root_branch) = ctx.tx.get_root_branch()? else {
+        return Err(not_initialized("root branch"));
+    };
+
+    let mut children = branch_children(ctx)?;
+
+    let mut labels: HashMap<String, String> = HashMap::new();
+    let mut annotations: Vec<String> = Vec::new();
+    let remote = ctx.tx.get_remote()?;
+    let push_remote_name = match &remote {
+        Some(remote) => Some(push_remote(&ctx.tx, remote)?),
+        None => None,
+    };
+    let timestamps = ctx.tx.get_timestamps()?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    for branch in effective_branches(ctx)? {
+        let Some(counts) = commit_counts(ctx, &branch, push_remote_name.as_deref()) else {
+            continue;
+        };
+        if ctx.output == Output::GithubActions {
+            annotations.extend(stack_problems(ctx, &branch, &counts)?);
+        }
+        let mut label = counts.to_string();
+        if let Some(updated_at) = timestamps
+            .get(&branch.name)
+            .and_then(|timestamps| timestamps.updated_at)
+        {
+            label.push_str(&format!(
+                " touched {}",
+                format_age(now.saturating_sub(updated_at))
+            ));
+        }
+        if log_opt.stat {
+            let (files, added, removed) =
+                git::diff_size(&ctx.repo_root, &branch.parent, &branch.name)?;
+            match files {
+                1 => label.push_str(&format!(" [1 file, +{added} -{removed}]")),
+                _ => label.push_str(&format!(" [{files} files, +{added} -{removed}]")),
+            }
+        }
+        labels.insert(branch.name, label);
+    }
+    let archived_branches = ctx.tx.get_archived_branches()?;
+    if log_opt.all {
+        for branch in &archived_branches {
+            let label = labels.entry(branch.clone()).or_default();
+            if !label.is_empty() {
+                label.push(' ');
+            }
+            label.push_str("(archived)");
+        }
+    }
+    for (branch, note) in ctx.tx.get_notes()? {
+        let label = labels.entry(branch).or_default();
+        if !label.is_empty() {
+            label.push(' ');
+        }
+        label.push_str(&format!("# {note}"));
+    }
+    if !log_opt.all {
+        hide_archived_branches(&root_branch, &mut children, &archived_branches);
+    }
+
+    let mut links: HashMap<String, String> = HashMap::new();
+    if log_opt.format != Format::Tree {
+        if let Some(github) = remote
+            .as_deref()
+            .and_then(|remote| connect_github(ctx, remote).ok())
+        {
+            let branches: Vec<String> = ctx
+                .tx
+                .get_all_branches()?
+                .into_iter()
+                .map(|branch| branch.name)
+                .collect();
+            for (branch, status) in find_pull_request_statuses(&ctx.tx, &github, &branches)? {
+                let label = labels.entry(branch.clone()).or_default();
+                if !label.is_empty() {
+                    label.push(' ');
+                }
+                label.push_str(&format!("#{}", status.pull_request.number));
+                links.insert(branch, status.pull_request.html_url);
+            }
+        }
+    }
+
+    let tree = render::Tree {
+        root: &root_branch,
+        children: &children,
+        current_branch: current_branch.as_deref(),
+        labels: &labels,
+    };
+    match log_opt.format {
+        Format::Tree => print!("{}", render::render_tree(log_opt.style.resolve(), &tree)),
+        Format::Mermaid => print!("{}", render::render_mermaid(&tree, &links)),
+        Format::Dot => print!("{}", render::render_dot(&tree, &links)),
+    }
+    if ctx.output == Output::GithubActions {
+        for annotation in &annotations {
+            println!("{annotation}");
+        }
+        if !annotations.is_empty() {
+            return Err(annotate::Annotated(format!(
+                "Found {} problems in the stack.",
+                annotations.len()
+            ))
+            .into());
+        }
+        println!(
+            "{}",
+            annotate::annotation(
+                annotate::Level::Notice,
+                "Stack is healthy",
+                "Every branch is up to date with its parent and its remote branch."
+            )
+        );
+    }
+    Ok(())
+}
+
+/// Describes what needs fixing about `branch`, as GitHub Actions annotations.
+fn stack_problems(
+    ctx: &Context,
+    branch: &database::Branch,
+    counts: &CommitCounts,
+) -> anyhow::Result<Vec<String>> {
+    let mut problems = Vec::new();
+    if counts.behind > 0 {
+        let conflicts = git::conflicting_paths(&ctx.repo_root, &branch.parent, &branch.name)?;
+        if conflicts.is_empty() {
+            problems.push(annotate::annotation(
+                annotate::Level::Error,
+                "Needs restack",
+                &format!(
+                    "`{}` is behind `{}`. Run `dmd restack` to rebase it.",
+                    branch.name, branch.parent
+                ),
+            ));
+        } else {
+            problems.push(annotate::annotation(
+                annotate::Level::Error,
+                "Restack conflict",
+                &format!(
+                    "`{}` conflicts with `{}` in {}. Run `dmd restack` and resolve the conflicts.",
+                    branch.name,
+                    branch.parent,
+                    conflicts.join(", ")
+                ),
+            ));
+        }
+    }
+    if let Some((to_push, to_pull)) = counts.remote {
+        if to_push > 0 && to_pull > 0 {
+            problems.push(annotate::annotation(
+                annotate::Level::Error,
+                "Diverged from remote",
+                &format!(
+                    "`{}` has diverged from its remote branch, with {to_push} commits to push and {to_pull} to pull.",
+                    branch.name
+                ),
+            ));
+        }
+    }
+    Ok(problems)
+}
+
+/// How far a branch is ahead of and behind its parent and its remote branch.
+struct CommitCounts {
+    ahead: usize,
+    behind: usize,
+    /// The commits to push and to pull, or `None` if the branch hasn't been pushed.
+    remote: Option<(usize, usize)>,
+}
+
+impl std::fmt::Display for CommitCounts {
+    /// Formats the counts like `(2 commits, 1 behind parent, 2 to push)`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut counts = vec![match self.ahead {
+            1 => "1 commit".to_owned(),
+            ahead => format!("{ahead} commits"),
+        }];
+        if self.behind > 0 {
+            counts.push(format!("{} behind parent", self.behind));
+        }
+        match self.remote {
+            Some((to_push, to_pull)) => {
+                if to_push > 0 {
+                    counts.push(format!("{to_push} to push"));
+                }
+                if to_pull > 0 {
+                    counts.push(format!("{to_pull} to pull"));
+                }
+            }
+            None => counts.push("not pushed".to_owned()),
+        }
+        write!(f, "({})", counts.join(", "))
+    }
+}
+
+/// Counts the commits between `branch` and its parent and remote branch.
+/// Returns `None` if the branch is missing from Git.
+fn commit_counts(
+    ctx: &Context,
+    branch: &database::Branch,
+    remote: Option<&str>,
+) -> Option<CommitCounts> {
+    let count = |from: &str, to: &str| git::count_commits(&ctx.repo_root, from, to).ok();
+    let remote_branch_name = remote_branch_name(&ctx.tx, &branch.name).ok()?;
+    let remote_branch = remote.map(|remote| format!("refs/remotes/{remote}/{remote_branch_name}"));
+    Some(CommitCounts {
+        ahead: count(&branch.parent, &branch.name)?,
+        behind: count(&branch.name, &branch.parent)?,
+        remote: remote_branch.and_then(|remote_branch| {
+            Some((
+                count(&remote_branch, &branch.name)?,
+                count(&branch.name, &remote_branch)?,
+            ))
+        }),
+    })
+}
+
+/// Returns every tracked branch, with the branch it's built on in place of its tracked parent:
+/// a stack with a target branch is compared against the target, instead of the root branch.
+fn effective_branches(ctx: &Context) -> anyhow::Result<Vec<database::Branch>> {
+    let mut branches = Vec::new();
+    for branch in ctx.tx.get_all_branches()? {
+        let parent = ctx
+            .tx
+            .get_effective_parent(&branch.name)?
+            .unwrap_or(branch.parent);
+        branches.push(database::Branch {
+            name: branch.name,
+            parent,
+        });
+    }
+    Ok(branches)
+}
+
+/// Maps each tracked branch to its children.
+fn branch_children(ctx: &Context) -> anyhow::Result<HashMap<String, Vec<String>>> {
+    let mut children: HashMap<String, Vec<String>> = HashMap::new();
+    for branch in ctx.tx.get_all_branches()? {
+        children.entry(branch.parent).or_default().push(branch.name);
+    }
+    Ok(children)
+}
+
+fn move_branch(ctx: &mut Context, move_opt: &MoveOpt) -> anyhow::Result<()> {
+    ensure_submodules_clean(ctx, "move")?;
+    let current_branch = git::get_current_branch(&ctx.repo_root)?;
+    let (branch, onto) = if move_opt.interactive {
+        match pick_move(ctx, &current_branch)? {
+            Some(choice) => choice,
+            None => return Ok(()),
+        }
+    } else {
+        let branch = match &move_opt.branch {
+            Some(branch) => resolve_branch(ctx, branch)?,
+            None => current_branch.clone(),
+        };
+        let Some(onto) = &move_opt.onto else {
+            anyhow::bail!("Provide a branch to move onto with `--onto`, or use `--interactive`.");
+        };
+        (branch, resolve_branch(ctx, onto)?)
+    };
+    // The interactive picker has already shown what moves and asked.
+    let confirmed = move_opt.interactive;
+
+    let Some(old_parent) = ctx.tx.get_parent(&branch)? else {
+        return Err(exit::error(
+            ExitCode::NotTracked,
+            format!("Cannot move `{branch}`, because it is not tracked or is the root branch."),
+        ));
+    };
+    if ctx.tx.get_parent(&onto)?.is_none() && ctx.tx.get_root_branch()?.as_ref() != Some(&onto) {
+        return Err(exit::error(
+            ExitCode::NotTracked,
+            format!("Cannot move `{branch}` onto `{onto}`, because `{onto}` is not tracked."),
+        ));
+    }
+    if onto == branch || ctx.tx.get_descendants(&branch)?.contains(&onto) {
+        anyhow::bail!(
+            "Cannot move `{branch}` onto `{onto}`, because `{onto}` is built on top of it."
+        );
+    }
+    ensure_unprotected(&ctx.tx, &branch, "rebase")?;
+    ensure_history(
+        ctx,
+        &[database::Branch {
+            name: branch.clone(),
+            parent: old_parent.clone(),
+        }],
+    )?;
+    let descendants = ctx.tx.get_descendants(&branch)?;
+    if !confirmed
+        && !descendants.is_empty()
+        && !prompt::confirm(
+            ctx.yes,
+            &format!(
+                "Move `{branch}` onto `{onto}`, along with the {} branches above it?",
+                descendants.len()
+            ),
+        )?
+    {
+        return Err(exit::error(
+            ExitCode::Aborted,
+            format!("{RED}Not moving `{branch}`.{RESET}"),
+        ));
+    }
+
+    // Only the branch's own commits move, which start after where it was last based on its old parent.
+    let old_base = own_commits_base(ctx, &branch, &old_parent)?;
+    snapshot_branches(
+        ctx,
+        &std::iter::once(branch.clone())
+            .chain(descendants)
+            .collect::<Vec<_>>(),
+    )?;
+
+    let guard = git::BranchGuard::new(
+        ctx.repo_root.clone(),
+        current_branch,
+        ctx.recurse_submodules,
+    )?;
+    ctx.tx.set_parent(&branch, &onto)?;
+    // Moving a branch says where it goes, so it stops following its stack's old target.
+    ctx.tx.set_target_branch(&branch, None)?;
+    ctx.tx.checkpoint()?;
+    println!("Moving `{branch}` from `{old_parent}` onto `{onto}`...");
+    let options = rebase_options(&ctx.tx)?;
+    if let Err(err) = git::rebase_onto(&ctx.repo_root, &onto, &old_base, &branch, &options) {
+        // Leave the user in the middle of the rebase, instead of switching branches out from under it.
+        guard.dismiss();
+        return Err(exit::error(ExitCode::Conflict, format!("{err:#}\nOnce you've finished the rebase, run `dmd restack` to restack the branches above `{branch}`."
+        )));
+    }
+    ctx.tx
+        .set_base_commit(&branch, &git::rev_parse(&ctx.repo_root, &onto)?)?;
+    ctx.tx.checkpoint()?;
+    restack_descendants(ctx, &branch, guard)
+}
+
+/// Finds the commit after which `branch`'s own commits start,
+/// which is where it was last based on `parent`, if that's still in its history.
+fn own_commits_base(ctx: &Context, branch: &str, parent: &str) -> anyhow::Result<String> {
+    match ctx.tx.get_base_commit(branch)? {
+        Some(base_commit) if git::is_ancestor_of(&ctx.repo_root, &base_commit, branch)? => {
+            Ok(base_commit)
+        }
+        _ => git::merge_base(&ctx.repo_root, parent, branch),
+    }
+}
+
+/// Asks which branch to move and where to, showing the tree of branches to pick from,
+/// and then previews the result. Returns `None` if the user backs out.
+fn pick_move(ctx: &mut Context, current_branch: &str) -> anyhow::Result<Option<(String, String)>> {
+    if !std::io::IsTerminal::is_terminal(&std::io::stdin()) {
+        anyhow::bail!("`--interactive` needs a terminal. Use `--onto` instead.");
+    }
+    let Some(root_branch) = ctx.tx.get_root_branch()? else {
+        return Err(not_initialized("root branch"));
+    };
+    let style = render::Style::detect();
+    let mut children = branch_children(ctx)?;
+    hide_archived_branches(
+        &root_branch,
+        &mut children,
+        &ctx.tx.get_archived_branches()?,
+    );
+    let no_labels = HashMap::new();
+    let tree = render::Tree {
+        root: &root_branch,
+        children: &children,
+        current_branch: Some(current_branch),
+        labels: &no_labels,
+    };
+
+    // The root branch can't be moved.
+    let movable: Vec<(String, String)> = render::tree_lines(style, &tree)
+        .into_iter()
+        .skip(1)
+        .collect();
+    if movable.is_empty() {
+        println!("There are no branches to move.");
+        return Ok(None);
+    }
+    let lines: Vec<&str> = movable.iter().map(|(_, line)| line.as_str()).collect();
+    let Some(index) = dialoguer::Select::new()
+        .with_prompt("Branch to move")
+        .items(&lines)
+        .default(
+            movable
+                .iter()
+                .position(|(branch, _)| branch == current_branch)
+                .unwrap_or(0),
+        )
+        .interact_opt()?
+    else {
+        return Ok(None);
+    };
+    let branch = movable[index].0.clone();
+
+    let descendants = ctx.tx.get_descendants(&branch)?;
+    let parents: Vec<(String, String)> = render::tree_lines(style, &tree)
+        .into_iter()
+        .filter(|(candidate, _)| *candidate != branch && !descendants.contains(candidate))
+        .collect();
+    let lines: Vec<&str> = parents.iter().map(|(_, line)| line.as_str()).collect();
+    let Some(index) = dialoguer::Select::new()
+        .with_prompt(format!("Move `{branch}` onto"))
+        .items(&lines)
+        .interact_opt()?
+    else {
+        return Ok(None);
+    };
+    let onto = parents[index].0.clone();
+
+    let mut moved_children = children.clone();
+    for siblings in moved_children.values_mut() {
+        siblings.retain(|sibling| *sibling != branch);
+    }
+    moved_children
+        .entry(onto.clone())
+        .or_default()
+        .push(branch.clone());
+    println!("After the move:");
+    print!(
+        "{}",
+        render::render_tree(
+            style,
+            &render::Tree {
+                children: &moved_children,
+                ..tree
+            },
+        )
+    );
+    println!("This rebases `{branch}` onto `{onto}`, and restacks the branches above it:");
+    for descendant in &descendants {
+        println!("  {descendant}");
+    }
+    if descendants.is_empty() {
+        println!("  (none)");
+    }
+    if !prompt::confirm(ctx.yes, "Move it?")? {
+        return Err(exit::error(
+            ExitCode::Aborted,
+            format!("{RED}Not moving `{branch}`.{RESET}"),
+        ));
+    }
+    Ok(Some((branch, onto)))
+}
+
+fn note(ctx: &mut Context, note_opt: &NoteOpt) -> anyhow::Result<()> {
+    let branch = resolve_branch_or_current(ctx, &note_opt.branch)?;
+    match &note_opt.note {
+        Some(note) => ctx.tx.set_note(&branch, Some(note))?,
+        None if note_opt.clear => ctx.tx.set_note(&branch, None)?,
+        None => {
+            if let Some(note) = ctx.tx.get_note(&branch)? {
+                println!("{note}");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn outdated(ctx: &mut Context) -> anyhow::Result<()> {
+    let outdated_branches = outdated_branches(ctx, None, &[])?;
+    if outdated_branches.is_empty() {
+        println!("Every branch is up to date with its parent.");
+    }
+    for (branch, reason) in outdated_branches {
+        println!("{branch}: {reason}");
+    }
+    Ok(())
+}
+
+/// Finds the branches which `dmd restack` would rebase, with the reason each one would be,
+/// in the order they'd be restacked. With a `remote`, the branches at the bottom of each stack
+/// are compared with their parent's remote branch instead, where it has one.
+/// The `merged` branches are left out, as if they were no longer tracked,
+/// so that the branches on them are compared with their parents instead.
+fn outdated_branches(
+    ctx: &mut Context,
+    remote: Option<&str>,
+    merged: &[String],
+) -> anyhow::Result<Vec<(String, String)>> {
+    let Some(root_branch) = ctx.tx.get_root_branch()? else {
+        return Err(not_initialized("root branch"));
+    };
+    let mut branches = Vec::new();
+    // Where each merged branch's children end up, which is its own parent.
+    let mut merged_parents: HashMap<String, String> = HashMap::new();
+    for base_branch in ctx.tx.get_children(&root_branch)? {
+        for mut branch in ctx.tx.get_branches_in_stack(&base_branch)? {
+            if let Some(parent) = merged_parents.get(&branch.parent) {
+                branch.parent = parent.clone();
+            }
+            if merged.contains(&branch.name) {
+                merged_parents.insert(branch.name, branch.parent);
+            } else if git::branch_exists(&ctx.repo_root, &branch.name)? {
+                branches.push(branch);
+            }
+        }
+    }
+    let ctx: &Context = ctx;
+    let plan = plan_restack(ctx, &branches, |parent| {
+        // The root branch, or the target branch a stack is built on.
+        let is_stack_bottom = ctx.tx.get_parent(parent)?.is_none();
+        Ok(match remote {
+            Some(remote)
+                if is_stack_bottom
+                    && git::rev_parse(
+                        &ctx.repo_root,
+                        &format!("refs/remotes/{remote}/{parent}"),
+                    )
+                    .is_ok() =>
+            {
+                format!("{remote}/{parent}")
+            }
+            _ => parent.to_owned(),
+        })
+    })?;
+    Ok(plan
+        .into_iter()
+        .filter_map(|(branch, reason)| Some((branch.name.clone(), reason?)))
+        .collect())
+}
+
+/// Works out which of `branches`, in stack order, `dmd restack` would rebase onto their parents,
+/// pairing each one with the reason it would be, or `None` if it's already up to date.
+/// A branch is rebased if its parent has moved on, or if its parent is going to be restacked itself.
+/// Each parent is looked for at `upstream(parent)`, which can stand in for it, like its remote branch.
+fn plan_restack<'a>(
+    ctx: &Context,
+    branches: &'a [database::Branch],
+    upstream: impl Fn(&str) -> anyhow::Result<String>,
+) -> anyhow::Result<Vec<(&'a database::Branch, Option<String>)>> {
+    let mut plan: Vec<(&database::Branch, Option<String>)> = Vec::new();
+    for branch in branches {
+        let parent_restacked = plan
+            .iter()
+            .any(|(planned, reason)| planned.name == branch.parent && reason.is_some());
+        let reason = if parent_restacked {
+            Some(format!("`{}` is outdated", branch.parent))
+        } else {
+            let parent = upstream(&branch.parent)?;
+            if git::is_ancestor_of(&ctx.repo_root, &parent, &branch.name)? {
+                None
+            } else {
+                let behind = git::count_commits(&ctx.repo_root, &branch.name, &parent)?;
+                Some(format!("{behind} behind `{parent}`"))
+            }
+        };
+        plan.push((branch, reason));
+    }
+    Ok(plan)
+}
+
+fn pick(ctx: &mut Context, pick_opt: &PickOpt) -> anyhow::Result<()> {
+    ensure_submodules_clean(ctx, "pick")?;
+    let current_branch = git::get_current_branch(&ctx.repo_root)?;
+    let target = &resolve_branch(ctx, &pick_opt.into)?;
+    if ctx.tx.get_parent(target)?.is_none() {
+        return Err(exit::error(
+            ExitCode::NotTracked,
+            format!(
+                "Cannot pick into `{target}`, because it is not tracked or is the root branch."
+            ),
+        ));
+    }
+    ensure_unprotected(&ctx.tx, target, "rebase")?;
+    let Ok(commit) = git::rev_parse(&ctx.repo_root, &format!("{}^{{commit}}", pick_opt.commit))
+    else {
+        anyhow::bail!("Cannot find a commit named `{}`.", pick_opt.commit);
+    };
+    if git::is_ancestor_of(&ctx.repo_root, &commit, target)? {
+        anyhow::bail!("`{target}` already contains {}.", pick_opt.commit);
+    }
+
+    // The branch above `target` whose own commits include `commit`, which it's moved out of.
+    let mut owner = None;
+    for descendant in ctx.tx.get_descendants(target)? {
+        let Some(parent) = ctx.tx.get_effective_parent(&descendant)? else {
+            continue;
+        };
+        if git::is_ancestor_of(&ctx.repo_root, &commit, &descendant)?
+            && !git::is_ancestor_of(&ctx.repo_root, &commit, &parent)?
+        {
+            owner = Some(descendant);
+            break;
+        }
+    }
+    if let Some(owner) = &owner {
+        ensure_unprotected(&ctx.tx, owner, "rebase")?;
+    }
+
+    let guard = git::BranchGuard::new(
+        ctx.repo_root.clone(),
+        current_branch,
+        ctx.recurse_submodules,
+    )?;
+    git::checkout(&ctx.repo_root, target, ctx.recurse_submodules)?;
+    if let Err(err) = git::cherry_pick(&ctx.repo_root, &commit, ctx.recurse_submodules) {
+        guard.dismiss();
+        return Err(exit::error(ExitCode::Conflict, format!("{err:#}\nResolve the conflicts and run `git cherry-pick --continue`, or `git cherry-pick --abort` to give up."
+        )));
+    }
+    if let Some(owner) = &owner {
+        println!("Removing {} from `{owner}`...", pick_opt.commit);
+        if let Err(err) = git::rebase_onto(
+            &ctx.repo_root,
+            &format!("{commit}^"),
+            &commit,
+            owner,
+            &rebase_options(&ctx.tx)?,
+        ) {
+            // Leave the user in the middle of the rebase, instead of switching branches out from under it.
+            guard.dismiss();
+            return Err(exit::error(ExitCode::Conflict, format!("{err:#}\nOnce you've finished the rebase, run `dmd restack` to restack the branches above `{target}`."
+            )));
+        }
+    }
+    restack_descendants(ctx, target, guard)
+}
+
+/// Describes the current branch's place in its stack for `dmd prompt`, or returns `None` if it isn't tracked.
+/// Reads the database without the usual `Context`, so that it's quick and never waits on a lock.
+fn shell_prompt(cwd: &Path) -> anyhow::Result<Option<String>> {
+    let (common_dir, branch) = git::get_common_dir_and_branch(cwd)?;
+    let Some(branch) = branch else {
+        return Ok(None);
+    };
+    let database_path = common_dir.join("diamond.sqlite3");
+    if !database_path.exists() {
+        return Ok(None);
+    }
+    let mut database = Database::open_read_only(database_path)?;
+    let tx = database.transaction()?;
+    let Some(parent) = tx.get_effective_parent(&branch)? else {
+        return Ok(None);
+    };
+
+    let mut stack = vec![branch.clone(), parent.clone()];
+    while let Some(ancestor) = tx.get_effective_parent(stack.last().unwrap())? {
+        // A cycle would be a bug elsewhere, but shouldn't hang the shell.
+        if stack.contains(&ancestor) {
+            break;
+        }
+        stack.push(ancestor);
+    }
+    stack.reverse();
+    let (ahead, behind) = git::ahead_behind(cwd, &parent, &branch)?;
+    Ok(Some(render_prompt(&stack, ahead, behind)))
+}
+
+/// Renders `stack`, from its root to the current branch, with the current branch's commit counts.
+fn render_prompt(stack: &[String], ahead: usize, behind: usize) -> String {
+    let mut names: Vec<String> = stack.to_vec();
+    if let Some(current) = names.last_mut() {
+        current.insert(0, '*');
+    }
+    let mut prompt = names.join("→");
+    let counts: Vec<String> = [(ahead, '↑'), (behind, '↓')]
+        .iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, arrow)| format!("{count}{arrow}"))
+        .collect();
+    if !counts.is_empty() {
+        prompt.push_str(&format!(" ({})", counts.join(" ")));
+    }
+    prompt
+}
+
+fn pr(ctx: &mut Context, pr_opt: &PrOpt) -> anyhow::Result<()> {
+    match pr_opt {
+        PrOpt::Checks(pr_checks_opt) => pr_checks(ctx, pr_checks_opt),
+        PrOpt::Comment(pr_comment_opt) => pr_comment(ctx, pr_comment_opt),
+        PrOpt::Merge(pr_merge_opt) => pr_merge(ctx, pr_merge_opt),
+        PrOpt::Watch(pr_watch_opt) => pr_watch(ctx, pr_watch_opt),
+    }
+}
+
+fn pr_merge(ctx: &mut Context, pr_merge_opt: &PrMergeOpt) -> anyhow::Result<()> {
+    let current_branch = git::get_current_branch(&ctx.repo_root)?;
+    let Some(parent) = ctx.tx.get_parent(&current_branch)? else {
+        return Err(exit::error(
+            ExitCode::NotTracked,
+            format!(
+                "Cannot merge `{current_branch}`, because it is not tracked or is the root branch."
+            ),
+        ));
+    };
+    if ctx.tx.get_root_branch()?.as_ref() != Some(&parent) {
+        anyhow::bail!(
+            "Cannot merge `{current_branch}` before `{parent}`, which it's built on. \
+            Use `dmd land` to land both."
+        );
+    }
+    land(
+        ctx,
+        &LandOpt {
+            wait: true,
+            until: Some(current_branch),
+            interval: pr_merge_opt.interval,
+            method: pr_merge_opt.method,
+        },
+    )
+}
+
+/// Connects to GitHub and finds the open pull request for `branch`, failing if it hasn't been submitted.
+fn connect_pull_request(ctx: &mut Context, branch: &str) -> anyhow::Result<(GitHub, PullRequest)> {
+    let Some(remote_name) = ctx.tx.get_remote()? else {
+        return Err(not_initialized("remote"));
+    };
+    let github = connect_github(ctx, &remote_name)?;
+    let Some(pull_request) = find_pull_request(&ctx.tx, &github, branch)? else {
+        anyhow::bail!("Cannot find an open pull request for `{branch}`. Has it been submitted?");
+    };
+    Ok((github, pull_request))
+}
+
+fn pr_comment(ctx: &mut Context, pr_comment_opt: &PrCommentOpt) -> anyhow::Result<()> {
+    let current_branch = git::get_current_branch(&ctx.repo_root)?;
+    let (github, pull_request) = connect_pull_request(ctx, &current_branch)?;
+    github.create_comment(pull_request.number, &pr_comment_opt.message)?;
+    println!("Commented on {}.", pull_request.html_url);
+    Ok(())
+}
+
+fn pr_checks(ctx: &mut Context, pr_checks_opt: &PrChecksOpt) -> anyhow::Result<()> {
+    let current_branch = git::get_current_branch(&ctx.repo_root)?;
+    let (github, pull_request) = connect_pull_request(ctx, &current_branch)?;
+
+    let check_runs = github.get_check_runs(&pull_request.head.sha)?;
+    if check_runs.is_empty() {
+        println!(
+            "No checks have been reported for {}.",
+            pull_request.html_url
+        );
+    }
+    for check_run in &check_runs {
+        let status = match check_run.check_status() {
+            CheckStatus::Pending => "running",
+            CheckStatus::Success => "passed",
+            CheckStatus::Failure => "failed",
+        };
+        let duration = match check_run.duration() {
+            Some(duration) => format!(
+                "{}m{:02}s",
+                duration.as_secs() / 60,
+                duration.as_secs() % 60
+            ),
+            None => "-".to_owned(),
+        };
+        println!(
+            "{status:>8}  {duration:>7}  {}  {}",
+            check_run.name,
+            check_run.html_url.as_deref().unwrap_or("")
+        );
+    }
+
+    if pr_checks_opt.fail_fast
+        && check_runs
+            .iter()
+            .any(|check_run| check_run.check_status() == CheckStatus::Failure)
+    {
+        anyhow::bail!("{RED}Checks failed for `{current_branch}`.{RESET}");
+    }
+    Ok(())
+}
+
+fn pr_watch(ctx: &mut Context, pr_watch_opt: &PrWatchOpt) -> anyhow::Result<()> {
+    let current_branch = git::get_current_branch(&ctx.repo_root)?;
+    let Some(remote_name) = ctx.tx.get_remote()? else {
+        return Err(not_initialized("remote"));
+    };
+    let github = connect_github(ctx, &remote_name)?;
+
+    let branches: Vec<String> = ctx
+        .tx
+        .get_branches_in_stack(&current_branch)?
+        .into_iter()
+        .map(|branch| branch.name)
+        .collect();
+
+    // On a terminal, each refresh replaces the last one instead of scrolling.
+    let redraw = std::io::IsTerminal::is_terminal(&std::io::stdout());
+    let mut lines_drawn = 0;
+    loop {
+        interrupt::check()?;
+        // Each refresh is a single query for the whole stack, however tall it is.
+        let mut pull_requests = find_pull_request_statuses(&ctx.tx, &github, &branches)?;
+        if pull_requests.is_empty() {
+            anyhow::bail!("None of the branches in this stack have open pull requests.");
+        }
+        let mut statuses = Vec::new();
+
+        if redraw && lines_drawn > 0 {
+            print!("\x1b[{lines_drawn}A\x1b[J");
+        }
+        lines_drawn = 0;
+        for branch in &branches {
+            let Some(pull_request_status) = pull_requests.remove(branch) else {
+                continue;
+            };
+            let status = match pull_request_status.check_status {
+                CheckStatus::Pending => "running",
+                CheckStatus::Success => "passed",
+                CheckStatus::Failure => "failed",
+            };
+            println!(
+                "{status:>8}  {branch} ({})",
+                pull_request_status.pull_request.html_url
+            );
+            statuses.push(pull_request_status.check_status);
+            lines_drawn += 1;
+        }
+
+        if statuses.contains(&CheckStatus::Failure) {
+            anyhow::bail!("{RED}Checks failed for at least one pull request in the stack.{RESET}");
+        }
+        if !statuses.contains(&CheckStatus::Pending) {
+            return Ok(());
+        }
+        if !redraw {
+            println!();
+        }
+        interrupt::sleep(Duration::from_secs(pr_watch_opt.interval))?;
+    }
+}
+
+/// Removes archived branches from `children`, unless they have unarchived descendants
+/// which need them to stay in the tree. Returns true if `branch` should be hidden.
+fn hide_archived_branches(
+    branch: &str,
+    children: &mut HashMap<String, Vec<String>>,
+    archived_branches: &[String],
+) -> bool {
+    let mut branch_children = children.remove(branch).unwrap_or_default();
+    branch_children.retain(|child| !hide_archived_branches(child, children, archived_branches));
+    let hidden =
+        branch_children.is_empty() && archived_branches.iter().any(|archived| archived == branch);
+    if !branch_children.is_empty() {
+        children.insert(branch.to_owned(), branch_children);
+    }
+    hidden
+}
+
+fn pop(ctx: &mut Context, pop_opt: &PopOpt) -> anyhow::Result<()> {
+    let branch = git::get_current_branch(&ctx.repo_root)?;
+    let Some(parent) = ctx.tx.get_effective_parent(&branch)? else {
+        return Err(exit::error(
+            ExitCode::NotTracked,
+            format!("Cannot pop `{branch}`, because it is not tracked or is the root branch."),
+        ));
+    };
+    ensure_unprotected(&ctx.tx, &branch, "remove")?;
+    if !git::is_ancestor_of(&ctx.repo_root, &parent, &branch)? {
+        anyhow::bail!(
+            "{RED}Cannot pop `{branch}`, because it's behind `{parent}`. Run `dmd restack` first.{RESET}"
+        );
+    }
+    let children = ctx.tx.get_children(&branch)?;
+    if pop_opt.keep_commits {
+        // The root branch and target branches only change by landing pull requests.
+        if ctx.tx.get_parent(&parent)?.is_none() {
+            anyhow::bail!(
+                "{RED}Cannot pop `{branch}` with `--keep-commits`, \
+                because that would commit to `{parent}`, which isn't a tracked branch.{RESET}"
+            );
+        }
+        ensure_unprotected(&ctx.tx, &parent, "rewrite")?;
+    } else if !children.is_empty() {
+        // Its commits would only be left in its children, instead of being undone.
+        anyhow::bail!(
+            "{RED}Cannot pop `{branch}`, because these branches are built on it: {}. \
+            Pass `--keep-commits` to move its commits onto `{parent}` instead.{RESET}",
+            children.join(", ")
+        );
+    }
+
+    snapshot_branches(ctx, std::slice::from_ref(&branch))?;
+    let commit = git::rev_parse(&ctx.repo_root, &branch)?;
+    if pop_opt.keep_commits {
+        git::update_ref(&ctx.repo_root, &format!("refs/heads/{parent}"), &commit)?;
+    }
+    // Switching `HEAD` without a checkout leaves the branch's files as they are,
+    // so its changes show up as staged changes on the parent.
+    git::set_head(&ctx.repo_root, &parent)?;
+    git::delete_branch(&ctx.repo_root, &branch)?;
+
+    ctx.tx.remove_branch(&branch)?;
+    for child in &children {
+        // The parent now has everything the children were built on.
+        ctx.tx.set_base_commit(child, &commit)?;
+    }
+    if pop_opt.keep_commits {
+        println!("Popped `{branch}`, moving its commits onto `{parent}`.");
+    } else {
+        println!("Popped `{branch}`. Its changes are staged on `{parent}`.");
+    }
+    Ok(())
+}
+
+fn recover(ctx: &mut Context, recover_opt: &RecoverOpt) -> anyhow::Result<()> {
+    let root_branch = match (ctx.tx.get_root_branch()?, &recover_opt.root_branch) {
+        (Some(root_branch), _) => root_branch,
+        (None, Some(root_branch)) => {
+            if !git::branch_exists(&ctx.repo_root, root_branch)? {
+                anyhow::bail!("There is no branch named `{root_branch}`.");
+            }
+            ctx.tx.set_root_branch(root_branch)?;
+            root_branch.clone()
+        }
+        (None, None) => {
+            return Err(exit::error(
+                ExitCode::NotInitialized,
+                "Cannot tell which branch is the root branch. Pass it with `--root-branch`.",
+            ))
+        }
+    };
+    if ctx.tx.get_remote()?.is_none() {
+        match &recover_opt.remote {
+            Some(remote) if !git::remote_exists(&ctx.repo_root, remote)? => {
+                anyhow::bail!("There is no remote named `{remote}`.");
+            }
+            Some(remote) => ctx.tx.set_remote(remote)?,
+            None if git::remote_exists(&ctx.repo_root, "origin")? => ctx.tx.set_remote("origin")?,
+            None => {}
+        }
+    }
+
+    let local_branches: Vec<String> = git::list_refs(&ctx.repo_root, "refs/heads/")?
+        .into_iter()
+        .map(|(reference, _)| reference.trim_start_matches("refs/heads/").to_owned())
+        .collect();
+    let mut found: Vec<(String, String, &str)> = Vec::new();
+    for branch in &local_branches {
+        if *branch == root_branch || ctx.tx.get_parent(branch)?.is_some() {
+            continue;
+        }
+        // Commits which the branches below this one share were made on those branches,
+        // so their trailers name the wrong parent.
+        let descendants = git::branches_containing(&ctx.repo_root, branch)?;
+        let others: Vec<String> = local_branches
+            .iter()
+            .filter(|other| !descendants.contains(other))
+            .cloned()
+            .collect();
+        let (parent, source) =
+            match git::own_trailer(&ctx.repo_root, branch, &others, PARENT_TRAILER)? {
+                Some(parent) => (parent, "its `Diamond-Parent:` trailer"),
+                None => match git::created_from(&ctx.repo_root, branch)? {
+                    Some(parent) => (parent, "its reflog"),
+                    None => continue,
+                },
+            };
+        if parent != *branch && local_branches.contains(&parent) {
+            found.push((branch.clone(), parent, source));
+        }
+    }
+
+    // A branch can only be tracked on a tracked branch, so parents go first.
+    let mut recovered = 0;
+    loop {
+        let mut remaining = Vec::new();
+        let mut tracked_any = false;
+        for (branch, parent, source) in std::mem::take(&mut found) {
+            if parent != root_branch && ctx.tx.get_parent(&parent)?.is_none() {
+                remaining.push((branch, parent, source));
+                continue;
+            }
+            track_branch(ctx, &branch, &parent)?;
+            println!("Tracked `{branch}` on `{parent}`, going by {source}.");
+            recovered += 1;
+            tracked_any = true;
+        }
+        found = remaining;
+        if found.is_empty() || !tracked_any {
+            break;
+        }
+    }
+    for (branch, parent, _) in &found {
+        println!("Skipped `{branch}`, because its parent `{parent}` can't be tracked.");
+    }
+    if recovered == 0 {
+        println!("Found nothing to recover.");
+    }
+    Ok(())
+}
+
+fn remote_name(ctx: &mut Context, remote_name_opt: &RemoteNameOpt) -> anyhow::Result<()> {
+    let branch = resolve_branch_or_current(ctx, &remote_name_opt.branch)?;
+    match &remote_name_opt.name {
+        Some(name) => {
+            if !git::is_valid_branch_name(name)? {
+                anyhow::bail!("{RED}`{name}` is not a valid branch name.{RESET}");
+            }
+            ctx.tx.set_remote_name(&branch, Some(name))?;
+            println!("`{branch}` will be pushed as `{name}`.");
+        }
+        None if remote_name_opt.clear => ctx.tx.set_remote_name(&branch, None)?,
+        None => println!("{}", remote_branch_name(&ctx.tx, &branch)?),
+    }
+    Ok(())
+}
+
+fn remove(ctx: &mut Context, remove_opt: &RemoveOpt) -> anyhow::Result<()> {
+    let branch = resolve_branch(ctx, &remove_opt.branch)?;
+    ensure_unprotected(&ctx.tx, &branch, "remove")?;
+    ctx.tx.remove_branch(&branch)?;
+    Ok(())
+}
+
+fn restack(ctx: &mut Context, restack_opt: &RestackOpt) -> anyhow::Result<()> {
+    restack_scope(ctx, restack_opt, Scope::Stack)
+}
+
+fn restack_scope(ctx: &mut Context, restack_opt: &RestackOpt, scope: Scope) -> anyhow::Result<()> {
+    ensure_submodules_clean(ctx, "restack")?;
+    let current_branch = git::get_current_branch(&ctx.repo_root)?;
+    let branch = match &restack_opt.branch {
+        Some(branch) => Some(resolve_branch(ctx, branch)?),
+        None => None,
+    };
+    let stack_branch = match &branch {
+        Some(branch) if ctx.tx.get_parent(branch)?.is_none() => {
+            return Err(exit::error(
+                ExitCode::NotTracked,
+                format!(
+                    "Cannot restack `{branch}`, because it is not tracked or is the root branch."
+                ),
+            ));
+        }
+        Some(branch) => branch.clone(),
+        None => current_branch.clone(),
+    };
+
+    let branches_in_stack = branches_in_scope(ctx, &stack_branch, scope)?;
+    let branches_in_stack = skip_archived(ctx, branches_in_stack)?;
+    ensure_history(ctx, &branches_in_stack)?;
+
+    let plan = plan_restack(ctx, &branches_in_stack, |parent| Ok(parent.to_owned()))?;
+    let restacked_branches: Vec<&str> = plan
+        .iter()
+        .filter(|(_, reason)| reason.is_some())
+        .map(|(branch, _)| branch.name.as_str())
+        .collect();
+
+    for branch in &restacked_branches {
+        ensure_unprotected(&ctx.tx, branch, "restack")?;
+    }
+
+    if restack_opt.plan {
+        println!("Restack plan:");
+        for (branch, reason) in &plan {
+            if reason.is_some() {
+                println!("  rebase `{}` onto `{}`", branch.name, branch.parent);
+            } else {
+                println!(
+                    "  skip   `{}` (already up to date with `{}`)",
+                    branch.name, branch.parent
+                );
+            }
+        }
+        if restacked_branches.is_empty() {
+            println!("Nothing to restack.");
+            return Ok(());
+        }
+        if !prompt::confirm(ctx.yes, "Restack these branches?")? {
+            return Err(exit::error(
+                ExitCode::Aborted,
+                format!("{RED}Not restacking.{RESET}"),
+            ));
+        }
+    }
+
+    let restacked_branches: Vec<String> = restacked_branches
+        .iter()
+        .map(|branch| branch.to_string())
+        .collect();
+    let guard = restack_guard(ctx, &current_branch, &restacked_branches)?;
+    snapshot_branches(ctx, &restacked_branches)?;
+    for (branch, reason) in plan {
+        if reason.is_none() {
+            // The branch already contains its parent's tip, so that's its new base.
+            ctx.tx.set_base_commit(
+                &branch.name,
+                &git::rev_parse(&ctx.repo_root, &branch.parent)?,
+            )?;
+            ctx.summary
+                .record(&branch.name, Outcome::Skipped, "already up to date");
+            continue;
+        }
+        println!("Restacking `{}` onto `{}`...", branch.name, branch.parent);
+        if let Err(err) = restack_onto_parent(ctx, &branch.name, &branch.parent) {
+            ctx.summary
+                .record(&branch.name, Outcome::Failed, failure_detail(&err));
+            if err.is::<interrupt::Interrupted>() {
+                return Err(exit::error(
+                    ExitCode::Interrupted,
+                    format!("{err:#} Run `dmd restack` again to restack the rest of the stack."),
+                ));
+            }
+            // Leave the user in the middle of the rebase, instead of switching branches out from under it.
+            guard.dismiss();
+            return Err(exit::error(ExitCode::Conflict, format!("{err:#}\nOnce you've finished the rebase, run `dmd restack` again to restack the rest of the stack."
+            )));
+        }
+        // Keep the restacked branches' new bases, even if a later branch is interrupted or conflicts.
+        ctx.tx.checkpoint()?;
+    }
+
+    guard.release()
+}
+
+/// Where snapshots of branches are saved, as `<prefix><id>/<branch>`,
+/// where the id is the time the snapshot was taken in nanoseconds, so that ids don't collide.
+const SNAPSHOT_PREFIX: &str = "refs/diamond/backup/";
+
+/// Where the commit each branch in a snapshot was based on is saved, as `<prefix><id>/<branch>`.
+const SNAPSHOT_BASE_PREFIX: &str = "refs/diamond/backup-base/";
+
+/// How many snapshots to keep before the oldest ones are deleted.
+const SNAPSHOTS_KEPT: usize = 20;
+
+/// A branch saved in a snapshot, and the reference which holds it.
+struct SnapshotBranch {
+    reference: String,
+    branch: String,
+    commit: String,
+    /// The reference holding the commit the branch's own commits started after, and that commit.
+    base: Option<(String, String)>,
+}
+
+/// Saves where each of `branches` is right now, so that `dmd restore-snapshot` can put them back.
+fn snapshot_branches(ctx: &Context, branches: &[String]) -> anyhow::Result<()> {
+    if branches.is_empty() {
+        return Ok(());
+    }
+    let id = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_nanos();
+    for branch in branches {
+        git::update_ref(
+            &ctx.repo_root,
+            &format!("{SNAPSHOT_PREFIX}{id}/{branch}"),
+            &git::rev_parse(&ctx.repo_root, branch)?,
+        )?;
+        if let Some(base_commit) = ctx.tx.get_base_commit(branch)? {
+            git::update_ref(
+                &ctx.repo_root,
+                &format!("{SNAPSHOT_BASE_PREFIX}{id}/{branch}"),
+                &base_commit,
+            )?;
+        }
+    }
+    prune_snapshots(ctx, SNAPSHOTS_KEPT)?;
+    Ok(())
+}
+
+/// Deletes all but the newest `keep` snapshots, returning how many were deleted.
+fn prune_snapshots(ctx: &Context, keep: usize) -> anyhow::Result<usize> {
+    let snapshots = list_snapshots(ctx)?;
+    let pruned = snapshots.len().saturating_sub(keep);
+    for branches in snapshots.values().take(pruned) {
+        for snapshot_branch in branches {
+            git::delete_ref(&ctx.repo_root, &snapshot_branch.reference)?;
+            if let Some((base_reference, _)) = &snapshot_branch.base {
+                git::delete_ref(&ctx.repo_root, base_reference)?;
+            }
+        }
+    }
+    Ok(pruned)
+}
+
+/// Returns each snapshot's branches, keyed by the snapshot's id.
+fn list_snapshots(ctx: &Context) -> anyhow::Result<BTreeMap<u128, Vec<SnapshotBranch>>> {
+    let mut bases = HashMap::new();
+    for (reference, commit) in git::list_refs(&ctx.repo_root, SNAPSHOT_BASE_PREFIX)? {
+        if let Some(key) = reference.strip_prefix(SNAPSHOT_BASE_PREFIX) {
+            bases.insert(key.to_owned(), (reference.clone(), commit));
+        }
+    }
+    let mut snapshots = BTreeMap::new();
+    for (reference, commit) in git::list_refs(&ctx.repo_root, SNAPSHOT_PREFIX)? {
+        let Some(key) = reference.strip_prefix(SNAPSHOT_PREFIX) else {
+            continue;
+        };
+        let Some((id, branch)) = key.split_once('/') else {
+            continue;
+        };
+        let Ok(id) = id.parse::<u128>() else {
+            continue;
+        };
+        let base = bases.remove(key);
+        let branch = branch.to_owned();
+        snapshots
+            .entry(id)
+            .or_insert_with(Vec::new)
+            .push(SnapshotBranch {
+                reference,
+                branch,
+                commit,
+                base,
+            });
+    }
+    Ok(snapshots)
+}
+
+fn restore_snapshot(
+    ctx: &mut Context,
+    restore_snapshot_opt: &RestoreSnapshotOpt,
+) -> anyhow::Result<()> {
+    let snapshots = list_snapshots(ctx)?;
+    if restore_snapshot_opt.list {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        for (id, branches) in snapshots.iter().rev() {
+            let names: Vec<&str> = branches
+                .iter()
+                .map(|snapshot_branch| snapshot_branch.branch.as_str())
+                .collect();
+            let taken_at = (id / 1_000_000_000) as u64;
+            println!(
+                "{id} ({}): {}",
+                format_age(now.saturating_sub(taken_at)),
+                names.join(", ")
+            );
+        }
+        return Ok(());
+    }
+
+    let branches = match restore_snapshot_opt.snapshot {
+        Some(id) => match snapshots.get(&id) {
+            Some(branches) => branches,
+            None => anyhow::bail!(
+                "{RED}There is no snapshot `{id}`. See `dmd restore-snapshot --list`.{RESET}"
+            ),
+        },
+        None => match snapshots.values().next_back() {
+            Some(branches) => branches,
+            None => anyhow::bail!("{RED}There are no snapshots to restore.{RESET}"),
+        },
+    };
+
+    // The checked out branch goes first, since it's the one which can fail because of local changes.
+    let current_branch = git::get_current_branch(&ctx.repo_root)?;
+    let mut branches: Vec<&SnapshotBranch> = branches.iter().collect();
+    branches.sort_by_key(|snapshot_branch| snapshot_branch.branch != current_branch);
+    for SnapshotBranch {
+        branch,
+        commit,
+        base,
+        ..
+    } in branches
+    {
+        git::reset_branch(&ctx.repo_root, branch, commit, ctx.recurse_submodules)?;
+        // Otherwise the next restack would replay commits from where the branch was rebased to.
+        if let Some((_, base_commit)) = base {
+            ctx.tx.set_base_commit(branch, base_commit)?;
+        }
+        println!(
+            "Restored `{branch}` to {}.",
+            &commit[..commit.len().min(12)]
+        );
+    }
+    Ok(())
+}
+
+fn revert(ctx: &mut Context, revert_opt: &RevertOpt) -> anyhow::Result<()> {
+    ensure_submodules_clean(ctx, "revert")?;
+    let current_branch = git::get_current_branch(&ctx.repo_root)?;
+    let Ok(commit) = git::rev_parse(&ctx.repo_root, &format!("{}^{{commit}}", revert_opt.commit))
+    else {
+        anyhow::bail!("Cannot find a commit named `{}`.", revert_opt.commit);
+    };
+
+    // The commit belongs to the branch which has it, but whose parent doesn't.
+    let mut owner = None;
+    for branch in ctx.tx.get_all_branches()? {
+        if git::is_ancestor_of(&ctx.repo_root, &commit, &branch.name)?
+            && !git::is_ancestor_of(&ctx.repo_root, &commit, &branch.parent)?
+        {
+            owner = Some(branch.name);
+            break;
+        }
+    }
+    let Some(owner) = owner else {
+        anyhow::bail!(
+            "{} isn't one of the commits on a tracked branch. Use `git revert` instead.",
+            revert_opt.commit
+        );
+    };
+    ensure_unprotected(&ctx.tx, &owner, "rebase")?;
+
+    let guard = git::BranchGuard::new(
+        ctx.repo_root.clone(),
+        current_branch,
+        ctx.recurse_submodules,
+    )?;
+    git::checkout(&ctx.repo_root, &owner, ctx.recurse_submodules)?;
+    println!("Reverting {} on `{owner}`...", revert_opt.commit);
+    if let Err(err) = git::revert(&ctx.repo_root, &commit, ctx.recurse_submodules) {
+        guard.dismiss();
+        return Err(exit::error(
+            ExitCode::Conflict,
+            format!(
+                "{err:#}\nResolve the conflicts and run `git revert --continue`, \
+                then `dmd restack` to restack the branches above `{owner}`."
+            ),
+        ));
+    }
+    restack_descendants(ctx, &owner, guard)
+}
+
+fn review(ctx: &mut Context, review_opt: &ReviewOpt) -> anyhow::Result<()> {
+    let branch = resolve_branch_or_current(ctx, &review_opt.branch)?;
+    let (github, pull_request) = connect_pull_request(ctx, &branch)?;
+    let threads = github.get_review_threads(pull_request.number)?;
+    for thread in &threads {
+        if !(review_opt.unresolved && thread.is_resolved) {
+            println!("{}", format_review_thread(thread));
+        }
+    }
+    let unresolved = threads.iter().filter(|thread| !thread.is_resolved).count();
+    println!(
+        "{unresolved} of {} review threads unresolved on {}.",
+        threads.len(),
+        pull_request.html_url
+    );
+    Ok(())
+}
+
+/// How many lines of the diff are shown above each review thread, ending at the line it's on.
+const REVIEW_CONTEXT_LINES: usize = 4;
+
+/// Describes a review thread as the file and line it's on, the end of the diff there, and then each of its comments.
+fn format_review_thread(thread: &ReviewThread) -> String {
+    let mut text = thread.path.clone();
+    if let Some(line) = thread.line.or(thread.original_line) {
+        text.push_str(&format!(":{line}"));
+    }
+    let states: Vec<&str> = [
+        (thread.is_outdated, "outdated"),
+        (thread.is_resolved, "resolved"),
+    ]
+    .into_iter()
+    .filter_map(|(applies, state)| applies.then_some(state))
+    .collect();
+    if !states.is_empty() {
+        text.push_str(&format!(" ({})", states.join(", ")));
+    }
+    text.push('\n');
+
+    // Every comment in a thread is on the same diff, so the first one's is shown.
+    if let Some(comment) = thread.comments.first() {
+        let diff: Vec<&str> = comment
+            .diff_hunk
+            .lines()
+            .filter(|line| !line.starts_with("@@"))
+            .collect();
+        for line in &diff[diff.len().saturating_sub(REVIEW_CONTEXT_LINES)..] {
+            text.push_str(&format!("    {line}\n"));
+        }
+    }
+    for comment in &thread.comments {
+        let author = comment
+            .author
+            .as_ref()
+            .map_or("ghost", |author| author.login.as_str());
+        let mut lines = comment.body.lines();
+        text.push_str(&format!(
+            "  {author}: {}\n",
+            lines.next().unwrap_or_default()
+        ));
+        for line in lines {
+            text.push_str(&format!("    {line}\n"));
+        }
+    }
+    text
+}
+
+fn serve(ctx: &mut Context, serve_opt: &ServeOpt) -> anyhow::Result<()> {
+    let path = match &serve_opt.socket {
+        Some(path) => path.clone(),
+        None => git::get_common_dir(&ctx.repo_root)?.join("diamond.sock"),
+    };
+    if path.exists() {
+        if UnixStream::connect(&path).is_ok() {
+            anyhow::bail!(
+                "{RED}`dmd serve` is already listening on {}.{RESET}",
+                path.display()
+            );
+        }
+        // Left behind by a server which didn't get to clean up after itself.
+        std::fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    println!("Listening on {}.", path.display());
+    // Nothing is held open between requests, so that other `dmd` commands can run in the meantime.
+    ctx.tx.checkpoint()?;
+    // Clients can't answer questions asked on the server's terminal,
+    // so their requests are taken as agreed to, like with `--yes`.
+    ctx.yes = true;
+    let result = serve_clients(ctx, listener);
+    let _ = std::fs::remove_file(&path);
+    result
+}
+
+/// A request read from a client, with where to send its response and whether it shut the server down.
+type ClientRequest = (String, mpsc::Sender<(Option<serde_json::Value>, bool)>);
+
+/// Answers requests until a client asks the server to shut down, or Ctrl-C is pressed.
+/// Each client is read from on its own thread, so that one which stays connected doesn't keep others waiting,
+/// but the requests are answered one at a time, since they share the repo.
+fn serve_clients(ctx: &mut Context, listener: UnixListener) -> anyhow::Result<()> {
+    let (sender, requests) = mpsc::channel::<ClientRequest>();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            // A client going away only ends its own connection.
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let sender = sender.clone();
+            std::thread::spawn(move || serve_client(stream, sender));
+        }
+    });
+    loop {
+        // Waits in short slices, since Ctrl-C only sets a flag for the server to notice.
+        interrupt::check()?;
+        let (line, respond) = match requests.recv_timeout(Duration::from_millis(100)) {
+            Ok(request) => request,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        let (response, shutdown) = match rpc::parse_request(&line) {
+            Ok(request) => {
+                let result = rpc_call(ctx, &request);
+                let response = request.id.as_ref().map(|id| rpc::response(id, result));
+                (response, request.method == "shutdown")
+            }
+            Err(response) => (Some(response), false),
+        };
+        let _ = respond.send((response, shutdown));
+        if shutdown {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Passes each of a client's requests on to the server, and writes back the responses.
+fn serve_client(mut stream: UnixStream, sender: mpsc::Sender<ClientRequest>) -> anyhow::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (respond, response) = mpsc::channel();
+        sender.send((line, respond))?;
+        let (response, shutdown) = response.recv()?;
+        if let Some(response) = response {
+            writeln!(stream, "{response}")?;
+        }
+        if shutdown {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Runs the method `request` calls, keeping its changes to the database only if it succeeds,
+/// the same way a single invocation of `dmd` would.
+fn rpc_call(ctx: &mut Context, request: &rpc::Request) -> Result<serde_json::Value, rpc::Error> {
+    ctx.summary = Summary::default();
+    let result = match request.method.as_str() {
+        "log" => rpc_log(ctx),
+        "status" => rpc_status(ctx),
+        "checkout" => {
+            let params: rpc::CheckoutParams = request.params()?;
+            let checkout_opt = CheckoutOpt {
+                branch: Some(params.branch.clone()),
+                stack: None,
+                pr: None,
+            };
+            checkout(ctx, &checkout_opt).map(|()| serde_json::json!({ "branch": params.branch }))
+        }
+        "restack" => {
+            let params: rpc::RestackParams = request.params()?;
+            let restack_opt = RestackOpt {
+                branch: params.branch,
+                plan: false,
+            };
+            restack(ctx, &restack_opt).map(|()| ctx.summary.to_json())
+        }
+        "submit" => {
+            let params: rpc::SubmitParams = request.params()?;
+            let submit_opt = SubmitOpt {
+                no_verify: false,
+                push_options: vec![],
+                flush: false,
+                no_pr: params.no_pr,
+                no_reviewers: false,
+                closes: vec![],
+            };
+            submit(ctx, &submit_opt).map(|()| ctx.summary.to_json())
+        }
+        "shutdown" => Ok(serde_json::Value::Null),
+        method => return Err(rpc::Error::method_not_found(method)),
+    };
+    match &result {
+        Ok(_) => ctx.tx.checkpoint()?,
+        Err(err) if err.is::<SubmitQueuedError>() => ctx.tx.checkpoint()?,
+        Err(_) => ctx.tx.rollback_to_checkpoint()?,
+    }
+    Ok(result?)
+}
+
+/// Lists every tracked branch with its parent, like `dmd log` shows them.
+fn rpc_log(ctx: &mut Context) -> anyhow::Result<serde_json::Value> {
+    let notes = ctx.tx.get_notes()?;
+    let archived_branches = ctx.tx.get_archived_branches()?;
+    let branches: Vec<serde_json::Value> = ctx
+        .tx
+        .get_all_branches()?
+        .into_iter()
+        .map(|branch| {
+            serde_json::json!({
+                "note": notes.get(&branch.name),
+                "archived": archived_branches.contains(&branch.name),
+                "name": branch.name,
+                "parent": branch.parent,
+            })
+        })
+        .collect();
+    Ok(serde_json::json!({
+        "root": ctx.tx.get_root_branch()?,
+        "current": git::get_current_branch(&ctx.repo_root).ok(),
+        "branches": branches,
+    }))
+}
+
+/// Describes the current branch: its parent, its stack, and how far it is from its parent and its remote branch.
+fn rpc_status(ctx: &mut Context) -> anyhow::Result<serde_json::Value> {
+    let current_branch = git::get_current_branch(&ctx.repo_root)?;
+    let Some(parent) = ctx.tx.get_effective_parent(&current_branch)? else {
+        return Ok(serde_json::json!({ "branch": current_branch, "tracked": false }));
+    };
+    let stack: Vec<String> = ctx
+        .tx
+        .get_branches_in_stack(&current_branch)?
+        .into_iter()
+        .map(|branch| branch.name)
+        .collect();
+    let push_remote_name = match ctx.tx.get_remote()? {
+        Some(remote) => Some(push_remote(&ctx.tx, &remote)?),
+        None => None,
+    };
+    let branch = database::Branch {
+        name: current_branch.clone(),
+        parent: parent.clone(),
+    };
+    let counts = commit_counts(ctx, &branch, push_remote_name.as_deref());
+    Ok(serde_json::json!({
+        "branch": current_branch,
+        "tracked": true,
+        "parent": parent,
+        "stack": stack,
+        "ahead": counts.as_ref().map(|counts| counts.ahead),
+        "behind": counts.as_ref().map(|counts| counts.behind),
+        "to_push": counts.as_ref().and_then(|counts| counts.remote).map(|(to_push, _)| to_push),
+        "to_pull": counts.as_ref().and_then(|counts| counts.remote).map(|(_, to_pull)| to_pull),
+    }))
+}
+
+fn status(ctx: &mut Context) -> anyhow::Result<()> {
+    let root_branch = ctx.tx.get_root_branch()?;
+    if let Ok(current_branch) = git::get_current_branch(&ctx.repo_root) {
+        match ctx.tx.get_effective_parent(&current_branch)? {
+            Some(parent) => println!("On `{current_branch}`, on top of `{parent}`."),
+            None if root_branch.as_ref() == Some(&current_branch) => {
+                println!("On the root branch `{current_branch}`.")
+            }
+            None => println!("On `{current_branch}`, which isn't tracked."),
+        }
+    }
+    let Some(report) = ctx.tx.get_daemon_report()? else {
+        println!("`dmd daemon` hasn't checked the repo yet.");
+        return Ok(());
+    };
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    println!(
+        "`dmd daemon` checked the repo {}.",
+        format_age(now.saturating_sub(report.checked_at))
+    );
+    let mut to_sync = Vec::new();
+    if let (Some(root_branch), 1..) = (&root_branch, report.root_behind) {
+        to_sync.push(format!(
+            "`{root_branch}` is {} behind the remote.",
+            report.root_behind
+        ));
+    }
+    // Branches can be untracked or restacked since the check.
+    for branch in &report.merged {
+        if ctx.tx.get_parent(branch)?.is_some() {
+            to_sync.push(format!("`{branch}` looks merged."));
+        }
+    }
+    let mut to_restack = Vec::new();
+    for (branch, reason) in &report.outdated {
+        if ctx.tx.get_parent(branch)?.is_some() {
+            to_restack.push(format!("`{branch}` needs restacking: {reason}."));
+        }
+    }
+    for line in to_sync.iter().chain(&to_restack) {
+        println!("  {line}");
+    }
+    if !to_sync.is_empty() {
+        println!("Run `dmd sync` to catch up.");
+    } else if !to_restack.is_empty() {
+        println!("Run `dmd restack` to catch up.");
+    } else {
+        println!("Everything was up to date.");
+    }
+    Ok(())
+}
+
+fn squash_stack(ctx: &mut Context, squash_stack_opt: &SquashStackOpt) -> anyhow::Result<()> {
+    let current_branch = git::get_current_branch(&ctx.repo_root)?;
+    let branches = ctx.tx.get_branches_in_stack(&current_branch)?;
+    let (Some(bottom), Some(top)) = (branches.first(), branches.last()) else {
+        return Err(exit::error(
+            ExitCode::NotTracked,
+            format!("`{current_branch}` is not part of a stack."),
+        ));
+    };
+    if branches.len() == 1 {
+        println!("`{}` is the only branch in its stack.", bottom.name);
+        return Ok(());
+    }
+
+    for branch in &branches {
+        let children: Vec<&str> = branches
+            .iter()
+            .filter(|child| child.parent == branch.name)
+            .map(|child| child.name.as_str())
+            .collect();
+        if children.len() > 1 {
+            anyhow::bail!(
+                "{RED}Cannot squash the stack, because more than one branch is built on `{}`: {}.{RESET}",
+                branch.name,
+                children.join(", ")
+            );
+        }
+        if branch.name != bottom.name
+            && !git::is_ancestor_of(&ctx.repo_root, &branch.parent, &branch.name)?
+        {
+            anyhow::bail!(
+                "{RED}Cannot squash the stack, because `{}` is behind `{}`. Run `dmd restack` first.{RESET}",
+                branch.name,
+                branch.parent
+            );
+        }
+    }
+
+    let verb = if squash_stack_opt.dry_run {
+        "Would squash"
+    } else {
+        "Squashing"
+    };
+    println!(
+        "{verb} {} branches into `{}`, on top of `{}`:",
+        branches.len(),
+        bottom.name,
+        bottom.parent
+    );
+    for branch in &branches {
+        println!("  {}", branch.name);
+        let mut commits = git::missing_commits(&ctx.repo_root, &branch.parent, &branch.name)?;
+        commits.reverse();
+        for commit in commits {
+            println!("    {commit}");
+        }
+    }
+    if squash_stack_opt.dry_run {
+        return Ok(());
+    }
+
+    let others: Vec<String> = branches[1..]
+        .iter()
+        .map(|branch| branch.name.clone())
+        .collect();
+    ensure_unprotected(&ctx.tx, &bottom.name, "rewrite")?;
+    for branch in &others {
+        ensure_unprotected(&ctx.tx, branch, "remove")?;
+    }
+    ensure_submodules_clean(ctx, "squash the stack")?;
+    let action = if squash_stack_opt.delete_branches {
+        "Untrack and delete"
+    } else {
+        "Untrack"
+    };
+    if !prompt::confirm(ctx.yes, &format!("{action} {}?", others.join(", ")))? {
+        return Err(exit::error(ExitCode::Aborted, "Not squashing the stack."));
+    }
+
+    let bottom_name = bottom.name.clone();
+    let base_commit = own_commits_base(ctx, &bottom.name, &bottom.parent)?;
+    let commit = git::rev_parse(&ctx.repo_root, &top.name)?;
+    let mut snapshot = vec![bottom_name.clone()];
+    snapshot.extend(others.iter().cloned());
+    snapshot_branches(ctx, &snapshot)?;
+
+    git::reset_branch(
+        &ctx.repo_root,
+        &bottom_name,
+        &commit,
+        ctx.recurse_submodules,
+    )?;
+    if current_branch != bottom_name {
+        git::checkout(&ctx.repo_root, &bottom_name, ctx.recurse_submodules)?;
+    }
+    for branch in &others {
+        if squash_stack_opt.delete_branches {
+            git::delete_branch(&ctx.repo_root, branch)?;
+        }
+        ctx.tx.remove_branch(branch)?;
+    }
+    ctx.tx.set_base_commit(&bottom_name, &base_commit)?;
+    println!(
+        "Squashed the stack into `{bottom_name}`. The pull requests of {} are left open.",
+        others.join(", ")
+    );
+    Ok(())
+}
+
+fn stack(ctx: &mut Context, stack_opt: &StackOpt) -> anyhow::Result<()> {
+    match stack_opt {
+        StackOpt::Name(stack_name_opt) => {
+            let current_branch = git::get_current_branch(&ctx.repo_root)?;
+            let Some(base_branch) = ctx.tx.get_ancestors(&current_branch)?.into_iter().next()
+            else {
+                anyhow::bail!("`{current_branch}` is not part of a stack.");
+            };
+            ctx.tx
+                .set_stack_name(&base_branch.name, &stack_name_opt.name)?;
+            println!(
+                "Named the stack starting at `{}` `{}`.",
+                base_branch.name, stack_name_opt.name
+            );
+        }
+        StackOpt::List => {
+            let Some(root_branch) = ctx.tx.get_root_branch()? else {
+                return Err(not_initialized("root branch"));
+            };
+            for base_branch in ctx.tx.get_children(&root_branch)? {
+                let branches = ctx.tx.get_branches_in_stack(&base_branch)?;
+                let Some(top_branch) = branches.last() else {
+                    continue;
+                };
+                match ctx.tx.get_stack_name(&base_branch)? {
+                    Some(name) => println!("{name}"),
+                    None => println!("{}", top_branch.name),
+                }
+                for branch in &branches {
+                    println!("  {}", branch.name);
+                }
+            }
+        }
+        StackOpt::Target(stack_target_opt) => {
+            let current_branch = git::get_current_branch(&ctx.repo_root)?;
+            let Some(base_branch) = ctx.tx.get_ancestors(&current_branch)?.into_iter().next()
+            else {
+                anyhow::bail!("`{current_branch}` is not part of a stack.");
+            };
+            let Some(root_branch) = ctx.tx.get_root_branch()? else {
+                return Err(not_initialized("root branch"));
+            };
+            match &stack_target_opt.branch {
+                Some(target) => {
+                    if !git::branch_exists(&ctx.repo_root, target)? {
+                        anyhow::bail!(
+                            "{RED}Cannot target `{target}`, because there's no local branch by that name. \
+                            Check it out first.{RESET}"
+                        );
+                    }
+                    if ctx.tx.get_parent(target)?.is_some() || target == &root_branch {
+                        anyhow::bail!(
+                            "{RED}Cannot target `{target}`, because it's tracked. \
+                            Use `dmd move --onto {target}` to build on it instead.{RESET}"
+                        );
+                    }
+                    ctx.tx.set_target_branch(&base_branch.name, Some(target))?;
+                    println!(
+                        "The stack starting at `{}` now targets `{target}`. \
+                        Run `dmd restack` to move it onto `{target}`.",
+                        base_branch.name
+                    );
+                }
+                None if stack_target_opt.clear => {
+                    ctx.tx.set_target_branch(&base_branch.name, None)?;
+                    println!(
+                        "The stack starting at `{}` now targets `{root_branch}`.",
+                        base_branch.name
+                    );
+                }
+                None => println!(
+                    "{}",
+                    ctx.tx
+                        .get_target_branch(&base_branch.name)?
+                        .unwrap_or(root_branch)
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn stats(ctx: &mut Context) -> anyhow::Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    print!("{}", render_stats(ctx, now)?);
+    Ok(())
+}
+
+/// Describes each stack on the root branch as of `now`, in seconds since the Unix epoch,
+/// followed by the oldest branch in any of them.
+fn render_stats(ctx: &mut Context, now: u64) -> anyhow::Result<String> {
+    let Some(root_branch) = ctx.tx.get_root_branch()? else {
+        return Err(not_initialized("root branch"));
+    };
+
+    let timestamps = ctx.tx.get_timestamps()?;
+    let mut out = String::new();
+    let mut oldest_overall: Option<(String, u64)> = None;
+    for base_branch in ctx.tx.get_children(&root_branch)? {
+        let branches = ctx.tx.get_branches_in_stack(&base_branch)?;
+        if branches.is_empty() {
+            continue;
+        }
+
+        let mut lines = Vec::new();
+        let mut total_commits = 0;
+        let mut oldest: Option<(String, u64)> = None;
+        for branch in &branches {
+            let commits = git::count_commits(&ctx.repo_root, &branch.parent, &branch.name)?;
+            let (_, added, removed) = git::diff_size(&ctx.repo_root, &branch.parent, &branch.name)?;
+            total_commits += commits;
+            let mut line = format!("  {}: {commits} commits, +{added} -{removed}", branch.name);
+            let timestamps = timestamps.get(&branch.name);
+            let created_at = timestamps.and_then(|timestamps| timestamps.created_at);
+            if let Some(created_at) = created_at {
+                line.push_str(&format!(
+                    ", created {}",
+                    format_age(now.saturating_sub(created_at))
+                ));
+            }
+            if let Some(updated_at) = timestamps.and_then(|timestamps| timestamps.updated_at) {
+                line.push_str(&format!(
+                    ", touched {}",
+                    format_age(now.saturating_sub(updated_at))
+                ));
+            }
+            lines.push(line);
+
+            // A branch which was tracked after it was started is as old as its first commit.
+            let authored_at =
+                git::oldest_author_time(&ctx.repo_root, &branch.parent, &branch.name)?;
+            let started_at = match (created_at, authored_at) {
+                (Some(created_at), Some(authored_at)) => Some(created_at.min(authored_at)),
+                (started_at, None) | (None, started_at) => started_at,
+            };
+            if let Some(started_at) = started_at {
+                if oldest
+                    .as_ref()
+                    .is_none_or(|(_, oldest)| started_at < *oldest)
+                {
+                    oldest = Some((branch.name.clone(), started_at));
+                }
+            }
+        }
+
+        let name = ctx
+            .tx
+            .get_stack_name(&base_branch)?
+            .unwrap_or(base_branch.clone());
+        let behind = git::count_commits(&ctx.repo_root, &base_branch, &root_branch)?;
+        let age = match &oldest {
+            Some((branch, started_at)) => format!(
+                "oldest branch `{branch}` started {}",
+                format_age(now.saturating_sub(*started_at))
+            ),
+            None => "no commits".to_owned(),
+        };
+        out.push_str(&format!(
+            "{name}: {} branches, {total_commits} commits, {behind} behind `{root_branch}`, {age}\n",
+            branches.len()
+        ));
+        for line in lines {
+            out.push_str(&format!("{line}\n"));
+        }
+        if let Some((branch, started_at)) = oldest {
+            if oldest_overall
+                .as_ref()
+                .is_none_or(|(_, oldest)| started_at < *oldest)
+            {
+                oldest_overall = Some((branch, started_at));
+            }
+        }
+    }
+    if let Some((branch, started_at)) = oldest_overall {
+        out.push_str(&format!(
+            "Oldest branch: `{branch}`, started {}.\n",
+            format_age(now.saturating_sub(started_at))
+        ));
+    }
+    Ok(out)
+}
+
+/// Returns when the oldest commit on any of `branches` was authored.
+fn oldest_author_time(ctx: &Context, branches: &[database::Branch]) -> anyhow::Result<Option<u64>> {
+    let mut oldest: Option<u64> = None;
+    for branch in branches {
+        if let Some(time) = git::oldest_author_time(&ctx.repo_root, &branch.parent, &branch.name)? {
+            oldest = Some(oldest.map_or(time, |oldest| oldest.min(time)));
+        }
+    }
+    Ok(oldest)
+}
+
+/// Warns about the stacks whose oldest commit is older than `retention.warn-stack-age`,
+/// since they're likely to be forgotten or increasingly painful to restack.
+fn warn_stale_stacks(ctx: &mut Context, root_branch: &str) -> anyhow::Result<()> {
+    let Some(days) = config::get_days(&ctx.tx, config::RETENTION_WARN_STACK_AGE)? else {
+        return Ok(());
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    for base_branch in ctx.tx.get_children(root_branch)? {
+        let branches = ctx.tx.get_branches_in_stack(&base_branch)?;
+        let Some(time) = oldest_author_time(ctx, &branches)? else {
+            continue;
+        };
+        let age = now.saturating_sub(time);
+        if age <= days * 86400 {
+            continue;
+        }
+        let name = ctx
+            .tx
+            .get_stack_name(&base_branch)?
+            .unwrap_or(base_branch.clone());
+        println!(
+            "Warning: the stack `{name}` was started {}. Land or archive it soon.",
+            format_age(age)
+        );
+        ctx.summary.record(
+            &base_branch,
+            Outcome::Stale,
+            format!("oldest commit {}", format_age(age)),
+        );
+    }
+    Ok(())
+}
+
+/// Formats a number of seconds as a rough age, like `3 days ago`.
+fn format_age(seconds: u64) -> String {
+    let (count, unit) = match seconds {
+        0..=3599 => (seconds / 60, "minute"),
+        3600..=86399 => (seconds / 3600, "hour"),
+        _ => (seconds / 86400, "day"),
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    format!("{count} {unit}{plural} ago")
+}
+
+/// Returns the branches in `scope` of `branch`, in the same order as `get_branches_in_stack`.
+fn branches_in_scope(
+    ctx: &mut Context,
+    branch: &str,
+    scope: Scope,
+) -> anyhow::Result<Vec<database::Branch>> {
+    let branches_in_stack = ctx.tx.get_branches_in_stack(branch)?;
+    let in_scope: Vec<String> = match scope {
+        Scope::Stack => return Ok(branches_in_stack),
+        Scope::Upstack => std::iter::once(branch.to_owned())
+            .chain(ctx.tx.get_descendants(branch)?)
+            .collect(),
+        Scope::Downstack => ctx
+            .tx
+            .get_ancestors(branch)?
+            .into_iter()
+            .map(|ancestor| ancestor.name)
+            .collect(),
+    };
+    Ok(branches_in_stack
+        .into_iter()
+        .filter(|branch| in_scope.contains(&branch.name))
+        .collect())
+}
+
+fn scoped(ctx: &mut Context, scoped_opt: &ScopedOpt, scope: Scope) -> anyhow::Result<()> {
+    match scoped_opt {
+        ScopedOpt::Restack(restack_opt) => restack_scope(ctx, restack_opt, scope),
+        ScopedOpt::Submit(submit_opt) => submit_scope(ctx, submit_opt, scope),
+    }
+}
+
+/// Returns the bottom branch of the stack named `name`.
+fn find_stack(ctx: &Context, name: &str) -> anyhow::Result<String> {
+    let Some(base_branch) = ctx.tx.get_stack_base(name)? else {
+        anyhow::bail!("There is no stack named `{name}`. See `dmd stack list`.");
+    };
+    Ok(base_branch)
+}
+
+fn submit(ctx: &mut Context, submit_opt: &SubmitOpt) -> anyhow::Result<()> {
+    submit_scope(ctx, submit_opt, Scope::Stack)
+}
+
+fn submit_scope(ctx: &mut Context, submit_opt: &SubmitOpt, scope: Scope) -> anyhow::Result<()> {
+    let Some(remote_name) = ctx.tx.get_remote()? else {
+        return Err(not_initialized("remote"));
+    };
+    let remote = git::parse_remote(&ctx.repo_root, &remote_name)?;
+
+    let mut push_options = push_options(&ctx.tx)?;
+    push_options.no_verify |= submit_opt.no_verify;
+    push_options
+        .push_options
+        .extend(submit_opt.push_options.iter().cloned());
+
+    let manage_pull_requests =
+        !submit_opt.no_pr && config::get_bool(&ctx.tx, config::SUBMIT_PULL_REQUESTS)?;
+    let mut in_scope = None;
+    if !submit_opt.flush {
+        let current_branch = git::get_current_branch(&ctx.repo_root)?;
+        if !submit_opt.closes.is_empty() {
+            ctx.tx
+                .add_closed_issues(&current_branch, &submit_opt.closes)?;
+        }
+        in_scope = Some(queue_stack(
+            ctx,
+            &current_branch,
+            scope,
+            manage_pull_requests,
+        )?);
+        ctx.tx.checkpoint()?;
+    }
+
+    let mut queue = ctx.tx.get_queued_operations()?;
+    if let Some(in_scope) = &in_scope {
+        // Operations left queued for other branches, like another stack's, wait for a flush.
+        queue.retain(|operation| in_scope.contains(&operation.branch));
+    }
+    if submit_opt.no_pr {
+        // Pull request updates queued by earlier submits are left for a later flush.
+        queue.retain(|operation| operation.kind == OperationKind::Push);
+    }
+    if queue.is_empty() {
+        if submit_opt.flush {
+            println!("Nothing is queued.");
+        }
+        return Ok(());
+    }
+    let show_pr_links = !submit_opt.no_pr && !manage_pull_requests;
+    if let Err(err) = run_queued_operations(
+        ctx,
+        &remote_name,
+        remote,
+        &push_options,
+        queue,
+        show_pr_links,
+        !submit_opt.no_reviewers,
+    ) {
+        let remaining = ctx
+            .tx
+            .get_queued_operations()?
+            .iter()
+            .map(|operation| match operation.kind {
+                OperationKind::Push => format!("push `{}`", operation.branch),
+                OperationKind::PullRequest { .. } => {
+                    format!("update the pull request for `{}`", operation.branch)
+                }
+            })
+            .collect();
+        return Err(SubmitQueuedError { err, remaining }.into());
+    }
+    Ok(())
+}
+
+/// Replaces any operations queued for the branches in `scope` of `branch`
+/// with the pushes, and pull request updates if `pull_requests` is set, needed to submit them as they are now.
+/// Returns the branches in `scope`.
+fn queue_stack(
+    ctx: &mut Context,
+    branch: &str,
+    scope: Scope,
+    pull_requests: bool,
+) -> anyhow::Result<Vec<String>> {
+    // The whole stack is walked even when only part of it is submitted,
+    // so that pull request bases and positions are the same either way.
+    let branches_in_stack = ctx.tx.get_branches_in_stack(branch)?;
+    let in_scope: Vec<String> = branches_in_scope(ctx, branch, scope)?
+        .into_iter()
+        .map(|branch| branch.name)
+        .collect();
+    for branch in &in_scope {
+        ensure_unprotected(&ctx.tx, branch, "push")?;
+        ctx.tx.clear_queued_operations(branch)?;
+    }
+    // Empty branches are skipped, so PRs built on top of them target their nearest submitted ancestor.
+    // Since the skipped branch has no commits, this doesn't change the PR's diff.
+    let mut pr_bases: HashMap<String, String> = HashMap::new();
+    let mut submitted_branches = Vec::new();
+    for branch in branches_in_stack {
+        let pr_base = pr_bases
+            .get(&branch.parent)
+            .cloned()
+            .unwrap_or_else(|| branch.parent.clone());
+        if git::count_commits(&ctx.repo_root, &branch.parent, &branch.name)? == 0 {
+            if in_scope.contains(&branch.name) {
+                eprintln!(
+                    "{RED}Skipping `{}`, because it has no commits on top of `{}`.{RESET}",
+                    branch.name, branch.parent
+                );
+                ctx.summary.record(
+                    &branch.name,
+                    Outcome::Skipped,
+                    format!("no commits on top of `{}`", branch.parent),
+                );
+            }
+            pr_bases.insert(branch.name, pr_base);
+            continue;
+        }
+        if in_scope.contains(&branch.name) {
+            ctx.tx
+                .queue_operation(&branch.name, &pr_base, &OperationKind::Push)?;
+        }
+        submitted_branches.push((branch.name, pr_base));
+    }
+
+    if pull_requests {
+        let Some(remote_name) = ctx.tx.get_remote()? else {
+            return Err(not_initialized("remote"));
+        };
+        let push_remote_name = push_remote(&ctx.tx, &remote_name)?;
+        let title_prefix = config::get_bool(&ctx.tx, config::SUBMIT_TITLE_PREFIX)?;
+        let stack_size = submitted_branches.len();
+        for (i, (branch, pr_base)) in submitted_branches.iter().enumerate() {
+            if !in_scope.contains(branch) {
+                continue;
+            }
+            // A pull request from a fork can only merge into a branch of the repo it's opened against,
+            // and the branches it's stacked on are only pushed to the fork.
+            if push_remote_name != remote_name && ctx.tx.get_parent(pr_base)?.is_some() {
+                anyhow::bail!(
+                    "{RED}Cannot open a pull request for `{branch}` onto `{pr_base}`, because `{pr_base}` is only pushed to `{push_remote_name}`, \
+                    and pull requests from a fork can only merge into branches of `{remote_name}`. \
+                    Land `{pr_base}` first, or submit with `--no-pr` to only push.{RESET}"
+                );
+            }
+            let position = title_prefix.then_some((i + 1, stack_size));
+            ctx.tx
+                .queue_operation(branch, pr_base, &OperationKind::PullRequest { position })?;
+        }
+    }
+    Ok(in_scope)
+}
+
+/// Runs `queue` in order, removing each operation from the submit queue once it succeeds.
+/// When `show_pr_links` is set, each push prints a link to open a pull request for the branch.
+fn run_queued_operations(
+    ctx: &mut Context,
+    remote_name: &str,
+    remote: git::Remote,
+    push_options: &git::PushOptions,
+    queue: Vec<QueuedOperation>,
+    show_pr_links: bool,
+    request_reviewers: bool,
+) -> anyhow::Result<()> {
+    let push_remote_name = push_remote(&ctx.tx, remote_name)?;
+    git::fetch(&ctx.repo_root, &push_remote_name, ctx.git_timeout)?;
+    // Pull requests from a fork name their branch along with the fork's owner.
+    let head_owner = (show_pr_links && push_remote_name != remote_name)
+        .then(|| git::parse_remote(&ctx.repo_root, &push_remote_name))
+        .transpose()?
+        .map(|push_remote| push_remote.organization);
+    let github = if queue
+        .iter()
+        .any(|operation| matches!(operation.kind, OperationKind::PullRequest { .. }))
+    {
+        Some(connect_github(ctx, remote_name)?)
+    } else {
+        None
+    };
+
+    // The commits each branch was at before it was pushed during this run, to describe what changed.
+    let mut previous_commits: HashMap<String, String> = HashMap::new();
+    for operation in queue {
+        let result = match (&operation.kind, &github) {
+            (OperationKind::Push, _) => {
+                if let Some(pushed_commit) = ctx.tx.get_pushed_commit(&operation.branch)? {
+                    previous_commits.insert(operation.branch.clone(), pushed_commit);
+                }
+                let result = push_branch(ctx, &push_remote_name, &operation.branch, push_options);
+                if result.is_ok() && show_pr_links {
+                    let head = remote_branch_name(&ctx.tx, &operation.branch)?;
+                    let head = match &head_owner {
+                        Some(owner) => format!("{owner}:{head}"),
+                        None => head,
+                    };
+                    println!(
+                        "[{}] -> {}",
+                        operation.branch,
+                        remote.new_pr_url(&remote_branch_name(&ctx.tx, &operation.base)?, &head),
+                    );
+                }
+                result
+            }
+            (OperationKind::PullRequest { position }, Some(github)) => update_pull_request(
+                ctx,
+                github,
+                &operation.branch,
+                &operation.base,
+                *position,
+                previous_commits.get(&operation.branch).map(String::as_str),
+                request_reviewers,
+            ),
+            (OperationKind::PullRequest { .. }, None) => Err(anyhow::anyhow!(
+                "Cannot update the pull request for `{}` without connecting to GitHub.",
+                operation.branch
+            )),
+        };
+        if let Err(err) = result {
+            ctx.summary
+                .record(&operation.branch, Outcome::Failed, failure_detail(&err));
+            return Err(err);
+        }
+        // The queue doubles as a journal, so a rerun after a crash only redoes what's left.
+        ctx.tx.remove_queued_operation(operation.id)?;
+        ctx.tx.checkpoint()?;
+    }
+    Ok(())
+}
+
+/// Creates or updates the pull request for `branch`, so that it merges into `base`.
+/// `previous_commit` is where `branch` was before it was just pushed, if it was,
+/// which is used to describe the update in a comment when the `submit.comment` config is set.
+fn update_pull_request(
+    ctx: &mut Context,
+    github: &GitHub,
+    branch: &str,
+    base: &str,
+    position: Option<(usize, usize)>,
+    previous_commit: Option<&str>,
+    request_reviewers: bool,
+) -> anyhow::Result<()> {
+    let remote_base = remote_branch_name(&ctx.tx, base)?;
+    let (outcome, pull_request) = match find_pull_request(&ctx.tx, github, branch)? {
+        Some(pull_request) => {
+            if pull_request.base.branch != remote_base {
+                github.set_pull_request_base(pull_request.number, &remote_base)?;
+            }
+            retitle_pull_request(github, &pull_request, position)?;
+            // Only the stack section is rewritten, to keep any edits made to the rest of the body.
+            let old_body = pull_request.body.clone().unwrap_or_default();
+            let new_body = github::body_with_closed_issues(
+                &github::body_with_stack_section(&old_body, &stack_section(ctx, branch)?),
+                &closed_issues(&ctx.tx, branch)?,
+            );
+            if new_body != old_body {
+                github.set_pull_request_body(pull_request.number, &new_body)?;
+            }
+            if let Some(previous_commit) = previous_commit {
+                if config::get_bool(&ctx.tx, config::SUBMIT_COMMENT)?
+                    && previous_commit != pull_request.head.sha
+                {
+                    let comment =
+                        describe_update(ctx, previous_commit, &pull_request.head.sha, base)?;
+                    github.create_comment(pull_request.number, &comment)?;
+                }
+            }
+            (Outcome::Updated, pull_request)
+        }
+        None => {
+            let (mut subject, body) = git::first_commit_message(&ctx.repo_root, base, branch)?
+                .unwrap_or_else(|| (branch.to_owned(), String::new()));
+            let projects = config::get_list(&ctx.tx, config::SUBMIT_TICKET_PROJECTS)?;
+            let ticket = ticket::find_in_branch(branch, &projects)
+                .or_else(|| ticket::find_in_message(&format!("{subject}\n\n{body}"), &projects));
+            let mut description = pull_request_description(ctx, branch, base)?.unwrap_or(body);
+            if let Some(ticket) = &ticket {
+                if let Some(pattern) = ctx.tx.get_config(config::SUBMIT_TICKET_TITLE)? {
+                    subject = ticket::title_with_key(&pattern, ticket, &subject);
+                }
+                if let Some(url) = ctx.tx.get_config(config::SUBMIT_TICKET_URL)? {
+                    description = ticket::body_with_link(&description, &url, ticket);
+                }
+            }
+            let pull_request = github.create_pull_request(
+                &remote_branch_name(&ctx.tx, branch)?,
+                &remote_base,
+                &github::title_with_position(&subject, position),
+                &github::body_with_closed_issues(
+                    &github::body_with_stack_section(&description, &stack_section(ctx, branch)?),
+                    &closed_issues(&ctx.tx, branch)?,
+                ),
+            )?;
+            (Outcome::Created, pull_request)
+        }
+    };
+    println!("[{branch}] -> {}", pull_request.html_url);
+    ctx.tx.set_pull_request(branch, pull_request.number)?;
+    ctx.summary
+        .record(branch, outcome, pull_request.html_url.clone());
+    if let Err(err) = add_labels(ctx, github, &pull_request, branch, base) {
+        eprintln!("Failed to label the pull request for `{branch}`: {err}");
+    }
+    if request_reviewers {
+        // Reviews are a nicety, so failing to request them shouldn't hold up the rest of the submit.
+        if let Err(err) = request_code_owners(ctx, github, &pull_request, branch, base) {
+            eprintln!("Failed to request reviews on `{branch}`: {err}");
+        }
+    }
+    Ok(())
+}
+
+/// Adds the labels from the `submit.labels` rules which match `branch` to its pull request.
+/// Labels are only ever added, so that ones added by hand are left alone.
+fn add_labels(
+    ctx: &Context,
+    github: &GitHub,
+    pull_request: &github::PullRequest,
+    branch: &str,
+    base: &str,
+) -> anyhow::Result<()> {
+    let rules = config::get_label_rules(&ctx.tx)?;
+    if rules.is_empty() {
+        return Ok(());
+    }
+    let paths = git::changed_paths(&ctx.repo_root, base, branch)?;
+    let mut labels: Vec<String> = Vec::new();
+    for label in rules
+        .iter()
+        .filter_map(|rule| rule.label_for(branch, &paths))
+    {
+        let already_labelled = pull_request
+            .labels
+            .iter()
+            .any(|existing| existing.name == label);
+        if !already_labelled && !labels.iter().any(|added| added == label) {
+            labels.push(label.to_owned());
+        }
+    }
+    if labels.is_empty() {
+        return Ok(());
+    }
+    github.add_labels(pull_request.number, &labels)?;
+    println!("Labelled `{branch}` with {}.", labels.join(", "));
+    Ok(())
+}
+
+/// Requests reviews on `pull_request` from the owners of the paths `branch` changes,
+/// according to the CODEOWNERS file on `base`, like GitHub itself does.
+/// The author, and anyone who has already been asked or has already reviewed it, are left out.
+fn request_code_owners(
+    ctx: &Context,
+    github: &GitHub,
+    pull_request: &github::PullRequest,
+    branch: &str,
+    base: &str,
+) -> anyhow::Result<()> {
+    let mut contents = None;
+    for path in codeowners::LOCATIONS {
+        contents = git::show_file(&ctx.repo_root, base, path)?;
+        if contents.is_some() {
+            break;
+        }
+    }
+    let Some(contents) = contents else {
+        return Ok(());
+    };
+    let paths = git::changed_paths(&ctx.repo_root, base, branch)?;
+    let mut reviewers = codeowners::reviewers(&codeowners::parse(&contents), &paths);
+
+    let author = pull_request.user.as_ref().map(|user| user.login.clone());
+    let mut skipped: Vec<String> = pull_request
+        .requested_reviewers
+        .iter()
+        .map(|user| user.login.clone())
+        .chain(author)
+        .collect();
+    if !reviewers.users.is_empty() {
+        skipped.extend(github.get_reviewers(pull_request.number)?);
+    }
+    reviewers.users.retain(|user| !skipped.contains(user));
+    reviewers.teams.retain(|team| {
+        !pull_request
+            .requested_teams
+            .iter()
+            .any(|requested| requested.slug == *team)
+    });
+    if reviewers.users.is_empty() && reviewers.teams.is_empty() {
+        return Ok(());
+    }
+
+    github.request_reviewers(pull_request.number, &reviewers.users, &reviewers.teams)?;
+    let names: Vec<String> = reviewers
+        .users
+        .iter()
+        .chain(&reviewers.teams)
+        .map(|name| format!("@{name}"))
+        .collect();
+    println!("Requested reviews on `{branch}` from {}.", names.join(", "));
+    Ok(())
+}
+
+/// Reads the description for `branch`'s pull request from the `.diamond/<branch>.md` file on the branch,
+/// or else from the newest `PR-Body:` trailer on its commits.
+fn pull_request_description(
+    ctx: &Context,
+    branch: &str,
+    base: &str,
+) -> anyhow::Result<Option<String>> {
+    if let Some(description) =
+        git::show_file(&ctx.repo_root, branch, &format!(".diamond/{branch}.md"))?
+    {
+        return Ok(Some(description));
+    }
+    git::latest_trailer(&ctx.repo_root, base, branch, "PR-Body")
+}
+
+/// Lists the branches in `branch`'s stack for its pull request's body, pointing out `branch`.
+fn stack_section(ctx: &mut Context, branch: &str) -> anyhow::Result<String> {
+    let mut lines = vec!["**Stack**".to_owned(), String::new()];
+    for (i, stack_branch) in ctx.tx.get_branches_in_stack(branch)?.iter().enumerate() {
+        let marker = if stack_branch.name == branch {
+            " ← this pull request"
+        } else {
+            ""
+        };
+        lines.push(format!("{}. `{}`{marker}", i + 1, stack_branch.name));
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Describes how a branch changed when it was pushed again, moving from `old` to `new`.
+fn describe_update(ctx: &Context, old: &str, new: &str, base: &str) -> anyhow::Result<String> {
+    let short = |commit: &str| commit.chars().take(7).collect::<String>();
+    let new_commits = git::new_commits(&ctx.repo_root, old, new, base)?;
+    let mut comment = format!("Updated from {} to {}", short(old), short(new));
+    if new_commits.is_empty() {
+        comment.push_str(&format!(
+            ", rebasing onto the latest `{base}` with no other changes."
+        ));
+    } else {
+        comment.push_str(", adding:\n");
+        for commit in new_commits {
+            comment.push_str(&format!("\n- {commit}"));
+        }
+    }
+    Ok(comment)
+}
+
+/// Renumbers the titles of the open pull requests in the stack containing `branch`,
+/// after branches have been added to or removed from it.
+fn renumber_pull_requests(ctx: &mut Context, github: &GitHub, branch: &str) -> anyhow::Result<()> {
+    let mut pull_requests = Vec::new();
+    for branch in ctx.tx.get_branches_in_stack(branch)? {
+        if let Some(pull_request) = find_pull_request(&ctx.tx, github, &branch.name)? {
+            pull_requests.push(pull_request);
+        }
+    }
+    for (i, pull_request) in pull_requests.iter().enumerate() {
+        retitle_pull_request(github, pull_request, Some((i + 1, pull_requests.len())))?;
+    }
+    Ok(())
+}
+
+fn retitle_pull_request(
+    github: &GitHub,
+    pull_request: &PullRequest,
+    position: Option<(usize, usize)>,
+) -> anyhow::Result<()> {
+    let title = github::title_with_position(&pull_request.title, position);
+    if title != pull_request.title {
+        github.set_pull_request_title(pull_request.number, &title)?;
+    }
+    Ok(())
+}
+
+fn swap(ctx: &mut Context) -> anyhow::Result<()> {
+    ensure_submodules_clean(ctx, "swap")?;
+    let branch = git::get_current_branch(&ctx.repo_root)?;
+    let Some(parent) = ctx.tx.get_parent(&branch)? else {
+        return Err(exit::error(
+            ExitCode::NotTracked,
+            format!("Cannot swap `{branch}`, because it is not tracked or is the root branch."),
+        ));
+    };
+    let Some(grandparent) = ctx.tx.get_parent(&parent)? else {
+        return Err(exit::error(
+            ExitCode::NotTracked,
+            format!(
+                "Cannot swap `{branch}` with `{parent}`, because `{parent}` is the root branch."
+            ),
+        ));
+    };
+    // The bottom branch of a stack with a target branch is built on the target instead.
+    let target = ctx.tx.get_target_branch(&parent)?;
+    let new_base = target.clone().unwrap_or_else(|| grandparent.clone());
+    ensure_unprotected(&ctx.tx, &branch, "rebase")?;
+    ensure_unprotected(&ctx.tx, &parent, "rebase")?;
+    ensure_history(
+        ctx,
+        &[
+            database::Branch {
+                name: branch.clone(),
+                parent: parent.clone(),
+            },
+            database::Branch {
+                name: parent.clone(),
+                parent: new_base.clone(),
+            },
+        ],
+    )?;
+
+    // The parent's other children stay on it, so they'd end up on top of `branch` too.
+    let siblings: Vec<String> = ctx
+        .tx
+        .get_children(&parent)?
+        .into_iter()
+        .filter(|child| child != &branch)
+        .collect();
+    if !siblings.is_empty() {
+        let names: Vec<String> = siblings
+            .iter()
+            .map(|sibling| format!("`{sibling}`"))
+            .collect();
+        println!(
+            "{} {} also built on `{parent}`, so swapping would put them on top of `{branch}` too.",
+            names.join(", "),
+            if names.len() == 1 { "is" } else { "are" },
+        );
+        if !prompt::confirm(ctx.yes, "Swap anyway?")? {
+            return Err(exit::error(
+                ExitCode::Aborted,
+                format!("{RED}Not swapping `{branch}` with `{parent}`.{RESET}"),
+            ));
+        }
+    }
+
+    let branch_base = own_commits_base(ctx, &branch, &parent)?;
+    let parent_base = own_commits_base(ctx, &parent, &new_base)?;
+    let children = ctx.tx.get_children(&branch)?;
+    let mut child_bases = Vec::new();
+    for child in &children {
+        child_bases.push(own_commits_base(ctx, child, &branch)?);
+    }
+    snapshot_branches(
+        ctx,
+        &std::iter::once(parent.clone())
+            .chain(ctx.tx.get_descendants(&parent)?)
+            .collect::<Vec<_>>(),
+    )?;
+
+    let guard = git::BranchGuard::new(
+        ctx.repo_root.clone(),
+        branch.clone(),
+        ctx.recurse_submodules,
+    )?;
+    // Record the new order before rewriting anything, so that if a rebase stops on conflicts,
+    // `dmd restack` picks up where this left off.
+    ctx.tx.set_parent(&branch, &grandparent)?;
+    ctx.tx.set_parent(&parent, &branch)?;
+    ctx.tx.set_base_commit(&parent, &parent_base)?;
+    for (child, child_base) in children.iter().zip(&child_bases) {
+        ctx.tx.set_parent(child, &parent)?;
+        ctx.tx.set_base_commit(child, child_base)?;
+    }
+    if let Some(stack_name) = ctx.tx.get_stack_name(&parent)? {
+        ctx.tx.move_stack_name(&stack_name, &branch)?;
+    }
+    if let Some(target) = &target {
+        ctx.tx.set_target_branch(&branch, Some(target))?;
+        ctx.tx.set_target_branch(&parent, None)?;
+    }
+    ctx.tx.checkpoint()?;
+
+    println!("Swapping `{branch}` with `{parent}`...");
+    let options = rebase_options(&ctx.tx)?;
+    for (rebased, onto, old_base) in [
+        (&branch, &new_base, &branch_base),
+        (&parent, &branch, &parent_base),
+    ] {
+        if let Err(err) = git::rebase_onto(&ctx.repo_root, onto, old_base, rebased, &options) {
+            // Leave the user in the middle of the rebase, instead of switching branches out from under it.
+            guard.dismiss();
+            return Err(exit::error(
+                ExitCode::Conflict,
+                format!(
+                    "{err:#}\nOnce you've finished the rebase, run `dmd restack` to finish the swap."
+                ),
+            ));
+        }
+        ctx.tx
+            .set_base_commit(rebased, &git::rev_parse(&ctx.repo_root, onto)?)?;
+        ctx.tx.checkpoint()?;
+    }
+    restack_descendants(ctx, &parent, guard)
+}
+
+fn sync(ctx: &mut Context, sync_opt: &SyncOpt) -> anyhow::Result<()> {
+    ensure_submodules_clean(ctx, "sync")?;
+    let current_branch = git::get_current_branch(&ctx.repo_root)?;
+    let stack_branch = match &sync_opt.stack {
+        Some(stack_name) => find_stack(ctx, stack_name)?,
+        None => current_branch.clone(),
+    };
+    let stack_branch_names: Vec<String> = ctx
+        .tx
+        .get_branches_in_stack(&stack_branch)?
+        .into_iter()
+        .map(|branch| branch.name)
+        .collect();
+    let guard = restack_guard(ctx, &current_branch, &stack_branch_names)?;
+
+    let Some(remote) = ctx.tx.get_remote()? else {
+        return Err(exit::error(
+            ExitCode::NotInitialized,
+            format!("{RED}Cannot find origin. Is the repo initialized?{RESET}"),
+        ));
+    };
+    let Some(root_branch) = ctx.tx.get_root_branch()? else {
+        return Err(not_initialized("root branch"));
+    };
+    if config::get_bool(&ctx.tx, config::SYNC_CLEAN)? {
+        clean(ctx, &CleanOpt { dry_run: false })?;
+    }
+    let root_commit = git::rev_parse(&ctx.repo_root, &root_branch)?;
+    pull_branch(ctx, &remote, &root_branch, &root_branch)?;
+    if git::rev_parse(&ctx.repo_root, &root_branch)? != root_commit {
+        ctx.summary
+            .record(&root_branch, Outcome::Pulled, format!("from `{remote}`"));
+    }
+    // Pruning drops the remote-tracking refs of deleted branches, e.g. ones deleted after being merged,
+    // so that they aren't pulled below or mistaken for diverged copies when pushing.
+    let push_remote_name = push_remote(&ctx.tx, &remote)?;
+    let mut pruned = git::fetch_prune(&ctx.repo_root, &remote, ctx.git_timeout)?;
+    if push_remote_name != remote {
+        pruned.extend(git::fetch_prune(
+            &ctx.repo_root,
+            &push_remote_name,
+            ctx.git_timeout,
+        )?);
+    }
+    for remote_branch in pruned {
+        println!("Pruned `{remote_branch}`, which was deleted from the remote.");
+    }
+
+    let branches_in_stack = ctx.tx.get_branches_in_stack(&stack_branch)?;
+    let mut branches_in_stack = skip_archived(ctx, branches_in_stack)?;
+    // A stack with a target branch is restacked onto it, so it needs to be up to date too.
+    let target = match branches_in_stack.first() {
+        Some(bottom) => ctx.tx.get_target_branch(&bottom.name)?,
+        None => None,
+    };
+    if let Some(target) = target {
+        let target_commit = git::rev_parse(&ctx.repo_root, &target)?;
+        pull_branch(ctx, &remote, &target, &target)?;
+        if git::rev_parse(&ctx.repo_root, &target)? != target_commit {
+            ctx.summary
+                .record(&target, Outcome::Pulled, format!("from `{remote}`"));
+        }
+    }
+    // Without a GitHub token there are no pull requests to check, so merged branches are left to `dmd land`.
+    if let Ok(github) = connect_github(ctx, &remote) {
+        // Merged branches which were archived are kept until they're old enough to stop tracking.
+        if config::get_days(&ctx.tx, config::RETENTION_UNTRACK_MERGED_AFTER)?.is_some() {
+            let mut archived_branches = Vec::new();
+            for name in ctx.tx.get_archived_branches()? {
+                if let Some(parent) = ctx.tx.get_parent(&name)? {
+                    if git::branch_exists(&ctx.repo_root, &name)? {
+                        archived_branches.push(database::Branch { name, parent });
+                    }
+                }
+            }
+            untrack_merged_branches(ctx, &github, &archived_branches)?;
+        }
+        let untracked = untrack_merged_branches(ctx, &github, &branches_in_stack)?;
+        if !untracked.is_empty() {
+            branches_in_stack = branches_in_stack
+                .into_iter()
+                .filter(|branch| !untracked.contains(&branch.name))
+                .map(|branch| {
+                    Ok(database::Branch {
+                        parent: ctx.tx.get_parent(&branch.name)?.unwrap_or(branch.parent),
+                        name: branch.name,
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?;
+        }
+    }
+    for branch in &branches_in_stack {
+        ensure_unprotected(&ctx.tx, &branch.name, "restack")?;
+    }
+    ensure_history(ctx, &branches_in_stack)?;
+    let branch_names: Vec<String> = branches_in_stack
+        .iter()
+        .map(|branch| branch.name.clone())
+        .collect();
+    snapshot_branches(ctx, &branch_names)?;
+
+    // Branches which an earlier sync of this stack got through before it stopped, and which haven't moved since.
+    // Each stack has its own journal, named after the branch at its bottom, so syncing one doesn't forget another's.
+    let journal = format!(
+        "sync {}",
+        stack_branch_names.first().unwrap_or(&stack_branch)
+    );
+    let progress = ctx.tx.get_progress(&journal)?;
+    for branch in branches_in_stack {
+        let synced_commit = progress.get(&branch.name);
+        if synced_commit.is_some()
+            && git::rev_parse(&ctx.repo_root, &branch.name).ok().as_ref() == synced_commit
+            && git::is_ancestor_of(&ctx.repo_root, &branch.parent, &branch.name)?
+        {
+            println!(
+                "Skipping `{}`, which the last sync already restacked.",
+                branch.name
+            );
+            ctx.summary
+                .record(&branch.name, Outcome::Skipped, "restacked by the last sync");
+            continue;
+        }
+        println!("Restacking `{}` onto `{}`...", branch.name, branch.parent);
+        // Branches which haven't been submitted yet have nothing to pull.
+        let remote_branch = format!(
+            "refs/remotes/{push_remote_name}/{}",
+            remote_branch_name(&ctx.tx, &branch.name)?
+        );
+        if let Ok(remote_commit) = git::rev_parse(&ctx.repo_root, &remote_branch) {
+            pull_tracked_branch(ctx, &push_remote_name, &branch.name, &remote_commit)?;
+        }
+        if let Err(err) = restack_onto_parent(ctx, &branch.name, &branch.parent) {
+            ctx.summary
+                .record(&branch.name, Outcome::Failed, failure_detail(&err));
+            // The guard puts the user back where they started.
+            if err.is::<interrupt::Interrupted>() {
+                return Err(exit::error(
+                    ExitCode::Interrupted,
+                    format!("{err:#} Run `dmd sync` again to pick up where it left off."),
+                ));
+            }
+            // Leave the user in the middle of the rebase, instead of switching branches out from under it.
+            guard.dismiss();
+            return Err(exit::error(ExitCode::Conflict, format!("{err:#}\nOnce you've finished the rebase, run `dmd sync` again to pick up where it left off."
+            )));
+        }
+        // Only recorded once the branch has been both pulled and restacked.
+        ctx.tx.record_progress(
+            &journal,
+            &branch.name,
+            &git::rev_parse(&ctx.repo_root, &branch.name)?,
+        )?;
+        ctx.tx.checkpoint()?;
+    }
+
+    ctx.tx.clear_progress(&journal)?;
+    warn_stale_stacks(ctx, &root_branch)
+}
+
+/// Brings the commits on `remote_commit`, where `branch` is on the remote, into the local branch.
+///
+/// If someone else force-pushed the branch, e.g. on a shared stack, it can't be fast-forwarded,
+/// so this shows how the two have diverged and offers to reset the local branch to the remote one.
+fn pull_tracked_branch(
+    ctx: &mut Context,
+    remote_name: &str,
+    branch: &str,
+    remote_commit: &str,
+) -> anyhow::Result<()> {
+    let local_commit = git::rev_parse(&ctx.repo_root, branch)?;
+    if git::is_ancestor_of(&ctx.repo_root, remote_commit, branch)? {
+        return Ok(());
+    }
+    let remote_branch_name = remote_branch_name(&ctx.tx, branch)?;
+    if git::is_ancestor_of(&ctx.repo_root, branch, remote_commit)? {
+        pull_branch(ctx, remote_name, &remote_branch_name, branch)?;
+        ctx.summary
+            .record(branch, Outcome::Pulled, format!("from `{remote_name}`"));
+        return Ok(());
+    }
+    // The branch was rewritten locally, e.g. restacked, since it was last pushed, so there's nothing new to pull.
+    if ctx.tx.get_pushed_commit(branch)?.as_deref() == Some(remote_commit) {
+        return Ok(());
+    }
+
+    let remote_branch = format!("{remote_name}/{remote_branch_name}");
+    let remote_only = git::missing_commits(&ctx.repo_root, branch, remote_commit)?;
+    let local_only = git::missing_commits(&ctx.repo_root, remote_commit, branch)?;
+    println!("`{remote_branch}` was force-pushed, and has diverged from `{branch}`.");
+    println!("Only on `{remote_branch}`:");
+    for commit in &remote_only {
+        println!("  {commit}");
+    }
+    if remote_only.is_empty() {
+        println!("  (no new changes)");
+    }
+    // Without local changes, resetting can't lose anything, so there's no need to ask.
+    if !local_only.is_empty() {
+        println!("Only on `{branch}`, and lost by resetting it:");
+        for commit in &local_only {
+            println!("  {commit}");
+        }
+        if !prompt::confirm(
+            ctx.yes,
+            &format!("Reset `{branch}` to `{remote_branch}`, and restack the branches above it?"),
+        )? {
+            return Err(exit::error(
+                ExitCode::DivergedRemote,
+                format!(
+                    "{RED}`{branch}` has diverged from `{remote_branch}`. \
+                    Bring its commits into `{branch}`, or reset it with `git reset --hard {remote_branch}`, \
+                    and then run `dmd sync` again.{RESET}"
+                ),
+            ));
+        }
+    }
+    git::reset_branch(
+        &ctx.repo_root,
+        branch,
+        remote_commit,
+        ctx.recurse_submodules,
+    )?;
+    ctx.tx.set_pushed_commit(branch, remote_commit)?;
+    println!(
+        "Reset `{branch}` from {} to `{remote_branch}`.",
+        &local_commit[..7]
+    );
+    ctx.summary.record(
+        branch,
+        Outcome::Pulled,
+        format!("reset to force-pushed `{remote_branch}`"),
+    );
+    Ok(())
+}
+
+fn track(ctx: &mut Context, track_opt: &TrackOpt) -> anyhow::Result<()> {
+    let current_branch = git::get_current_branch(&ctx.repo_root)?;
+
+    let Some(root_branch) = ctx.tx.get_root_branch()? else {
+        return Err(not_initialized("root branch"));
+    };
+
+    if track_opt.with_ancestors {
+        return track_with_ancestors(ctx, &current_branch);
+    }
+    let parent = match &track_opt.parent {
+        Some(parent) => resolve_branch(ctx, parent)?,
+        None => root_branch,
+    };
+    if !git::is_ancestor_of(&ctx.repo_root, &parent, &current_branch)? {
+        anyhow::bail!("Cannot track {current_branch} as branching off of {parent}, because {parent} is not its ancestor.");
+    }
+    track_branch(ctx, &current_branch, &parent)
+}
+
+/// Resolves a branch named on the command line. Tracked and local branches are taken as they are.
+/// With `branch.match-prefix` set, the start of exactly one tracked branch's name stands for it.
+/// Anything else fails, suggesting the tracked branches it's closest to.
+fn resolve_branch(ctx: &Context, name: &str) -> anyhow::Result<String> {
+    let mut branches: Vec<String> = ctx
+        .tx
+        .get_all_branches()?
+        .into_iter()
+        .map(|branch| branch.name)
+        .collect();
+    branches.extend(ctx.tx.get_root_branch()?);
+    if branches.iter().any(|branch| branch == name) || git::branch_exists(&ctx.repo_root, name)? {
+        return Ok(name.to_owned());
+    }
+    if config::get_bool(&ctx.tx, config::BRANCH_MATCH_PREFIX)? {
+        match fuzzy::prefix_matches(name, &branches).as_slice() {
+            [] => {}
+            [branch] => {
+                eprintln!("Taking `{name}` to mean `{branch}`.");
+                return Ok((*branch).to_owned());
+            }
+            matches => {
+                return Err(exit::error(
+                    ExitCode::NotTracked,
+                    format!(
+                        "`{name}` could be any of {}.",
+                        matches
+                            .iter()
+                            .map(|branch| format!("`{branch}`"))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                ))
+            }
+        }
+    }
+    let message = match fuzzy::suggestions(name, &branches).as_slice() {
+        [] => format!("There is no branch named `{name}`."),
+        [suggestion] => format!("There is no branch named `{name}`. Did you mean `{suggestion}`?"),
+        suggestions => format!(
+            "There is no branch named `{name}`. Did you mean one of {}?",
+            suggestions
+                .iter()
+                .map(|branch| format!("`{branch}`"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    Err(exit::error(ExitCode::NotTracked, message))
+}
+
+/// Resolves an optional branch named on the command line, defaulting to the current branch.
+fn resolve_branch_or_current(ctx: &Context, name: &Option<String>) -> anyhow::Result<String> {
+    match name {
+        Some(name) => resolve_branch(ctx, name),
+        None => git::get_current_branch(&ctx.repo_root),
+    }
+}
+
+fn track_branch(ctx: &mut Context, branch: &str, parent: &str) -> anyhow::Result<()> {
+    ctx.tx.create_branch(parent, branch)?;
+    ctx.tx
+        .set_base_commit(branch, &git::merge_base(&ctx.repo_root, parent, branch)?)?;
+    Ok(())
+}
+
+/// Tracks `branch` on top of the nearest local branch it's built on,
+/// and keeps going down through untracked branches until it reaches a tracked one.
+fn track_with_ancestors(ctx: &mut Context, branch: &str) -> anyhow::Result<()> {
+    let local_branches: Vec<(String, String)> = git::list_refs(&ctx.repo_root, "refs/heads/")?
+        .into_iter()
+        .map(|(reference, commit)| {
+            (
+                reference.trim_start_matches("refs/heads/").to_owned(),
+                commit,
+            )
+        })
+        .collect();
+
+    let mut tracked: Vec<String> = ctx
+        .tx
+        .get_all_branches()?
+        .into_iter()
+        .map(|branch| branch.name)
+        .collect();
+    tracked.extend(ctx.tx.get_root_branch()?);
+
+    let mut chain: Vec<(String, String)> = Vec::new();
+    let mut current = branch.to_owned();
+    loop {
+        let current_commit = git::rev_parse(&ctx.repo_root, &current)?;
+        // The nearest branch is the one with the fewest commits between it and `current`,
+        // and a tracked branch wins a tie, so that the walk stops at the first tracked branch it reaches.
+        // Untracked branches at the same commit as `current` could just as well be built on it, so they're skipped.
+        let mut nearest: Option<(usize, bool, &str)> = None;
+        for (candidate, commit) in &local_branches {
+            let is_tracked = tracked.contains(candidate);
+            if *candidate == current
+                || (*commit == current_commit && !is_tracked)
+                || !git::is_ancestor_of(&ctx.repo_root, candidate, &current)?
+            {
+                continue;
+            }
+            let distance = git::count_commits(&ctx.repo_root, candidate, &current)?;
+            if nearest.is_none_or(|(nearest_distance, nearest_tracked, _)| {
+                (distance, !is_tracked) < (nearest_distance, !nearest_tracked)
+            }) {
+                nearest = Some((distance, is_tracked, candidate));
+            }
+        }
+        let Some((_, is_tracked, parent)) = nearest else {
+            anyhow::bail!(
+                "Cannot track `{current}`, because it isn't built on any tracked branch."
+            );
+        };
+        let parent = parent.to_owned();
+        chain.push((current, parent.clone()));
+        if is_tracked {
+            break;
+        }
+        current = parent;
+    }
+
+    // Tracked from the bottom up, since each branch's parent has to be tracked first.
+    for (branch, parent) in chain.iter().rev() {
+        track_branch(ctx, branch, parent)?;
+        println!("Tracked `{branch}` on top of `{parent}`.");
+    }
+    Ok(())
+}
+
+fn trunk(ctx: &mut Context, trunk_opt: &TrunkOpt) -> anyhow::Result<()> {
+    let Some(root_branch) = ctx.tx.get_root_branch()? else {
+        return Err(not_initialized("root branch"));
+    };
+    let Some(new_root_branch) = &trunk_opt.rename_to else {
+        println!("{root_branch}");
+        return Ok(());
+    };
+    let Some(remote) = ctx.tx.get_remote()? else {
+        return Err(not_initialized("remote"));
+    };
+
+    git::fetch(&ctx.repo_root, &remote, ctx.git_timeout)?;
+    let upstream = format!("{remote}/{new_root_branch}");
+    if git::rev_parse(&ctx.repo_root, &format!("refs/remotes/{upstream}")).is_err() {
+        anyhow::bail!("Cannot find `{new_root_branch}` on `{remote}`. Has it been renamed yet?");
+    }
+    git::set_upstream(&ctx.repo_root, new_root_branch, &upstream)?;
+    ctx.tx.rename_root_branch(new_root_branch)?;
+    println!("Moved the root branch from `{root_branch}` to `{new_root_branch}`.");
+    Ok(())
+}
+
+/// Rebases `branch` onto the tip of `parent`, or merges `parent` into it
+/// when the `restack.strategy` config is `merge`.
+/// If we know which commit `branch` was built on top of, only the commits after that base are moved,
+/// so that a parent whose history was rewritten (amended, squash-merged) doesn't have its old commits replayed.
+/// If Ctrl-C is pressed, this stops before starting, or aborts the rebase and fails with `Interrupted`.
+fn restack_onto_parent(ctx: &mut Context, branch: &str, parent: &str) -> anyhow::Result<()> {
+    interrupt::check()?;
+    let in_worktree = uses_worktrees(ctx)?
+        && git::get_current_branch(&ctx.repo_root).ok().as_deref() != Some(branch);
+    let mut rebased = false;
+    if in_worktree {
+        let worktree = git::Worktree::add(&ctx.repo_root, branch, ctx.recurse_submodules)?;
+        match rebase_onto_parent(ctx, worktree.path(), branch, parent) {
+            Ok(()) => rebased = true,
+            Err(err) if err.is::<interrupt::Interrupted>() => return Err(err),
+            Err(_) => {
+                // Conflicts are resolved in the user's own working tree, like any other restack.
+                git::abort_in_progress(worktree.path())?;
+                println!("`{branch}` has conflicts, so checking it out to resolve them...");
+            }
+        }
+    }
+    if !rebased {
+        let repo_root = ctx.repo_root.clone();
+        if in_worktree {
+            let current_branch = git::get_current_branch(&repo_root)?;
+            let guard =
+                git::BranchGuard::new(repo_root.clone(), current_branch, ctx.recurse_submodules)?;
+            if let Err(err) = rebase_onto_parent(ctx, &repo_root, branch, parent) {
+                guard.dismiss();
+                return Err(err);
+            }
+            guard.release()?;
+        } else {
+            rebase_onto_parent(ctx, &repo_root, branch, parent)?;
+        }
+    }
+    ctx.tx
+        .set_base_commit(branch, &git::rev_parse(&ctx.repo_root, parent)?)?;
+    ctx.summary.record(branch, Outcome::Rebased, "");
+    Ok(())
+}
+
+/// Rebases or merges `branch` onto `parent` in the working tree at `git_root`.
+fn rebase_onto_parent(
+    ctx: &Context,
+    git_root: &Path,
+    branch: &str,
+    parent: &str,
+) -> anyhow::Result<()> {
+    let options = rebase_options(&ctx.tx)?;
+    let result = match ctx.tx.get_base_commit(branch)? {
+        _ if ctx.tx.get_config(config::RESTACK_STRATEGY)?.as_deref() == Some("merge") => {
+            git::merge(git_root, parent, branch, ctx.recurse_submodules)
+        }
+        Some(base_commit) if git::is_ancestor_of(git_root, &base_commit, branch)? => {
+            git::rebase_onto(git_root, parent, &base_commit, branch, &options)
+        }
+        _ => git::rebase(git_root, parent, branch, &options),
+    };
+    if result.is_err() && interrupt::interrupted() {
+        git::abort_in_progress(git_root)?;
+        return Err(interrupt::Interrupted.into());
+    }
+    result
+}
+
+/// Whether `restack.worktree` is set, so branches other than the checked-out one are rewritten
+/// without touching the working tree.
+fn uses_worktrees(ctx: &Context) -> anyhow::Result<bool> {
+    config::get_bool(&ctx.tx, config::RESTACK_WORKTREE)
+}
+
+/// Guards the checked-out branch while `branches` are restacked, unless there are none,
+/// or `restack.worktree` means that it's left alone because it isn't one of them.
+fn restack_guard(
+    ctx: &Context,
+    current_branch: &str,
+    branches: &[String],
+) -> anyhow::Result<git::BranchGuard> {
+    if branches.is_empty()
+        || uses_worktrees(ctx)? && !branches.iter().any(|branch| branch == current_branch)
+    {
+        return Ok(git::BranchGuard::inactive());
+    }
+    git::BranchGuard::new(
+        ctx.repo_root.clone(),
+        current_branch.to_owned(),
+        ctx.recurse_submodules,
+    )
+}
+
+/// Pulls `remote_branch` from `remote` into `branch`,
+/// without checking `branch` out if `restack.worktree` is set and it isn't the current branch.
+fn pull_branch(
+    ctx: &Context,
+    remote: &str,
+    remote_branch: &str,
+    branch: &str,
+) -> anyhow::Result<()> {
+    if uses_worktrees(ctx)?
+        && git::get_current_branch(&ctx.repo_root).ok().as_deref() != Some(branch)
+    {
+        return git::fast_forward(
+            &ctx.repo_root,
+            remote,
+            remote_branch,
+            branch,
+            ctx.git_timeout,
+        );
+    }
+    git::pull(
+        &ctx.repo_root,
+        remote,
+        remote_branch,
+        branch,
+        ctx.recurse_submodules,
+        ctx.git_timeout,
+    )
+}
+
+/// The first line of `err` without colors, to show in the summary table.
+fn failure_detail(err: &anyhow::Error) -> String {
+    let message = err.to_string().replace(RED, "").replace(RESET, "");
+    message.lines().next().unwrap_or_default().to_owned()
+}
+
+fn rebase_options(tx: &Transaction) -> anyhow::Result<git::RebaseOptions> {
+    let gpg_sign = match tx.get_config(config::REBASE_GPG_SIGN)?.as_deref() {
+        None | Some("false") => None,
+        Some("true") => Some(String::new()),
+        Some(key_id) => Some(key_id.to_owned()),
+    };
+    Ok(git::RebaseOptions {
+        gpg_sign,
+        signoff: config::get_bool(tx, config::REBASE_SIGNOFF)?,
+        rerere: config::get_bool(tx, config::REBASE_RERERE)?,
+        recurse_submodules: config::get_bool(tx, config::SUBMODULES_RECURSE)?,
+    })
+}
+
+fn push_options(tx: &Transaction) -> anyhow::Result<git::PushOptions> {
+    Ok(git::PushOptions {
+        no_verify: config::get_bool(tx, config::PUSH_NO_VERIFY)?,
+        push_options: config::get_list(tx, config::PUSH_OPTIONS)?,
+        timeout: config::get_seconds(tx, config::GIT_TIMEOUT)?.map(Duration::from_secs),
+    })
+}
+
+/// Force-pushes `branch` to the remote, after checking that nobody else has pushed commits to it
+/// which the push would throw away.
+fn push_branch(
+    ctx: &mut Context,
+    remote_name: &str,
+    branch: &str,
+    options: &git::PushOptions,
+) -> anyhow::Result<()> {
+    let remote_branch_name = remote_branch_name(&ctx.tx, branch)?;
+    let remote_branch = format!("{remote_name}/{remote_branch_name}");
+    let remote_commit =
+        git::rev_parse(&ctx.repo_root, &format!("refs/remotes/{remote_branch}")).ok();
+    if let Some(remote_commit) = &remote_commit {
+        let pushed_by_us = ctx.tx.get_pushed_commit(branch)?.as_ref() == Some(remote_commit);
+        if !pushed_by_us && !git::is_ancestor_of(&ctx.repo_root, remote_commit, branch)? {
+            let missing_commits = git::missing_commits(&ctx.repo_root, branch, remote_commit)?;
+            if !missing_commits.is_empty() {
+                return Err(exit::error(
+                    ExitCode::DivergedRemote,
+                    format!(
+                        "{RED}`{branch}` has diverged from `{remote_branch}`, \
+                        which has commits that aren't in your local branch:\n  {}\n\
+                        Bring them into `{branch}` (e.g. `git pull --rebase {remote_name} {branch}`) before pushing.{RESET}",
+                        missing_commits.join("\n  "),
+                    ),
+                ));
+            }
+        }
+    }
+
+    let local_commit = git::rev_parse(&ctx.repo_root, branch)?;
+    if remote_commit.as_ref() == Some(&local_commit) {
+        println!("`{branch}` is already up to date on `{remote_name}`.");
+        ctx.tx.set_pushed_commit(branch, &local_commit)?;
+        ctx.summary.record(
+            branch,
+            Outcome::Skipped,
+            format!("already up to date on `{remote_name}`"),
+        );
+        return Ok(());
+    }
+    if let Some(remote_commit) = &remote_commit {
+        if !git::is_ancestor_of(&ctx.repo_root, remote_commit, branch)? {
+            ensure_force_pushable(ctx, remote_name, &remote_branch_name)?;
+            if !prompt::confirm(
+                ctx.yes,
+                &format!("Force-push `{branch}`, replacing its history on `{remote_name}`?"),
+            )? {
+                return Err(exit::error(
+                    ExitCode::Aborted,
+                    format!("{RED}Not force-pushing `{branch}`.{RESET}"),
+                ));
+            }
+        }
+    }
+    git::push_branch(
+        &ctx.repo_root,
+        remote_name,
+        branch,
+        &remote_branch_name,
+        remote_commit.as_deref(),
+        options,
+    )?;
+    ctx.tx.set_pushed_commit(branch, &local_commit)?;
+    ctx.summary.record(branch, Outcome::Pushed, "");
+    Ok(())
+}
+
+/// Refuses to force-push `branch` when GitHub's branch protection or rulesets would reject it,
+/// to explain why instead of leaving it to Git's error.
+/// This is skipped when there's no GitHub token, and when GitHub can't be asked.
+fn ensure_force_pushable(ctx: &mut Context, remote_name: &str, branch: &str) -> anyhow::Result<()> {
+    let Ok(github) = connect_github(ctx, remote_name) else {
+        return Ok(());
+    };
+    match github.force_push_blocker(branch) {
+        Ok(Some(reason)) => anyhow::bail!(
+            "{RED}Cannot force-push `{branch}` to `{remote_name}`, because {reason}.{RESET}"
+        ),
+        Ok(None) => Ok(()),
+        Err(err) => {
+            eprintln!("Failed to check the branch protection of `{branch}`: {err}");
+            Ok(())
+        }
+    }
+}
+
+/// Finds the root branch or tracked branch which is pushed to `remote_branch`, if there is one.
+fn find_branch_pushed_to(ctx: &Context, remote_branch: &str) -> anyhow::Result<Option<String>> {
+    if ctx.tx.get_root_branch()?.as_deref() == Some(remote_branch) {
+        return Ok(Some(remote_branch.to_owned()));
+    }
+    for branch in ctx.tx.get_all_branches()? {
+        if remote_branch_name(&ctx.tx, &branch.name)? == remote_branch {
+            return Ok(Some(branch.name));
+        }
+    }
+    Ok(None)
+}
+
+/// The name `branch` has on the remote, which is its own name unless it's been set with `dmd remote-name`.
+fn remote_branch_name(tx: &Transaction, branch: &str) -> anyhow::Result<String> {
+    Ok(tx
+        .get_remote_name(branch)?
+        .unwrap_or_else(|| branch.to_owned()))
+}
+
+/// Finds the open pull request for `branch`, under the name it has on the remote.
+fn find_pull_request(
+    tx: &Transaction,
+    github: &GitHub,
+    branch: &str,
+) -> anyhow::Result<Option<PullRequest>> {
+    github.find_pull_request(&remote_branch_name(tx, branch)?)
+}
+
+/// Finds the open pull requests for `branches` and the status of their checks in bulk,
+/// under the names the branches have on the remote. Branches without open pull requests are left out.
+fn find_pull_request_statuses(
+    tx: &Transaction,
+    github: &GitHub,
+    branches: &[String],
+) -> anyhow::Result<HashMap<String, PullRequestStatus>> {
+    let mut remote_names = Vec::new();
+    for branch in branches {
+        remote_names.push(remote_branch_name(tx, branch)?);
+    }
+    let mut statuses = github.find_pull_request_statuses(&remote_names)?;
+    Ok(branches
+        .iter()
+        .zip(&remote_names)
+        .filter_map(|(branch, remote_name)| Some((branch.clone(), statuses.remove(remote_name)?)))
+        .collect())
+}
+
+/// The remote which branches are pushed to, which is the `push.remote` config when it's set,
+/// like a fork, and otherwise the repo's remote.
+fn push_remote(tx: &Transaction, remote_name: &str) -> anyhow::Result<String> {
+    Ok(tx
+        .get_config(config::PUSH_REMOTE)?
+        .unwrap_or_else(|| remote_name.to_owned()))
+}
+
+/// Stops tracking the branches whose pull requests were merged, moving their children onto their parents,
+/// and points out the ones whose pull requests were closed without being merged.
+/// With `retention.untrack-merged-after`, merged branches are archived instead of asked about,
+/// and stop being tracked once they were merged longer ago than that.
+/// Returns the branches which are no longer tracked or were archived.
+fn untrack_merged_branches(
+    ctx: &mut Context,
+    github: &GitHub,
+    branches: &[database::Branch],
+) -> anyhow::Result<Vec<String>> {
+    let mut untracked = Vec::new();
+    for branch in branches {
+        if config::is_protected(&ctx.tx, &branch.name)? {
+            continue;
+        }
+        let Some(pull_request) =
+            github.find_latest_pull_request(&remote_branch_name(&ctx.tx, &branch.name)?)?
+        else {
+            continue;
+        };
+        // Commits made since the pull request closed belong to some other change.
+        let commit = git::rev_parse(&ctx.repo_root, &branch.name)?;
+        if pull_request.state != "closed" || pull_request.head.sha != commit {
+            continue;
+        }
+        if pull_request.merged_at.is_none() {
+            println!(
+                "The pull request for `{}` was closed without being merged: {}\nRun `dmd remove {}` if you're done with it.",
+                branch.name, pull_request.html_url, branch.name
+            );
+            continue;
+        }
+        match config::get_days(&ctx.tx, config::RETENTION_UNTRACK_MERGED_AFTER)? {
+            Some(days) => {
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)?
+                    .as_secs();
+                let age = now.saturating_sub(pull_request.merged_time().unwrap_or(0));
+                if age <= days * 86400 {
+                    // It was archived by an earlier sync, and is kept until it's old enough.
+                    if ctx.tx.get_archived_branches()?.contains(&branch.name) {
+                        continue;
+                    }
+                    println!(
+                        "Archiving `{}`, which was merged in #{} {}.",
+                        branch.name,
+                        pull_request.number,
+                        format_age(age)
+                    );
+                    ctx.tx.set_archived(&branch.name, true)?;
+                    ctx.summary.record(
+                        &branch.name,
+                        Outcome::Archived,
+                        format!("merged in #{} {}", pull_request.number, format_age(age)),
+                    );
+                    untracked.push(branch.name.clone());
+                    continue;
+                }
+            }
+            None => {
+                if !prompt::confirm(
+                    ctx.yes,
+                    &format!(
+                        "`{}` was merged in #{}. Stop tracking it?",
+                        branch.name, pull_request.number
+                    ),
+                )? {
+                    continue;
+                }
+            }
+        }
+
+        // Like in `dmd land`, the children keep only their own commits,
+        // because a squash or rebase merge doesn't bring the branch's commits into its parent.
+        println!(
+            "`{}` was merged in #{}, so it's no longer tracked.",
+            branch.name, pull_request.number
+        );
+        let children = ctx.tx.get_children(&branch.name)?;
+        ctx.tx.remove_branch(&branch.name)?;
+        for child in children {
+            ctx.tx.set_base_commit(&child, &commit)?;
+        }
+        ctx.summary.record(
+            &branch.name,
+            Outcome::Untracked,
+            format!("merged in #{}", pull_request.number),
+        );
+        untracked.push(branch.name.clone());
+    }
+    Ok(untracked)
+}
+
+/// Connects to GitHub for the repo behind `remote_name`, which pull requests are opened against,
+/// looking for their branches in the push remote's repo.
+fn connect_github(ctx: &mut Context, remote_name: &str) -> anyhow::Result<GitHub> {
+    let remote = git::parse_remote(&ctx.repo_root, remote_name)?;
+    let push_remote = git::parse_remote(&ctx.repo_root, &push_remote(&ctx.tx, remote_name)?)?;
+    // The endpoint is found once and reused, so that credential helpers are only asked for the token once.
+    let endpoint = match &ctx.github_endpoint {
+        Some(endpoint) => endpoint.clone(),
+        None => github::Endpoint::find(
+            &ctx.repo_root,
+            &remote,
+            ctx.tx.get_config(config::GITHUB_API_URL)?.as_deref(),
+        )?,
+    };
+    ctx.github_endpoint = Some(endpoint.clone());
+    let mut github = GitHub::new(remote, endpoint)
+        .head_owner(push_remote.organization)
+        .cache_dir(git::get_common_dir(&ctx.repo_root)?.join("diamond-cache"))
+        .verbose(ctx.verbose);
+    if let Some(proxy) = ctx.tx.get_config(config::GITHUB_PROXY)? {
+        github = github.proxy(&proxy)?;
+    }
+    Ok(github)
+}
+
+fn not_initialized(what: &str) -> anyhow::Error {
+    exit::error(
+        ExitCode::NotInitialized,
+        format!("{RED}Cannot find {what}. Configure repo with `dmd init`.{RESET}"),
+    )
+}
+
+fn ensure_unprotected(tx: &Transaction, branch: &str, action: &str) -> anyhow::Result<()> {
+    if config::is_protected(tx, branch)? {
+        anyhow::bail!(
+            "{RED}Refusing to {action} `{branch}`, because it matches the `{}` config.{RESET}",
+            config::PROTECTED_BRANCHES
+        );
+    }
+    Ok(())
+}
+
+/// How many commits of history to fetch at first when a shallow clone is missing history.
+const SHALLOW_DEEPEN_BY: usize = 100;
+
+/// In a shallow clone, fetches history until each branch shares a commit with its parent,
+/// which restacking needs to tell the branch's own commits apart from its parent's.
+fn ensure_history(ctx: &Context, branches: &[database::Branch]) -> anyhow::Result<()> {
+    if !git::is_shallow(&ctx.repo_root)? {
+        return Ok(());
+    }
+    let missing_history = || -> anyhow::Result<Option<&database::Branch>> {
+        for branch in branches {
+            if !git::has_merge_base(&ctx.repo_root, &branch.parent, &branch.name)? {
+                return Ok(Some(branch));
+            }
+        }
+        Ok(None)
+    };
+    if missing_history()?.is_none() {
+        return Ok(());
+    }
+
+    let Some(remote) = ctx.tx.get_remote()? else {
+        return Err(not_initialized("remote"));
+    };
+    println!("Fetching more history, because this is a shallow clone...");
+    git::deepen(
+        &ctx.repo_root,
+        &remote,
+        Some(SHALLOW_DEEPEN_BY),
+        ctx.git_timeout,
+    )?;
+    if missing_history()?.is_some() {
+        println!("Fetching the full history...");
+        git::deepen(&ctx.repo_root, &remote, None, ctx.git_timeout)?;
+    }
+    if let Some(branch) = missing_history()? {
+        anyhow::bail!(
+            "{RED}Cannot find where `{}` diverged from `{}`, even with the full history of `{remote}`.{RESET}",
+            branch.name,
+            branch.parent
+        );
+    }
+    Ok(())
+}
+
+/// Refuses to `action` when a submodule has local changes,
+/// which checking out other branches could throw away or leave in a confusing state.
+fn ensure_submodules_clean(ctx: &Context, action: &str) -> anyhow::Result<()> {
+    // Without submodules, there's no need to ask Git about them.
+    if !ctx.repo_root.join(".gitmodules").exists() {
+        return Ok(());
+    }
+    let dirty = git::dirty_submodules(&ctx.repo_root)?;
+    if !dirty.is_empty() {
+        return Err(exit::error(
+            ExitCode::DirtyWorktree,
+            format!(
+                "{RED}Cannot {action}, because these submodules have local changes: {}.{RESET}\n\
+                Commit or stash them, or run `git submodule update` to discard them.",
+                dirty.join(", ")
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::TestRepo;
+
+    fn create_branch(repo: &mut TestRepo, branch: &str) -> anyhow::Result<()> {
+        repo.run(|ctx| {
+            create(
+                ctx,
+                &CreateOpt {
+                    branch: Some(branch.to_owned()),
+                    message: None,
+                    all: false,
+                    slugify: false,
+                    closes: vec![],
+                },
+            )
+        })
+    }
+
+    #[test]
+    fn test_create_tracks_branch() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+
+        assert_eq!(repo.current_branch()?, "ch/branch-2");
+        repo.run(|ctx| {
+            assert_eq!(ctx.tx.get_parent("ch/branch-1")?, Some("main".to_owned()));
+            assert_eq!(
+                ctx.tx.get_parent("ch/branch-2")?,
+                Some("ch/branch-1".to_owned())
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_track_existing_branch() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        repo.git(&["checkout", "--quiet", "-b", "ch/untracked"])?;
+        repo.commit("one.txt", "one", "One")?;
+
+        let track_opt = TrackOpt {
+            parent: None,
+            with_ancestors: false,
+        };
+        repo.run(|ctx| track(ctx, &track_opt))?;
+        repo.run(|ctx| {
+            assert_eq!(ctx.tx.get_parent("ch/untracked")?, Some("main".to_owned()));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_restack_after_amending_parent() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+
+        repo.git(&["checkout", "--quiet", "ch/branch-1"])?;
+        std::fs::write(repo.root.join("one.txt"), "one, amended")?;
+        repo.git(&["commit", "--quiet", "--all", "--amend", "--no-edit"])?;
+
+        repo.run(|ctx| {
+            restack(
+                ctx,
+                &RestackOpt {
+                    branch: None,
+                    plan: false,
+                },
+            )?;
+            assert_eq!(
+                ctx.summary.render(),
+                "\
+Summary:
+  Branch       Result   Details
+  ch/branch-1  skipped  already up to date
+  ch/branch-2  rebased
+"
+            );
+            Ok(())
+        })?;
+
+        assert_eq!(repo.current_branch()?, "ch/branch-1");
+        assert!(repo.is_ancestor("ch/branch-1", "ch/branch-2")?);
+        assert_eq!(
+            repo.git(&["rev-list", "--count", "ch/branch-1..ch/branch-2"])?,
+            "1"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_restack_with_merge_strategy_keeps_history() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        repo.run(|ctx| ctx.tx.set_config(config::RESTACK_STRATEGY, "merge"))?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        let one = repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        let two = repo.commit("two.txt", "two", "Two")?;
+
+        repo.git(&["checkout", "--quiet", "ch/branch-1"])?;
+        repo.commit("one.txt", "one, again", "One again")?;
+        repo.run(|ctx| {
+            restack(
+                ctx,
+                &RestackOpt {
+                    branch: None,
+                    plan: false,
+                },
+            )
+        })?;
+
+        assert!(repo.is_ancestor("ch/branch-1", "ch/branch-2")?);
+        assert!(repo.is_ancestor(&one, "ch/branch-1")?);
+        assert!(repo.is_ancestor(&two, "ch/branch-2")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_restack_in_worktree_leaves_working_tree_alone() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        repo.run(|ctx| ctx.tx.set_config(config::RESTACK_WORKTREE, "true"))?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+        repo.commit("main.txt", "main", "Main")?;
+        std::fs::write(repo.root.join("main.txt"), "uncommitted")?;
+        let modified = std::fs::metadata(repo.root.join("main.txt"))?.modified()?;
+
+        let restack_opt = RestackOpt {
+            branch: Some("ch/branch-1".to_owned()),
+            plan: false,
+        };
+        repo.run(|ctx| restack(ctx, &restack_opt))?;
+        assert!(repo.is_ancestor("main", "ch/branch-1")?);
+        assert!(repo.is_ancestor("ch/branch-1", "ch/branch-2")?);
+        assert_eq!(repo.current_branch()?, "main");
+        assert_eq!(repo.git(&["status", "--porcelain"])?, "M main.txt");
+        assert_eq!(
+            std::fs::metadata(repo.root.join("main.txt"))?.modified()?,
+            modified
+        );
+        assert_eq!(repo.git(&["worktree", "list"])?.lines().count(), 1);
+
+        // Conflicts are left to resolve in the working tree, as usual.
+        repo.git(&["checkout", "--quiet", "--", "main.txt"])?;
+        repo.commit("two.txt", "conflicting", "Conflict")?;
+        assert!(repo.run(|ctx| restack(ctx, &restack_opt)).is_err());
+        assert!(repo.root.join(".git/rebase-merge").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_branches_on_archived_branches_are_not_restacked() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/shelved")?;
+        repo.commit("shelved.txt", "shelved", "Shelved")?;
+        create_branch(&mut repo, "ch/above")?;
+        let above_commit = repo.commit("above.txt", "above", "Above")?;
+        repo.run(|ctx| {
+            archive(
+                ctx,
+                &ArchiveOpt {
+                    branch: Some("ch/shelved".to_owned()),
+                    recursive: false,
+                    list: false,
+                },
+            )
+        })?;
+
+        // `main` moves on, on the remote too.
+        repo.git(&["checkout", "--quiet", "main"])?;
+        repo.commit("upstream.txt", "upstream", "Upstream")?;
+        repo.git(&["push", "--quiet", "origin", "main"])?;
+        repo.git(&["checkout", "--quiet", "ch/above"])?;
+
+        repo.run(|ctx| {
+            restack(
+                ctx,
+                &RestackOpt {
+                    branch: None,
+                    plan: false,
+                },
+            )?;
+            // The archived branch hasn't been pushed, so it mustn't be pulled as if it was a target branch.
+            sync(ctx, &SyncOpt { stack: None })
+        })?;
+        assert_eq!(repo.git(&["rev-parse", "ch/above"])?, above_commit);
+        assert!(!repo.is_ancestor("main", "ch/shelved")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_restacks_onto_updated_root() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+
+        // Someone else lands a change on `main`.
+        repo.git(&["checkout", "--quiet", "main"])?;
+        let upstream_commit = repo.commit("upstream.txt", "upstream", "Upstream")?;
+        repo.git(&["push", "--quiet", "origin", "main"])?;
+        repo.git(&["reset", "--quiet", "--hard", "HEAD~1"])?;
+        repo.git(&["checkout", "--quiet", "ch/branch-2"])?;
+
+        repo.run(|ctx| sync(ctx, &SyncOpt { stack: None }))?;
+
+        assert_eq!(repo.current_branch()?, "ch/branch-2");
+        assert_eq!(repo.git(&["rev-parse", "main"])?, upstream_commit);
+        assert!(repo.is_ancestor("main", "ch/branch-1")?);
+        assert!(repo.is_ancestor("ch/branch-1", "ch/branch-2")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_warns_about_stale_stacks() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        repo.run(|ctx| ctx.tx.set_config(config::RETENTION_WARN_STACK_AGE, "60"))?;
+        create_branch(&mut repo, "ch/old")?;
+        repo.commit("old.txt", "old", "Old")?;
+        repo.git(&[
+            "commit",
+            "--quiet",
+            "--amend",
+            "--no-edit",
+            "--date=2000-01-01T00:00:00Z",
+        ])?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+        create_branch(&mut repo, "ch/new")?;
+        repo.commit("new.txt", "new", "New")?;
+
+        repo.run(|ctx| {
+            sync(ctx, &SyncOpt { stack: None })?;
+            let summary = ctx.summary.render();
+            assert!(summary.contains("ch/old  stale"), "{summary}");
+            assert_eq!(summary.matches("stale").count(), 1, "{summary}");
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_archives_merged_branches_until_they_are_old() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let github = repo.mock_github()?;
+        repo.run(|ctx| {
+            ctx.tx.set_config(config::SUBMIT_PULL_REQUESTS, "true")?;
+            ctx.tx
+                .set_config(config::RETENTION_UNTRACK_MERGED_AFTER, "30")
+        })?;
+        let submit_opt = SubmitOpt {
+            no_verify: false,
+            push_options: vec![],
+            flush: false,
+            no_pr: false,
+            no_reviewers: false,
+            closes: vec![],
+        };
+        create_branch(&mut repo, "ch/recent")?;
+        repo.commit("recent.txt", "recent", "Recent")?;
+        repo.run(|ctx| submit(ctx, &submit_opt))?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+        create_branch(&mut repo, "ch/old")?;
+        repo.commit("old.txt", "old", "Old")?;
+        repo.run(|ctx| submit(ctx, &submit_opt))?;
+
+        let now = repo.git(&[
+            "log",
+            "-1",
+            "--format=%cd",
+            "--date=format-local:%Y-%m-%dT%H:%M:%SZ",
+        ])?;
+        github.merge("ch/recent", &now);
+        repo.git(&["checkout", "--quiet", "ch/recent"])?;
+        repo.run(|ctx| {
+            sync(ctx, &SyncOpt { stack: None })?;
+            let summary = ctx.summary.render();
+            assert!(summary.contains("ch/recent  archived"), "{summary}");
+            assert_eq!(ctx.tx.get_archived_branches()?, vec!["ch/recent"]);
+            Ok(())
+        })?;
+
+        github.merge("ch/old", "2000-01-01T00:00:00Z");
+        repo.git(&["checkout", "--quiet", "ch/old"])?;
+        repo.run(|ctx| {
+            sync(ctx, &SyncOpt { stack: None })?;
+            let summary = ctx.summary.render();
+            assert!(summary.contains("ch/old  untracked"), "{summary}");
+            assert!(!summary.contains("ch/recent"), "{summary}");
+            assert_eq!(ctx.tx.get_parent("ch/old")?, None);
+            assert_eq!(ctx.tx.get_archived_branches()?, vec!["ch/recent"]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_sync_prunes_deleted_remote_branches() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        repo.git(&["push", "--quiet", "origin", "ch/branch-1"])?;
+        repo.remote_git(&["branch", "--quiet", "--delete", "--force", "ch/branch-1"])?;
+
+        repo.run(|ctx| sync(ctx, &SyncOpt { stack: None }))?;
+
+        assert!(repo
+            .git(&[
+                "rev-parse",
+                "--verify",
+                "--quiet",
+                "refs/remotes/origin/ch/branch-1"
+            ])
+            .is_err());
+        assert!(repo.is_ancestor("main", "ch/branch-1")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_resets_to_force_pushed_branch() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+
+        // A teammate rewrites `ch/branch-1` and force-pushes it.
+        repo.git(&["checkout", "--quiet", "ch/branch-1"])?;
+        repo.git(&["push", "--quiet", "origin", "ch/branch-1"])?;
+        let old_commit = repo.git(&["rev-parse", "HEAD"])?;
+        std::fs::write(repo.root.join("one.txt"), "one, rewritten")?;
+        repo.git(&["commit", "--quiet", "--all", "--amend", "--no-edit"])?;
+        repo.git(&["push", "--quiet", "--force", "origin", "ch/branch-1"])?;
+        let new_commit = repo.git(&["rev-parse", "HEAD"])?;
+        repo.git(&["reset", "--quiet", "--hard", &old_commit])?;
+        repo.git(&["checkout", "--quiet", "ch/branch-2"])?;
+
+        repo.run(|ctx| sync(ctx, &SyncOpt { stack: None }))?;
+
+        assert_eq!(repo.git(&["rev-parse", "ch/branch-1"])?, new_commit);
+        assert!(repo.is_ancestor("ch/branch-1", "ch/branch-2")?);
+        assert_eq!(
+            repo.git(&["rev-list", "--count", "ch/branch-1..ch/branch-2"])?,
+            "1"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_submit_pushes_stack() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+
+        repo.run(|ctx| {
+            submit(
+                ctx,
+                &SubmitOpt {
+                    no_verify: false,
+                    push_options: vec![],
+                    flush: false,
+                    no_pr: false,
+                    no_reviewers: false,
+                    closes: vec![],
+                },
+            )
+        })?;
+
+        for branch in ["ch/branch-1", "ch/branch-2"] {
+            assert_eq!(
+                repo.remote_git(&["rev-parse", branch])?,
+                repo.git(&["rev-parse", branch])?,
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_submit_pushes_to_remote_name() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "wip/one")?;
+        repo.commit("one.txt", "one", "One")?;
+        repo.run(|ctx| {
+            remote_name(
+                ctx,
+                &RemoteNameOpt {
+                    name: Some("users/chris/one".to_owned()),
+                    branch: None,
+                    clear: false,
+                },
+            )
+        })?;
+
+        let submit_opt = SubmitOpt {
+            no_verify: false,
+            push_options: vec![],
+            flush: false,
+            no_pr: false,
+            no_reviewers: false,
+            closes: vec![],
+        };
+        repo.run(|ctx| submit(ctx, &submit_opt))?;
+        assert_eq!(
+            repo.remote_git(&["rev-parse", "users/chris/one"])?,
+            repo.git(&["rev-parse", "wip/one"])?,
+        );
+        assert!(repo
+            .remote_git(&["rev-parse", "--verify", "--quiet", "wip/one"])
+            .is_err());
+
+        // Rewriting the branch force-pushes over the renamed remote branch.
+        std::fs::write(repo.root.join("one.txt"), "one, amended")?;
+        repo.git(&["commit", "--quiet", "--all", "--amend", "--no-edit"])?;
+        repo.run(|ctx| submit(ctx, &submit_opt))?;
+        assert_eq!(
+            repo.remote_git(&["rev-parse", "users/chris/one"])?,
+            repo.git(&["rev-parse", "wip/one"])?,
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_submit_skips_branches_already_on_remote() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+
+        // Counts the pushes which actually reach the remote.
+        let pushes = repo.root.join("pushes.log");
+        let hook = repo.root.join(".git").join("hooks").join("pre-push");
+        std::fs::create_dir_all(hook.parent().unwrap())?;
+        std::fs::write(&hook, format!("#!/bin/sh\ncat >> '{}'\n", pushes.display()))?;
+        std::fs::set_permissions(&hook, std::os::unix::fs::PermissionsExt::from_mode(0o755))?;
+
+        let submit_opt = SubmitOpt {
+            no_verify: false,
+            push_options: vec![],
+            flush: false,
+            no_pr: false,
+            no_reviewers: false,
+            closes: vec![],
+        };
+        repo.run(|ctx| submit(ctx, &submit_opt))?;
+        assert_eq!(std::fs::read_to_string(&pushes)?.lines().count(), 2);
+
+        repo.commit("two.txt", "two, again", "Two again")?;
+        repo.run(|ctx| submit(ctx, &submit_opt))?;
+        let pushed: Vec<String> = std::fs::read_to_string(&pushes)?
+            .lines()
+            .map(str::to_owned)
+            .collect();
+        assert_eq!(pushed.len(), 3);
+        assert!(pushed[2].starts_with("refs/heads/ch/branch-2 "));
+        Ok(())
+    }
+
+    #[test]
+    fn test_submit_pushes_to_push_remote() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let fork = repo.remote.with_file_name("fork.git");
+        repo.remote_git(&["init", "--quiet", "--bare", &fork.display().to_string()])?;
+        repo.git(&["remote", "add", "fork", &fork.display().to_string()])?;
+        repo.run(|ctx| config::set(&mut ctx.tx, config::find_key(config::PUSH_REMOTE)?, "fork"))?;
+
+        create_branch(&mut repo, "ch/branch-1")?;
+        let commit = repo.commit("one.txt", "one", "One")?;
+        let submit_opt = SubmitOpt {
+            no_verify: false,
+            push_options: vec![],
+            flush: false,
+            no_pr: true,
+            no_reviewers: false,
+            closes: vec![],
+        };
+        repo.run(|ctx| submit(ctx, &submit_opt))?;
+
+        assert_eq!(
+            repo.git(&["rev-parse", "refs/remotes/fork/ch/branch-1"])?,
+            commit
+        );
+        assert!(repo
+            .remote_git(&["rev-parse", "--verify", "--quiet", "ch/branch-1"])
+            .is_err());
+
+        // Stacked pull requests can't be opened from a fork, since the branches they'd merge into aren't upstream.
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        let submit_opt = SubmitOpt {
+            no_pr: false,
+            ..submit_opt
+        };
+        repo.run(|ctx| {
+            config::set(
+                &mut ctx.tx,
+                config::find_key(config::SUBMIT_PULL_REQUESTS)?,
+                "true",
+            )
+        })?;
+        let err = repo.run(|ctx| submit(ctx, &submit_opt)).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("pull requests from a fork can only merge into branches of `origin`"),
+            "{err}"
+        );
+        assert!(repo
+            .git(&[
+                "rev-parse",
+                "--verify",
+                "--quiet",
+                "refs/remotes/fork/ch/branch-2"
+            ])
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_submit_refuses_to_overwrite_diverged_remote() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        let local_commit = repo.commit("one.txt", "one", "One")?;
+        repo.git(&["push", "--quiet", "origin", "ch/branch-1"])?;
+
+        // Someone else pushes to the branch, which we don't have locally.
+        repo.commit("other.txt", "other", "Someone else's change")?;
+        repo.git(&["push", "--quiet", "origin", "ch/branch-1"])?;
+        let remote_commit = repo.git(&["rev-parse", "HEAD"])?;
+        repo.git(&["reset", "--quiet", "--hard", &local_commit])?;
+        repo.commit("one.txt", "one, again", "One again")?;
+
+        let submit_opt = SubmitOpt {
+            no_verify: false,
+            push_options: vec![],
+            flush: false,
+            no_pr: false,
+            no_reviewers: false,
+            closes: vec![],
+        };
+        assert!(repo.run(|ctx| submit(ctx, &submit_opt)).is_err());
+        assert_eq!(
+            repo.remote_git(&["rev-parse", "ch/branch-1"])?,
+            remote_commit
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_submit_queues_pushes_while_remote_is_unreachable() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+
+        let offline_remote = repo.remote.with_extension("offline");
+        std::fs::rename(&repo.remote, &offline_remote)?;
+        let submit_opt = SubmitOpt {
+            no_verify: false,
+            push_options: vec![],
+            flush: false,
+            no_pr: false,
+            no_reviewers: false,
+            closes: vec![],
+        };
+        let err = repo.run(|ctx| submit(ctx, &submit_opt)).unwrap_err();
+        assert!(err.is::<SubmitQueuedError>());
+        std::fs::rename(&offline_remote, &repo.remote)?;
+
+        // Submitting another stack leaves what's queued for this one alone.
+        repo.git(&["checkout", "--quiet", "main"])?;
+        create_branch(&mut repo, "ch/other")?;
+        repo.commit("other.txt", "other", "Other")?;
+        repo.run(|ctx| submit(ctx, &submit_opt))?;
+        repo.remote_git(&["rev-parse", "ch/other"])?;
+        assert!(repo
+            .remote_git(&["rev-parse", "--verify", "--quiet", "ch/branch-1"])
+            .is_err());
+
+        let flush_opt = SubmitOpt {
+            flush: true,
+            ..submit_opt
+        };
+        repo.run(|ctx| submit(ctx, &flush_opt))?;
+        assert_eq!(
+            repo.remote_git(&["rev-parse", "ch/branch-1"])?,
+            repo.git(&["rev-parse", "ch/branch-1"])?,
+        );
+        repo.run(|ctx| {
+            assert!(ctx.tx.get_queued_operations()?.is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_submit_opens_and_updates_pull_requests() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let github = repo.mock_github()?;
+        repo.run(|ctx| ctx.tx.set_config(config::SUBMIT_PULL_REQUESTS, "true"))?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+
+        let submit_opt = SubmitOpt {
+            no_verify: false,
+            push_options: vec![],
+            flush: false,
+            no_pr: false,
+            no_reviewers: false,
+            closes: vec![],
+        };
+        repo.run(|ctx| submit(ctx, &submit_opt))?;
+        let pull_requests = github.pull_requests();
+        assert_eq!(pull_requests.len(), 2);
+        assert_eq!(
+            (
+                pull_requests[0].head.as_str(),
+                pull_requests[0].base.as_str()
+            ),
+            ("ch/branch-1", "main")
+        );
+        assert_eq!(
+            (
+                pull_requests[1].head.as_str(),
+                pull_requests[1].base.as_str()
+            ),
+            ("ch/branch-2", "ch/branch-1")
+        );
+        assert_eq!(pull_requests[1].title, "Two");
+        assert!(pull_requests[1].body.contains("ch/branch-1"));
+
+        // Submitting again updates the same pull requests instead of opening new ones.
+        repo.run(|ctx| ctx.tx.set_config(config::SUBMIT_TITLE_PREFIX, "true"))?;
+        repo.run(|ctx| submit(ctx, &submit_opt))?;
+        let pull_requests = github.pull_requests();
+        assert_eq!(pull_requests.len(), 2);
+        assert_eq!(pull_requests[0].title, "[1/2] One");
+        assert_eq!(pull_requests[1].title, "[2/2] Two");
+        Ok(())
+    }
+
+    #[test]
+    fn test_submit_opens_pull_request_once_when_response_is_lost() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let github = repo.mock_github()?;
+        repo.run(|ctx| ctx.tx.set_config(config::SUBMIT_PULL_REQUESTS, "true"))?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+
+        // The pull request is opened, but its response never makes it back.
+        github.lose_response("POST", "/pulls");
+        let submit_opt = SubmitOpt {
+            no_verify: false,
+            push_options: vec![],
+            flush: false,
+            no_pr: false,
+            no_reviewers: false,
+            closes: vec![],
+        };
+        repo.run(|ctx| submit(ctx, &submit_opt))?;
+        let pull_requests = github.pull_requests();
+        assert_eq!(pull_requests.len(), 1);
+        repo.run(|ctx| {
+            assert_eq!(
+                ctx.tx.get_pull_requests()?.get("ch/branch-1"),
+                Some(&pull_requests[0].number)
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_find_pull_request_statuses_fetches_whole_stack() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let _github = repo.mock_github()?;
+        repo.run(|ctx| ctx.tx.set_config(config::SUBMIT_PULL_REQUESTS, "true"))?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        repo.run(|ctx| {
+            submit(
+                ctx,
+                &SubmitOpt {
+                    no_verify: false,
+                    push_options: vec![],
+                    flush: false,
+                    no_pr: false,
+                    no_reviewers: false,
+                    closes: vec![],
+                },
+            )
+        })?;
+        create_branch(&mut repo, "ch/branch-3")?;
+
+        repo.run(|ctx| {
+            let github = connect_github(ctx, "origin")?;
+            let branches = ["ch/branch-1", "ch/branch-2", "ch/branch-3"].map(str::to_owned);
+            let statuses = find_pull_request_statuses(&ctx.tx, &github, &branches)?;
+            assert_eq!(statuses.len(), 2);
+            let status = &statuses["ch/branch-2"];
+            assert_eq!(status.pull_request.base.branch, "ch/branch-1");
+            assert_eq!(
+                status.pull_request.head.sha,
+                git::rev_parse(&ctx.repo_root, "ch/branch-2")?
+            );
+            assert_eq!(status.check_status, CheckStatus::Success);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_github_requests_go_through_configured_proxy() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let proxy = repo.mock_github()?;
+        repo.run(|ctx| {
+            ctx.tx
+                .set_config(config::GITHUB_PROXY, &proxy.endpoint().api_url)?;
+            // The API's host doesn't resolve, so requests only succeed through the proxy.
+            ctx.github_endpoint = Some(github::Endpoint {
+                api_url: "http://api.github.invalid".to_owned(),
+                token: "mock-token".to_owned(),
+            });
+            let github = connect_github(ctx, "origin")?;
+            assert!(github.find_pull_request("ch/branch-1")?.is_none());
+            Ok(())
+        })?;
+        assert_eq!(proxy.tunnels(), ["api.github.invalid:80"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_review_threads_show_their_diff_and_comments() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let github = repo.mock_github()?;
+        repo.run(|ctx| ctx.tx.set_config(config::SUBMIT_PULL_REQUESTS, "true"))?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        repo.run(|ctx| {
+            submit(
+                ctx,
+                &SubmitOpt {
+                    no_verify: false,
+                    push_options: vec![],
+                    flush: false,
+                    no_pr: false,
+                    no_reviewers: false,
+                    closes: vec![],
+                },
+            )
+        })?;
+        github.add_review_thread(mock_github::MockReviewThread {
+            number: 1,
+            path: "one.txt".to_owned(),
+            line: 5,
+            diff_hunk: "@@ -0,0 +1,5 @@\n+a\n+b\n+c\n+d\n+e".to_owned(),
+            resolved: false,
+            comments: vec![
+                ("alice".to_owned(), "Why five?\nFour is plenty.".to_owned()),
+                ("bob".to_owned(), "Fair.".to_owned()),
+            ],
+        });
+        github.add_review_thread(mock_github::MockReviewThread {
+            number: 1,
+            path: "one.txt".to_owned(),
+            line: 1,
+            diff_hunk: "@@ -0,0 +1,1 @@\n+a".to_owned(),
+            resolved: true,
+            comments: vec![("alice".to_owned(), "Nit.".to_owned())],
+        });
+
+        repo.run(|ctx| {
+            let github = connect_github(ctx, "origin")?;
+            let threads = github.get_review_threads(1)?;
+            let text: Vec<String> = threads.iter().map(format_review_thread).collect();
+            assert_eq!(
+                text,
+                [
+                    "one.txt:5\n    +b\n    +c\n    +d\n    +e\n  alice: Why five?\n    Four is plenty.\n  bob: Fair.\n",
+                    "one.txt:1 (resolved)\n    +a\n  alice: Nit.\n",
+                ]
+            );
+            assert!(github.get_review_threads(2).is_err());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_force_push_blocker_reads_branch_protection() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let github = repo.mock_github()?;
+        create_branch(&mut repo, "ch/fix#1")?;
+        repo.commit("one.txt", "one", "One")?;
+        repo.git(&["push", "--quiet", "origin", "ch/fix#1"])?;
+
+        repo.run(|ctx| {
+            let client = connect_github(ctx, "origin")?;
+            assert_eq!(client.force_push_blocker("ch/fix#1")?, None);
+            github.protect("ch/fix#1", true);
+            assert_eq!(client.force_push_blocker("ch/fix#1")?, None);
+            github.protect("ch/fix#1", false);
+            assert_eq!(
+                client.force_push_blocker("ch/fix#1")?.as_deref(),
+                Some("it's a protected branch")
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_pr_checks_reads_every_page() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let github = repo.mock_github()?;
+        repo.run(|ctx| ctx.tx.set_config(config::SUBMIT_PULL_REQUESTS, "true"))?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        repo.run(|ctx| {
+            submit(
+                ctx,
+                &SubmitOpt {
+                    no_verify: false,
+                    push_options: vec![],
+                    flush: false,
+                    no_pr: false,
+                    no_reviewers: false,
+                    closes: vec![],
+                },
+            )
+        })?;
+        for i in 0..100 {
+            github.add_check_run(&format!("test-{i}"), "success");
+        }
+        // Only the second page of check runs has the failure.
+        github.add_check_run("lint", "failure");
+
+        repo.run(|ctx| {
+            let github = connect_github(ctx, "origin")?;
+            let sha = git::rev_parse(&ctx.repo_root, "ch/branch-1")?;
+            let check_runs = github.get_check_runs(&sha)?;
+            assert_eq!(check_runs.len(), 101);
+            assert_eq!(
+                check_runs[100].duration(),
+                Some(std::time::Duration::from_secs(90))
+            );
+
+            let err = pr_checks(ctx, &PrChecksOpt { fail_fast: true }).unwrap_err();
+            assert!(err.to_string().contains("Checks failed for `ch/branch-1`."));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_checkout_pr_fetches_and_tracks_its_branch() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let _github = repo.mock_github()?;
+        repo.run(|ctx| ctx.tx.set_config(config::SUBMIT_PULL_REQUESTS, "true"))?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        repo.run(|ctx| {
+            submit(
+                ctx,
+                &SubmitOpt {
+                    no_verify: false,
+                    push_options: vec![],
+                    flush: false,
+                    no_pr: false,
+                    no_reviewers: false,
+                    closes: vec![],
+                },
+            )
+        })?;
+        // As though the top of the stack had been opened by someone else.
+        repo.git(&["checkout", "--quiet", "main"])?;
+        repo.git(&["branch", "--quiet", "-D", "ch/branch-2"])?;
+        repo.run(|ctx| ctx.tx.remove_branch("ch/branch-2"))?;
+
+        let checkout_pr = |repo: &mut TestRepo, number| {
+            repo.run(|ctx| {
+                checkout(
+                    ctx,
+                    &CheckoutOpt {
+                        branch: None,
+                        stack: None,
+                        pr: Some(number),
+                    },
+                )
+            })
+        };
+        let err = checkout_pr(&mut repo, 1).unwrap_err();
+        assert!(err.to_string().contains("already exists"));
+        // A failed checkout doesn't leave the new branch behind, so it can be retried.
+        std::fs::write(repo.root.join("two.txt"), "in the way")?;
+        assert!(checkout_pr(&mut repo, 2).is_err());
+        assert!(repo.git(&["rev-parse", "--verify", "ch/branch-2"]).is_err());
+        std::fs::remove_file(repo.root.join("two.txt"))?;
+        checkout_pr(&mut repo, 2)?;
+        assert_eq!(repo.current_branch()?, "ch/branch-2");
+        assert_eq!(
+            repo.git(&["rev-parse", "HEAD"])?,
+            repo.remote_git(&["rev-parse", "ch/branch-2"])?
+        );
+        repo.run(|ctx| {
+            assert_eq!(
+                ctx.tx.get_parent("ch/branch-2")?.as_deref(),
+                Some("ch/branch-1")
+            );
+            assert_eq!(ctx.tx.get_pull_requests()?["ch/branch-2"], 2);
+            Ok(())
+        })?;
+        assert!(checkout_pr(&mut repo, 3).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_tracks_a_chain_of_pull_requests() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let _github = repo.mock_github()?;
+        repo.run(|ctx| ctx.tx.set_config(config::SUBMIT_PULL_REQUESTS, "true"))?;
+        for (branch, file) in [
+            ("ch/branch-1", "one.txt"),
+            ("ch/branch-2", "two.txt"),
+            ("ch/branch-3", "three.txt"),
+        ] {
+            create_branch(&mut repo, branch)?;
+            repo.commit(file, file, file)?;
+        }
+        repo.run(|ctx| {
+            submit(
+                ctx,
+                &SubmitOpt {
+                    no_verify: false,
+                    push_options: vec![],
+                    flush: false,
+                    no_pr: false,
+                    no_reviewers: false,
+                    closes: vec![],
+                },
+            )
+        })?;
+        // As though the stack had been made with another tool.
+        repo.git(&["checkout", "--quiet", "main"])?;
+        for branch in ["ch/branch-3", "ch/branch-2", "ch/branch-1"] {
+            repo.git(&["branch", "--quiet", "-D", branch])?;
+            repo.run(|ctx| ctx.tx.remove_branch(branch))?;
+        }
+
+        repo.run(|ctx| import(ctx, &ImportOpt { from_prs: 3 }))?;
+        repo.run(|ctx| {
+            let pull_requests = ctx.tx.get_pull_requests()?;
+            for (number, branch, parent) in [
+                (1, "ch/branch-1", "main"),
+                (2, "ch/branch-2", "ch/branch-1"),
+                (3, "ch/branch-3", "ch/branch-2"),
+            ] {
+                assert_eq!(ctx.tx.get_parent(branch)?.as_deref(), Some(parent));
+                assert_eq!(pull_requests[branch], number);
+            }
+            Ok(())
+        })?;
+        assert_eq!(
+            repo.git(&["rev-parse", "ch/branch-3"])?,
+            repo.remote_git(&["rev-parse", "ch/branch-3"])?
+        );
+        let err = repo
+            .run(|ctx| import(ctx, &ImportOpt { from_prs: 3 }))
+            .unwrap_err();
+        assert!(err.to_string().contains("already tracked"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_adopts_existing_branches() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let _github = repo.mock_github()?;
+        repo.run(|ctx| ctx.tx.set_config(config::SUBMIT_PULL_REQUESTS, "true"))?;
+        for (branch, file) in [
+            ("ch/branch-1", "one.txt"),
+            ("ch/branch-2", "two.txt"),
+            ("ch/branch-3", "three.txt"),
+        ] {
+            create_branch(&mut repo, branch)?;
+            repo.commit(file, file, file)?;
+        }
+        repo.run(|ctx| {
+            submit(
+                ctx,
+                &SubmitOpt {
+                    no_verify: false,
+                    push_options: vec![],
+                    flush: false,
+                    no_pr: false,
+                    no_reviewers: false,
+                    closes: vec![],
+                },
+            )
+        })?;
+        // As though the stack had been made here before diamond, and work carried on in the middle of it.
+        for branch in ["ch/branch-3", "ch/branch-2", "ch/branch-1"] {
+            repo.run(|ctx| ctx.tx.remove_branch(branch))?;
+        }
+        repo.git(&["checkout", "--quiet", "ch/branch-2"])?;
+        let ahead = repo.commit("two.txt", "two, again", "Two again")?;
+        repo.git(&["branch", "--quiet", "--force", "ch/branch-3", "ch/branch-1"])?;
+
+        let err = repo
+            .run(|ctx| import(ctx, &ImportOpt { from_prs: 3 }))
+            .unwrap_err();
+        assert!(err.to_string().contains("missing some of its commits"));
+
+        repo.git(&["branch", "--quiet", "--delete", "--force", "ch/branch-3"])?;
+        repo.run(|ctx| import(ctx, &ImportOpt { from_prs: 3 }))?;
+        assert_eq!(repo.current_branch()?, "ch/branch-2");
+        assert_eq!(repo.git(&["rev-parse", "ch/branch-2"])?, ahead);
+        assert_eq!(
+            repo.git(&["rev-parse", "ch/branch-3"])?,
+            repo.remote_git(&["rev-parse", "ch/branch-3"])?
+        );
+        repo.run(|ctx| {
+            assert_eq!(ctx.tx.get_parent("ch/branch-1")?.as_deref(), Some("main"));
+            assert_eq!(
+                ctx.tx.get_parent("ch/branch-3")?.as_deref(),
+                Some("ch/branch-2")
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_dash_groups_open_pull_requests_by_stack() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let mock_github = repo.mock_github()?;
+        repo.run(|ctx| ctx.tx.set_config(config::SUBMIT_PULL_REQUESTS, "true"))?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        repo.run(|ctx| {
+            submit(
+                ctx,
+                &SubmitOpt {
+                    no_verify: false,
+                    push_options: vec![],
+                    flush: false,
+                    no_pr: false,
+                    no_reviewers: false,
+                    closes: vec![],
+                },
+            )
+        })?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+        create_branch(&mut repo, "ch/unsubmitted")?;
+        mock_github.review("ch/branch-1", "APPROVED");
+
+        repo.run(|ctx| {
+            let pull_requests = ctx.tx.get_pull_requests()?;
+            assert_eq!(pull_requests["ch/branch-1"], 1);
+            assert_eq!(pull_requests["ch/branch-2"], 2);
+            // A stale number is replaced with the open pull request from the branch.
+            ctx.tx.set_pull_request("ch/branch-1", 99)?;
+
+            let github = connect_github(ctx, "origin")?;
+            assert_eq!(
+                dash_stacks(ctx, &github)?,
+                [(
+                    "ch/branch-2".to_owned(),
+                    vec![
+                        "#1 ch/branch-1: approved, checks passed, opened 0 minutes ago (https://github.com/diamond/test-repo/pull/1)"
+                            .to_owned(),
+                        "#2 ch/branch-2: checks passed, opened 0 minutes ago (https://github.com/diamond/test-repo/pull/2)"
+                            .to_owned(),
+                    ]
+                )]
+            );
+            assert_eq!(ctx.tx.get_pull_requests()?["ch/branch-1"], 1);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_land_merges_pull_request_and_restacks_children() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let github = repo.mock_github()?;
+        repo.run(|ctx| ctx.tx.set_config(config::SUBMIT_PULL_REQUESTS, "true"))?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        repo.run(|ctx| {
+            submit(
+                ctx,
+                &SubmitOpt {
+                    no_verify: false,
+                    push_options: vec![],
+                    flush: false,
+                    no_pr: false,
+                    no_reviewers: false,
+                    closes: vec![],
+                },
+            )
+        })?;
+
+        repo.git(&["checkout", "--quiet", "ch/branch-1"])?;
+        repo.run(|ctx| {
+            land(
+                ctx,
+                &LandOpt {
+                    wait: false,
+                    until: None,
+                    interval: 30,
+                    method: Some(MergeMethod::Squash),
+                },
+            )
+        })?;
+        assert!(github.pull_request("ch/branch-1").unwrap().merged);
+        assert_eq!(github.pull_request("ch/branch-2").unwrap().base, "main");
+        assert!(repo.is_ancestor("main", "ch/branch-2")?);
+        assert_eq!(
+            repo.git(&["rev-parse", "main"])?,
+            repo.remote_git(&["rev-parse", "main"])?
+        );
+        assert_eq!(repo.git(&["show", "main:one.txt"])?, "one");
+        repo.run(|ctx| {
+            assert_eq!(ctx.tx.get_parent("ch/branch-2")?.as_deref(), Some("main"));
+            assert_eq!(ctx.tx.get_parent("ch/branch-1")?, None);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_pr_merge_only_merges_the_current_branch() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let github = repo.mock_github()?;
+        repo.run(|ctx| ctx.tx.set_config(config::SUBMIT_PULL_REQUESTS, "true"))?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        repo.run(|ctx| {
+            submit(
+                ctx,
+                &SubmitOpt {
+                    no_verify: false,
+                    push_options: vec![],
+                    flush: false,
+                    no_pr: false,
+                    no_reviewers: false,
+                    closes: vec![],
+                },
+            )
+        })?;
+        github.add_check_run("build", "success");
+
+        repo.git(&["checkout", "--quiet", "ch/branch-1"])?;
+        repo.run(|ctx| {
+            pr_merge(
+                ctx,
+                &PrMergeOpt {
+                    interval: 30,
+                    method: Some(MergeMethod::Squash),
+                },
+            )
+        })?;
+        assert!(github.pull_request("ch/branch-1").unwrap().merged);
+        assert!(!github.pull_request("ch/branch-2").unwrap().merged);
+        assert_eq!(github.pull_request("ch/branch-2").unwrap().base, "main");
+        Ok(())
+    }
+
+    #[test]
+    fn test_land_wait_lands_the_whole_stack() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let github = repo.mock_github()?;
+        repo.run(|ctx| ctx.tx.set_config(config::SUBMIT_PULL_REQUESTS, "true"))?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        create_branch(&mut repo, "ch/branch-3")?;
+        repo.commit("three.txt", "three", "Three")?;
+        repo.run(|ctx| {
+            submit(
+                ctx,
+                &SubmitOpt {
+                    no_verify: false,
+                    push_options: vec![],
+                    flush: false,
+                    no_pr: false,
+                    no_reviewers: false,
+                    closes: vec![],
+                },
+            )
+        })?;
+
+        repo.git(&["checkout", "--quiet", "ch/branch-1"])?;
+        repo.run(|ctx| {
+            land(
+                ctx,
+                &LandOpt {
+                    wait: true,
+                    until: None,
+                    interval: 30,
+                    method: Some(MergeMethod::Squash),
+                },
+            )
+        })?;
+        for branch in ["ch/branch-1", "ch/branch-2", "ch/branch-3"] {
+            assert!(github.pull_request(branch).unwrap().merged, "{branch}");
+        }
+        assert_eq!(repo.git(&["show", "main:three.txt"])?, "three");
+        repo.run(|ctx| {
+            assert!(ctx.tx.get_all_branches()?.is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_land_remembers_merge_when_restacking_conflicts() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let github = repo.mock_github()?;
+        repo.run(|ctx| ctx.tx.set_config(config::SUBMIT_PULL_REQUESTS, "true"))?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        repo.run(|ctx| {
+            submit(
+                ctx,
+                &SubmitOpt {
+                    no_verify: false,
+                    push_options: vec![],
+                    flush: false,
+                    no_pr: false,
+                    no_reviewers: false,
+                    closes: vec![],
+                },
+            )
+        })?;
+        // Someone else lands a change to the same file as `ch/branch-2` in the meantime.
+        repo.git(&["checkout", "--quiet", "main"])?;
+        repo.commit("two.txt", "other", "Other")?;
+        repo.git(&["push", "--quiet", "origin", "main"])?;
+        repo.git(&["reset", "--quiet", "--hard", "HEAD~1"])?;
+
+        repo.git(&["checkout", "--quiet", "ch/branch-1"])?;
+        let result = repo.run(|ctx| {
+            land(
+                ctx,
+                &LandOpt {
+                    wait: false,
+                    until: None,
+                    interval: 30,
+                    method: Some(MergeMethod::Squash),
+                },
+            )
+        });
+        assert!(result.is_err());
+        assert!(github.pull_request("ch/branch-1").unwrap().merged);
+        let _ = repo.git(&["rebase", "--abort"]);
+        repo.run(|ctx| {
+            assert_eq!(ctx.tx.get_parent("ch/branch-1")?, None);
+            assert_eq!(ctx.tx.get_parent("ch/branch-2")?.as_deref(), Some("main"));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_recover_rebuilds_stacks_from_trailers_and_reflog() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        repo.run(|ctx| {
+            config::set(
+                &mut ctx.tx,
+                config::find_key(config::COMMIT_PARENT_TRAILER)?,
+                "true",
+            )
+        })?;
+        for (branch, file) in [("ch/branch-1", "one.txt"), ("ch/branch-2", "two.txt")] {
+            std::fs::write(repo.root.join(file), branch)?;
+            repo.git(&["add", file])?;
+            repo.run(|ctx| {
+                create(
+                    ctx,
+                    &CreateOpt {
+                        branch: Some(branch.to_owned()),
+                        message: Some(format!("Add {file}")),
+                        all: false,
+                        slugify: false,
+                        closes: vec![],
+                    },
+                )
+            })?;
+        }
+        assert_eq!(
+            repo.git(&[
+                "log",
+                "-1",
+                "--format=%(trailers:key=Diamond-Parent,valueonly)"
+            ])?
+            .trim(),
+            "ch/branch-1"
+        );
+        // Without a trailer, the reflog knows where the branch came from.
+        repo.git(&["checkout", "--quiet", "-b", "ch/side", "ch/branch-1"])?;
+        repo.commit("side.txt", "side", "Side")?;
+
+        repo.run(|ctx| {
+            ctx.tx.remove_branch("ch/branch-2")?;
+            ctx.tx.remove_branch("ch/branch-1")?;
+            recover(
+                ctx,
+                &RecoverOpt {
+                    root_branch: None,
+                    remote: None,
+                },
+            )?;
+            assert_eq!(ctx.tx.get_parent("ch/branch-1")?.as_deref(), Some("main"));
+            assert_eq!(
+                ctx.tx.get_parent("ch/branch-2")?.as_deref(),
+                Some("ch/branch-1")
+            );
+            assert_eq!(
+                ctx.tx.get_parent("ch/side")?.as_deref(),
+                Some("ch/branch-1")
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_daemon_records_merged_and_outdated_branches() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+        create_branch(&mut repo, "ch/other")?;
+        repo.commit("other.txt", "other", "Other")?;
+        // `ch/branch-1` lands on the remote's `main`, which the local `main` hasn't caught up with.
+        repo.git(&["push", "--quiet", "origin", "ch/branch-1:main"])?;
+        let main_commit = repo.git(&["rev-parse", "main"])?;
+
+        let daemon_opt = DaemonOpt {
+            interval: 0,
+            once: true,
+        };
+        repo.run(|ctx| daemon(ctx, &daemon_opt))?;
+        repo.run(|ctx| {
+            let report = ctx.tx.get_daemon_report()?.unwrap();
+            assert_eq!(report.root_behind, 1);
+            assert_eq!(report.merged, ["ch/branch-1"]);
+            assert_eq!(
+                report.outdated,
+                [("ch/other".to_owned(), "1 behind `origin/main`".to_owned())]
+            );
+            Ok(())
+        })?;
+        // Nothing was pulled or rewritten.
+        assert_eq!(repo.git(&["rev-parse", "main"])?, main_commit);
+        assert_eq!(
+            repo.git(&["rev-parse", "--abbrev-ref", "HEAD"])?,
+            "ch/other"
+        );
+
+        // Once the remote's `main` moves on, the branch on the merged one is behind it, not behind the merged branch.
+        repo.git(&["checkout", "--quiet", "--detach", "ch/branch-1"])?;
+        repo.commit("three.txt", "three", "Three")?;
+        repo.git(&["push", "--quiet", "origin", "HEAD:main"])?;
+        repo.git(&["checkout", "--quiet", "ch/other"])?;
+        repo.run(|ctx| daemon(ctx, &daemon_opt))?;
+        repo.run(|ctx| {
+            let report = ctx.tx.get_daemon_report()?.unwrap();
+            assert_eq!(report.merged, ["ch/branch-1"]);
+            assert_eq!(
+                report.outdated,
+                [
+                    (
+                        "ch/branch-2".to_owned(),
+                        "1 behind `origin/main`".to_owned()
+                    ),
+                    ("ch/other".to_owned(), "2 behind `origin/main`".to_owned()),
+                ]
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_resolve_branch_suggests_and_matches_prefixes() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        create_branch(&mut repo, "ch/fix-leak")?;
+        repo.git(&["branch", "untracked"])?;
+        repo.run(|ctx| {
+            assert_eq!(resolve_branch(ctx, "ch/branch-1")?, "ch/branch-1");
+            assert_eq!(resolve_branch(ctx, "main")?, "main");
+            assert_eq!(resolve_branch(ctx, "untracked")?, "untracked");
+
+            let err = resolve_branch(ctx, "ch/fix-lek").unwrap_err();
+            assert_eq!(exit::code(&err), ExitCode::NotTracked);
+            assert_eq!(
+                err.to_string(),
+                "There is no branch named `ch/fix-lek`. Did you mean `ch/fix-leak`?"
+            );
+            assert_eq!(
+                resolve_branch(ctx, "branch-3").unwrap_err().to_string(),
+                "There is no branch named `branch-3`. Did you mean one of `ch/branch-1`, `ch/branch-2`?"
+            );
+            // Prefixes are only accepted when `branch.match-prefix` is set.
+            assert!(resolve_branch(ctx, "ch/fix").is_err());
+            ctx.tx.set_config(config::BRANCH_MATCH_PREFIX, "true")?;
+            assert_eq!(resolve_branch(ctx, "ch/fix")?, "ch/fix-leak");
+            assert_eq!(
+                resolve_branch(ctx, "ch/br").unwrap_err().to_string(),
+                "`ch/br` could be any of `ch/branch-1`, `ch/branch-2`."
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_outdated_lists_branches_restack_would_rebase() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+        create_branch(&mut repo, "ch/other")?;
+        repo.commit("other.txt", "other", "Other")?;
+        repo.run(|ctx| {
+            assert!(outdated_branches(ctx, None, &[])?.is_empty());
+            Ok(())
+        })?;
+
+        repo.git(&["checkout", "--quiet", "ch/branch-1"])?;
+        repo.commit("one.txt", "one, amended", "Amend one")?;
+        repo.run(|ctx| {
+            assert_eq!(
+                outdated_branches(ctx, None, &[])?,
+                [(
+                    "ch/branch-2".to_owned(),
+                    "1 behind `ch/branch-1`".to_owned()
+                )]
+            );
+            Ok(())
+        })?;
+
+        repo.git(&["checkout", "--quiet", "main"])?;
+        repo.commit("main.txt", "main", "Main")?;
+        repo.run(|ctx| {
+            assert_eq!(
+                outdated_branches(ctx, None, &[])?,
+                [
+                    ("ch/branch-1".to_owned(), "1 behind `main`".to_owned()),
+                    (
+                        "ch/branch-2".to_owned(),
+                        "`ch/branch-1` is outdated".to_owned()
+                    ),
+                    ("ch/other".to_owned(), "1 behind `main`".to_owned()),
+                ]
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_serve_answers_json_rpc_requests() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+
+        let socket = repo.root.join(".git").join("test.sock");
+        let client = {
+            let socket = socket.clone();
+            std::thread::spawn(move || -> anyhow::Result<Vec<serde_json::Value>> {
+                // A client which stays connected without asking anything doesn't hold up the others.
+                let _idle = loop {
+                    match UnixStream::connect(&socket) {
+                        Ok(stream) => break stream,
+                        Err(_) => std::thread::sleep(Duration::from_millis(10)),
+                    }
+                };
+                let stream = UnixStream::connect(&socket)?;
+                let mut writer = stream.try_clone()?;
+                let mut lines = BufReader::new(stream).lines();
+                let mut responses = Vec::new();
+                for request in [
+                    r#"{"jsonrpc": "2.0", "id": 1, "method": "status"}"#,
+                    r#"{"jsonrpc": "2.0", "id": 2, "method": "checkout", "params": {"branch": "ch/branch-1"}}"#,
+                    r#"{"jsonrpc": "2.0", "method": "log"}"#,
+                    r#"{"jsonrpc": "2.0", "id": 3, "method": "log"}"#,
+                    r#"{"jsonrpc": "2.0", "id": 4, "method": "checkout", "params": {"branch": "ch/missing"}}"#,
+                    r#"{"jsonrpc": "2.0", "id": 5, "method": "frobnicate"}"#,
+                    r#"{"jsonrpc": "2.0", "id": 6, "method": "shutdown"}"#,
+                ] {
+                    writeln!(writer, "{request}")?;
+                }
+                for line in lines.by_ref() {
+                    responses.push(serde_json::from_str(&line?)?);
+                }
+                Ok(responses)
+            })
+        };
+        repo.run(|ctx| {
+            serve(
+                ctx,
+                &ServeOpt {
+                    socket: Some(socket.clone()),
+                },
+            )
+        })?;
+        let responses = client.join().unwrap()?;
+        assert!(!socket.exists());
+
+        // The notification isn't answered.
+        assert_eq!(responses.len(), 6);
+        assert_eq!(responses[0]["result"]["branch"], "ch/branch-2");
+        assert_eq!(responses[0]["result"]["parent"], "ch/branch-1");
+        assert_eq!(responses[0]["result"]["ahead"], 1);
+        assert_eq!(
+            responses[0]["result"]["stack"],
+            serde_json::json!(["ch/branch-1", "ch/branch-2"])
+        );
+        assert_eq!(responses[1]["result"]["branch"], "ch/branch-1");
+        assert_eq!(responses[2]["id"], 3);
+        assert_eq!(responses[2]["result"]["current"], "ch/branch-1");
+        assert_eq!(
+            responses[2]["result"]["branches"].as_array().unwrap().len(),
+            2
+        );
+        assert!(responses[3]["error"]["message"].is_string());
+        assert_eq!(responses[4]["error"]["code"], -32601);
+        assert_eq!(responses[5]["result"], serde_json::Value::Null);
+        assert_eq!(repo.current_branch()?, "ch/branch-1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_foreach_passes_arguments_through() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.run(|ctx| {
+            foreach(
+                ctx,
+                &ForeachOpt {
+                    fail_fast: true,
+                    command: [
+                        "git",
+                        "commit",
+                        "--quiet",
+                        "--allow-empty",
+                        "-m",
+                        "Don't split $this",
+                    ]
+                    .map(str::to_owned)
+                    .to_vec(),
+                },
+            )
+        })?;
+        for branch in ["ch/branch-1", "ch/branch-2"] {
+            assert_eq!(
+                repo.git(&["log", "-1", "--format=%s", branch])?,
+                "Don't split $this"
+            );
+        }
+        assert_eq!(repo.current_branch()?, "ch/branch-2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_stack_problems() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/conflicting")?;
+        repo.commit("shared.txt", "branch", "Branch")?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+        create_branch(&mut repo, "ch/behind")?;
+        repo.commit("behind.txt", "behind", "Behind")?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+        repo.commit("shared.txt", "main", "Main")?;
+
+        repo.run(|ctx| {
+            let counts = CommitCounts {
+                ahead: 1,
+                behind: 1,
+                remote: None,
+            };
+            let problems = stack_problems(
+                ctx,
+                &database::Branch {
+                    name: "ch/behind".to_owned(),
+                    parent: "main".to_owned(),
+                },
+                &counts,
+            )?;
+            assert_eq!(problems.len(), 1);
+            assert!(problems[0].starts_with("::error title=Needs restack::"));
+
+            let counts = CommitCounts {
+                remote: Some((1, 2)),
+                ..counts
+            };
+            let problems = stack_problems(
+                ctx,
+                &database::Branch {
+                    name: "ch/conflicting".to_owned(),
+                    parent: "main".to_owned(),
+                },
+                &counts,
+            )?;
+            assert_eq!(problems.len(), 2);
+            assert!(problems[0].starts_with("::error title=Restack conflict::"));
+            assert!(problems[0].contains("shared.txt"));
+            assert!(problems[1].starts_with("::error title=Diverged from remote::"));
+
+            let counts = CommitCounts {
+                behind: 0,
+                remote: Some((1, 0)),
+                ..counts
+            };
+            assert!(stack_problems(
+                ctx,
+                &database::Branch {
+                    name: "ch/behind".to_owned(),
+                    parent: "main".to_owned(),
+                },
+                &counts,
+            )?
+            .is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_render_stats() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/new")?;
+        repo.commit("new.txt", "new", "New")?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+        create_branch(&mut repo, "ch/base")?;
+        repo.commit("base.txt", "base", "Base")?;
+        create_branch(&mut repo, "ch/old")?;
+        repo.commit("old.txt", "old", "Old")?;
+        repo.git(&[
+            "commit",
+            "--quiet",
+            "--amend",
+            "--no-edit",
+            "--date=2000-01-01T00:00:00Z",
+        ])?;
+
+        repo.run(|ctx| {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs();
+            let stats = render_stats(ctx, now)?;
+            assert!(
+                stats.contains("ch/new: 1 branches, 1 commits, 0 behind `main`, oldest branch `ch/new` started 0 minutes ago"),
+                "{stats}"
+            );
+            assert!(
+                stats.contains("ch/base: 2 branches, 2 commits, 0 behind `main`, oldest branch `ch/old` started"),
+                "{stats}"
+            );
+            assert!(
+                stats.ends_with(&format!(
+                    "Oldest branch: `ch/old`, started {}.\n",
+                    format_age(now - 946684800)
+                )),
+                "{stats}"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_shell_prompt() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        assert_eq!(shell_prompt(&repo.root)?, None);
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        repo.commit("three.txt", "three", "Three")?;
+        assert_eq!(
+            shell_prompt(&repo.root)?.as_deref(),
+            Some("main→ch/branch-1→*ch/branch-2 (2↑)")
+        );
+
+        repo.git(&["checkout", "--quiet", "ch/branch-1"])?;
+        repo.commit("one.txt", "one, amended", "Amend one")?;
+        repo.git(&["checkout", "--quiet", "ch/branch-2"])?;
+        assert_eq!(
+            shell_prompt(&repo.root)?.as_deref(),
+            Some("main→ch/branch-1→*ch/branch-2 (2↑ 1↓)")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_prompt() {
+        let stack = ["main".to_owned(), "ch/one".to_owned()];
+        assert_eq!(render_prompt(&stack, 0, 0), "main→*ch/one");
+        assert_eq!(render_prompt(&stack, 0, 3), "main→*ch/one (3↓)");
+    }
+
+    #[test]
+    fn test_config_set_validates_and_applies() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let set = |key: &str, value: &str| {
+            ConfigOpt::Set(ConfigSetOpt {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            })
+        };
+        assert!(repo
+            .run(|ctx| config(ctx, &set(config::REMOTE, "upstream")))
+            .is_err());
+        assert!(repo
+            .run(|ctx| config(ctx, &set(config::ROOT_BRANCH, "not-a-branch")))
+            .is_err());
+        repo.run(|ctx| config(ctx, &set(config::ROOT_BRANCH, "main")))?;
+
+        repo.run(|ctx| config(ctx, &set(config::BRANCH_PREFIX, "ch/")))?;
+        create_branch(&mut repo, "feature")?;
+        assert_eq!(repo.current_branch()?, "ch/feature");
+        repo.run(|ctx| {
+            assert_eq!(ctx.tx.get_parent("ch/feature")?.as_deref(), Some("main"));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_config_edit_applies_valid_changes() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        repo.run(|ctx| ctx.tx.set_config(config::BRANCH_PREFIX, "ch/"))?;
+
+        // Edits the file the way someone would in their editor.
+        repo.run(|ctx| {
+            let contents = config::render_file(&ctx.tx)?
+                .replace("# rebase.signoff =\n", "rebase.signoff = yes\n")
+                .replace("branch-prefix = ch/\n", "# branch-prefix = ch/\n");
+            let changes = config_changes(ctx, &contents)?;
+            assert_eq!(changes.len(), 2);
+            apply_config_changes(ctx, changes)
+        })?;
+        repo.run(|ctx| {
+            assert_eq!(
+                ctx.tx.get_config(config::REBASE_SIGNOFF)?.as_deref(),
+                Some("true")
+            );
+            assert_eq!(ctx.tx.get_config(config::BRANCH_PREFIX)?, None);
+
+            // Rendering the config again changes nothing.
+            assert!(config_changes(ctx, &config::render_file(&ctx.tx)?)?.is_empty());
+
+            let contents = config::render_file(&ctx.tx)?;
+            assert!(
+                config_changes(ctx, &contents.replace("remote = origin", "# remote =")).is_err()
+            );
+            assert!(config_changes(
+                ctx,
+                &contents.replace("remote = origin", "remote = upstream")
+            )
+            .is_err());
+            assert!(config_changes(
+                ctx,
+                &contents.replace("rebase.signoff = true", "rebase.signoff = maybe")
+            )
+            .is_err());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_edit_restacks_descendants() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        repo.commit("two.txt", "two", "Two")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("three.txt", "three", "Three")?;
+
+        // Drop the first commit on `ch/branch-1`, in place of an editor.
+        repo.git(&["config", "sequence.editor", "sed -i -e 1d"])?;
+        repo.run(|ctx| {
+            edit(
+                ctx,
+                &EditOpt {
+                    branch: Some("ch/branch-1".to_owned()),
+                },
+            )
+        })?;
+
+        assert_eq!(repo.current_branch()?, "ch/branch-2");
+        assert!(repo.is_ancestor("ch/branch-1", "ch/branch-2")?);
+        assert_eq!(
+            repo.git(&["log", "--format=%s", "main..ch/branch-2"])?,
+            "Three\nTwo"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_edit_stops_when_restacking_a_descendant_conflicts() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        repo.commit("two.txt", "two", "Two")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("one.txt", "one, changed", "Change one")?;
+
+        // Dropping `One` conflicts with the change to it on `ch/branch-2`.
+        repo.git(&["config", "sequence.editor", "sed -i -e 1d"])?;
+        let err = repo
+            .run(|ctx| {
+                edit(
+                    ctx,
+                    &EditOpt {
+                        branch: Some("ch/branch-1".to_owned()),
+                    },
+                )
+            })
+            .unwrap_err();
+
+        assert_eq!(exit::code(&err), ExitCode::Conflict);
+        assert!(repo.root.join(".git/rebase-merge").exists());
+        repo.git(&["rebase", "--abort"])?;
+        assert_eq!(repo.current_branch()?, "ch/branch-2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_edit_stops_when_the_rebase_stops() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        let mut base_commit = None;
+        repo.run(|ctx| {
+            base_commit = ctx.tx.get_base_commit("ch/branch-1")?;
+            Ok(())
+        })?;
+
+        // Stop at the first commit on `ch/branch-1`, in place of an editor.
+        repo.git(&["config", "sequence.editor", "sed -i -e 1s/^pick/edit/"])?;
+        let err = repo
+            .run(|ctx| {
+                edit(
+                    ctx,
+                    &EditOpt {
+                        branch: Some("ch/branch-1".to_owned()),
+                    },
+                )
+            })
+            .unwrap_err();
+
+        assert_eq!(exit::code(&err), ExitCode::Conflict);
+        assert!(repo.root.join(".git/rebase-merge").exists());
+        repo.run(|ctx| {
+            // The branches above it aren't restacked onto a rebase which hasn't finished.
+            assert_eq!(ctx.tx.get_base_commit("ch/branch-1")?, base_commit);
+            Ok(())
+        })?;
+        repo.git(&["rebase", "--abort"])?;
+        assert_eq!(repo.current_branch()?, "ch/branch-1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_pick_moves_commit_into_lower_branch() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        let fix = repo.commit("one.txt", "one, fixed", "Fix one")?;
+
+        repo.run(|ctx| {
+            pick(
+                ctx,
+                &PickOpt {
+                    commit: fix.clone(),
+                    into: "ch/branch-1".to_owned(),
+                },
+            )
+        })?;
+
+        assert_eq!(repo.current_branch()?, "ch/branch-2");
+        assert_eq!(
+            repo.git(&["log", "--format=%s", "main..ch/branch-1"])?,
+            "Fix one\nOne"
+        );
+        assert_eq!(
+            repo.git(&["log", "--format=%s", "ch/branch-1..ch/branch-2"])?,
+            "Two"
+        );
+        assert!(repo.is_ancestor("ch/branch-1", "ch/branch-2")?);
+
+        // A commit isn't picked out of a protected branch, and the branch it'd go into is left alone.
+        let other_fix = repo.commit("one.txt", "one, fixed again", "Fix one again")?;
+        let before = repo.git(&["rev-parse", "ch/branch-1"])?;
+        repo.run(|ctx| ctx.tx.set_config(config::PROTECTED_BRANCHES, "ch/branch-2"))?;
+        assert!(repo
+            .run(|ctx| {
+                pick(
+                    ctx,
+                    &PickOpt {
+                        commit: other_fix.clone(),
+                        into: "ch/branch-1".to_owned(),
+                    },
+                )
+            })
+            .is_err());
+        assert_eq!(repo.git(&["rev-parse", "ch/branch-1"])?, before);
+        Ok(())
+    }
+
+    #[test]
+    fn test_revert_on_owning_branch() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        let one = repo.commit("one.txt", "one", "One")?;
+        repo.commit("other.txt", "other", "Other")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+
+        repo.run(|ctx| {
+            revert(
+                ctx,
+                &RevertOpt {
+                    commit: one.clone(),
+                },
+            )
+        })?;
+
+        assert_eq!(repo.current_branch()?, "ch/branch-2");
+        assert_eq!(
+            repo.git(&["log", "-1", "--format=%s", "ch/branch-1"])?,
+            "Revert \"One\""
+        );
+        assert!(repo.is_ancestor("ch/branch-1", "ch/branch-2")?);
+        assert!(!repo.root.join("one.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_trunk_rename() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        let trunk_opt = TrunkOpt {
+            rename_to: Some("trunk".to_owned()),
+        };
+        assert!(repo.run(|ctx| trunk(ctx, &trunk_opt)).is_err());
+
+        repo.remote_git(&["branch", "--move", "main", "trunk"])?;
+        repo.run(|ctx| trunk(ctx, &trunk_opt))?;
+
+        assert_eq!(
+            repo.git(&["rev-parse", "--abbrev-ref", "trunk@{upstream}"])?,
+            "origin/trunk"
+        );
+        repo.run(|ctx| {
+            assert_eq!(ctx.tx.get_root_branch()?.as_deref(), Some("trunk"));
+            assert_eq!(ctx.tx.get_parent("ch/branch-1")?.as_deref(), Some("trunk"));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_sync_resumes_after_conflict() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("README.md", "# Ours\n", "Two")?;
+
+        // Someone else lands a change on `main` which conflicts with `ch/branch-2`.
+        repo.git(&["checkout", "--quiet", "main"])?;
+        repo.commit("README.md", "# Theirs\n", "Upstream")?;
+        repo.git(&["push", "--quiet", "origin", "main"])?;
+        repo.git(&["reset", "--quiet", "--hard", "HEAD~1"])?;
+        repo.git(&["checkout", "--quiet", "ch/branch-2"])?;
+
+        assert!(repo.run(|ctx| sync(ctx, &SyncOpt { stack: None })).is_err());
+        let branch_1 = repo.git(&["rev-parse", "ch/branch-1"])?;
+        let main = repo.git(&["rev-parse", "main"])?;
+        repo.run(|ctx| {
+            // `ch/branch-1` was restacked before the conflict, and that's kept.
+            assert_eq!(
+                ctx.tx.get_progress("sync ch/branch-1")?.get("ch/branch-1"),
+                Some(&branch_1)
+            );
+            assert_eq!(ctx.tx.get_base_commit("ch/branch-1")?, Some(main));
+            // Another stack's sync which stopped partway through.
+            ctx.tx
+                .record_progress("sync ch/other", "ch/other", "abc123")?;
+            Ok(())
+        })?;
+
+        std::fs::write(repo.root.join("README.md"), "# Both\n")?;
+        repo.git(&["add", "README.md"])?;
+        repo.git(&["-c", "core.editor=true", "rebase", "--continue"])?;
+        repo.run(|ctx| sync(ctx, &SyncOpt { stack: None }))?;
+
+        assert_eq!(repo.git(&["rev-parse", "ch/branch-1"])?, branch_1);
+        assert!(repo.is_ancestor("ch/branch-1", "ch/branch-2")?);
+        repo.run(|ctx| {
+            assert!(ctx.tx.get_progress("sync ch/branch-1")?.is_empty());
+            assert_eq!(ctx.tx.get_progress("sync ch/other")?.len(), 1);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_restore_snapshot_undoes_restack() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        let original_commit = repo.commit("two.txt", "two", "Two")?;
+        let base_commit = |repo: &mut TestRepo| {
+            let mut base_commit = None;
+            repo.run(|ctx| {
+                base_commit = ctx.tx.get_base_commit("ch/branch-2")?;
+                Ok(())
+            })?;
+            anyhow::Ok(base_commit)
+        };
+        let original_base = base_commit(&mut repo)?;
+
+        repo.git(&["checkout", "--quiet", "ch/branch-1"])?;
+        std::fs::write(repo.root.join("one.txt"), "one, amended")?;
+        repo.git(&["commit", "--quiet", "--all", "--amend", "--no-edit"])?;
+        repo.run(|ctx| {
+            restack(
+                ctx,
+                &RestackOpt {
+                    branch: None,
+                    plan: false,
+                },
+            )
+        })?;
+        assert_ne!(repo.git(&["rev-parse", "ch/branch-2"])?, original_commit);
+        assert_ne!(base_commit(&mut repo)?, original_base);
+
+        let restore_opt = RestoreSnapshotOpt {
+            snapshot: None,
+            list: false,
+        };
+        repo.run(|ctx| restore_snapshot(ctx, &restore_opt))?;
+        assert_eq!(repo.git(&["rev-parse", "ch/branch-2"])?, original_commit);
+        assert_eq!(base_commit(&mut repo)?, original_base);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshots_taken_together_are_kept_apart() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        repo.run(|ctx| {
+            snapshot_branches(ctx, &["ch/branch-1".to_owned()])?;
+            snapshot_branches(ctx, &["ch/branch-1".to_owned()])?;
+            let snapshots = list_snapshots(ctx)?;
+            assert_eq!(snapshots.len(), 2);
+            assert!(snapshots
+                .values()
+                .flatten()
+                .all(|snapshot_branch| snapshot_branch.base.is_some()));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_restack_named_branch_from_elsewhere() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+
+        repo.git(&["checkout", "--quiet", "ch/branch-1"])?;
+        std::fs::write(repo.root.join("one.txt"), "one, amended")?;
+        repo.git(&["commit", "--quiet", "--all", "--amend", "--no-edit"])?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+
+        let restack_opt = RestackOpt {
+            branch: Some("ch/branch-2".to_owned()),
+            plan: false,
+        };
+        repo.run(|ctx| restack(ctx, &restack_opt))?;
+
+        assert_eq!(repo.current_branch()?, "main");
+        assert!(repo.is_ancestor("ch/branch-1", "ch/branch-2")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_stack() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        repo.git(&["checkout", "--quiet", "-b", "release/1.2"])?;
+        repo.commit("version.txt", "1.2", "Release 1.2")?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        repo.run(|ctx| ctx.tx.set_stack_name("ch/branch-1", "fix"))?;
+
+        let copy_stack_opt = CopyStackOpt {
+            onto: "release/1.2".to_owned(),
+            suffix: None,
+        };
+        repo.run(|ctx| copy_stack(ctx, &copy_stack_opt))?;
+        assert_eq!(repo.current_branch()?, "ch/branch-2");
+        assert_eq!(
+            repo.git(&["log", "--format=%s", "main..ch/branch-2-release-1.2"])?,
+            "Two\nOne\nRelease 1.2"
+        );
+        repo.run(|ctx| {
+            assert_eq!(
+                ctx.tx.get_parent("ch/branch-1-release-1.2")?.as_deref(),
+                Some("main")
+            );
+            assert_eq!(
+                ctx.tx
+                    .get_target_branch("ch/branch-1-release-1.2")?
+                    .as_deref(),
+                Some("release/1.2")
+            );
+            assert_eq!(
+                ctx.tx.get_parent("ch/branch-2-release-1.2")?.as_deref(),
+                Some("ch/branch-1-release-1.2")
+            );
+            assert_eq!(
+                ctx.tx.get_stack_base("fix-release-1.2")?.as_deref(),
+                Some("ch/branch-1-release-1.2")
+            );
+            assert_eq!(ctx.tx.get_parent("ch/branch-1")?.as_deref(), Some("main"));
+            Ok(())
+        })?;
+
+        // Copies which already exist are skipped.
+        repo.run(|ctx| copy_stack(ctx, &copy_stack_opt))?;
+
+        // Unless their rebase was aborted, leaving them where they started.
+        repo.git(&[
+            "branch",
+            "--force",
+            "ch/branch-2-release-1.2",
+            "ch/branch-2",
+        ])?;
+        repo.run(|ctx| copy_stack(ctx, &copy_stack_opt))?;
+        assert_eq!(
+            repo.git(&["log", "--format=%s", "main..ch/branch-2-release-1.2"])?,
+            "Two\nOne\nRelease 1.2"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_restack_onto_target_branch() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        repo.git(&["checkout", "--quiet", "-b", "release/1.2"])?;
+        repo.commit("version.txt", "1.2", "Release 1.2")?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+
+        let target = |branch: Option<&str>| {
+            StackOpt::Target(StackTargetOpt {
+                branch: branch.map(str::to_owned),
+                clear: false,
+            })
+        };
+        // Tracked branches are built on with `dmd move` instead.
+        assert!(repo.run(|ctx| stack(ctx, &target(Some("main")))).is_err());
+        repo.run(|ctx| stack(ctx, &target(Some("release/1.2"))))?;
+        repo.run(|ctx| {
+            assert_eq!(
+                outdated_branches(ctx, None, &[])?,
+                vec![
+                    (
+                        "ch/branch-1".to_owned(),
+                        "1 behind `release/1.2`".to_owned()
+                    ),
+                    (
+                        "ch/branch-2".to_owned(),
+                        "`ch/branch-1` is outdated".to_owned()
+                    ),
+                ]
+            );
+            Ok(())
+        })?;
+        repo.run(|ctx| {
+            restack(
+                ctx,
+                &RestackOpt {
+                    branch: None,
+                    plan: false,
+                },
+            )
+        })?;
+        assert_eq!(
+            repo.git(&["log", "--format=%s", "main..ch/branch-2"])?,
+            "Two\nOne\nRelease 1.2"
+        );
+        assert_eq!(
+            shell_prompt(&repo.root)?.as_deref(),
+            Some("release/1.2→ch/branch-1→*ch/branch-2 (1↑)")
+        );
+        repo.run(|ctx| {
+            assert!(outdated_branches(ctx, None, &[])?.is_empty());
+            assert_eq!(ctx.tx.get_parent("ch/branch-1")?.as_deref(), Some("main"));
+            assert_eq!(
+                ctx.tx.get_target_branch("ch/branch-1")?.as_deref(),
+                Some("release/1.2")
+            );
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_compares_stacks_against_their_target_branch() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        repo.git(&["checkout", "--quiet", "-b", "release/1.2"])?;
+        repo.commit("version.txt", "1.2", "Release 1.2")?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        repo.run(|ctx| {
+            stack(
+                ctx,
+                &StackOpt::Target(StackTargetOpt {
+                    branch: Some("release/1.2".to_owned()),
+                    clear: false,
+                }),
+            )
+        })?;
+        repo.run(|ctx| {
+            restack(
+                ctx,
+                &RestackOpt {
+                    branch: None,
+                    plan: false,
+                },
+            )
+        })?;
+        // `main` moves on, which the stack doesn't need to keep up with.
+        repo.git(&["checkout", "--quiet", "main"])?;
+        repo.commit("main.txt", "main", "Main")?;
+        repo.git(&["checkout", "--quiet", "ch/branch-1"])?;
+
+        repo.run(|ctx| {
+            let branches = effective_branches(ctx)?;
+            assert_eq!(branches[0].parent, "release/1.2");
+            let counts = commit_counts(ctx, &branches[0], None).unwrap();
+            assert_eq!((counts.ahead, counts.behind), (1, 0));
+            // Fails when it finds the stack behind.
+            ctx.output = Output::GithubActions;
+            log(
+                ctx,
+                &LogOpt {
+                    style: StyleChoice::Ascii,
+                    format: Format::Tree,
+                    all: false,
+                    stat: true,
+                },
+            )
+        })
+    }
+
+    #[test]
+    fn test_gc() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+        repo.git(&["branch", "--quiet", "--delete", "--force", "ch/branch-1"])?;
+        for timestamp in ["1", "2", "3"] {
+            repo.git(&[
+                "update-ref",
+                &format!("{SNAPSHOT_PREFIX}{timestamp}/main"),
+                "main",
+            ])?;
+        }
+
+        repo.run(|ctx| gc(ctx, &GcOpt { keep_snapshots: 1 }))?;
+        repo.run(|ctx| {
+            assert_eq!(ctx.tx.get_parent("ch/branch-1")?, None);
+            assert_eq!(
+                list_snapshots(ctx)?.into_keys().collect::<Vec<_>>(),
+                vec![3]
+            );
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_pop() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+
+        let pop_opt = PopOpt {
+            keep_commits: false,
+        };
+        repo.run(|ctx| pop(ctx, &pop_opt))?;
+        assert_eq!(repo.current_branch()?, "ch/branch-1");
+        assert!(repo
+            .git(&["rev-parse", "--verify", "--quiet", "ch/branch-2"])
+            .is_err());
+        assert_eq!(repo.git(&["status", "--porcelain"])?, "A  two.txt");
+        repo.run(|ctx| {
+            assert_eq!(ctx.tx.get_parent("ch/branch-2")?, None);
+            Ok(())
+        })?;
+
+        // A branch with children can only give its commits to its parent.
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.git(&["commit", "--quiet", "--message", "Two"])?;
+        create_branch(&mut repo, "ch/branch-3")?;
+        repo.git(&["checkout", "--quiet", "ch/branch-2"])?;
+        assert!(repo.run(|ctx| pop(ctx, &pop_opt)).is_err());
+        let branch_2 = repo.git(&["rev-parse", "ch/branch-2"])?;
+        repo.run(|ctx| pop(ctx, &PopOpt { keep_commits: true }))?;
+        assert_eq!(repo.current_branch()?, "ch/branch-1");
+        assert_eq!(repo.git(&["rev-parse", "ch/branch-1"])?, branch_2);
+        assert_eq!(repo.git(&["status", "--porcelain"])?, "");
+        repo.run(|ctx| {
+            assert_eq!(
+                ctx.tx.get_parent("ch/branch-3")?.as_deref(),
+                Some("ch/branch-1")
+            );
+            Ok(())
+        })?;
+
+        // The root branch never gets commits this way.
+        let main = repo.git(&["rev-parse", "main"])?;
+        assert!(repo
+            .run(|ctx| pop(ctx, &PopOpt { keep_commits: true }))
+            .is_err());
+        assert_eq!(repo.git(&["rev-parse", "main"])?, main);
+        Ok(())
+    }
+
+    #[test]
+    fn test_squash_stack() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        create_branch(&mut repo, "ch/branch-3")?;
+        repo.commit("three.txt", "three", "Three")?;
+        let top = repo.git(&["rev-parse", "HEAD"])?;
+        let branch_2 = repo.git(&["rev-parse", "ch/branch-2"])?;
+
+        let squash_stack_opt = |dry_run, delete_branches| SquashStackOpt {
+            dry_run,
+            delete_branches,
+        };
+        repo.run(|ctx| squash_stack(ctx, &squash_stack_opt(true, false)))?;
+        assert_eq!(repo.current_branch()?, "ch/branch-3");
+        repo.run(|ctx| {
+            assert_eq!(
+                ctx.tx.get_parent("ch/branch-3")?.as_deref(),
+                Some("ch/branch-2")
+            );
+            Ok(())
+        })?;
+
+        repo.run(|ctx| squash_stack(ctx, &squash_stack_opt(false, false)))?;
+        assert_eq!(repo.current_branch()?, "ch/branch-1");
+        assert_eq!(repo.git(&["rev-parse", "ch/branch-1"])?, top);
+        assert_eq!(
+            repo.git(&["log", "--format=%s", "main..ch/branch-1"])?,
+            "Three\nTwo\nOne"
+        );
+        // The other branches are only untracked.
+        assert_eq!(repo.git(&["rev-parse", "ch/branch-2"])?, branch_2);
+        assert_eq!(repo.git(&["rev-parse", "ch/branch-3"])?, top);
+        repo.run(|ctx| {
+            assert_eq!(ctx.tx.get_parent("ch/branch-1")?.as_deref(), Some("main"));
+            assert_eq!(ctx.tx.get_parent("ch/branch-2")?, None);
+            assert_eq!(ctx.tx.get_parent("ch/branch-3")?, None);
+            Ok(())
+        })?;
+
+        repo.git(&["checkout", "--quiet", "main"])?;
+        create_branch(&mut repo, "ch/other-1")?;
+        repo.commit("other-1.txt", "other", "Other 1")?;
+        create_branch(&mut repo, "ch/other-2")?;
+        repo.commit("other-2.txt", "other", "Other 2")?;
+        repo.run(|ctx| squash_stack(ctx, &squash_stack_opt(false, true)))?;
+        assert!(repo
+            .git(&["rev-parse", "--verify", "--quiet", "ch/other-2"])
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_swap() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        create_branch(&mut repo, "ch/branch-3")?;
+        repo.commit("three.txt", "three", "Three")?;
+        repo.git(&["checkout", "--quiet", "ch/branch-2"])?;
+
+        repo.run(swap)?;
+        assert_eq!(repo.current_branch()?, "ch/branch-2");
+        assert_eq!(
+            repo.git(&["log", "--format=%s", "main..ch/branch-3"])?,
+            "Three\nOne\nTwo"
+        );
+        assert_eq!(
+            repo.git(&["log", "--format=%s", "main..ch/branch-2"])?,
+            "Two"
+        );
+        repo.run(|ctx| {
+            assert_eq!(ctx.tx.get_parent("ch/branch-2")?.as_deref(), Some("main"));
+            assert_eq!(
+                ctx.tx.get_parent("ch/branch-1")?.as_deref(),
+                Some("ch/branch-2")
+            );
+            assert_eq!(
+                ctx.tx.get_parent("ch/branch-3")?.as_deref(),
+                Some("ch/branch-1")
+            );
+            Ok(())
+        })?;
+
+        // The bottom branch can't be swapped with the root branch.
+        assert!(repo.run(swap).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_swap_asks_before_moving_the_parents_other_children() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/sibling")?;
+        let sibling_commit = repo.commit("sibling.txt", "sibling", "Sibling")?;
+        repo.git(&["checkout", "--quiet", "ch/branch-1"])?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+
+        repo.run(|ctx| {
+            ctx.yes = false;
+            let err = swap(ctx).unwrap_err();
+            assert_eq!(exit::code(&err), ExitCode::Aborted);
+            assert_eq!(
+                ctx.tx.get_parent("ch/branch-2")?.as_deref(),
+                Some("ch/branch-1")
+            );
+            Ok(())
+        })?;
+        assert_eq!(repo.git(&["rev-parse", "ch/sibling"])?, sibling_commit);
+
+        repo.run(swap)?;
+        assert_eq!(
+            repo.git(&["log", "--format=%s", "main..ch/sibling"])?,
+            "Sibling\nOne\nTwo"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        repo.git(&["branch", "ch/existing"])?;
+        let plan = repo.root.join(".git").join("plan.toml");
+        std::fs::write(
+            &plan,
+            r#"
+[[branch]]
+name = "ch/parser"
+message = "Add a parser"
+
+[[branch]]
+name = "ch/parser-tests"
+
+[[branch]]
+name = "ch/existing"
+parent = "main"
+"#,
+        )?;
+
+        // The plan's path is relative to where `dmd` runs, which is the repo here, not where the tests run.
+        repo.run(|ctx| {
+            apply(
+                ctx,
+                &ApplyOpt {
+                    plan: PathBuf::from(".git/plan.toml"),
+                },
+            )
+        })?;
+        assert_eq!(repo.current_branch()?, "main");
+        assert_eq!(
+            repo.git(&["log", "-1", "--format=%s", "ch/parser-tests"])?,
+            "Add a parser"
+        );
+        repo.run(|ctx| {
+            assert_eq!(ctx.tx.get_parent("ch/parser")?.as_deref(), Some("main"));
+            assert_eq!(
+                ctx.tx.get_parent("ch/parser-tests")?.as_deref(),
+                Some("ch/parser")
+            );
+            assert_eq!(ctx.tx.get_parent("ch/existing")?.as_deref(), Some("main"));
+            Ok(())
+        })?;
+
+        // Applying it again changes nothing, but a plan which disagrees with what's tracked is refused.
+        repo.run(|ctx| apply(ctx, &ApplyOpt { plan: plan.clone() }))?;
+        std::fs::write(
+            &plan,
+            "[[branch]]\nname = \"ch/parser-tests\"\nparent = \"main\"\n",
+        )?;
+        assert!(repo
+            .run(|ctx| apply(ctx, &ApplyOpt { plan: plan.clone() }))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_restack_keeps_uncommitted_changes() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+        let main = repo.commit("main.txt", "main", "Main")?;
+        repo.git(&["checkout", "--quiet", "ch/branch-1"])?;
+        std::fs::write(repo.root.join("one.txt"), "edited")?;
+
+        repo.run(|ctx| {
+            restack(
+                ctx,
+                &RestackOpt {
+                    branch: None,
+                    plan: false,
+                },
+            )
+        })?;
+        assert!(repo.is_ancestor(&main, "ch/branch-2")?);
+        assert_eq!(repo.current_branch()?, "ch/branch-1");
+        assert_eq!(
+            std::fs::read_to_string(repo.root.join("one.txt"))?,
+            "edited"
+        );
+        assert_eq!(repo.git(&["stash", "list"])?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_branch_guard_returns_to_commit_of_deleted_branch() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        let commit = repo.commit("one.txt", "one", "One")?;
+
+        let guard = git::BranchGuard::new(repo.root.clone(), "ch/branch-1".to_owned(), false)?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+        repo.git(&["branch", "--quiet", "-D", "ch/branch-1"])?;
+        drop(guard);
+        assert_eq!(repo.current_branch()?, "");
+        assert_eq!(repo.git(&["rev-parse", "HEAD"])?, commit);
+        Ok(())
+    }
+
+    #[test]
+    fn test_restack_leaves_changes_alone_when_up_to_date() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        let path = repo.root.join("one.txt");
+        std::fs::write(&path, "uncommitted")?;
+        let modified = std::fs::metadata(&path)?.modified()?;
+
+        repo.run(|ctx| {
+            restack(
+                ctx,
+                &RestackOpt {
+                    branch: None,
+                    plan: false,
+                },
+            )
+        })?;
+        // Stashing and unstashing would have rewritten the file.
+        assert_eq!(std::fs::metadata(&path)?.modified()?, modified);
+        assert_eq!(std::fs::read_to_string(&path)?, "uncommitted");
+        assert!(repo.git(&["stash", "list"])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_restack_refuses_dirty_submodules() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let library = TestRepo::new()?;
+        repo.git(&[
+            "-c",
+            "protocol.file.allow=always",
+            "submodule",
+            "--quiet",
+            "add",
+            &library.root.display().to_string(),
+            "library",
+        ])?;
+        repo.git(&["commit", "--quiet", "--message", "Add library"])?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+
+        std::fs::write(repo.root.join("library").join("README.md"), "edited")?;
+        let restack_opt = RestackOpt {
+            branch: None,
+            plan: false,
+        };
+        let err = repo.run(|ctx| restack(ctx, &restack_opt)).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("submodules have local changes: library."));
+
+        repo.git(&["-C", "library", "checkout", "--quiet", "README.md"])?;
+        repo.run(|ctx| restack(ctx, &restack_opt))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_restack_fetches_history_missing_from_shallow_clone() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+        repo.commit("main.txt", "main", "Main")?;
+        repo.git(&["push", "--quiet", "origin", "main"])?;
+        repo.git(&["checkout", "--quiet", "ch/branch-1"])?;
+
+        // Cut off the history behind `main`, as if it had been fetched with `--depth 1`.
+        let main = repo.git(&["rev-parse", "main"])?;
+        std::fs::write(repo.root.join(".git").join("shallow"), format!("{main}\n"))?;
+        assert_eq!(repo.git(&["rev-parse", "--is-shallow-repository"])?, "true");
+
+        let restack_opt = RestackOpt {
+            branch: None,
+            plan: false,
+        };
+        repo.run(|ctx| restack(ctx, &restack_opt))?;
+        assert_eq!(
+            repo.git(&["rev-parse", "--is-shallow-repository"])?,
+            "false"
+        );
+        assert!(repo.is_ancestor("main", "ch/branch-1")?);
+        assert_eq!(
+            repo.git(&["rev-list", "--count", "main..ch/branch-1"])?,
+            "1"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_hook_install_keeps_foreign_hooks() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let hooks_dir = repo.root.join(".git").join("hooks");
+        std::fs::create_dir_all(&hooks_dir)?;
+        std::fs::write(hooks_dir.join("post-commit"), "#!/bin/sh\nmake lint\n")?;
+
+        let install = |force| HookOpt::Install(HookInstallOpt { force });
+        assert!(repo.run(|ctx| hook(ctx, &install(false))).is_err());
+        assert!(!hooks_dir.join("post-checkout").exists());
+
+        repo.run(|ctx| hook(ctx, &install(true)))?;
+        for name in HOOKS {
+            let contents = std::fs::read_to_string(hooks_dir.join(name))?;
+            assert!(contents.contains(HOOK_MARKER));
+            assert!(contents.contains(&format!("dmd hook run {name}")));
+        }
+        // Hooks installed by diamond are replaced without `--force`.
+        repo.run(|ctx| hook(ctx, &install(false)))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_track_with_ancestors() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/tracked")?;
+        repo.commit("tracked.txt", "tracked", "Tracked")?;
+        for branch in ["ch/untracked-1", "ch/untracked-2", "ch/current"] {
+            repo.git(&["checkout", "--quiet", "-b", branch])?;
+            repo.commit(&format!("{}.txt", &branch[3..]), branch, branch)?;
+        }
+        // Unrelated branches, even ones further down the same history, are skipped over.
+        repo.git(&["branch", "ch/unrelated", "main"])?;
+        // An untracked branch at the same commit as a tracked one loses to it.
+        repo.git(&["branch", "ch/copy-of-tracked", "ch/tracked"])?;
+
+        let track_opt = TrackOpt {
+            parent: None,
+            with_ancestors: true,
+        };
+        repo.run(|ctx| track(ctx, &track_opt))?;
+        repo.run(|ctx| {
+            assert_eq!(
+                ctx.tx.get_parent("ch/current")?.as_deref(),
+                Some("ch/untracked-2")
+            );
+            assert_eq!(
+                ctx.tx.get_parent("ch/untracked-2")?.as_deref(),
+                Some("ch/untracked-1")
+            );
+            assert_eq!(
+                ctx.tx.get_parent("ch/untracked-1")?.as_deref(),
+                Some("ch/tracked")
+            );
+            assert_eq!(ctx.tx.get_parent("ch/unrelated")?, None);
+            assert_eq!(ctx.tx.get_parent("ch/copy-of-tracked")?, None);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_downstack_submit_skips_branches_above() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        repo.git(&["checkout", "--quiet", "ch/branch-1"])?;
+
+        let scoped_opt = ScopedOpt::Submit(SubmitOpt {
+            no_verify: false,
+            push_options: vec![],
+            flush: false,
+            no_pr: false,
+            no_reviewers: false,
+            closes: vec![],
+        });
+        repo.run(|ctx| scoped(ctx, &scoped_opt, Scope::Downstack))?;
+        assert_eq!(
+            repo.remote_git(&["rev-parse", "ch/branch-1"])?,
+            repo.git(&["rev-parse", "ch/branch-1"])?,
+        );
+        assert!(repo
+            .remote_git(&["rev-parse", "--verify", "--quiet", "ch/branch-2"])
+            .is_err());
+
+        repo.run(|ctx| scoped(ctx, &scoped_opt, Scope::Upstack))?;
+        assert_eq!(
+            repo.remote_git(&["rev-parse", "ch/branch-2"])?,
+            repo.git(&["rev-parse", "ch/branch-2"])?,
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_issue_from_branch_name() {
+        assert_eq!(issue_from_branch_name("ch/gh-123-fix-leak"), Some(123));
+        assert_eq!(issue_from_branch_name("123-fix-leak"), None);
+        assert_eq!(issue_from_branch_name("ch/2024-cleanup"), None);
+        assert_eq!(issue_from_branch_name("ch/gh-fix-leak"), None);
+        assert_eq!(issue_from_branch_name("gh-7"), Some(7));
+        assert_eq!(issue_from_branch_name("ch/fix-leak-123"), None);
+        assert_eq!(issue_from_branch_name("ch/2fa-login"), None);
+        assert_eq!(issue_from_branch_name("123/fix-leak"), None);
+    }
+
+    #[test]
+    fn test_closed_issues() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        repo.run(|ctx| {
+            create(
+                ctx,
+                &CreateOpt {
+                    branch: Some("ch/gh-12-fix-leak".to_owned()),
+                    message: None,
+                    all: false,
+                    slugify: false,
+                    closes: vec![34],
+                },
+            )
+        })?;
+        repo.run(|ctx| {
+            ctx.tx.add_closed_issues("ch/gh-12-fix-leak", &[34, 56])?;
+            assert_eq!(
+                closed_issues(&ctx.tx, "ch/gh-12-fix-leak")?,
+                vec![34, 56, 12]
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(slugify("Chris/Fix the crash!"), "chris/fix-the-crash");
+        assert_eq!(slugify("-x..y/ /feature_z.lock"), "x-y/feature_z-lock");
+        assert_eq!(slugify("~^:"), "");
+        assert_eq!(slugify("Zoë/Café crème"), "zoë/café-crème");
+    }
+
+    #[test]
+    fn test_create_validates_branch_names() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        let create_opt = |branch: &str, slugify: bool| CreateOpt {
+            branch: Some(branch.to_owned()),
+            message: None,
+            all: false,
+            slugify,
+            closes: vec![],
+        };
+        assert!(repo
+            .run(|ctx| create(ctx, &create_opt("ch/fix crash", false)))
+            .is_err());
+        repo.run(|ctx| create(ctx, &create_opt("ch/Fix crash!", true)))?;
+        assert_eq!(repo.current_branch()?, "ch/fix-crash");
+
+        repo.run(|ctx| {
+            ctx.tx
+                .set_config(config::BRANCH_ALLOWED_PREFIXES, "ch/,fix/")
+        })?;
+        assert!(repo
+            .run(|ctx| create(ctx, &create_opt("feature", false)))
+            .is_err());
+        repo.run(|ctx| create(ctx, &create_opt("fix/typo", false)))?;
+        assert_eq!(repo.current_branch()?, "fix/typo");
+        Ok(())
+    }
+
+    #[test]
+    fn test_branch_name_from_message() {
+        assert_eq!(
+            branch_name_from_message("Fix crash when the remote is missing\n\nDetails."),
+            "fix-crash-when-the-remote-is-missing"
+        );
+        assert_eq!(
+            branch_name_from_message("[cli] Don't panic on `dmd log`!"),
+            "cli-don-t-panic-on-dmd-log"
+        );
+        assert_eq!(
+            branch_name_from_message(
+                "Refactor the submit queue so that pushes and pull requests retry separately"
+            ),
+            "refactor-the-submit-queue-so-that-pushes-and-pull"
+        );
+        assert_eq!(branch_name_from_message("!!!"), "");
+    }
+
+    #[test]
+    fn test_create_with_message_names_branch_and_commits() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        std::fs::write(repo.root.join("one.txt"), "one")?;
+        repo.git(&["add", "one.txt"])?;
+        let create_opt = CreateOpt {
+            branch: None,
+            message: Some("Add the first file".to_owned()),
+            all: false,
+            slugify: false,
+            closes: vec![],
+        };
+        repo.run(|ctx| create(ctx, &create_opt))?;
+
+        assert_eq!(repo.current_branch()?, "add-the-first-file");
+        assert_eq!(
+            repo.git(&["log", "-1", "--format=%s"])?,
+            "Add the first file"
+        );
+        repo.run(|ctx| {
+            assert_eq!(
+                ctx.tx.get_parent("add-the-first-file")?.as_deref(),
+                Some("main")
+            );
+            Ok(())
+        })?;
+
+        // Nothing is staged, so there's nothing to commit.
+        assert!(repo.run(|ctx| create(ctx, &create_opt)).is_err());
+
+        // A branch whose commit fails isn't left behind.
+        repo.run(|ctx| {
+            ctx.tx
+                .set_config(config::BRANCH_NAME_TEMPLATE, "wip/{message}")
+        })?;
+        std::fs::write(repo.root.join("two.txt"), "two")?;
+        repo.git(&["add", "two.txt"])?;
+        let hook = repo.root.join(".git").join("hooks").join("pre-commit");
+        std::fs::create_dir_all(hook.parent().unwrap())?;
+        std::fs::write(
+            &hook,
+            "#!/bin/sh
+exit 1
+",
+        )?;
+        std::fs::set_permissions(&hook, std::os::unix::fs::PermissionsExt::from_mode(0o755))?;
+        let create_opt = CreateOpt {
+            message: Some("Add the second file".to_owned()),
+            ..create_opt
+        };
+        assert!(repo.run(|ctx| create(ctx, &create_opt)).is_err());
+        assert_eq!(repo.current_branch()?, "add-the-first-file");
+        assert!(repo
+            .git(&[
+                "rev-parse",
+                "--verify",
+                "--quiet",
+                "refs/heads/wip/add-the-second-file"
+            ])
+            .is_err());
+
+        std::fs::remove_file(&hook)?;
+        repo.run(|ctx| create(ctx, &create_opt))?;
+        assert_eq!(repo.current_branch()?, "wip/add-the-second-file");
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_with_all_stages_tracked_changes() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        std::fs::write(repo.root.join("README.md"), "# Changed\n")?;
+        std::fs::write(repo.root.join("untracked.txt"), "untracked")?;
+        let create_opt = CreateOpt {
+            branch: Some("ch/change-readme".to_owned()),
+            message: Some("Change the README".to_owned()),
+            all: true,
+            slugify: false,
+            closes: vec![],
+        };
+        repo.run(|ctx| create(ctx, &create_opt))?;
+
+        assert_eq!(repo.current_branch()?, "ch/change-readme");
+        assert_eq!(
+            repo.git(&["show", "--name-only", "--format=", "HEAD"])?,
+            "README.md"
+        );
+        assert_eq!(repo.git(&["status", "--porcelain"])?, "?? untracked.txt");
+        Ok(())
+    }
+
+    #[test]
+    fn test_pull_request_description() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One\n\nPR-Body: Adds the first file.")?;
+        repo.commit("two.txt", "two", "Two")?;
+        repo.run(|ctx| {
+            assert_eq!(
+                pull_request_description(ctx, "ch/branch-1", "main")?.as_deref(),
+                Some("Adds the first file.")
+            );
+            assert_eq!(pull_request_description(ctx, "main", "main")?, None);
+            Ok(())
+        })?;
+
+        std::fs::create_dir_all(repo.root.join(".diamond/ch"))?;
+        repo.commit(".diamond/ch/branch-1.md", "# One\n", "Describe the branch")?;
+        repo.run(|ctx| {
+            assert_eq!(
+                pull_request_description(ctx, "ch/branch-1", "main")?.as_deref(),
+                Some("# One\n")
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_create_refuses_colliding_names() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.git(&["push", "--quiet", "origin", "ch/branch-1:ch/pushed"])?;
+        repo.git(&["fetch", "--quiet", "origin"])?;
+        repo.git(&["tag", "v1"])?;
+
+        for (branch, reason) in [
+            (
+                "ch/branch-1",
+                "a local branch named `ch/branch-1` already exists",
+            ),
+            (
+                "ch/branch-1/more",
+                "it would clash with the local branch `ch/branch-1`",
+            ),
+            ("ch", "it would clash with the local branch `ch/branch-1`"),
+            (
+                "ch/pushed",
+                "the remote branch `origin/ch/pushed` already exists",
+            ),
+            ("v1", "a tag named `v1` already exists"),
+        ] {
+            let err = create_branch(&mut repo, branch).unwrap_err();
+            assert!(err.to_string().contains(reason), "{err}");
+        }
+        assert_eq!(repo.current_branch()?, "ch/branch-1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_onto_another_branch() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        let one = repo.commit("one.txt", "one", "One")?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("two.txt", "two", "Two")?;
+        create_branch(&mut repo, "ch/branch-3")?;
+        repo.commit("three.txt", "three", "Three")?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+        create_branch(&mut repo, "ch/other")?;
+        repo.commit("other.txt", "other", "Other")?;
+
+        let move_opt = MoveOpt {
+            branch: Some("ch/branch-2".to_owned()),
+            onto: Some("ch/other".to_owned()),
+            interactive: false,
+        };
+        // Without `--yes`, moving the branches above it too is declined.
+        repo.run(|ctx| {
+            ctx.yes = false;
+            let err = move_branch(ctx, &move_opt).unwrap_err();
+            assert_eq!(exit::code(&err), ExitCode::Aborted);
+            assert_eq!(
+                ctx.tx.get_parent("ch/branch-2")?.as_deref(),
+                Some("ch/branch-1")
+            );
+            Ok(())
+        })?;
+        repo.run(|ctx| move_branch(ctx, &move_opt))?;
+
+        assert_eq!(repo.current_branch()?, "ch/other");
+        assert!(repo.is_ancestor("ch/other", "ch/branch-2")?);
+        assert!(repo.is_ancestor("ch/branch-2", "ch/branch-3")?);
+        // Only `ch/branch-2`'s own commit moves, not the commit from its old parent.
+        assert!(!repo.is_ancestor(&one, "ch/branch-2")?);
+        assert_eq!(
+            repo.git(&["rev-list", "--count", "ch/other..ch/branch-2"])?,
+            "1"
+        );
+        repo.run(|ctx| {
+            assert_eq!(
+                ctx.tx.get_parent("ch/branch-2")?.as_deref(),
+                Some("ch/other")
+            );
+            Ok(())
+        })?;
+
+        let cycle_opt = MoveOpt {
+            branch: Some("ch/branch-2".to_owned()),
+            onto: Some("ch/branch-3".to_owned()),
+            interactive: false,
+        };
+        assert!(repo.run(|ctx| move_branch(ctx, &cycle_opt)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_remembers_new_parent_on_conflict() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("file.txt", "one", "One")?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+        create_branch(&mut repo, "ch/branch-2")?;
+        repo.commit("file.txt", "two", "Two")?;
+
+        let move_opt = MoveOpt {
+            branch: None,
+            onto: Some("ch/branch-1".to_owned()),
+            interactive: false,
+        };
+        let err = repo.run(|ctx| move_branch(ctx, &move_opt)).unwrap_err();
+        assert_eq!(exit::code(&err), ExitCode::Conflict);
+        // Git is left mid-rebase onto the new parent, so that's where the branch is tracked.
+        repo.run(|ctx| {
+            assert_eq!(
+                ctx.tx.get_parent("ch/branch-2")?.as_deref(),
+                Some("ch/branch-1")
+            );
+            Ok(())
+        })?;
+        repo.git(&["rebase", "--abort"])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_commit_counts() -> anyhow::Result<()> {
+        let mut repo = TestRepo::new()?;
+        create_branch(&mut repo, "ch/branch-1")?;
+        repo.commit("one.txt", "one", "One")?;
+        repo.git(&["push", "--quiet", "origin", "ch/branch-1"])?;
+        repo.git(&["fetch", "--quiet", "origin"])?;
+        repo.commit("two.txt", "two", "Two")?;
+        repo.git(&["checkout", "--quiet", "main"])?;
+        repo.commit("main.txt", "main", "Main")?;
+
+        repo.run(|ctx| {
+            let branch = database::Branch {
+                name: "ch/branch-1".to_owned(),
+                parent: "main".to_owned(),
+            };
+            assert_eq!(
+                commit_counts(ctx, &branch, Some("origin")).map(|counts| counts.to_string()),
+                Some("(2 commits, 1 behind parent, 1 to push)".to_owned())
+            );
+            assert_eq!(
+                commit_counts(ctx, &branch, None).map(|counts| counts.to_string()),
+                Some("(2 commits, 1 behind parent, not pushed)".to_owned())
+            );
+            Ok(())
+        })
     }
-    anyhow::bail!("Working directory is not in a Git repo: {cwd:?}");
 }
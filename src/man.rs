@@ -0,0 +1,346 @@
+use std::path::Path;
+use structopt::clap::{App, AppSettings, ErrorKind};
+
+/// A rendered man page, named like `dmd-pr-checks.1`.
+pub struct Page {
+    pub file_name: String,
+    pub contents: String,
+}
+
+/// Renders a man page for `app` and one for each of its visible subcommands, recursively.
+///
+/// clap 2 has no public API for walking an `App`, so each page is made from the `--help` output
+/// of its command, which leaves out hidden arguments and subcommands the same way.
+pub fn render_pages(app: &App) -> anyhow::Result<Vec<Page>> {
+    let mut pages = Vec::new();
+    render_page(app, &[app.get_name()], &mut pages)?;
+    Ok(pages)
+}
+
+/// Writes the man pages for `app` into `out_dir`, returning the paths which were written.
+pub fn write_pages(app: &App, out_dir: &Path) -> anyhow::Result<Vec<String>> {
+    std::fs::create_dir_all(out_dir)?;
+    let mut written = Vec::new();
+    for page in render_pages(app)? {
+        let path = out_dir.join(&page.file_name);
+        std::fs::write(&path, page.contents)?;
+        written.push(path.display().to_string());
+    }
+    Ok(written)
+}
+
+/// The parts of a command's `--help` output.
+#[derive(Default)]
+struct Help {
+    description: String,
+    usage: String,
+    arguments: Vec<(String, String)>,
+    options: Vec<(String, String)>,
+    subcommands: Vec<(String, String)>,
+}
+
+/// Runs `path --help` against `app` and returns what clap printed, without wrapping or colors.
+fn help_text(app: &App, path: &[&str]) -> anyhow::Result<String> {
+    let mut args = path.to_vec();
+    args.push("--help");
+    let app = app
+        .clone()
+        .set_term_width(0)
+        .global_setting(AppSettings::ColorNever);
+    match app.get_matches_from_safe(args) {
+        Err(err) if err.kind == ErrorKind::HelpDisplayed => Ok(err.message),
+        Err(err) => Err(anyhow::anyhow!(
+            "Couldn't get the help for `{}`: {}",
+            path.join(" "),
+            err.message
+        )),
+        Ok(_) => Err(anyhow::anyhow!(
+            "`{} --help` didn't print any help",
+            path.join(" ")
+        )),
+    }
+}
+
+/// Splits clap's help output into its sections.
+///
+/// The first line names the command, and the description runs up to `USAGE:`.
+/// Each section lists items indented by up to 8 spaces, with their help either on the same line
+/// or, in long help, on the lines below, indented further.
+fn parse_help(text: &str) -> Help {
+    let mut help = Help::default();
+    let mut lines = text.lines().skip(1);
+    let mut description = Vec::new();
+    for line in lines.by_ref() {
+        if line == "USAGE:" {
+            break;
+        }
+        description.push(line);
+    }
+    help.description = description.join("\n").trim().to_owned();
+
+    let mut section = "USAGE:";
+    for line in lines {
+        if !line.starts_with(' ') {
+            if !line.is_empty() {
+                section = line;
+            }
+            continue;
+        }
+        let items = match section {
+            "USAGE:" => {
+                if help.usage.is_empty() {
+                    help.usage = line.trim().to_owned();
+                }
+                continue;
+            }
+            "ARGS:" => &mut help.arguments,
+            "FLAGS:" | "OPTIONS:" => &mut help.options,
+            "SUBCOMMANDS:" => &mut help.subcommands,
+            _ => continue,
+        };
+        let indent = line.len() - line.trim_start().len();
+        let line = line.trim();
+        match items.last_mut() {
+            Some((_, description)) if indent > 8 || line.is_empty() => {
+                description.push('\n');
+                description.push_str(line);
+            }
+            _ => {
+                let (term, description) = line.split_once("  ").unwrap_or((line, ""));
+                items.push((term.to_owned(), description.trim().to_owned()));
+            }
+        }
+    }
+    for (_, description) in help
+        .arguments
+        .iter_mut()
+        .chain(&mut help.options)
+        .chain(&mut help.subcommands)
+    {
+        *description = description.trim().to_owned();
+    }
+    help
+}
+
+fn render_page(app: &App, path: &[&str], pages: &mut Vec<Page>) -> anyhow::Result<()> {
+    let help = parse_help(&help_text(app, path)?);
+    let command = path.join(" ");
+    let page_name = path.join("-");
+
+    let mut out = String::new();
+    out.push_str(&format!(
+        ".TH {} 1 \"\" \"{} {}\"\n",
+        escape(&page_name.to_uppercase()),
+        path[0],
+        env!("CARGO_PKG_VERSION"),
+    ));
+
+    out.push_str(".SH NAME\n");
+    if help.description.is_empty() {
+        out.push_str(&format!("{}\n", escape(&page_name)));
+    } else {
+        out.push_str(&format!(
+            "{} \\- {}\n",
+            escape(&page_name),
+            escape(first_line(&help.description))
+        ));
+    }
+
+    out.push_str(".SH SYNOPSIS\n");
+    out.push_str(&format!("\\fB{}\\fR", escape(&command)));
+    let arguments = help.usage.strip_prefix(&command).unwrap_or(&help.usage);
+    if !arguments.trim().is_empty() {
+        out.push(' ');
+        out.push_str(&style(arguments.trim()));
+    }
+    out.push('\n');
+
+    if !help.description.is_empty() {
+        out.push_str(".SH DESCRIPTION\n");
+        out.push_str(&paragraphs(&help.description));
+    }
+
+    if !help.arguments.is_empty() {
+        out.push_str(".SH ARGUMENTS\n");
+        push_items(&mut out, &styled(&help.arguments));
+    }
+
+    if !help.options.is_empty() {
+        out.push_str(".SH OPTIONS\n");
+        push_items(&mut out, &styled(&help.options));
+    }
+
+    let subcommands: Vec<&(String, String)> = help
+        .subcommands
+        .iter()
+        .filter(|(name, _)| name != "help")
+        .collect();
+    if !subcommands.is_empty() {
+        out.push_str(".SH SUBCOMMANDS\n");
+        let items: Vec<(String, String)> = subcommands
+            .iter()
+            .map(|(name, about)| {
+                (
+                    format!("\\fB{}\\-{}\\fR(1)", escape(&page_name), escape(name)),
+                    first_line(about).to_owned(),
+                )
+            })
+            .collect();
+        push_items(&mut out, &items);
+    }
+
+    if path.len() > 1 {
+        out.push_str(".SH SEE ALSO\n");
+        out.push_str(&format!(
+            "\\fB{}\\fR(1)\n",
+            escape(&path[..path.len() - 1].join("-"))
+        ));
+    }
+
+    pages.push(Page {
+        file_name: format!("{page_name}.1"),
+        contents: out,
+    });
+    for (name, _) in subcommands {
+        let mut path = path.to_vec();
+        path.push(name);
+        render_page(app, &path, pages)?;
+    }
+    Ok(())
+}
+
+/// Styles the words of a usage line or an item from clap's help, like `-s, --stack <stack>`:
+/// switches in bold and values in italics.
+fn style(text: &str) -> String {
+    text.split(' ')
+        .map(|word| {
+            let start = word.len() - word.trim_start_matches(['[', '(']).len();
+            let end = word.trim_end_matches([']', ')', ',', '.']).len();
+            if start >= end {
+                return escape(word);
+            }
+            let (prefix, core, suffix) = (&word[..start], &word[start..end], &word[end..]);
+            let core = match core
+                .strip_prefix('<')
+                .and_then(|core| core.strip_suffix('>'))
+            {
+                Some(value) => format!("\\fI{}\\fR", escape(value)),
+                None if core.starts_with('-') => format!("\\fB{}\\fR", escape(core)),
+                None => escape(core),
+            };
+            format!("{}{core}{}", escape(prefix), escape(suffix))
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn styled(items: &[(String, String)]) -> Vec<(String, String)> {
+    items
+        .iter()
+        .map(|(term, description)| (style(term), description.clone()))
+        .collect()
+}
+
+fn push_items(out: &mut String, items: &[(String, String)]) {
+    for (term, description) in items {
+        out.push_str(".TP\n");
+        out.push_str(term);
+        out.push('\n');
+        out.push_str(&paragraphs(description).replacen(".PP\n", "", 1));
+    }
+}
+
+/// Renders doc comment text as roff paragraphs, where blank lines separate paragraphs.
+fn paragraphs(text: &str) -> String {
+    let mut out = String::new();
+    for paragraph in text.split("\n\n") {
+        let paragraph = paragraph.trim();
+        if paragraph.is_empty() {
+            continue;
+        }
+        out.push_str(".PP\n");
+        for line in paragraph.lines() {
+            out.push_str(&escape(line.trim()));
+            out.push('\n');
+        }
+    }
+    out
+}
+
+fn first_line(text: &str) -> &str {
+    text.lines().next().unwrap_or("").trim_end_matches('.')
+}
+
+/// Escapes text so roff prints it as-is.
+fn escape(text: &str) -> String {
+    let escaped = text.replace('\\', "\\e").replace('-', "\\-");
+    if escaped.starts_with('.') || escaped.starts_with('\'') {
+        format!("\\&{escaped}")
+    } else {
+        escaped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::clap::{Arg, SubCommand};
+
+    #[test]
+    fn test_render_pages() -> anyhow::Result<()> {
+        let app = App::new("dmd")
+            .about("Manages stacks of branches.")
+            .arg(
+                Arg::with_name("verbose")
+                    .short("v")
+                    .long("verbose")
+                    .help("Print more."),
+            )
+            .subcommand(
+                SubCommand::with_name("sync")
+                    .about("Syncs the stack.\n\nRestacks every branch.")
+                    .arg(Arg::with_name("stack").long("stack").takes_value(true))
+                    .arg(
+                        Arg::with_name("branch")
+                            .required(true)
+                            .help("The branch to sync.")
+                            .long_help("The branch to sync.\n\nDefaults to the current branch."),
+                    ),
+            )
+            .subcommand(SubCommand::with_name("secret").setting(AppSettings::Hidden));
+
+        let pages = render_pages(&app)?;
+        let names: Vec<&str> = pages.iter().map(|page| page.file_name.as_str()).collect();
+        assert_eq!(names, vec!["dmd.1", "dmd-sync.1"]);
+
+        assert!(pages[0]
+            .contents
+            .contains(".SH NAME\ndmd \\- Manages stacks of branches\n"));
+        assert!(pages[0]
+            .contents
+            .contains(".TP\n\\fB\\-v\\fR, \\fB\\-\\-verbose\\fR\nPrint more.\n"));
+        assert!(pages[0]
+            .contents
+            .contains("\\fBdmd\\-sync\\fR(1)\nSyncs the stack\n"));
+
+        let sync = &pages[1].contents;
+        assert!(sync.starts_with(".TH DMD\\-SYNC 1 "));
+        assert!(sync.contains(".SH SYNOPSIS\n\\fBdmd sync\\fR [OPTIONS] \\fIbranch\\fR\n"));
+        assert!(sync.contains(
+            ".SH ARGUMENTS\n.TP\n\\fIbranch\\fR\nThe branch to sync.\n.PP\nDefaults to the current branch.\n"
+        ));
+        assert!(sync.contains(".TP\n\\fB\\-\\-stack\\fR \\fIstack\\fR\n"));
+        assert!(
+            sync.contains(".SH DESCRIPTION\n.PP\nSyncs the stack.\n.PP\nRestacks every branch.\n")
+        );
+        assert!(sync.contains(".SH SEE ALSO\n\\fBdmd\\fR(1)\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("--flush"), "\\-\\-flush");
+        assert_eq!(escape(".hidden"), "\\&.hidden");
+        assert_eq!(escape("a\\b"), "a\\eb");
+    }
+}
@@ -0,0 +1,829 @@
+//! An in-process stand-in for the parts of the GitHub REST and GraphQL APIs which diamond uses,
+//! so that commands which open, update, and merge pull requests can be tested end to end
+//! without a network connection or a token.
+//!
+//! Pull requests are kept in memory. Their branches are read from a bare repo standing in for the remote,
+//! and merging a pull request merges its branch there, the way GitHub would.
+
+use crate::github::Endpoint;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::SystemTime;
+
+/// A mock GitHub serving requests on a local port until it's dropped.
+pub struct MockGitHub {
+    api_url: String,
+    state: Arc<Mutex<State>>,
+    stopped: Arc<AtomicBool>,
+    server: Option<JoinHandle<()>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MockPullRequest {
+    pub number: u64,
+    pub head: String,
+    pub base: String,
+    pub title: String,
+    pub body: String,
+    /// Either `open` or `closed`, where merged pull requests are also closed.
+    pub state: String,
+    pub merged: bool,
+    pub labels: Vec<String>,
+    pub comments: Vec<String>,
+    pub reviewers: Vec<String>,
+    /// The commit the head branch was last seen at, which is kept once the branch is deleted.
+    head_sha: String,
+    created_at: String,
+    merged_at: Option<String>,
+    /// Like `APPROVED` or `CHANGES_REQUESTED`, or `None` while no review is required.
+    review_decision: Option<String>,
+}
+
+/// A thread of review comments on a line of a pull request.
+#[derive(Clone, Debug)]
+pub struct MockReviewThread {
+    pub number: u64,
+    pub path: String,
+    pub line: u64,
+    pub diff_hunk: String,
+    pub resolved: bool,
+    /// Each comment's author and body, in order.
+    pub comments: Vec<(String, String)>,
+}
+
+struct State {
+    /// The bare repo which branches are pushed to.
+    remote: PathBuf,
+    pull_requests: Vec<MockPullRequest>,
+    review_threads: Vec<MockReviewThread>,
+    /// The name and conclusion of each check run, which are reported for every commit.
+    check_runs: Vec<(String, String)>,
+    /// The protected branches, and whether each one allows force-pushes.
+    protected_branches: Vec<(String, bool)>,
+    /// The hosts clients have asked to tunnel to, when the mock is used as their proxy.
+    tunnels: Vec<String>,
+    /// The methods and path endings of requests which are handled, but answered with a 502 as if the response was lost.
+    lost_responses: Vec<(String, String)>,
+}
+
+/// A request, with its path split from its query.
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    body: Value,
+}
+
+impl MockGitHub {
+    /// Starts serving the repo whose branches are in the bare repo at `remote`.
+    pub fn start(remote: PathBuf) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let api_url = format!("http://{}", listener.local_addr()?);
+        let state = Arc::new(Mutex::new(State {
+            remote,
+            pull_requests: Vec::new(),
+            review_threads: Vec::new(),
+            check_runs: Vec::new(),
+            protected_branches: Vec::new(),
+            tunnels: Vec::new(),
+            lost_responses: Vec::new(),
+        }));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let server = {
+            let state = state.clone();
+            let stopped = stopped.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    // A client which goes away mid-request only fails its own request.
+                    if let Ok(stream) = stream {
+                        let _ = serve(&state, stream);
+                    }
+                }
+            })
+        };
+        Ok(Self {
+            api_url,
+            state,
+            stopped,
+            server: Some(server),
+        })
+    }
+
+    pub fn endpoint(&self) -> Endpoint {
+        Endpoint {
+            api_url: self.api_url.clone(),
+            token: "mock-token".to_owned(),
+        }
+    }
+
+    /// Lists every pull request opened so far, in the order they were opened.
+    pub fn pull_requests(&self) -> Vec<MockPullRequest> {
+        self.state.lock().unwrap().pull_requests.clone()
+    }
+
+    /// Finds the most recently opened pull request whose head is `branch`.
+    pub fn pull_request(&self, branch: &str) -> Option<MockPullRequest> {
+        self.pull_requests()
+            .into_iter()
+            .rev()
+            .find(|pull_request| pull_request.head == branch)
+    }
+
+    /// Squash merges the open pull request whose head is `branch`, as if it was merged at `merged_at`,
+    /// like `2000-01-01T00:00:00Z`.
+    pub fn merge(&self, branch: &str, merged_at: &str) {
+        let mut state = self.state.lock().unwrap();
+        let remote = state.remote.clone();
+        let pull_request = state
+            .pull_requests
+            .iter_mut()
+            .rev()
+            .find(|pull_request| pull_request.head == branch && pull_request.state == "open")
+            .unwrap();
+        merge(&remote, pull_request, "squash").unwrap();
+        pull_request.state = "closed".to_owned();
+        pull_request.merged = true;
+        pull_request.merged_at = Some(merged_at.to_owned());
+    }
+
+    /// Records the review decision, like `APPROVED`, on the open pull request from `branch`.
+    pub fn review(&self, branch: &str, decision: &str) {
+        let mut state = self.state.lock().unwrap();
+        let pull_request = state
+            .pull_requests
+            .iter_mut()
+            .rev()
+            .find(|pull_request| pull_request.head == branch && pull_request.state == "open")
+            .unwrap();
+        pull_request.review_decision = Some(decision.to_owned());
+    }
+
+    /// Starts a review thread on the pull request numbered `thread.number`.
+    pub fn add_review_thread(&self, thread: MockReviewThread) {
+        self.state.lock().unwrap().review_threads.push(thread);
+    }
+
+    /// Reports a finished check run named `name` for every commit, with a conclusion like `success`.
+    pub fn add_check_run(&self, name: &str, conclusion: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .check_runs
+            .push((name.to_owned(), conclusion.to_owned()));
+    }
+
+    /// Protects `branch`, letting it be force-pushed to only if `allow_force_pushes` is set.
+    pub fn protect(&self, branch: &str, allow_force_pushes: bool) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .protected_branches
+            .retain(|(protected, _)| protected != branch);
+        state
+            .protected_branches
+            .push((branch.to_owned(), allow_force_pushes));
+    }
+
+    /// Handles the next `method` request whose path ends with `path`, but answers it with a 502,
+    /// like a gateway which timed out after GitHub had already acted on the request.
+    pub fn lose_response(&self, method: &str, path: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .lost_responses
+            .push((method.to_owned(), path.to_owned()));
+    }
+
+    /// Lists the hosts which clients using the mock as a proxy have connected to.
+    pub fn tunnels(&self) -> Vec<String> {
+        self.state.lock().unwrap().tunnels.clone()
+    }
+}
+
+impl Drop for MockGitHub {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wakes the server up, so that it sees it's been stopped.
+        let _ = TcpStream::connect(self.api_url.trim_start_matches("http://"));
+        if let Some(server) = self.server.take() {
+            let _ = server.join();
+        }
+    }
+}
+
+fn serve(state: &Mutex<State>, stream: TcpStream) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = read_request(&mut reader)?;
+    let mut stream = stream;
+    // Acting as a proxy, the mock answers the tunnelled request itself instead of forwarding it.
+    if request.method == "CONNECT" {
+        state.lock().unwrap().tunnels.push(request.path);
+        write!(stream, "HTTP/1.1 200 Connection established\r\n\r\n")?;
+        stream.flush()?;
+        request = read_request(&mut reader)?;
+    }
+    let (status, body) = {
+        let mut state = state.lock().unwrap();
+        let response = respond(&mut state, &request);
+        let lost = state.lost_responses.iter().position(|(method, path)| {
+            *method == request.method && request.path.ends_with(path.as_str())
+        });
+        match lost {
+            Some(lost) => {
+                state.lost_responses.remove(lost);
+                (502, json!({ "message": "Bad Gateway" }))
+            }
+            None => response,
+        }
+    };
+    let body = body.to_string();
+    let reason = match status {
+        200 => "OK",
+        201 => "Created",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        502 => "Bad Gateway",
+        _ => "Unprocessable Entity",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn read_request(reader: &mut impl BufRead) -> anyhow::Result<Request> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        anyhow::bail!("Malformed request line: {request_line:?}");
+    };
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse()?;
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| (percent_decode(name), percent_decode(value)))
+        .collect();
+    Ok(Request {
+        method: method.to_owned(),
+        path: percent_decode(path),
+        query,
+        body: serde_json::from_slice(&body).unwrap_or(Value::Null),
+    })
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 3;
+                        continue;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Handles `request`, returning the response's status and body.
+fn respond(state: &mut State, request: &Request) -> (u16, Value) {
+    if (request.method.as_str(), request.path.as_str()) == ("POST", "/graphql") {
+        return graphql(state, &request.body);
+    }
+    // Paths look like `/repos/{owner}/{repo}/...`, and every request is for the one repo.
+    let segments: Vec<&str> = request.path.trim_start_matches('/').split('/').collect();
+    let (owner, repo, rest) = match segments.as_slice() {
+        ["repos", owner, repo, rest @ ..] => (*owner, *repo, rest),
+        _ => return not_found(),
+    };
+    let number = |segment: &str| segment.parse::<u64>().ok();
+    let query = |name: &str| {
+        request
+            .query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+    let field = |name: &str| request.body.get(name).and_then(Value::as_str);
+    let strings = |name: &str| -> Vec<String> {
+        request
+            .body
+            .get(name)
+            .and_then(Value::as_array)
+            .map(|values| {
+                values
+                    .iter()
+                    .filter_map(|value| value.as_str().map(str::to_owned))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    match (request.method.as_str(), rest) {
+        ("GET", ["pulls"]) => {
+            let head = query("head").map(strip_owner);
+            let wanted_state = query("state").unwrap_or("open");
+            let mut pull_requests = Vec::new();
+            // Like GitHub, the most recently opened pull requests come first.
+            for pull_request in state.pull_requests.iter().rev() {
+                if head.is_some_and(|head| pull_request.head != head)
+                    || (wanted_state != "all" && pull_request.state != wanted_state)
+                {
+                    continue;
+                }
+                pull_requests.push(to_json(&state.remote, owner, repo, pull_request));
+            }
+            (200, Value::Array(pull_requests))
+        }
+        ("POST", ["pulls"]) => {
+            let (Some(head), Some(base)) = (field("head").map(strip_owner), field("base")) else {
+                return unprocessable("`head` and `base` are required.");
+            };
+            let Some(head_sha) = rev_parse(&state.remote, head) else {
+                return unprocessable(&format!("`{head}` does not exist."));
+            };
+            if rev_parse(&state.remote, base).is_none() {
+                return unprocessable(&format!("`{base}` does not exist."));
+            }
+            if state
+                .pull_requests
+                .iter()
+                .any(|pull_request| pull_request.head == head && pull_request.state == "open")
+            {
+                return unprocessable(&format!("A pull request already exists for `{head}`."));
+            }
+            let pull_request = MockPullRequest {
+                number: state.pull_requests.len() as u64 + 1,
+                head: head.to_owned(),
+                base: base.to_owned(),
+                title: field("title").unwrap_or_default().to_owned(),
+                body: field("body").unwrap_or_default().to_owned(),
+                state: "open".to_owned(),
+                merged: false,
+                labels: Vec::new(),
+                comments: Vec::new(),
+                reviewers: Vec::new(),
+                head_sha,
+                created_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+                merged_at: None,
+                review_decision: None,
+            };
+            let response = to_json(&state.remote, owner, repo, &pull_request);
+            state.pull_requests.push(pull_request);
+            (201, response)
+        }
+        ("PATCH", ["pulls", n]) => {
+            let remote = state.remote.clone();
+            let Some(pull_request) = find(state, number(n)) else {
+                return not_found();
+            };
+            if let Some(base) = field("base") {
+                if rev_parse(&remote, base).is_none() {
+                    return unprocessable(&format!("`{base}` does not exist."));
+                }
+                pull_request.base = base.to_owned();
+            }
+            if let Some(title) = field("title") {
+                pull_request.title = title.to_owned();
+            }
+            if let Some(body) = field("body") {
+                pull_request.body = body.to_owned();
+            }
+            (200, to_json(&remote, owner, repo, pull_request))
+        }
+        ("PUT", ["pulls", n, "merge"]) => {
+            let remote = state.remote.clone();
+            let Some(pull_request) = find(state, number(n)) else {
+                return not_found();
+            };
+            if pull_request.state != "open" {
+                return (405, json!({ "message": "Pull Request is not mergeable" }));
+            }
+            let method = field("merge_method").unwrap_or("merge");
+            match merge(&remote, pull_request, method) {
+                Some(merged_at) => {
+                    pull_request.state = "closed".to_owned();
+                    pull_request.merged = true;
+                    pull_request.merged_at = Some(merged_at);
+                    (200, json!({ "merged": true }))
+                }
+                None => (405, json!({ "message": "Pull Request is not mergeable" })),
+            }
+        }
+        ("GET", ["pulls", n, "reviews"]) => match find(state, number(n)) {
+            Some(_) => (200, json!([])),
+            None => not_found(),
+        },
+        ("POST", ["pulls", n, "requested_reviewers"]) => {
+            let remote = state.remote.clone();
+            let Some(pull_request) = find(state, number(n)) else {
+                return not_found();
+            };
+            for reviewer in strings("reviewers") {
+                if !pull_request.reviewers.contains(&reviewer) {
+                    pull_request.reviewers.push(reviewer);
+                }
+            }
+            (201, to_json(&remote, owner, repo, pull_request))
+        }
+        ("POST", ["issues", n, "labels"]) => {
+            let Some(pull_request) = find(state, number(n)) else {
+                return not_found();
+            };
+            for label in strings("labels") {
+                if !pull_request.labels.contains(&label) {
+                    pull_request.labels.push(label);
+                }
+            }
+            (200, labels_json(&pull_request.labels))
+        }
+        ("POST", ["issues", n, "comments"]) => {
+            let Some(pull_request) = find(state, number(n)) else {
+                return not_found();
+            };
+            pull_request
+                .comments
+                .push(field("body").unwrap_or_default().to_owned());
+            (201, json!({ "body": field("body") }))
+        }
+        ("GET", ["commits", _, "check-runs"]) => {
+            let per_page = query("per_page").and_then(number).unwrap_or(30) as usize;
+            let page = query("page").and_then(number).unwrap_or(1) as usize;
+            let check_runs: Vec<Value> = state
+                .check_runs
+                .iter()
+                .skip(per_page * page.saturating_sub(1))
+                .take(per_page)
+                .map(|(name, conclusion)| {
+                    json!({
+                        "name": name,
+                        "status": "completed",
+                        "conclusion": conclusion,
+                        "html_url": null,
+                        "started_at": "2000-01-01T00:00:00Z",
+                        "completed_at": "2000-01-01T00:01:30Z",
+                    })
+                })
+                .collect();
+            (
+                200,
+                json!({ "total_count": state.check_runs.len(), "check_runs": check_runs }),
+            )
+        }
+        ("GET", ["commits", _, "status"]) => (
+            200,
+            json!({ "state": "pending", "total_count": 0, "statuses": [] }),
+        ),
+        // Branch names can contain slashes, so they take up the rest of the path.
+        ("GET", ["branches", branch @ .., "protection"]) => {
+            let branch = percent_decode(&branch.join("/"));
+            match state
+                .protected_branches
+                .iter()
+                .find(|(protected, _)| *protected == branch)
+            {
+                Some((_, allow_force_pushes)) => (
+                    200,
+                    json!({ "allow_force_pushes": { "enabled": allow_force_pushes } }),
+                ),
+                None => not_found(),
+            }
+        }
+        ("GET", ["branches", branch @ ..]) => {
+            let branch = percent_decode(&branch.join("/"));
+            let protected = state
+                .protected_branches
+                .iter()
+                .any(|(protected, _)| *protected == branch);
+            match rev_parse(&state.remote, &branch) {
+                Some(_) => (200, json!({ "name": branch, "protected": protected })),
+                None => not_found(),
+            }
+        }
+        ("GET", ["rules", "branches", ..]) => (200, json!([])),
+        _ => not_found(),
+    }
+}
+
+/// Answers the GraphQL queries diamond sends, for the open pull requests from the branches
+/// given as the variables `b0`, `b1`, and so on, and for the pull requests numbered `p0`, `p1`, and so on.
+fn graphql(state: &State, body: &Value) -> (u16, Value) {
+    let variables = &body["variables"];
+    let (Some(owner), Some(repo)) = (variables["owner"].as_str(), variables["repo"].as_str())
+    else {
+        return (
+            200,
+            json!({ "errors": [{ "message": "`owner` and `repo` are required." }] }),
+        );
+    };
+    if body["query"]
+        .as_str()
+        .unwrap_or_default()
+        .contains("reviewThreads")
+    {
+        return (200, review_threads(state, variables["number"].as_u64()));
+    }
+    let mut repository = serde_json::Map::new();
+    for (name, value) in variables.as_object().into_iter().flatten() {
+        let result = match (name.chars().next(), value) {
+            (Some('b'), Value::String(branch)) => {
+                let nodes: Vec<Value> = state
+                    .pull_requests
+                    .iter()
+                    .filter(|pull_request| {
+                        pull_request.head == *branch && pull_request.state == "open"
+                    })
+                    .map(|pull_request| graphql_node(&state.remote, owner, repo, pull_request))
+                    .collect();
+                json!({ "nodes": nodes })
+            }
+            (Some('p'), Value::Number(number)) => state
+                .pull_requests
+                .iter()
+                .find(|pull_request| Some(pull_request.number) == number.as_u64())
+                .map_or(Value::Null, |pull_request| {
+                    graphql_node(&state.remote, owner, repo, pull_request)
+                }),
+            _ => continue,
+        };
+        repository.insert(name.clone(), result);
+    }
+    (200, json!({ "data": { "repository": repository } }))
+}
+
+/// Lists the review threads on the pull request numbered `number`, all on one page.
+fn review_threads(state: &State, number: Option<u64>) -> Value {
+    if !state
+        .pull_requests
+        .iter()
+        .any(|pull_request| Some(pull_request.number) == number)
+    {
+        return json!({ "data": { "repository": { "pullRequest": null } } });
+    }
+    let nodes: Vec<Value> = state
+        .review_threads
+        .iter()
+        .filter(|thread| Some(thread.number) == number)
+        .map(|thread| {
+            let comments: Vec<Value> = thread
+                .comments
+                .iter()
+                .map(|(author, body)| {
+                    json!({
+                        "author": { "login": author },
+                        "body": body,
+                        "diffHunk": thread.diff_hunk,
+                    })
+                })
+                .collect();
+            json!({
+                "isResolved": thread.resolved,
+                "isOutdated": false,
+                "path": thread.path,
+                "line": thread.line,
+                "originalLine": thread.line,
+                "comments": { "nodes": comments },
+            })
+        })
+        .collect();
+    json!({
+        "data": {
+            "repository": {
+                "pullRequest": {
+                    "reviewThreads": {
+                        "pageInfo": { "hasNextPage": false, "endCursor": null },
+                        "nodes": nodes,
+                    },
+                },
+            },
+        },
+    })
+}
+
+/// Describes a pull request the way the GraphQL API does. No checks are ever reported.
+fn graphql_node(remote: &Path, owner: &str, repo: &str, pull_request: &MockPullRequest) -> Value {
+    let state = match (pull_request.state.as_str(), pull_request.merged) {
+        ("open", _) => "OPEN",
+        (_, true) => "MERGED",
+        (_, false) => "CLOSED",
+    };
+    let review_decision = pull_request.review_decision.clone();
+    let pull_request = to_json(remote, owner, repo, pull_request);
+    json!({
+        "number": pull_request["number"],
+        "title": pull_request["title"],
+        "body": pull_request["body"],
+        "url": pull_request["html_url"],
+        "state": state,
+        "createdAt": pull_request["created_at"],
+        "mergedAt": pull_request["merged_at"],
+        "reviewDecision": review_decision,
+        "headRefName": pull_request["head"]["ref"],
+        "headRefOid": pull_request["head"]["sha"],
+        "baseRefName": pull_request["base"]["ref"],
+        "baseRefOid": pull_request["base"]["sha"],
+        "headRepositoryOwner": { "login": owner },
+        "author": pull_request["user"],
+        "labels": { "nodes": pull_request["labels"] },
+        "commits": { "nodes": [{ "commit": { "statusCheckRollup": null } }] },
+    })
+}
+
+fn find(state: &mut State, number: Option<u64>) -> Option<&mut MockPullRequest> {
+    let number = number?;
+    state
+        .pull_requests
+        .iter_mut()
+        .find(|pull_request| pull_request.number == number)
+}
+
+/// Removes the `owner:` prefix from a head branch from a fork.
+fn strip_owner(head: &str) -> &str {
+    head.split_once(':').map_or(head, |(_, branch)| branch)
+}
+
+fn not_found() -> (u16, Value) {
+    (404, json!({ "message": "Not Found" }))
+}
+
+fn unprocessable(message: &str) -> (u16, Value) {
+    (422, json!({ "message": message }))
+}
+
+fn labels_json(labels: &[String]) -> Value {
+    labels
+        .iter()
+        .map(|label| json!({ "name": label }))
+        .collect()
+}
+
+/// Describes a pull request the way the REST API does.
+/// Like GitHub, the mock keeps `refs/pull/<number>/head` at the pull request's head,
+/// though it only catches up when the pull request is described.
+fn to_json(remote: &Path, owner: &str, repo: &str, pull_request: &MockPullRequest) -> Value {
+    let head_sha =
+        rev_parse(remote, &pull_request.head).unwrap_or_else(|| pull_request.head_sha.clone());
+    git(
+        remote,
+        &[
+            "update-ref",
+            &format!("refs/pull/{}/head", pull_request.number),
+            &head_sha,
+        ],
+    );
+    let base_sha = rev_parse(remote, &pull_request.base).unwrap_or_default();
+    let reviewers: Vec<Value> = pull_request
+        .reviewers
+        .iter()
+        .map(|login| json!({ "login": login }))
+        .collect();
+    json!({
+        "number": pull_request.number,
+        "title": pull_request.title,
+        "body": pull_request.body,
+        "html_url": format!("https://github.com/{owner}/{repo}/pull/{}", pull_request.number),
+        "head": { "ref": pull_request.head, "sha": head_sha, "user": { "login": owner } },
+        "base": { "ref": pull_request.base, "sha": base_sha },
+        "state": pull_request.state,
+        "created_at": pull_request.created_at,
+        "merged_at": pull_request.merged_at,
+        "user": { "login": owner },
+        "requested_reviewers": reviewers,
+        "requested_teams": [],
+        "labels": labels_json(&pull_request.labels),
+    })
+}
+
+/// Merges the pull request's head into its base in `remote` with `method`,
+/// returning when it was merged, or `None` if it conflicts.
+/// Rebase merges are made the same way as squash merges, as a single new commit on the base.
+fn merge(remote: &Path, pull_request: &mut MockPullRequest, method: &str) -> Option<String> {
+    let base_sha = rev_parse(remote, &pull_request.base)?;
+    let head_sha = rev_parse(remote, &pull_request.head)?;
+    pull_request.head_sha = head_sha.clone();
+    let tree = git(
+        remote,
+        &["merge-tree", "--write-tree", &base_sha, &head_sha],
+    )?;
+    let commit = match method {
+        "merge" => git(
+            remote,
+            &[
+                "commit-tree",
+                &tree,
+                "-p",
+                &base_sha,
+                "-p",
+                &head_sha,
+                "-m",
+                &format!(
+                    "Merge pull request #{} from {}",
+                    pull_request.number, pull_request.head
+                ),
+            ],
+        )?,
+        _ => git(
+            remote,
+            &[
+                "commit-tree",
+                &tree,
+                "-p",
+                &base_sha,
+                "-m",
+                &format!("{} (#{})", pull_request.title, pull_request.number),
+            ],
+        )?,
+    };
+    git(
+        remote,
+        &[
+            "update-ref",
+            &format!("refs/heads/{}", pull_request.base),
+            &commit,
+        ],
+    )?;
+    git(
+        remote,
+        &[
+            "show",
+            "--no-patch",
+            "--format=%cd",
+            "--date=format-local:%Y-%m-%dT%H:%M:%SZ",
+            &commit,
+        ],
+    )
+}
+
+fn rev_parse(remote: &Path, branch: &str) -> Option<String> {
+    git(
+        remote,
+        &[
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("refs/heads/{branch}"),
+        ],
+    )
+}
+
+/// Runs a Git command in `remote`, returning its trimmed stdout if it succeeds.
+fn git(remote: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .args([
+            "-c",
+            "user.name=GitHub",
+            "-c",
+            "user.email=noreply@github.com",
+        ])
+        .args(args)
+        .current_dir(remote)
+        .env("TZ", "UTC")
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
@@ -0,0 +1,31 @@
+use std::process::{Command, Stdio};
+
+/// Shows a desktop notification, using `osascript` on macOS and `notify-send` elsewhere.
+/// Notifications are best-effort: if neither tool is available, nothing is shown.
+pub fn send(title: &str, body: &str) {
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = Command::new("osascript");
+        command.args([
+            "-e",
+            &format!(
+                "display notification {} with title {}",
+                applescript_string(body),
+                applescript_string(title)
+            ),
+        ]);
+        command
+    } else {
+        let mut command = Command::new("notify-send");
+        command.args(["--app-name", "dmd", title, body]);
+        command
+    };
+    let _ = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+}
+
+fn applescript_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
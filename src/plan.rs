@@ -0,0 +1,89 @@
+use serde::Deserialize;
+
+/// One branch described by a plan file.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PlannedBranch {
+    pub name: String,
+    /// Defaults to the branch before it in the plan, or the current branch for the first one.
+    pub parent: Option<String>,
+    /// The message for an empty commit made on the branch when it's created,
+    /// so that it shows up in pull requests and logs before there's any code on it.
+    pub message: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Plan {
+    #[serde(default)]
+    branch: Vec<PlannedBranch>,
+}
+
+/// Parses a plan file, like:
+///
+/// ```toml
+/// [[branch]]
+/// name = "ch/parser"
+/// message = "Add a parser"
+///
+/// [[branch]]
+/// name = "ch/parser-tests"
+/// parent = "ch/parser"
+/// ```
+pub fn parse(contents: &str) -> anyhow::Result<Vec<PlannedBranch>> {
+    let plan: Plan =
+        toml::from_str(contents).map_err(|err| anyhow::anyhow!("Cannot parse the plan: {err}"))?;
+    for (index, branch) in plan.branch.iter().enumerate() {
+        if plan.branch[..index]
+            .iter()
+            .any(|earlier| earlier.name == branch.name)
+        {
+            anyhow::bail!("`{}` is in the plan more than once.", branch.name);
+        }
+    }
+    Ok(plan.branch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> anyhow::Result<()> {
+        let plan = parse(
+            r#"
+# Splits up the parser.
+[[branch]]
+name = "ch/parser"  # The first one.
+message = "Add a parser # for real"
+
+[[branch]]
+name = 'ch/parser-tests'
+parent = "ch/parser"
+message = "Test \"the\" parser"
+"#,
+        )?;
+        assert_eq!(
+            plan,
+            vec![
+                PlannedBranch {
+                    name: "ch/parser".to_owned(),
+                    parent: None,
+                    message: Some("Add a parser # for real".to_owned()),
+                },
+                PlannedBranch {
+                    name: "ch/parser-tests".to_owned(),
+                    parent: Some("ch/parser".to_owned()),
+                    message: Some("Test \"the\" parser".to_owned()),
+                },
+            ]
+        );
+
+        assert!(parse("name = \"ch/a\"").is_err());
+        assert!(parse("[[branch]]\nparent = \"main\"").is_err());
+        assert!(parse("[[branch]]\nname = ch/a").is_err());
+        assert!(parse("[[branch]]\nname = \"ch/a\"\ncolor = \"red\"").is_err());
+        assert!(parse("[[branch]]\nname = \"ch/a\"\n[[branch]]\nname = \"ch/a\"").is_err());
+        Ok(())
+    }
+}
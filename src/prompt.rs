@@ -0,0 +1,49 @@
+use std::io::{BufRead, IsTerminal, Write};
+
+/// Asks a yes/no question before doing something destructive, defaulting to no.
+///
+/// `yes` comes from `--yes`, which answers every question with yes without asking.
+/// Without it, nothing is done unless someone at a terminal agrees,
+/// so scripts have to opt into destructive operations explicitly.
+pub fn confirm(yes: bool, message: &str) -> anyhow::Result<bool> {
+    let stdin = std::io::stdin();
+    let terminal = stdin.is_terminal();
+    answer(yes, terminal, message, &mut stdin.lock())
+}
+
+fn answer(
+    yes: bool,
+    terminal: bool,
+    message: &str,
+    input: &mut impl BufRead,
+) -> anyhow::Result<bool> {
+    print!("{message} [y/N] ");
+    if yes {
+        println!("y (--yes)");
+        return Ok(true);
+    }
+    if !terminal {
+        println!("n (not a terminal, pass --yes to agree)");
+        return Ok(false);
+    }
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_answer() -> anyhow::Result<()> {
+        let mut no_input: &[u8] = b"";
+        assert!(answer(true, false, "Push?", &mut no_input)?);
+        assert!(!answer(false, false, "Push?", &mut "y\n".as_bytes())?);
+        assert!(answer(false, true, "Push?", &mut "Yes\n".as_bytes())?);
+        assert!(!answer(false, true, "Push?", &mut "\n".as_bytes())?);
+        assert!(!answer(false, true, "Push?", &mut no_input)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// The set of characters used to draw the stack graph.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Style {
+    Unicode,
+    Ascii,
+}
+
+impl Style {
+    /// Picks a style based on the environment.
+    /// Dumb terminals and non-UTF-8 locales get plain ASCII, everything else gets box-drawing characters.
+    pub fn detect() -> Self {
+        let term = std::env::var("TERM").ok();
+        let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+            .iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|value| !value.is_empty());
+        Self::detect_from(term.as_deref(), locale.as_deref())
+    }
+
+    fn detect_from(term: Option<&str>, locale: Option<&str>) -> Self {
+        if term == Some("dumb") {
+            return Style::Ascii;
+        }
+        let Some(locale) = locale else {
+            return Style::Ascii;
+        };
+        let locale = locale.to_lowercase();
+        if locale.contains("utf-8") || locale.contains("utf8") {
+            Style::Unicode
+        } else {
+            Style::Ascii
+        }
+    }
+
+    fn branch(&self) -> &'static str {
+        match self {
+            Style::Unicode => "├── ",
+            Style::Ascii => "|-- ",
+        }
+    }
+
+    fn last_branch(&self) -> &'static str {
+        match self {
+            Style::Unicode => "└── ",
+            Style::Ascii => "`-- ",
+        }
+    }
+
+    fn continuation(&self) -> &'static str {
+        match self {
+            Style::Unicode => "│   ",
+            Style::Ascii => "|   ",
+        }
+    }
+
+    fn marker(&self, current: bool) -> &'static str {
+        match (self, current) {
+            (Style::Unicode, true) => "◉",
+            (Style::Unicode, false) => "○",
+            (Style::Ascii, true) => "*",
+            (Style::Ascii, false) => "o",
+        }
+    }
+}
+
+/// A user's choice of style, as passed on the command line.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StyleChoice {
+    Auto,
+    Unicode,
+    Ascii,
+}
+
+impl StyleChoice {
+    pub fn resolve(&self) -> Style {
+        match self {
+            StyleChoice::Auto => Style::detect(),
+            StyleChoice::Unicode => Style::Unicode,
+            StyleChoice::Ascii => Style::Ascii,
+        }
+    }
+}
+
+impl FromStr for StyleChoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "auto" => Ok(StyleChoice::Auto),
+            "unicode" => Ok(StyleChoice::Unicode),
+            "ascii" => Ok(StyleChoice::Ascii),
+            _ => anyhow::bail!("Unknown style `{s}`, expected one of: auto, unicode, ascii."),
+        }
+    }
+}
+
+/// How `dmd log` prints the stack.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    Tree,
+    /// A Mermaid flowchart, which GitHub renders in Markdown.
+    Mermaid,
+    /// A Graphviz digraph.
+    Dot,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "tree" => Ok(Format::Tree),
+            "mermaid" => Ok(Format::Mermaid),
+            "dot" => Ok(Format::Dot),
+            _ => anyhow::bail!("Unknown format `{s}`, expected one of: tree, mermaid, dot."),
+        }
+    }
+}
+
+/// The tree of branches to render, rooted at `root`.
+pub struct Tree<'a> {
+    pub root: &'a str,
+    /// Maps each branch to its children, which are drawn in the order provided.
+    pub children: &'a HashMap<String, Vec<String>>,
+    pub current_branch: Option<&'a str>,
+    /// Extra text to show after a branch's name.
+    pub labels: &'a HashMap<String, String>,
+}
+
+/// Renders `tree` into a string, one branch per line.
+pub fn render_tree(style: Style, tree: &Tree) -> String {
+    tree_lines(style, tree)
+        .into_iter()
+        .map(|(_, line)| line + "\n")
+        .collect()
+}
+
+/// Renders `tree` into lines without trailing newlines, each paired with the branch it shows.
+pub fn tree_lines(style: Style, tree: &Tree) -> Vec<(String, String)> {
+    let mut output = vec![(tree.root.to_owned(), branch_line(style, tree, tree.root))];
+    render_children(style, tree, tree.root, &mut String::new(), &mut output);
+    output
+}
+
+/// Renders `tree` as a Mermaid flowchart.
+/// Branches in `links` link to the given URL, like their pull request.
+pub fn render_mermaid(tree: &Tree, links: &HashMap<String, String>) -> String {
+    let branches = walk(tree);
+    let mut output = "flowchart TD\n".to_owned();
+    for (i, (_, branch)) in branches.iter().enumerate() {
+        let mut text = branch.to_string();
+        if let Some(label) = tree.labels.get(*branch) {
+            text.push_str(&format!("<br/>{label}"));
+        }
+        output.push_str(&format!("    n{i}[\"{}\"]\n", text.replace('"', "#quot;")));
+    }
+    for (i, (parent, _)) in branches.iter().enumerate() {
+        if let Some(parent) = parent {
+            output.push_str(&format!("    n{parent} --> n{i}\n"));
+        }
+    }
+    for (i, (_, branch)) in branches.iter().enumerate() {
+        if let Some(link) = links.get(*branch) {
+            output.push_str(&format!(
+                "    click n{i} href \"{}\" _blank\n",
+                link.replace('"', "%22")
+            ));
+        }
+        if tree.current_branch == Some(*branch) {
+            output.push_str(&format!("    style n{i} stroke-width:3px\n"));
+        }
+    }
+    output
+}
+
+/// Renders `tree` as a Graphviz digraph.
+/// Branches in `links` link to the given URL, like their pull request.
+pub fn render_dot(tree: &Tree, links: &HashMap<String, String>) -> String {
+    let quote = |text: &str| {
+        let escaped = text.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("\"{}\"", escaped.replace('\n', "\\n"))
+    };
+    let branches = walk(tree);
+    let mut output = "digraph stack {\n    node [shape=box];\n".to_owned();
+    for (i, (_, branch)) in branches.iter().enumerate() {
+        let mut text = branch.to_string();
+        if let Some(label) = tree.labels.get(*branch) {
+            text.push_str(&format!("\n{label}"));
+        }
+        let mut attributes = vec![format!("label={}", quote(&text))];
+        if let Some(link) = links.get(*branch) {
+            attributes.push(format!("URL={}", quote(link)));
+        }
+        if tree.current_branch == Some(*branch) {
+            attributes.push("style=bold".to_owned());
+        }
+        output.push_str(&format!("    n{i} [{}];\n", attributes.join(", ")));
+    }
+    for (i, (parent, _)) in branches.iter().enumerate() {
+        if let Some(parent) = parent {
+            output.push_str(&format!("    n{parent} -> n{i};\n"));
+        }
+    }
+    output.push_str("}\n");
+    output
+}
+
+/// Lists every branch in `tree` depth-first, each paired with the index of its parent in the list.
+fn walk<'a>(tree: &'a Tree) -> Vec<(Option<usize>, &'a str)> {
+    fn walk_children<'a>(
+        tree: &'a Tree,
+        parent: usize,
+        branches: &mut Vec<(Option<usize>, &'a str)>,
+    ) {
+        for child in tree.children.get(branches[parent].1).into_iter().flatten() {
+            branches.push((Some(parent), child));
+            walk_children(tree, branches.len() - 1, branches);
+        }
+    }
+    let mut branches = vec![(None, tree.root)];
+    walk_children(tree, 0, &mut branches);
+    branches
+}
+
+fn branch_line(style: Style, tree: &Tree, branch: &str) -> String {
+    let marker = style.marker(tree.current_branch == Some(branch));
+    match tree.labels.get(branch) {
+        Some(label) => format!("{marker} {branch} {label}"),
+        None => format!("{marker} {branch}"),
+    }
+}
+
+fn render_children(
+    style: Style,
+    tree: &Tree,
+    branch: &str,
+    prefix: &mut String,
+    output: &mut Vec<(String, String)>,
+) {
+    let Some(branch_children) = tree.children.get(branch) else {
+        return;
+    };
+    for (i, child) in branch_children.iter().enumerate() {
+        let is_last = i + 1 == branch_children.len();
+        let connector = if is_last {
+            style.last_branch()
+        } else {
+            style.branch()
+        };
+        output.push((
+            child.clone(),
+            format!("{prefix}{connector}{}", branch_line(style, tree, child)),
+        ));
+
+        let prefix_len = prefix.len();
+        prefix.push_str(if is_last {
+            "    "
+        } else {
+            style.continuation()
+        });
+        render_children(style, tree, child, prefix, output);
+        prefix.truncate(prefix_len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example_children() -> HashMap<String, Vec<String>> {
+        let mut children = HashMap::new();
+        children.insert(
+            "main".to_owned(),
+            vec!["ch/branch-1".to_owned(), "ch/unrelated".to_owned()],
+        );
+        children.insert("ch/branch-1".to_owned(), vec!["ch/branch-2".to_owned()]);
+        children
+    }
+
+    #[test]
+    fn test_render_tree_unicode() {
+        let mut labels = HashMap::new();
+        labels.insert("ch/unrelated".to_owned(), "(archived)".to_owned());
+        let rendered = render_tree(
+            Style::Unicode,
+            &Tree {
+                root: "main",
+                children: &example_children(),
+                current_branch: Some("ch/branch-2"),
+                labels: &labels,
+            },
+        );
+        assert_eq!(
+            rendered,
+            "○ main\n├── ○ ch/branch-1\n│   └── ◉ ch/branch-2\n└── ○ ch/unrelated (archived)\n",
+        );
+    }
+
+    #[test]
+    fn test_render_tree_ascii() {
+        let rendered = render_tree(
+            Style::Ascii,
+            &Tree {
+                root: "main",
+                children: &example_children(),
+                current_branch: Some("main"),
+                labels: &HashMap::new(),
+            },
+        );
+        assert_eq!(
+            rendered,
+            "* main\n|-- o ch/branch-1\n|   `-- o ch/branch-2\n`-- o ch/unrelated\n",
+        );
+    }
+
+    #[test]
+    fn test_render_diagrams() {
+        let mut labels = HashMap::new();
+        labels.insert("ch/branch-1".to_owned(), "(1 commit)".to_owned());
+        let mut links = HashMap::new();
+        links.insert(
+            "ch/branch-1".to_owned(),
+            "https://github.com/o/r/pull/1".to_owned(),
+        );
+        let tree = Tree {
+            root: "main",
+            children: &example_children(),
+            current_branch: Some("ch/branch-2"),
+            labels: &labels,
+        };
+
+        assert_eq!(
+            render_mermaid(&tree, &links),
+            "flowchart TD\n\
+            \x20   n0[\"main\"]\n\
+            \x20   n1[\"ch/branch-1<br/>(1 commit)\"]\n\
+            \x20   n2[\"ch/branch-2\"]\n\
+            \x20   n3[\"ch/unrelated\"]\n\
+            \x20   n0 --> n1\n\
+            \x20   n1 --> n2\n\
+            \x20   n0 --> n3\n\
+            \x20   click n1 href \"https://github.com/o/r/pull/1\" _blank\n\
+            \x20   style n2 stroke-width:3px\n",
+        );
+        assert_eq!(
+            render_dot(&tree, &links),
+            "digraph stack {\n\
+            \x20   node [shape=box];\n\
+            \x20   n0 [label=\"main\"];\n\
+            \x20   n1 [label=\"ch/branch-1\\n(1 commit)\", URL=\"https://github.com/o/r/pull/1\"];\n\
+            \x20   n2 [label=\"ch/branch-2\", style=bold];\n\
+            \x20   n3 [label=\"ch/unrelated\"];\n\
+            \x20   n0 -> n1;\n\
+            \x20   n1 -> n2;\n\
+            \x20   n0 -> n3;\n\
+            }\n",
+        );
+    }
+
+    #[test]
+    fn test_detect_style() {
+        assert_eq!(
+            Style::detect_from(Some("xterm-256color"), Some("en_US.UTF-8")),
+            Style::Unicode,
+        );
+        assert_eq!(
+            Style::detect_from(Some("dumb"), Some("en_US.UTF-8")),
+            Style::Ascii,
+        );
+        assert_eq!(Style::detect_from(Some("xterm"), Some("C")), Style::Ascii);
+        assert_eq!(Style::detect_from(None, None), Style::Ascii);
+    }
+}
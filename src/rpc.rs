@@ -0,0 +1,185 @@
+//! The JSON-RPC 2.0 protocol which `dmd serve` speaks, with one message on each line.
+
+use crate::exit;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+#[derive(Debug, Deserialize)]
+pub struct Request {
+    jsonrpc: String,
+    /// Missing for notifications, which aren't answered.
+    pub id: Option<Value>,
+    pub method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// Why a request failed, sent back to the client as a JSON-RPC error.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Error {
+    pub code: i64,
+    pub message: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CheckoutParams {
+    pub branch: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RestackParams {
+    /// Restack relative to this branch, instead of the current branch.
+    pub branch: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SubmitParams {
+    /// Only push, without opening or updating pull requests.
+    pub no_pr: bool,
+}
+
+impl Request {
+    /// Reads the request's params as `T`, treating missing params as an empty object.
+    pub fn params<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        let params = match &self.params {
+            Value::Null => json!({}),
+            params => params.clone(),
+        };
+        serde_json::from_value(params).map_err(|err| Error {
+            code: INVALID_PARAMS,
+            message: format!("Invalid params for `{}`: {err}", self.method),
+        })
+    }
+}
+
+impl Error {
+    pub fn method_not_found(method: &str) -> Self {
+        Self {
+            code: METHOD_NOT_FOUND,
+            message: format!("Unknown method `{method}`."),
+        }
+    }
+}
+
+/// Errors from commands carry the exit code `dmd` would have exited with,
+/// so that clients can tell conflicts and the like apart.
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        Self {
+            code: exit::code(&err) as i64,
+            message: strip_colors(&format!("{err:#}")),
+        }
+    }
+}
+
+/// Parses a line sent by a client, or returns the error response to send back if it isn't a request.
+pub fn parse_request(line: &str) -> Result<Request, Value> {
+    let value: Value = serde_json::from_str(line)
+        .map_err(|err| error_response(&Value::Null, PARSE_ERROR, &err.to_string()))?;
+    let id = value.get("id").cloned().unwrap_or(Value::Null);
+    match serde_json::from_value::<Request>(value) {
+        Ok(request) if request.jsonrpc == "2.0" => Ok(request),
+        Ok(_) => Err(error_response(
+            &id,
+            INVALID_REQUEST,
+            "Only JSON-RPC 2.0 is supported.",
+        )),
+        Err(err) => Err(error_response(&id, INVALID_REQUEST, &err.to_string())),
+    }
+}
+
+/// Builds the response to the request with `id`.
+pub fn response(id: &Value, result: Result<Value, Error>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(err) => error_response(id, err.code, &err.message),
+    }
+}
+
+fn error_response(id: &Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+/// Removes the terminal color codes which error messages are printed with.
+fn strip_colors(message: &str) -> String {
+    let mut stripped = String::with_capacity(message.len());
+    let mut chars = message.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let request = parse_request(
+            r#"{"jsonrpc": "2.0", "id": 1, "method": "checkout", "params": {"branch": "ch/one"}}"#,
+        )
+        .unwrap();
+        assert_eq!(request.id, Some(json!(1)));
+        assert_eq!(request.method, "checkout");
+        let params: CheckoutParams = request.params().unwrap();
+        assert_eq!(params.branch, "ch/one");
+        // Params which a method doesn't take are ignored.
+        assert!(!request.params::<SubmitParams>().unwrap().no_pr);
+
+        let notification = parse_request(r#"{"jsonrpc": "2.0", "method": "log"}"#).unwrap();
+        assert_eq!(notification.id, None);
+        assert!(!notification.params::<SubmitParams>().unwrap().no_pr);
+        assert_eq!(
+            notification.params::<CheckoutParams>().unwrap_err().code,
+            INVALID_PARAMS
+        );
+
+        let err = parse_request("{").unwrap_err();
+        assert_eq!(err["error"]["code"], PARSE_ERROR);
+        assert_eq!(err["id"], Value::Null);
+        let err = parse_request(r#"{"jsonrpc": "1.0", "id": "a", "method": "log"}"#).unwrap_err();
+        assert_eq!(err["error"]["code"], INVALID_REQUEST);
+        assert_eq!(err["id"], "a");
+        let err = parse_request(r#"{"jsonrpc": "2.0", "id": 2}"#).unwrap_err();
+        assert_eq!(err["error"]["code"], INVALID_REQUEST);
+    }
+
+    #[test]
+    fn test_response() {
+        assert_eq!(
+            response(&json!(1), Ok(json!({ "branch": "ch/one" }))),
+            json!({ "jsonrpc": "2.0", "id": 1, "result": { "branch": "ch/one" } })
+        );
+        let err = Error::from(anyhow::anyhow!("\x1b[31mCannot find `ch/two`.\x1b[0m"));
+        assert_eq!(err.message, "Cannot find `ch/two`.");
+        assert_eq!(
+            response(&json!(2), Err(Error::method_not_found("nope"))),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 2,
+                "error": { "code": METHOD_NOT_FOUND, "message": "Unknown method `nope`." },
+            })
+        );
+    }
+}
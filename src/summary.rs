@@ -0,0 +1,146 @@
+/// Something that happened to a branch during a run.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    Pulled,
+    Rebased,
+    Skipped,
+    Pushed,
+    Created,
+    Updated,
+    Untracked,
+    Archived,
+    Stale,
+    Failed,
+}
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Pulled => "pulled",
+            Outcome::Rebased => "rebased",
+            Outcome::Skipped => "skipped",
+            Outcome::Pushed => "pushed",
+            Outcome::Created => "PR created",
+            Outcome::Updated => "PR updated",
+            Outcome::Untracked => "untracked",
+            Outcome::Archived => "archived",
+            Outcome::Stale => "stale",
+            Outcome::Failed => "failed",
+        }
+    }
+}
+
+/// What happened to each branch during `dmd sync`, `dmd submit`, or `dmd restack`,
+/// printed as a table at the end so it doesn't get lost in Git's output.
+#[derive(Default)]
+pub struct Summary {
+    /// Each branch with what happened to it, in the order the branches were first reached.
+    rows: Vec<(String, Vec<(Outcome, String)>)>,
+}
+
+impl Summary {
+    /// Records that `outcome` happened to `branch`, with `detail` like a link or the reason it failed.
+    pub fn record(&mut self, branch: &str, outcome: Outcome, detail: impl Into<String>) {
+        let entry = (outcome, detail.into());
+        match self.rows.iter_mut().find(|(name, _)| name == branch) {
+            Some((_, outcomes)) => outcomes.push(entry),
+            None => self.rows.push((branch.to_owned(), vec![entry])),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Lists what happened to each branch, for clients of `dmd serve`.
+    pub fn to_json(&self) -> serde_json::Value {
+        self.rows
+            .iter()
+            .map(|(branch, outcomes)| {
+                let outcomes: Vec<serde_json::Value> = outcomes
+                    .iter()
+                    .map(|(outcome, detail)| {
+                        serde_json::json!({ "outcome": outcome.as_str(), "detail": detail })
+                    })
+                    .collect();
+                serde_json::json!({ "branch": branch, "outcomes": outcomes })
+            })
+            .collect()
+    }
+
+    pub fn render(&self) -> String {
+        let rows: Vec<[String; 3]> = self
+            .rows
+            .iter()
+            .map(|(branch, outcomes)| {
+                let results: Vec<&str> = outcomes
+                    .iter()
+                    .map(|(outcome, _)| outcome.as_str())
+                    .collect();
+                let details: Vec<&str> = outcomes
+                    .iter()
+                    .map(|(_, detail)| detail.as_str())
+                    .filter(|detail| !detail.is_empty())
+                    .collect();
+                [branch.clone(), results.join(", "), details.join("; ")]
+            })
+            .collect();
+        let header = [
+            "Branch".to_owned(),
+            "Result".to_owned(),
+            "Details".to_owned(),
+        ];
+        let widths: Vec<usize> = (0..2)
+            .map(|column| {
+                std::iter::once(&header)
+                    .chain(&rows)
+                    .map(|row| row[column].chars().count())
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+
+        let mut out = String::from("Summary:\n");
+        for row in std::iter::once(&header).chain(&rows) {
+            let line = format!(
+                "  {:branch_width$}  {:result_width$}  {}",
+                row[0],
+                row[1],
+                row[2],
+                branch_width = widths[0],
+                result_width = widths[1],
+            );
+            out.push_str(line.trim_end());
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut summary = Summary::default();
+        assert!(summary.is_empty());
+        summary.record("ch/branch-1", Outcome::Rebased, "");
+        summary.record("ch/branch-2", Outcome::Failed, "conflicts in src/main.rs");
+        summary.record("ch/branch-1", Outcome::Pushed, "");
+        summary.record(
+            "ch/branch-1",
+            Outcome::Created,
+            "https://github.com/crockeo/diamond/pull/1",
+        );
+        assert_eq!(
+            summary.render(),
+            "\
+Summary:
+  Branch       Result                       Details
+  ch/branch-1  rebased, pushed, PR created  https://github.com/crockeo/diamond/pull/1
+  ch/branch-2  failed                       conflicts in src/main.rs
+"
+        );
+    }
+}
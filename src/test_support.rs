@@ -0,0 +1,155 @@
+use crate::config;
+use crate::database::Database;
+use crate::github::Endpoint;
+use crate::mock_github::MockGitHub;
+use crate::Context;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempdir::TempDir;
+
+/// A Git repo in a temporary directory, initialized for diamond,
+/// with a bare repo standing in for its remote at `origin`.
+///
+/// The remote's URL looks like a GitHub SSH URL so that it can be parsed like a real remote,
+/// but SSH is replaced with a script which serves the bare repo instead.
+pub struct TestRepo {
+    _temp_dir: TempDir,
+    pub root: PathBuf,
+    pub remote: PathBuf,
+    database: Database,
+    github_endpoint: Option<Endpoint>,
+}
+
+impl TestRepo {
+    pub fn new() -> anyhow::Result<Self> {
+        let temp_dir = TempDir::new("diamond-integration-tests")?;
+        let root = temp_dir.path().join("repo");
+        let remote = temp_dir.path().join("remote.git");
+        std::fs::create_dir_all(&root)?;
+
+        run_git(
+            temp_dir.path(),
+            &["init", "--quiet", "--bare", "remote.git"],
+        )?;
+
+        let fake_ssh = temp_dir.path().join("fake-ssh");
+        std::fs::write(
+            &fake_ssh,
+            format!(
+                "#!/bin/sh\nfor last; do :; done\nexec sh -c \"${{last%% *}} '{}'\"\n",
+                remote.display()
+            ),
+        )?;
+        std::fs::set_permissions(&fake_ssh, std::fs::Permissions::from_mode(0o755))?;
+
+        run_git(&root, &["init", "--quiet", "--initial-branch", "main"])?;
+        for (key, value) in [
+            ("user.name", "Diamond Tests"),
+            ("user.email", "diamond@example.com"),
+            ("commit.gpgsign", "false"),
+            ("core.sshCommand", &fake_ssh.display().to_string()),
+        ] {
+            run_git(&root, &["config", key, value])?;
+        }
+        run_git(
+            &root,
+            &[
+                "remote",
+                "add",
+                "origin",
+                "ssh://git@github.com/diamond/test-repo.git",
+            ],
+        )?;
+
+        let database = Database::new(root.join(".git").join("diamond.sqlite3"))?;
+        let mut repo = Self {
+            _temp_dir: temp_dir,
+            root,
+            remote,
+            database,
+            github_endpoint: None,
+        };
+        repo.commit("README.md", "# Test Repo\n", "Initial commit")?;
+        repo.git(&["push", "--quiet", "origin", "main"])?;
+        repo.run(|ctx| {
+            ctx.tx.set_remote("origin")?;
+            ctx.tx.set_root_branch("main")?;
+            Ok(())
+        })?;
+        Ok(repo)
+    }
+
+    /// Runs `f` with a fresh context, committing its transaction when it finishes
+    /// the same way a single invocation of `dmd` would.
+    pub fn run(
+        &mut self,
+        f: impl FnOnce(&mut Context) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let tx = self.database.transaction()?;
+        let mut ctx = Context {
+            repo_root: self.root.clone(),
+            cwd: self.root.clone(),
+            recurse_submodules: config::get_bool(&tx, config::SUBMODULES_RECURSE)?,
+            git_timeout: config::get_seconds(&tx, config::GIT_TIMEOUT)?
+                .map(std::time::Duration::from_secs),
+            tx,
+            verbose: false,
+            output: crate::annotate::Output::Text,
+            yes: true,
+            summary: Default::default(),
+            github_endpoint: self.github_endpoint.clone(),
+        };
+        let result = f(&mut ctx);
+        crate::finish(ctx, result)
+    }
+
+    /// Starts a mock GitHub for the remote, which commands run from now on talk to instead of GitHub.
+    pub fn mock_github(&mut self) -> anyhow::Result<MockGitHub> {
+        let github = MockGitHub::start(self.remote.clone())?;
+        self.github_endpoint = Some(github.endpoint());
+        Ok(github)
+    }
+
+    /// Runs a Git command in the repo, returning its trimmed stdout.
+    pub fn git(&self, args: &[&str]) -> anyhow::Result<String> {
+        run_git(&self.root, args)
+    }
+
+    /// Runs a Git command in the bare remote repo, returning its trimmed stdout.
+    pub fn remote_git(&self, args: &[&str]) -> anyhow::Result<String> {
+        run_git(&self.remote, args)
+    }
+
+    /// Writes `contents` to `path` and commits it on the current branch, returning the new commit.
+    pub fn commit(&self, path: &str, contents: &str, message: &str) -> anyhow::Result<String> {
+        std::fs::write(self.root.join(path), contents)?;
+        self.git(&["add", path])?;
+        self.git(&["commit", "--quiet", "--message", message])?;
+        self.git(&["rev-parse", "HEAD"])
+    }
+
+    pub fn current_branch(&self) -> anyhow::Result<String> {
+        self.git(&["branch", "--show-current"])
+    }
+
+    /// Returns true if `ancestor` is reachable from `commit`.
+    pub fn is_ancestor(&self, ancestor: &str, commit: &str) -> anyhow::Result<bool> {
+        let status = Command::new("git")
+            .args(["merge-base", "--is-ancestor", ancestor, commit])
+            .current_dir(&self.root)
+            .status()?;
+        Ok(status.success())
+    }
+}
+
+fn run_git(cwd: &Path, args: &[&str]) -> anyhow::Result<String> {
+    let output = Command::new("git").args(args).current_dir(cwd).output()?;
+    anyhow::ensure!(
+        output.status.success(),
+        "`git {}` failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
@@ -0,0 +1,143 @@
+/// Finds the first ticket key, like `ABC-123` for Jira or Linear, in a commit message.
+/// When `projects` are given, like `ABC`, only keys in one of them are found.
+pub fn find_in_message(message: &str, projects: &[String]) -> Option<String> {
+    let starts = std::iter::once(0).chain(
+        message
+            .char_indices()
+            .filter(|(_, c)| !c.is_ascii_alphanumeric())
+            .map(|(i, c)| i + c.len_utf8()),
+    );
+    starts
+        .filter_map(|start| parse_key(&message[start..]))
+        .find(|key| {
+            key.chars().all(|c| !c.is_ascii_lowercase())
+                && (projects.is_empty() || in_projects(key, projects))
+        })
+        .map(str::to_owned)
+}
+
+/// Finds the ticket key at the start of a part of `branch`, like `abc-123` in `ch/abc-123-fix-leak`,
+/// whose project is one of `projects`, like `ABC`. Branch names are often lowercase, so the key is uppercased.
+/// Lowercase words like `utf-8` in `ch/utf-8-support` look just like keys,
+/// so without any `projects` to tell them apart, nothing is found.
+pub fn find_in_branch(branch: &str, projects: &[String]) -> Option<String> {
+    branch
+        .split('/')
+        .filter_map(parse_key)
+        .find(|key| in_projects(key, projects))
+        .map(str::to_ascii_uppercase)
+}
+
+fn in_projects(key: &str, projects: &[String]) -> bool {
+    let project = key.split_once('-').map_or(key, |(project, _)| project);
+    projects
+        .iter()
+        .any(|known| known.eq_ignore_ascii_case(project))
+}
+
+/// Parses a key at the start of `text`: a project of two or more letters and digits starting with a letter,
+/// then `-` and a number, which isn't followed by more letters or digits.
+/// `gh-123` is left out, since that names a GitHub issue instead.
+fn parse_key(text: &str) -> Option<&str> {
+    let (project, rest) = text.split_once('-')?;
+    let number_len = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let after = &rest[number_len..];
+    let valid = project.len() >= 2
+        && project.starts_with(|c: char| c.is_ascii_alphabetic())
+        && project.chars().all(|c| c.is_ascii_alphanumeric())
+        && !project.eq_ignore_ascii_case("gh")
+        && number_len > 0
+        && !after.starts_with(|c: char| c.is_ascii_alphanumeric());
+    valid.then(|| &text[..project.len() + 1 + number_len])
+}
+
+/// Fills in a `submit.ticket-title` pattern, like `[{key}] {title}`,
+/// unless `title` already mentions `key`.
+pub fn title_with_key(pattern: &str, key: &str, title: &str) -> String {
+    if title.contains(key) {
+        return title.to_owned();
+    }
+    pattern.replace("{key}", key).replace("{title}", title)
+}
+
+/// Adds a link to the ticket, from a `submit.ticket-url` pattern like `https://acme.atlassian.net/browse/{key}`,
+/// to the end of `body`.
+pub fn body_with_link(body: &str, url_pattern: &str, key: &str) -> String {
+    let url = url_pattern.replace("{key}", key);
+    if body.contains(&url) {
+        return body.to_owned();
+    }
+    let link = format!("Ticket: [{key}]({url})");
+    let body = body.trim_end();
+    if body.is_empty() {
+        link
+    } else {
+        format!("{body}\n\n{link}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_in_message() {
+        assert_eq!(
+            find_in_message("ABC-123: Fix the leak", &[]).as_deref(),
+            Some("ABC-123")
+        );
+        assert_eq!(
+            find_in_message("Fix the leak\n\nRefs (PROJ2-7).", &[]).as_deref(),
+            Some("PROJ2-7")
+        );
+        assert_eq!(find_in_message("Fix abc-123 and GH-4", &[]), None);
+        assert_eq!(find_in_message("Bump to UTF-8X and X-1", &[]), None);
+        assert_eq!(
+            find_in_message("Support UTF-8 for ABC-123", &["abc".to_owned()]).as_deref(),
+            Some("ABC-123")
+        );
+    }
+
+    #[test]
+    fn test_find_in_branch() {
+        let projects = ["ABC".to_owned()];
+        assert_eq!(
+            find_in_branch("ch/abc-123-fix-leak", &projects).as_deref(),
+            Some("ABC-123")
+        );
+        assert_eq!(
+            find_in_branch("ABC-123", &projects).as_deref(),
+            Some("ABC-123")
+        );
+        assert_eq!(find_in_branch("ch/abc-123-fix-leak", &[]), None);
+        assert_eq!(find_in_branch("ch/utf-8-support", &projects), None);
+        assert_eq!(find_in_branch("ch/fix-leak-2", &projects), None);
+        assert_eq!(find_in_branch("ch/fix-leak-abc-123", &projects), None);
+        assert_eq!(find_in_branch("ch/gh-123-fix-leak", &projects), None);
+    }
+
+    #[test]
+    fn test_title_with_key() {
+        assert_eq!(
+            title_with_key("[{key}] {title}", "ABC-123", "Fix the leak"),
+            "[ABC-123] Fix the leak"
+        );
+        assert_eq!(
+            title_with_key("[{key}] {title}", "ABC-123", "ABC-123: Fix the leak"),
+            "ABC-123: Fix the leak"
+        );
+    }
+
+    #[test]
+    fn test_body_with_link() {
+        let url = "https://acme.atlassian.net/browse/{key}";
+        let body = body_with_link("Fixes a leak.", url, "ABC-123");
+        assert_eq!(
+            body,
+            "Fixes a leak.\n\nTicket: [ABC-123](https://acme.atlassian.net/browse/ABC-123)"
+        );
+        assert_eq!(body_with_link(&body, url, "ABC-123"), body);
+    }
+}